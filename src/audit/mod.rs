@@ -0,0 +1,154 @@
+use std::fs::{metadata, read_to_string, rename, OpenOptions};
+use std::io::{Error, ErrorKind, Write};
+use std::path::Path;
+
+use data_encoding::{BASE64_NOPAD, HEXLOWER};
+use ring::digest::{digest, SHA256};
+
+use crate::{AUDIT_LOG, BASE_DIRECTORY};
+
+// Written regardless of the log level, it lives outside of the temporary folder
+const AUDIT_LOG_FILE: &str = "audit.log";
+// The audit log is rotated to `audit.log.1` once it grows past this size, the oldest rotated file is `audit.log.<AUDIT_LOG_ROTATIONS>`
+const AUDIT_LOG_MAX_SIZE: u64 = 1024 * 1024;
+const AUDIT_LOG_ROTATIONS: u32 = 5;
+// `prev` of the first entry ever written
+const GENESIS_HASH: &str = "0000000000000000000000000000000000000000000000000000000000000000";
+
+/**
+ * One line of the audit log.
+ * Every entry carries the SHA256 hash (`prev`) of the entry written before it, including across rotated files,
+ *     so removing or editing an entry breaks the chain of every entry after it.
+ */
+#[derive(Serialize, Debug)]
+struct AuditEntry<'a> {
+    time: String,
+    client: &'a str,
+    topic: &'a str,
+    command: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    request: Option<&'a str>,
+    data: &'a str,
+    outcome: &'a str,
+    prev: String,
+}
+
+/**
+ * Appends a command received by the mqtt `client` ("component" or "neutron") on `topic` and its `outcome` to the audit log.
+ * Sensitive `data` has to be passed through `fingerprint()` by the caller.
+ * Failing to write the audit log is logged, it never stops a command from being processed.
+ *
+ * Mutex `AUDIT_LOG` is locked for the duration of the write, so the entries are chained in the order they are written.
+ */
+pub fn record(client: &str, topic: &str, command: &str, request: Option<&str>, data: &str, outcome: &str) {
+    let mut last_hash = match AUDIT_LOG.lock() {
+        Ok(last_hash) => last_hash,
+        Err(_) => {
+            error!("Could not lock AUDIT_LOG mutex. Command not audited: {}", command);
+            return;
+        }
+    };
+
+    if last_hash.is_none() {
+        *last_hash = Some(load_last_hash());
+    }
+
+    let entry = AuditEntry {
+        time: chrono::Utc::now().format("%Y-%m-%dT%H:%M:%S%.3fZ").to_string(),
+        client,
+        topic,
+        command,
+        request,
+        data,
+        outcome,
+        prev: last_hash.clone().unwrap_or_default(),
+    };
+
+    let line = match serde_json::to_string(&entry) {
+        Ok(line) => line,
+        Err(e) => {
+            error!("Could not serialize the audit log entry. {}", e);
+            return;
+        }
+    };
+
+    if let Err(e) = append(&line) {
+        error!("Could not write the audit log. Command not audited: {} {}", command, e);
+        return;
+    }
+
+    *last_hash = Some(hash_entry(&line));
+}
+
+/**
+ * Returns the fingerprint ("SHA256:<unpadded base64>") of sensitive command data, it's stored instead of the data.
+ */
+pub fn fingerprint(data: &str) -> String {
+    ["SHA256:", &BASE64_NOPAD.encode(digest(&SHA256, data.as_bytes()).as_ref())].concat()
+}
+
+/**
+ * Appends the `line` to the audit log, rotating the log first if it's grown past `AUDIT_LOG_MAX_SIZE`.
+ */
+fn append(line: &str) -> Result<(), Error> {
+    let log_path = get_log_path();
+
+    if let Ok(meta) = metadata(&log_path) {
+        if meta.len() >= AUDIT_LOG_MAX_SIZE {
+            rotate(&log_path)?;
+        }
+    }
+
+    let mut file = OpenOptions::new().create(true).append(true).open(&log_path)?;
+    file.write_all([line, "\n"].concat().as_bytes())
+}
+
+/**
+ * Shifts the rotated audit logs up by one (dropping the oldest one) and moves the current log to `audit.log.1`.
+ */
+fn rotate(log_path: &str) -> Result<(), Error> {
+    for index in (1..AUDIT_LOG_ROTATIONS).rev() {
+        let rotated = format!("{}.{}", log_path, index);
+        if Path::new(&rotated).exists() {
+            rename(&rotated, format!("{}.{}", log_path, index + 1))?;
+        }
+    }
+
+    rename(log_path, format!("{}.1", log_path))?;
+    info!("Audit log rotated.");
+
+    Ok(())
+}
+
+/**
+ * Returns the hash of the last entry in the audit log (or in the last rotated log, if the current one doesn't exist).
+ * If there are no entries, `GENESIS_HASH` is returned.
+ */
+fn load_last_hash() -> String {
+    let log_path = get_log_path();
+
+    for path in &[log_path.to_owned(), format!("{}.1", log_path)] {
+        match read_to_string(path) {
+            Ok(contents) => {
+                if let Some(line) = contents.lines().rev().find(|line| !line.trim().is_empty()) {
+                    return hash_entry(line);
+                }
+            }
+            Err(e) if e.kind() == ErrorKind::NotFound => continue,
+            Err(e) => {
+                error!("Could not read the audit log, the hash chain is restarted. {}", e);
+                break;
+            }
+        }
+    }
+
+    GENESIS_HASH.to_owned()
+}
+
+fn hash_entry(line: &str) -> String {
+    HEXLOWER.encode(digest(&SHA256, line.as_bytes()).as_ref())
+}
+
+fn get_log_path() -> String {
+    [BASE_DIRECTORY, AUDIT_LOG_FILE].concat()
+}
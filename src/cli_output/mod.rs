@@ -0,0 +1,132 @@
+use std::io::{Error, ErrorKind};
+
+use serde_json::json;
+
+// Exit codes of the CLI subcommands, every failure class has its own code so scripts don't have to parse the output
+pub const EXIT_SUCCESS: i32 = 0;
+pub const EXIT_FAILURE: i32 = 1; // The operation failed
+pub const EXIT_SETTINGS: i32 = 2; // The settings file could not be loaded
+pub const EXIT_INVALID_INPUT: i32 = 3; // An argument or a settings value is invalid
+pub const EXIT_NOT_FOUND: i32 = 4; // A component, certificate, file or binary doesn't exist
+pub const EXIT_PERMISSION_DENIED: i32 = 5;
+pub const EXIT_CHECK_FAILED: i32 = 6; // The command ran, but one of its checks/items failed
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OutputFormat {
+    Text,
+    Json,
+}
+
+impl OutputFormat {
+    /**
+     * Maps the value of the `--output` argument to an `OutputFormat`, anything but "json" is `Text`.
+     */
+    pub fn from_arg(value: Option<&str>) -> Self {
+        match value {
+            Some("json") => OutputFormat::Json,
+            _ => OutputFormat::Text,
+        }
+    }
+}
+
+/**
+ * Result of a CLI subcommand.
+ * In the text format, `text` is printed to stdout and `message` is logged.
+ * In the JSON format, one object with the status, exit code, message and `data` is printed to stdout instead.
+ */
+#[derive(Debug)]
+pub struct CliOutcome {
+    pub exit_code: i32,
+    pub message: String,
+    pub data: serde_json::Value,
+    pub text: Option<String>,
+}
+
+impl CliOutcome {
+    pub fn success(message: &str) -> Self {
+        Self {
+            exit_code: EXIT_SUCCESS,
+            message: message.to_owned(),
+            data: serde_json::Value::Null,
+            text: None,
+        }
+    }
+
+    pub fn failure(exit_code: i32, message: &str) -> Self {
+        Self {
+            exit_code,
+            message: message.to_owned(),
+            data: serde_json::Value::Null,
+            text: None,
+        }
+    }
+
+    /**
+     * Failure with the exit code matching the kind of the `error` (see `exit_code_for()`).
+     */
+    pub fn from_error(error: &Error) -> Self {
+        Self::failure(exit_code_for(error), &error.to_string())
+    }
+
+    /**
+     * The settings file could not be loaded, `settings::init()` already logged why.
+     */
+    pub fn settings_error() -> Self {
+        Self::failure(EXIT_SETTINGS, "Could not load the settings file.")
+    }
+
+    pub fn with_data(mut self, data: serde_json::Value) -> Self {
+        self.data = data;
+        self
+    }
+
+    pub fn with_text(mut self, text: String) -> Self {
+        self.text = Some(text);
+        self
+    }
+}
+
+/**
+ * Maps the kind of an `Error` returned by a subcommand to an exit code.
+ */
+pub fn exit_code_for(error: &Error) -> i32 {
+    match error.kind() {
+        ErrorKind::InvalidInput | ErrorKind::InvalidData | ErrorKind::AlreadyExists => EXIT_INVALID_INPUT,
+        ErrorKind::NotFound => EXIT_NOT_FOUND,
+        ErrorKind::PermissionDenied => EXIT_PERMISSION_DENIED,
+        _ => EXIT_FAILURE,
+    }
+}
+
+/**
+ * Prints the `outcome` in the requested `format` and exits with its exit code.
+ * Logs go to stderr, so in the JSON format stdout only carries the result object.
+ */
+pub fn exit(format: OutputFormat, outcome: CliOutcome) -> ! {
+    match format {
+        OutputFormat::Text => {
+            if let Some(text) = &outcome.text {
+                println!("{}", text);
+            }
+
+            if outcome.exit_code == EXIT_SUCCESS {
+                if !outcome.message.is_empty() {
+                    info!("{}", outcome.message);
+                }
+            } else {
+                error!("{}", outcome.message);
+            }
+        }
+        OutputFormat::Json => {
+            let result = json!({
+                "status": if outcome.exit_code == EXIT_SUCCESS { "success" } else { "error" },
+                "exit_code": outcome.exit_code,
+                "message": outcome.message,
+                "data": outcome.data,
+            });
+            println!("{}", result);
+        }
+    }
+
+    std::process::exit(outcome.exit_code);
+}
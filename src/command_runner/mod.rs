@@ -0,0 +1,338 @@
+use std::cell::RefCell;
+use std::fs::{copy, read_to_string};
+use std::io::{Error, ErrorKind, Read, Write};
+use std::os::unix::process::{CommandExt, ExitStatusExt};
+use std::path::{Path, PathBuf};
+use std::process::{Command, ExitStatus, Output, Stdio};
+use std::thread;
+use std::time::Duration;
+
+use nix::sys::signal::{killpg, Signal};
+use nix::unistd::Pid;
+use tempfile::NamedTempFile;
+use wait_timeout::ChildExt;
+
+/**
+ * Runs the external commands (openssl, chmod, chown, sh...) NECO depends on.
+ * Functions that shell out receive a `CommandRunner` instead of calling `Command::output()` themselves,
+ *     so the commands can be inspected (`Command::get_program()`, `Command::get_args()`) or faked instead of executed.
+ */
+pub trait CommandRunner {
+    fn output(&self, command: &mut Command) -> Result<Output, Error>;
+
+    /**
+     * Like `output()`, but the command is killed if it doesn't exit within `timeout`, returning an error of kind `TimedOut`.
+     * The processes the command started are killed with it, e.g. the actual command of a `sh -c` wrapper.
+     * The command gets no stdin, so commands waiting for input fail instead of hanging.
+     */
+    fn output_timeout(&self, command: &mut Command, timeout: Duration) -> Result<Output, Error>;
+
+    /**
+     * Copies the file `from` to `to` (`std::fs::copy()`), e.g. a key or certificate to its auxiliary paths.
+     * Part of the runner so dry runs record the copy instead of writing to the target path.
+     */
+    fn copy_file(&self, from: &str, to: &str) -> Result<u64, Error> {
+        copy(from, to)
+    }
+
+    /**
+     * Writes `contents` to a new temporary file for a command to read, e.g. an openssl `-extfile`.
+     * Part of the runner so dry runs record the contents instead of writing the file.
+     */
+    fn temp_file(&self, contents: &str) -> Result<TempFile, Error> {
+        let mut file = NamedTempFile::new()?;
+        file.write_all(contents.as_bytes())?;
+
+        Ok(TempFile {
+            path: file.path().to_owned(),
+            _file: Some(file),
+        })
+    }
+}
+
+/**
+ * A temporary file created by `CommandRunner::temp_file()`, it is removed when dropped.
+ * Keep it alive until the command reading it has ran.
+ */
+pub struct TempFile {
+    path: PathBuf,
+    _file: Option<NamedTempFile>,
+}
+
+impl TempFile {
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+/**
+ * `CommandRunner` used at runtime, commands are executed on the system.
+ */
+pub struct SystemCommandRunner;
+
+impl CommandRunner for SystemCommandRunner {
+    fn output(&self, command: &mut Command) -> Result<Output, Error> {
+        command.output()
+    }
+
+    fn output_timeout(&self, command: &mut Command, timeout: Duration) -> Result<Output, Error> {
+        // In its own process group, so a timeout kills everything the command started
+        let mut child = command
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .process_group(0)
+            .spawn()?;
+
+        // The pipes are drained while waiting, a command that fills up a pipe would never exit
+        let stdout = drain_pipe(child.stdout.take());
+        let stderr = drain_pipe(child.stderr.take());
+
+        match child.wait_timeout(timeout)? {
+            Some(status) => Ok(Output {
+                status,
+                stdout: stdout.join().unwrap_or_default(),
+                stderr: stderr.join().unwrap_or_default(),
+            }),
+            None => {
+                // The process group id is the pid of the child
+                if killpg(Pid::from_raw(child.id() as i32), Signal::SIGKILL).is_err() {
+                    child.kill()?;
+                }
+                child.wait()?;
+
+                Err(Error::new(
+                    ErrorKind::TimedOut,
+                    format!("Command timed out after {} seconds.", timeout.as_secs()),
+                ))
+            }
+        }
+    }
+}
+
+/**
+ * `CommandRunner` used for dry runs, the commands are recorded instead of executed and reported as successful with no output.
+ * File copies are recorded as `cp <from> <to>`, temporary files are not written and get a placeholder path.
+ * Passphrases passed as `pass:<passphrase>` arguments are redacted in the record,
+ *     the contents of `-extfile` files are recorded with the command so the extensions can be reviewed.
+ */
+#[derive(Default)]
+pub struct DryRunCommandRunner {
+    commands: RefCell<Vec<String>>,
+    // Placeholder paths of the temporary files with their contents
+    temp_files: RefCell<Vec<(String, String)>>,
+}
+
+impl DryRunCommandRunner {
+    /**
+     * Returns the recorded commands in the order they would have been executed.
+     */
+    pub fn commands(&self) -> Vec<String> {
+        self.commands.borrow().clone()
+    }
+
+    fn record(&self, command: &Command) {
+        let mut line = command.get_program().to_string_lossy().into_owned();
+        let mut extfiles: Vec<String> = Vec::new();
+        let mut previous_arg = String::new();
+
+        for arg in command.get_args() {
+            let arg = arg.to_string_lossy();
+
+            if arg.starts_with("pass:") {
+                line.push_str(" pass:<redacted>");
+            } else if arg.is_empty() || arg.contains(char::is_whitespace) {
+                line.push_str(&[" '", &arg, "'"].concat());
+            } else {
+                line.push_str(&[" ", arg.as_ref()].concat());
+            }
+
+            if previous_arg == "-extfile" {
+                extfiles.push(arg.to_string());
+            }
+            previous_arg = arg.into_owned();
+        }
+
+        for extfile in extfiles {
+            let temp_file = self.temp_files.borrow().iter().find(|(path, _)| *path == extfile).cloned();
+            let contents = match temp_file {
+                Some((_, contents)) => contents,
+                None => read_to_string(&extfile).unwrap_or_else(|e| format!("<could not read: {}>", e)),
+            };
+            line.push_str(&["\n  ", &extfile, ":\n    ", &contents.trim().replace('\n', "\n    ")].concat());
+        }
+
+        info!("Dry run: {}", line);
+        self.commands.borrow_mut().push(line);
+    }
+}
+
+impl CommandRunner for DryRunCommandRunner {
+    fn output(&self, command: &mut Command) -> Result<Output, Error> {
+        self.record(command);
+
+        Ok(Output {
+            status: ExitStatus::from_raw(0),
+            stdout: Vec::new(),
+            stderr: Vec::new(),
+        })
+    }
+
+    fn output_timeout(&self, command: &mut Command, _timeout: Duration) -> Result<Output, Error> {
+        self.output(command)
+    }
+
+    fn copy_file(&self, from: &str, to: &str) -> Result<u64, Error> {
+        self.record(Command::new("cp").arg(from).arg(to));
+
+        Ok(0)
+    }
+
+    fn temp_file(&self, contents: &str) -> Result<TempFile, Error> {
+        let mut temp_files = self.temp_files.borrow_mut();
+        let path = format!("<temp_file_{}>", temp_files.len() + 1);
+        temp_files.push((path.to_owned(), contents.to_owned()));
+
+        Ok(TempFile {
+            path: PathBuf::from(path),
+            _file: None,
+        })
+    }
+}
+
+/**
+ * `CommandRunner` for tests, the commands are recorded and answered with the queued results in order.
+ * Once the queue runs out, commands succeed with no output.
+ */
+#[cfg(test)]
+#[derive(Default)]
+pub struct FakeCommandRunner {
+    results: RefCell<std::collections::VecDeque<Result<Output, Error>>>,
+    commands: RefCell<Vec<String>>,
+}
+
+#[cfg(test)]
+impl FakeCommandRunner {
+    /**
+     * Queues a command that exits with `code` and writes `stdout`/`stderr`.
+     */
+    pub fn respond(self, code: i32, stdout: &str, stderr: &str) -> Self {
+        self.results.borrow_mut().push_back(Ok(Output {
+            status: ExitStatus::from_raw(code << 8),
+            stdout: stdout.as_bytes().to_vec(),
+            stderr: stderr.as_bytes().to_vec(),
+        }));
+        self
+    }
+
+    /**
+     * Queues a command that can't be ran, e.g. `TimedOut` for a command killed by `output_timeout()`.
+     */
+    pub fn fail(self, kind: ErrorKind) -> Self {
+        self.results.borrow_mut().push_back(Err(Error::new(kind, "Fake command failure.")));
+        self
+    }
+
+    /**
+     * Returns the recorded commands, the program and its arguments separated by spaces.
+     */
+    pub fn commands(&self) -> Vec<String> {
+        self.commands.borrow().clone()
+    }
+}
+
+#[cfg(test)]
+impl CommandRunner for FakeCommandRunner {
+    fn output(&self, command: &mut Command) -> Result<Output, Error> {
+        let line = std::iter::once(command.get_program())
+            .chain(command.get_args())
+            .map(|x| x.to_string_lossy().into_owned())
+            .collect::<Vec<String>>()
+            .join(" ");
+        self.commands.borrow_mut().push(line);
+
+        self.results.borrow_mut().pop_front().unwrap_or_else(|| {
+            Ok(Output {
+                status: ExitStatus::from_raw(0),
+                stdout: Vec::new(),
+                stderr: Vec::new(),
+            })
+        })
+    }
+
+    fn output_timeout(&self, command: &mut Command, _timeout: Duration) -> Result<Output, Error> {
+        self.output(command)
+    }
+}
+
+/**
+ * Describes why the command that produced `output` failed, for commands that exited with a non-zero code.
+ * Many tools write to stderr even when they succeed, so only the exit status decides if a command failed,
+ *     stderr is only used to explain the failure.
+ */
+pub fn failure_reason(output: &Output) -> String {
+    let stderr = String::from_utf8_lossy(&output.stderr);
+
+    if stderr.trim().is_empty() {
+        match output.status.code() {
+            Some(code) => format!("Exited with code {}.", code),
+            None => String::from("Killed by a signal."),
+        }
+    } else {
+        stderr.trim().to_owned()
+    }
+}
+
+/**
+ * Reads `pipe` to the end on a separate thread, the thread returns everything that was read.
+ */
+fn drain_pipe<R: Read + Send + 'static>(pipe: Option<R>) -> thread::JoinHandle<Vec<u8>> {
+    thread::spawn(move || {
+        let mut buffer = Vec::new();
+        if let Some(mut pipe) = pipe {
+            let _ = pipe.read_to_end(&mut buffer);
+        }
+        buffer
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn output_timeout_kills_the_processes_the_command_started() {
+        let dir = tempfile::tempdir().unwrap();
+        let pid_file = dir.path().join("sleep.pid");
+        let mut command = Command::new("sh");
+        command.arg("-c").arg(format!("sleep 30 & echo $! > {}; wait", pid_file.display()));
+
+        let e = SystemCommandRunner
+            .output_timeout(&mut command, Duration::from_secs(1))
+            .unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::TimedOut);
+
+        // The orphaned sleep is gone or a zombie waiting for init to reap it
+        let pid = read_to_string(&pid_file).unwrap();
+        thread::sleep(Duration::from_millis(200));
+        let state = read_to_string(["/proc/", pid.trim(), "/stat"].concat()).unwrap_or_default();
+        assert!(state.is_empty() || state.contains(") Z "), "{}", state);
+    }
+
+    #[test]
+    fn dry_run_records_temp_files_instead_of_writing_them() {
+        let runner = DryRunCommandRunner::default();
+
+        let extfile = runner.temp_file("[SAN]\nsubjectAltName=IP:10.0.0.1").unwrap();
+        assert!(!extfile.path().exists());
+
+        runner
+            .output(Command::new("openssl").args(["x509", "-extfile"]).arg(extfile.path()))
+            .unwrap();
+
+        assert_eq!(
+            runner.commands(),
+            vec!["openssl x509 -extfile <temp_file_1>\n  <temp_file_1>:\n    [SAN]\n    subjectAltName=IP:10.0.0.1"]
+        );
+    }
+}
@@ -1,13 +1,13 @@
 // #![allow(dead_code)]
 
+use std::collections::BTreeMap;
 use std::convert::TryInto;
 use std::fs;
-use std::io::{Error, ErrorKind, Write};
+use std::io::{Error, ErrorKind};
 use std::ops::Sub;
+use std::path::{Path, PathBuf};
 use std::process::Command;
-use std::time::{SystemTime, UNIX_EPOCH};
-
-use tempfile::NamedTempFile;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 use std::thread;
 use std::thread::JoinHandle;
@@ -19,22 +19,77 @@ use chrono::prelude::NaiveDateTime;
 use rand::prelude::thread_rng;
 use rand::seq::SliceRandom;
 
+use data_encoding::BASE64;
+use ring::digest::{digest, SHA256};
+
+use crate::command_runner::{failure_reason, CommandRunner, SystemCommandRunner};
+use crate::settings;
+use crate::settings::passphrases;
 use crate::settings::encryption_certificates::save_certificates;
-use crate::settings::structs::{CACertificate, CertificateSettings};
+use crate::settings::structs::{CACertificate, CertificatePaths, CertificateSettings, PassphrasePolicy};
 
-use crate::RESTART_NECO;
+use crate::mqtt_connection::is_component_client_certificate;
+use crate::{BASE_DIRECTORY, CERTIFICATES, RECONNECT_COMPONENT_MQTT, RESTART_NECO, SETTINGS};
 
 pub mod structs;
+use structs::CertificateFingerprint;
 
 const WATCHDOG_TIMEOUT: u64 = 24 * 60 * 60;
+// The watchdog wakes up this often to check if NECO is restarting or the watchdog was paused/resumed
+const WATCHDOG_POLL_INTERVAL: u64 = 30;
+// While this file exists in the base directory, the watchdog skips the renewal checks. It contains who paused the watchdog.
+const WATCHDOG_PAUSE_FILE: &str = "cert_watchdog.paused";
 
-const CHARSET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ\
+// Markers of the certificates in a PEM file
+const PEM_CERTIFICATE_BEGIN: &str = "-----BEGIN CERTIFICATE-----";
+const PEM_CERTIFICATE_END: &str = "-----END CERTIFICATE-----";
+
+pub const CHARSET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ\
     abcdefghijklmnopqrstuvwxyz\
     0123456789";
-const PASSPHRASE_LENGTH: u16 = 20; // 0 - 65535
+pub const PASSPHRASE_LENGTH: u16 = 20; // 1 - 65535
+
+// Curves accepted in the `ec:<curve>` key algorithm
+const SUPPORTED_EC_CURVES: [&str; 3] = ["prime256v1", "secp384r1", "secp521r1"];
+const MIN_RSA_KEY_LENGTH: u32 = 1024;
+const MAX_RSA_KEY_LENGTH: u32 = 16384;
+
+// Extensions every intermediate CA certificate is signed with, written to a temporary extfile
+const INTERMEDIATE_CA_EXTENSIONS: &str = "[INTERMEDIATE_CA]
+basicConstraints=critical,CA:TRUE
+keyUsage=critical,digitalSignature,cRLSign,keyCertSign
+subjectKeyIdentifier=hash
+authorityKeyIdentifier=keyid:always,issuer
+";
+
+// Component names with the result of regenerating their certificates
+pub type RegenerationResults = Vec<(String, Result<(), Error>)>;
+
+// Held by whoever renews certificates, the watchdog and the `renew_certificate` subcommand run in different processes
+const RENEWAL_LOCK_FILE: &str = "cert_renewal.lock";
+// Appended to the key and certificate paths when backing them up before a regeneration
+const CERTIFICATE_BACKUP_EXTENSION: &str = ".bak";
+const RENEWAL_LOCK_TIMEOUT: Duration = Duration::from_secs(60);
+// A lock older than this was left behind by a process that died while renewing
+const RENEWAL_LOCK_STALE: Duration = Duration::from_secs(10 * 60);
+
+/**
+ * Removes the renewal lock file when dropped.
+ */
+pub struct RenewalLock {
+    path: String,
+}
+
+impl Drop for RenewalLock {
+    fn drop(&mut self) {
+        if let Err(e) = fs::remove_file(&self.path) {
+            error!("Could not remove the certificate renewal lock. {}", e);
+        }
+    }
+}
 
 /**
- * Checks if all certificates/keys exist, if something is missing; certificate generation is ran (could be a CA cert or a child certificate).
+ * Checks if all certificates/keys exist and are intact (`is_certificate_intact()`), if something is missing or corrupt; certificate generation is ran (could be a CA cert or a child certificate).
  * Each certificate generation function returns the generated key passphrase which is then updated in the vector upon its return.
  * Before pushing the `CertificateSettings` struct to the `valid_certs` vector, we get the date last-modified of the crt file and save it to the struct.
  *     That way the certificate watchdog can periodically compare the current date with the `date-issued` and decide if the certificate needs renewal.
@@ -43,29 +98,86 @@ const PASSPHRASE_LENGTH: u16 = 20; // 0 - 65535
  * Before calling `start_watchdog()`, we call a settings function for saving the certificates to the settings file `settings::save_certificates`.
  *     All certificates get saved - the ones that error-out and the ones successfully generated.
  *
+ * Returns an error right away if the openssl binary can't be ran (`check_openssl()`).
+ *
  * Channels the return value from `start_watchdog()`.
  */
 pub fn init(certificates: &[CertificateSettings]) -> Result<JoinHandle<()>, Error> {
     info!("Initializing certificate watchdog...");
 
+    // Nothing below works without openssl, fail with a clear reason instead of failing on every certificate
+    match check_openssl(&SystemCommandRunner) {
+        Ok(version) => debug!("Using {}.", version),
+        Err(e) => return Err(e),
+    }
+
+    prepare_certificates(certificates)?;
+
+    start_watchdog()
+}
+
+/**
+ * Hands the `certificates` of reloaded settings to the running watchdog, generating the ones that are missing
+ *     the same way `init()` does, so certificates added to the settings file are managed without a restart.
+ * The renewal lock is held for the duration, so this never races with the watchdog.
+ * While the watchdog is paused nothing is generated, the watchdog picks the certificates up once it's resumed.
+ */
+pub fn reload(certificates: &[CertificateSettings]) -> Result<(), Error> {
+    if let Some(paused_by) = watchdog_paused_by() {
+        info!("Certificate watchdog is paused by {}. Not reloading the certificates.", paused_by);
+        return Ok(());
+    }
+
+    let _renewal_lock = lock_renewal()?;
+
+    prepare_certificates(certificates)
+}
+
+/**
+ * Checks, generates and dates the `certificates` (see `init()`), saves them and shares the valid ones with the watchdog.
+ */
+fn prepare_certificates(certificates: &[CertificateSettings]) -> Result<(), Error> {
+    // The settings file may have been edited by hand, nothing stopped it from reusing a path
+    for collision in find_certificate_path_collisions(certificates) {
+        warn!("Certificate path collision! {} One renewal will overwrite the other's file.", collision);
+    }
+
     let mut all_certs: Vec<CertificateSettings> = certificates.to_vec();
 
     let mut valid_certs: Vec<CertificateSettings> = Vec::new();
 
-    for mut cert in &mut all_certs {
+    'certs: for cert in &mut all_certs {
+        // Set if the main certificate gets (re)generated, the PKCS#12 bundle has to be exported again
+        let mut cert_generated = false;
+        // Set if a CA gets (re)generated, every certificate below it in the chain has to be generated again
+        let mut issuer_generated = false;
+
+        if !cert.intermediate_cas.is_empty() && cert.cert_authority.is_none() {
+            error!(
+                "Intermediate CAs of component {} need a CA certificate to be signed with. Skipping certificate...",
+                &cert.component_name
+            );
+            continue;
+        }
+
         if let Some(ca) = cert.cert_authority.as_mut() {
-            if fs::metadata(&ca.main_paths.cert).is_err()
-                || fs::metadata(&ca.main_paths.key).is_err()
-            {
-                match generate_ca(&cert.component_name, ca, false) {
+            let intact =
+                match is_certificate_intact(&SystemCommandRunner, &ca.main_paths, ca.encrypted, &ca.passphrase, &cert.component_name) {
+                    Ok(intact) => intact,
+                    Err(e) => {
+                        error!("{} Skipping certificate...", e);
+                        continue;
+                    }
+                };
+
+            if !intact {
+                backup_certificate_files(&ca.main_paths)?;
+
+                match generate_ca(&SystemCommandRunner, &cert.component_name, ca, &cert.passphrase_policy, false) {
                     Ok(passphrase) => {
                         // Update the passphrase so we can use it when generating a signed certificate
                         ca.passphrase = passphrase;
-
-                        match generate_certificate(cert, false) {
-                            Ok(pass) => cert.main_certificate.passphrase = pass,
-                            Err(e) => return Err(e),
-                        }
+                        issuer_generated = true;
                     }
                     Err(e) => return Err(e),
                 }
@@ -75,7 +187,7 @@ pub fn init(certificates: &[CertificateSettings]) -> Result<JoinHandle<()>, Erro
                     {
                         // If function returns Ok, break the loop since we're going to copy the cert/key to all aux locations
                         // Calling generate_ca(just_populate_aux = true) will skip creating a CA cert/key and will just distribute certs/keys to auxiliary paths
-                        if let Err(e) = generate_ca(&cert.component_name, ca, true) {
+                        if let Err(e) = generate_ca(&SystemCommandRunner, &cert.component_name, ca, &cert.passphrase_policy, true) {
                             return Err(e);
                         } else {
                             break;
@@ -98,16 +210,89 @@ pub fn init(certificates: &[CertificateSettings]) -> Result<JoinHandle<()>, Erro
             }
         }
 
+        // Intermediate CAs are checked from the top of the chain down, each one is signed by the one before it
+        for index in 0..cert.intermediate_cas.len() {
+            let issuer = intermediate_issuer(cert, index).cloned().unwrap();
+            let ca = &mut cert.intermediate_cas[index];
+
+            let intact = issuer_generated
+                || match is_certificate_intact(
+                    &SystemCommandRunner,
+                    &ca.main_paths,
+                    ca.encrypted,
+                    &ca.passphrase,
+                    &cert.component_name,
+                ) {
+                    Ok(intact) => intact,
+                    Err(e) => {
+                        error!("{} Skipping certificate...", e);
+                        continue 'certs;
+                    }
+                };
+
+            if issuer_generated || !intact {
+                backup_certificate_files(&ca.main_paths)?;
+
+                ca.passphrase = generate_intermediate_ca(
+                    &SystemCommandRunner,
+                    &cert.component_name,
+                    ca,
+                    &issuer,
+                    &cert.passphrase_policy,
+                    false,
+                )?;
+                issuer_generated = true;
+            } else if ca
+                .auxiliary_paths
+                .iter()
+                .any(|aux_path| fs::metadata(&aux_path.key).is_err() || fs::metadata(&aux_path.cert).is_err())
+            {
+                generate_intermediate_ca(
+                    &SystemCommandRunner,
+                    &cert.component_name,
+                    ca,
+                    &issuer,
+                    &cert.passphrase_policy,
+                    true,
+                )?;
+            }
+
+            if let Some(date) = get_date_issued(&ca.main_paths.cert) {
+                ca.date_issued = Some(date.to_string());
+            } else {
+                error!("Could not determine the intermediate CA certificate issue date. Skipping certificate...");
+                continue 'certs;
+            }
+        }
+
         // Check if the cert-key combo exist on the main path
-        if fs::metadata(&cert.main_certificate.main_paths.key).is_err()
-            || fs::metadata(&cert.main_certificate.main_paths.cert).is_err()
-        {
+        let intact = issuer_generated
+            || match is_certificate_intact(
+                &SystemCommandRunner,
+                &cert.main_certificate.main_paths,
+                cert.main_certificate.encrypted,
+                &cert.main_certificate.passphrase,
+                &cert.component_name,
+            ) {
+                Ok(intact) => intact,
+                Err(e) => {
+                    error!("{} Skipping certificate...", e);
+                    continue;
+                }
+            };
+
+        if issuer_generated || !intact {
+            backup_certificate_files(&cert.main_certificate.main_paths)?;
+
             // Generate new key-cert combo on the main and auxiliary paths
             // If the function returns Err, return init()
             // If we're Ok get the returned passphrase and update it in the cert
             //     struct so watchdog can renew without the need to restart NECO
-            match generate_certificate(cert, false) {
-                Ok(pass) => cert.main_certificate.passphrase = pass,
+            match generate_certificate(&SystemCommandRunner, cert, false) {
+                Ok(pass) => {
+                    cert.main_certificate.passphrase = pass;
+                    cert_generated = true;
+                }
                 Err(e) => return Err(e),
             }
         } else {
@@ -117,7 +302,7 @@ pub fn init(certificates: &[CertificateSettings]) -> Result<JoinHandle<()>, Erro
                 if fs::metadata(&aux_path.key).is_err() || fs::metadata(&aux_path.cert).is_err() {
                     // If function returns Ok, break the loop since we're going to copy the cert/key to all aux locations
                     // Calling generate_certificate(just_populate_aux = true) will skip creating a cert/key and will just distribute certs/keys to auxiliary paths
-                    if let Err(e) = generate_certificate(cert, true) {
+                    if let Err(e) = generate_certificate(&SystemCommandRunner, cert, true) {
                         return Err(e);
                     } else {
                         break;
@@ -126,6 +311,16 @@ pub fn init(certificates: &[CertificateSettings]) -> Result<JoinHandle<()>, Erro
             }
         }
 
+        // Export the chain file/PKCS#12 bundle if they're missing or the certificate in them is outdated
+        if cert_generated
+            || (!cert.main_certificate.chain_path.is_empty()
+                && fs::metadata(&cert.main_certificate.chain_path).is_err())
+            || (!cert.main_certificate.pkcs12_path.is_empty()
+                && fs::metadata(&cert.main_certificate.pkcs12_path).is_err())
+        {
+            export_certificate_bundles(&SystemCommandRunner, cert)?;
+        }
+
         // Calculate the exact time the certificate was created(last modified)
         if let Some(date) = get_date_issued(&cert.main_certificate.main_paths.cert) {
             cert.main_certificate.date_issued = Some(date.to_string());
@@ -140,7 +335,14 @@ pub fn init(certificates: &[CertificateSettings]) -> Result<JoinHandle<()>, Erro
         return Err(e);
     }
 
-    start_watchdog(valid_certs)
+    // Share the certificates with the watchdog
+    if let Ok(mut certificates) = CERTIFICATES.lock() {
+        *certificates = valid_certs;
+    } else {
+        return Err(Error::other("Could not lock CERTIFICATES mutex."));
+    }
+
+    Ok(())
 }
 
 /**
@@ -149,160 +351,257 @@ pub fn init(certificates: &[CertificateSettings]) -> Result<JoinHandle<()>, Erro
  *     gets the current time, calculates the difference in days then checks if the difference is >= `cert.duration - 10`.
  *     If it is, try to renew it (renewal by a CA or a key). If we, for some reason, fail renewing; continue the loop and write-out an error.
  *     If it is successful, update the `date-issued` key in the struct so we can compare against valid data.
+ * The certificates are taken from the shared `CERTIFICATES` vector, which is synced with the settings file before every check,
+ *     so certificates added, removed or renewed since NECO started are picked up.
+ * Every check is done while holding the renewal lock.
+ * While the watchdog is paused (see `pause_watchdog()`), the checks are skipped until it's resumed.
  * If the thread spawning failed, return an error containing the thread message.
  * If the thread spawning was successful, return the handle to the thread.
+ *
+ * Mutex `CERTIFICATES` is locked for the duration of every check.
  */
-fn start_watchdog(mut certificates: Vec<CertificateSettings>) -> Result<JoinHandle<()>, Error> {
+fn start_watchdog() -> Result<JoinHandle<()>, Error> {
     let watchdog = thread::Builder::new().name(String::from("CertWatchdog"));
 
     let handle = watchdog.spawn(move || loop {
-        for cert in &mut certificates {
-            // CA
-            if cert.cert_authority.is_some() {
-                let ca = cert.cert_authority.as_mut().unwrap();
-                let date_issued = ca.date_issued.as_ref().unwrap();
-
-                let parsed_date = NaiveDateTime::parse_from_str(date_issued, "%Y-%m-%d %H:%M:%S").unwrap();
-
-                // Get the number of days between todays date and the date obtained from the file
-                let difference_in_days = chrono::Utc::now()
-                    .naive_local()
-                    .signed_duration_since(parsed_date)
-                    .num_days();
-
-                // Check if the certificate is older than (duration - 10) days
-                if difference_in_days >= (ca.duration - 10) {
-                    warn!(
-                        "{} CA certificate needs renewal. Date issued: {}.",
-                        &cert.component_name, date_issued
-                    );
+        if let Some(paused_by) = watchdog_paused_by() {
+            debug!("Certificate watchdog is paused by {}. Skipping the renewal check.", paused_by);
 
-                    // Call the gen_csr_sign_with_key() and if it errors-out, log it.
-                    if let Err(e) = gen_csr_sign_with_key(
-                        &cert.component_name,
-                        &ca.main_paths.key,
-                        ca.encrypted,
-                        &ca.subj,
-                        &ca.passphrase,
-                        ca.duration,
-                        &ca.main_paths.cert,
-                    ) {
-                        error!("{}", e);
-                    } else {
-                        debug!(
-                            "Renewed CA certificate. Component: {}",
-                            &cert.component_name
-                        );
-
-                        // Update the date issued on the CA certificate
-                        if let Some(date) = get_date_issued(&ca.main_paths.cert) {
-                            ca.date_issued = Some(date.to_string());
-                        } else {
-                            error!("Could not determine the CA certificate issue date.");
+            if watchdog_sleep(WATCHDOG_POLL_INTERVAL) {
+                break;
+            }
+            continue;
+        }
+
+        // Certificates can't be renewed while someone else is renewing them
+        let renewal_lock = match lock_renewal() {
+            Ok(lock) => Some(lock),
+            Err(e) => {
+                error!("Skipping certificate renewal check. {}", e);
+                None
+            }
+        };
+
+        if renewal_lock.is_some() {
+            if let Ok(mut certificates) = CERTIFICATES.lock() {
+                refresh_certificates(&mut certificates);
+
+                for cert in certificates.iter_mut() {
+                    // Renewals need the current key passphrases, they're kept in the passphrase store
+                    if let Some(ca) = cert.cert_authority.as_mut() {
+                        if let Some(pass) = passphrases::lookup(&cert.component_name, "ca") {
+                            ca.passphrase = pass;
                         }
                     }
-                }
-            }
+                    for (index, ca) in cert.intermediate_cas.iter_mut().enumerate() {
+                        if let Some(pass) = passphrases::lookup(&cert.component_name, &passphrases::intermediate_key(index)) {
+                            ca.passphrase = pass;
+                        }
+                    }
+                    if let Some(pass) = passphrases::lookup(&cert.component_name, "main") {
+                        cert.main_certificate.passphrase = pass;
+                    }
 
-            // Main certificate
-            {
-                let date_issued = cert.main_certificate.date_issued.as_ref().unwrap();
-                // or
-                // let date_issued = if let Some(date_issued) = cert.date_issued.as_ref() {
-                //     date_issued
-                // } else {
-                //     thread::sleep(std::time::Duration::from_secs(WATCHDOG_TIMEOUT));
-                //     continue;
-                // };
-
-                let parsed_date =
-                    NaiveDateTime::parse_from_str(date_issued, "%Y-%m-%d %H:%M:%S").unwrap();
-
-                // Get the number of days between todays date and the date obtained from the file
-                let difference_in_days = chrono::Utc::now()
-                    .naive_local()
-                    .signed_duration_since(parsed_date)
-                    .num_days();
-
-                // Check if the certificate is older than (duration - 10) days
-                if difference_in_days >= (cert.main_certificate.duration - 10) {
-                    warn!(
-                        "{} certificate needs renewal. Date issued: {}.",
-                        &cert.component_name, date_issued
-                    );
+                    // CA
+                    if let Some(ca) = cert.cert_authority.as_mut() {
+                        let date_issued = ca.date_issued.as_ref().unwrap();
+
+                        let parsed_date = NaiveDateTime::parse_from_str(date_issued, "%Y-%m-%d %H:%M:%S").unwrap();
+
+                        // Get the number of days between todays date and the date obtained from the file
+                        let difference_in_days = chrono::Utc::now()
+                            .naive_local()
+                            .signed_duration_since(parsed_date)
+                            .num_days();
+
+                        // Check if the certificate is older than (duration - 10) days
+                        if difference_in_days >= (ca.duration - 10) {
+                            warn!(
+                                "{} CA certificate needs renewal. Date issued: {}.",
+                                &cert.component_name, date_issued
+                            );
+
+                            // Call the gen_csr_sign_with_key() and if it errors-out, log it.
+                            if let Err(e) = gen_csr_sign_with_key(
+                                &SystemCommandRunner,
+                                &cert.component_name,
+                                &ca.main_paths.key,
+                                ca.encrypted,
+                                &ca.subj,
+                                &ca.passphrase,
+                                ca.duration,
+                                &ca.main_paths.cert,
+                            ) {
+                                error!("{}", e);
+                            } else {
+                                debug!(
+                                    "Renewed CA certificate. Component: {}",
+                                    &cert.component_name
+                                );
+
+                                // Update the date issued on the CA certificate
+                                if let Some(date) = get_date_issued(&ca.main_paths.cert) {
+                                    ca.date_issued = Some(date.to_string());
+                                } else {
+                                    error!("Could not determine the CA certificate issue date.");
+                                }
+
+                                // The bundles contain the CA certificate, so they're outdated now
+                                if let Err(e) = export_certificate_bundles(&SystemCommandRunner, cert) {
+                                    error!("{}", e);
+                                }
+                            }
+                        }
+                    }
 
-                    // With this boolean we avoid code duplication and the use of the `continue`
-                    //     keyword that could cause a loop with no sleep between cycles
-                    let mut is_generated = true;
+                    // Intermediate CAs, each one is renewed (with its existing key) based on its own duration
+                    for index in 0..cert.intermediate_cas.len() {
+                        let issuer = match intermediate_issuer(cert, index) {
+                            Some(issuer) => issuer.clone(),
+                            None => break,
+                        };
+                        let ca = &mut cert.intermediate_cas[index];
+                        let date_issued = ca.date_issued.clone().unwrap_or_default();
+
+                        if needs_renewal(&date_issued, ca.duration) {
+                            warn!(
+                                "{} intermediate CA certificate needs renewal. Date issued: {}.",
+                                &cert.component_name, date_issued
+                            );
+
+                            if let Err(e) = renew_intermediate_ca(&SystemCommandRunner, &cert.component_name, ca, &issuer) {
+                                error!("{}", e);
+                            } else {
+                                debug!(
+                                    "Renewed intermediate CA certificate. Component: {}",
+                                    &cert.component_name
+                                );
+
+                                if let Some(date) = get_date_issued(&ca.main_paths.cert) {
+                                    ca.date_issued = Some(date.to_string());
+                                } else {
+                                    error!("Could not determine the intermediate CA certificate issue date.");
+                                }
 
-                    if cert.cert_authority.is_some() {
-                        if let Err(e) = gen_csr_sign_with_ca(cert, &cert.main_certificate.passphrase) {
-                            error!("{}", e);
-                            is_generated = false;
+                                if let Err(e) = export_certificate_bundles(&SystemCommandRunner, cert) {
+                                    error!("{}", e);
+                                }
+                            }
                         }
-                    } else if let Err(e) = gen_csr_sign_with_key(
-                        &cert.component_name,
-                        &cert.main_certificate.main_paths.key,
-                        cert.main_certificate.encrypted,
-                        &cert.main_certificate.subj,
-                        &cert.main_certificate.passphrase,
-                        cert.main_certificate.duration,
-                        &cert.main_certificate.main_paths.cert,
-                    ) {
-                        error!("{}", e);
-                        is_generated = false;
                     }
 
-                    if is_generated {
-                        debug!(
-                            "Renewed certificate with a {}. Component: {}",
+                    // Main certificate
+                    {
+                        let date_issued = cert.main_certificate.date_issued.as_ref().unwrap();
+                        // or
+                        // let date_issued = if let Some(date_issued) = cert.date_issued.as_ref() {
+                        //     date_issued
+                        // } else {
+                        //     thread::sleep(std::time::Duration::from_secs(WATCHDOG_TIMEOUT));
+                        //     continue;
+                        // };
+
+                        let parsed_date =
+                            NaiveDateTime::parse_from_str(date_issued, "%Y-%m-%d %H:%M:%S").unwrap();
+
+                        // Get the number of days between todays date and the date obtained from the file
+                        let difference_in_days = chrono::Utc::now()
+                            .naive_local()
+                            .signed_duration_since(parsed_date)
+                            .num_days();
+
+                        // Check if the certificate is older than (duration - 10) days
+                        if difference_in_days >= (cert.main_certificate.duration - 10) {
+                            warn!(
+                                "{} certificate needs renewal. Date issued: {}.",
+                                &cert.component_name, date_issued
+                            );
+
+                            // With this boolean we avoid code duplication and the use of the `continue`
+                            //     keyword that could cause a loop with no sleep between cycles
+                            let mut is_generated = true;
+
                             if cert.cert_authority.is_some() {
-                                "CA"
-                            } else {
-                                "key"
-                            },
-                            &cert.component_name
-                        );
-
-                        // Update the date issued on the main certificate
-                        if let Some(date) = get_date_issued(&cert.main_certificate.main_paths.cert)
-                        {
-                            cert.main_certificate.date_issued = Some(date.to_string());
-                        } else {
-                            error!("Could not determine the certificate issue date.");
+                                if let Err(e) = gen_csr_sign_with_ca(&SystemCommandRunner, cert, &cert.main_certificate.passphrase) {
+                                    error!("{}", e);
+                                    is_generated = false;
+                                }
+                            } else if let Err(e) = gen_csr_sign_with_key(
+                                &SystemCommandRunner,
+                                &cert.component_name,
+                                &cert.main_certificate.main_paths.key,
+                                cert.main_certificate.encrypted,
+                                &cert.main_certificate.subj,
+                                &cert.main_certificate.passphrase,
+                                cert.main_certificate.duration,
+                                &cert.main_certificate.main_paths.cert,
+                            ) {
+                                error!("{}", e);
+                                is_generated = false;
+                            }
+
+                            if is_generated {
+                                debug!(
+                                    "Renewed certificate with a {}. Component: {}",
+                                    if cert.cert_authority.is_some() {
+                                        "CA"
+                                    } else {
+                                        "key"
+                                    },
+                                    &cert.component_name
+                                );
+
+                                // Update the date issued on the main certificate
+                                if let Some(date) = get_date_issued(&cert.main_certificate.main_paths.cert)
+                                {
+                                    cert.main_certificate.date_issued = Some(date.to_string());
+                                } else {
+                                    error!("Could not determine the certificate issue date.");
+                                }
+
+                                if let Err(e) = export_certificate_bundles(&SystemCommandRunner, cert) {
+                                    error!("{}", e);
+                                }
+
+                                // The component backhaul connection keeps presenting the old certificate until it reconnects
+                                if let Ok(settings) = SETTINGS.lock() {
+                                    if is_component_client_certificate(&settings.component_mqtt_client, cert) {
+                                        RECONNECT_COMPONENT_MQTT.store(true, std::sync::atomic::Ordering::SeqCst);
+                                    }
+                                }
+                            }
                         }
                     }
+
+                    // Maybe: Broadcasting cert data to other NECOs if we're gonna run distributed...
+                    // This should be in the cert renewal block (watchdog)
+                    // Save the cert-key data and the date issued, then send it through mpsc
+                    // if let Some(date) = get_date_issued(&main_path.cert) {
+                    //     let data = structs::CertificateKeyPair {
+                    //         certificate: String::from("dummy cert"),
+                    //         key: String::from("dummy key"),
+                    //         date_issued: date.to_string()
+                    //     };
+
+                    //     if let Ok(option) = TX_WATCHDOG.lock() {
+                    //         if let Some(watchdog_tx) = &*option {
+                    //             if watchdog_tx.send(data).is_err() {
+                    //                 error!("Could not send data through mpsc.");
+                    //             }
+                    //         }
+                    //     }
+                    // } else {
+                    //     error!("Could not get correct date the cert-key were issued. Will not be able to send cert data through mpsc");
+                    // }
                 }
+            } else {
+                error!("Could not lock CERTIFICATES mutex.");
             }
+        }
 
-            // Maybe: Broadcasting cert data to other NECOs if we're gonna run distributed...
-            // This should be in the cert renewal block (watchdog)
-            // Save the cert-key data and the date issued, then send it through mpsc
-            // if let Some(date) = get_date_issued(&main_path.cert) {
-            //     let data = structs::CertificateKeyPair {
-            //         certificate: String::from("dummy cert"),
-            //         key: String::from("dummy key"),
-            //         date_issued: date.to_string()
-            //     };
-
-            //     if let Ok(option) = TX_WATCHDOG.lock() {
-            //         if let Some(watchdog_tx) = &*option {
-            //             if watchdog_tx.send(data).is_err() {
-            //                 error!("Could not send data through mpsc.");
-            //             }
-            //         }
-            //     }
-            // } else {
-            //     error!("Could not get correct date the cert-key were issued. Will not be able to send cert data through mpsc");
-            // }
-        }
-
-        thread::sleep(std::time::Duration::from_secs(WATCHDOG_TIMEOUT));
+        drop(renewal_lock);
 
-        // Here we check if NECO is about to restart, if it is; break the loop
-        if RESTART_NECO.load(std::sync::atomic::Ordering::SeqCst)
-        /* || cfg!(debug_assertions)*/
-        {
+        if watchdog_sleep(WATCHDOG_TIMEOUT) {
             break;
         }
     });
@@ -325,6 +624,7 @@ fn start_watchdog(mut certificates: Vec<CertificateSettings>) -> Result<JoinHand
  * If `just_populate_aux` is set to true - then only the copying of the main certificate/key to the auxiliary paths will be executed - cert/key generation is skipped.
  */
 pub fn generate_certificate(
+    runner: &dyn CommandRunner,
     certificate: &CertificateSettings,
     just_populate_aux: bool,
 ) -> Result<String, Error> {
@@ -339,19 +639,11 @@ pub fn generate_certificate(
                 &certificate.component_name
             );
 
-            let mut key_cmd = Command::new("openssl");
-            key_cmd.arg("genrsa");
-
-            if certificate.main_certificate.encrypted {
-                key_cmd.arg("-aes256");
-            }
-
-            key_cmd.args(&["-out", &certificate.main_certificate.main_paths.key]);
-
+            let mut passout = None;
             if certificate.main_certificate.encrypted {
-                match rand_passphrase() {
+                match rand_passphrase(&certificate.passphrase_policy) {
                     Some(passphrase) => {
-                        key_cmd.args(&["-passout", &["pass:", &passphrase].concat()]);
+                        passout = Some(["pass:", &passphrase].concat());
                         key_passphrase = passphrase;
                     }
                     None => {
@@ -363,36 +655,52 @@ pub fn generate_certificate(
                 }
             }
 
-            if certificate.main_certificate.key_len > 0 {
+            let mut key_cmd = Command::new("openssl");
+
+            // RSA keys keep using `key_len`, other algorithms are generated as the self-signed keys are
+            if certificate.algorithm.starts_with("rsa") {
+                if certificate.main_certificate.key_len <= 0 {
+                    return Err(Error::new(
+                        ErrorKind::InvalidData,
+                        "Key length needs to be bigger than 0.",
+                    ));
+                }
+
+                key_cmd.arg("genrsa");
+                if let Some(passout) = &passout {
+                    key_cmd.args(["-aes256", "-passout", passout]);
+                }
+                key_cmd.args(["-out", &certificate.main_certificate.main_paths.key]);
                 key_cmd.arg(&certificate.main_certificate.key_len.to_string());
-            } else {
-                return Err(Error::new(
-                    ErrorKind::InvalidData,
-                    "Key length needs to be bigger than 0.",
-                ));
-            }
 
-            match key_cmd.output() {
-                Ok(res) => {
-                    debug!(
-                        "Generating a key of length: {}.",
-                        &certificate.main_certificate.key_len
-                    );
-                    // OpenSSL command output is on stderr
-                    debug!("Command output: {}", String::from_utf8_lossy(&res.stderr));
+                debug!(
+                    "Generating a key of length: {}.",
+                    &certificate.main_certificate.key_len
+                );
+            } else {
+                key_cmd.arg("genpkey");
+                key_cmd.args(get_genpkey_args(&certificate.algorithm));
+                if let Some(passout) = &passout {
+                    key_cmd.args(["-aes256", "-pass", passout]);
                 }
-                Err(e) => return Err(e),
-            }
+                key_cmd.args(["-out", &certificate.main_certificate.main_paths.key]);
 
-            if let Err(e) = gen_csr_sign_with_ca(&certificate, &key_passphrase) {
-                return Err(e);
+                debug!("Generating a key with the algorithm: {}.", &certificate.algorithm);
             }
+
+            run_openssl(
+                runner,
+                &mut key_cmd,
+                &format!("Could not generate the certificate key. Component: {}", &certificate.component_name),
+            )?;
+
+            gen_csr_sign_with_ca(runner, certificate, &key_passphrase)?;
         } else {
             // Self-signed certificates
 
             let mut command = Command::new("openssl");
             command.arg("req");
-            command.args(&["-newkey", &certificate.algorithm]);
+            command.args(get_newkey_args(&certificate.algorithm));
             if !certificate.main_certificate.encrypted {
                 command.arg("-nodes");
             }
@@ -403,7 +711,7 @@ pub fn generate_certificate(
             command.args(&["-subj", &certificate.main_certificate.subj]);
             if certificate.main_certificate.encrypted {
                 let passphrase;
-                match rand_passphrase() {
+                match rand_passphrase(&certificate.passphrase_policy) {
                     Some(pass) => passphrase = pass,
                     None => {
                         return Err(Error::new(
@@ -418,17 +726,15 @@ pub fn generate_certificate(
                 key_passphrase = passphrase;
             }
 
-            match command.output() {
-                Ok(res) => {
-                    debug!(
-                        "Generating a self-signed certificate for component: {}.",
-                        &certificate.component_name
-                    );
-                    // OpenSSL command output is on stderr
-                    debug!("Command output: {}", String::from_utf8_lossy(&res.stderr));
-                }
-                Err(e) => return Err(e),
-            }
+            debug!(
+                "Generating a self-signed certificate for component: {}.",
+                &certificate.component_name
+            );
+            run_openssl(
+                runner,
+                &mut command,
+                &format!("Could not generate the self-signed certificate. Component: {}", &certificate.component_name),
+            )?;
         }
     }
 
@@ -442,14 +748,14 @@ pub fn generate_certificate(
         // If we fail at copying anywhere, we return Err
 
         if !certificate.main_certificate.main_paths.key.is_empty() && !path.key.is_empty() {
-            if let Err(e) = fs::copy(&certificate.main_certificate.main_paths.key, &path.key) {
+            if let Err(e) = runner.copy_file(&certificate.main_certificate.main_paths.key, &path.key) {
                 let msg = format!("Failed to copy key to auxiliary path. {}", e);
                 return Err(Error::new(ErrorKind::Other, msg));
             }
         }
 
         if !certificate.main_certificate.main_paths.cert.is_empty() && !path.cert.is_empty() {
-            if let Err(e) = fs::copy(&certificate.main_certificate.main_paths.cert, &path.cert) {
+            if let Err(e) = runner.copy_file(&certificate.main_certificate.main_paths.cert, &path.cert) {
                 let msg = format!("Failed to copy certificate to auxiliary path. {}", e);
                 return Err(Error::new(ErrorKind::Other, msg));
             }
@@ -464,147 +770,463 @@ pub fn generate_certificate(
 }
 
 /**
- * Generates a CSR (Certificate Signing Request) with the info from the `cert.main_certificate` struct.
- * That CSR is saved to the same path as the main certificate key, with the extension `.csr`.
- * The CSR is then signed with the CA (Certificate Authority) with the info from the `cert.cert_authority` struct.
- * The CSR file is removed after the certificate has been successfully signed.
+ * Sleeps for `seconds`, waking up every `WATCHDOG_POLL_INTERVAL` seconds.
+ * Returns early if the watchdog was paused and is now resumed, so the renewal check runs right away.
+ *
+ * Returns `true` if NECO is about to restart, meaning the watchdog loop has to break.
  */
-fn gen_csr_sign_with_ca(
-    cert: &CertificateSettings,
-    main_key_passphrase: &str,
-) -> Result<(), Error> {
-    let key_path = &cert.main_certificate.main_paths.key;
+fn watchdog_sleep(seconds: u64) -> bool {
+    let was_paused = watchdog_paused_by().is_some();
+    let mut slept = 0;
 
-    let csr_temp_path = if key_path.contains('.') {
-        [&key_path.split('.').take(1).collect::<String>(), ".csr"].concat()
-    } else {
-        return Err(Error::new(
-            ErrorKind::Other,
-            "Main certificate key path does not end with a file extension.",
-        ));
-    };
+    while slept < seconds {
+        let interval = WATCHDOG_POLL_INTERVAL.min(seconds - slept);
+        thread::sleep(Duration::from_secs(interval));
+        slept += interval;
 
-    let mut cmd_csr = Command::new("openssl");
-    cmd_csr.arg("req");
-    cmd_csr.args(&["-out", &csr_temp_path]);
-    cmd_csr.args(&["-key", &cert.main_certificate.main_paths.key]);
-    cmd_csr.arg("-new");
-    cmd_csr.args(&["-subj", &cert.main_certificate.subj]);
-    if cert.main_certificate.encrypted {
-        cmd_csr.args(&["-passin", &["pass:", main_key_passphrase].concat()]);
+        // Here we check if NECO is about to restart, if it is; break the loop
+        if RESTART_NECO.load(std::sync::atomic::Ordering::SeqCst) {
+            return true;
+        }
+
+        if was_paused && watchdog_paused_by().is_none() {
+            return false;
+        }
     }
 
-    let mut cmd_sign_crt;
-    if let Some(ca) = &cert.cert_authority {
-        cmd_sign_crt = Command::new("openssl");
-        cmd_sign_crt.arg("x509");
-        cmd_sign_crt.arg("-req");
-        cmd_sign_crt.args(&["-in", &csr_temp_path]);
-        cmd_sign_crt.args(&["-CA", &ca.main_paths.cert]);
-        cmd_sign_crt.args(&["-CAkey", &ca.main_paths.key]);
-        cmd_sign_crt.arg("-CAcreateserial");
-        cmd_sign_crt.args(&["-out", &cert.main_certificate.main_paths.cert]);
+    false
+}
 
-        if !cert.main_certificate.service_ips.is_empty() {
-            let san_ips = &cert.main_certificate.service_ips.join(",");
-            /*for ip in &cert.main_certificate.service_ips {
-                if !san_ips.is_empty() {
-                    san_ips = format!("{},", san_ips);
-                }
-                san_ips = format!("{}IP:{}", san_ips, ip)
-            }*/
+/**
+ * Pauses the certificate watchdog until `resume_watchdog()` is called, even across NECO restarts.
+ * Renewals requested with `regenerate_certificate()` still work while the watchdog is paused.
+ * `requested_by` describes who paused the watchdog and is logged and saved with the pause.
+ */
+pub fn pause_watchdog(requested_by: &str) -> Result<(), Error> {
+    let paused_by = format!("{} at {}", requested_by, chrono::Utc::now().format("%Y-%m-%d %H:%M:%S UTC"));
 
-            let sans = format!("\n[SAN]\nsubjectAltName={}", san_ips);
+    fs::write([BASE_DIRECTORY, WATCHDOG_PAUSE_FILE].concat(), &paused_by)?;
 
-            match NamedTempFile::new() {
-                Ok(mut file) => {
-                    if let Err(e) = file.write(sans.as_bytes()) {
-                        return Err(e);
-                    }
+    warn!("Certificate watchdog paused by {}.", paused_by);
+    Ok(())
+}
 
-                    match file.keep() {
-                        Ok(f) => {
-                            match (f.1).to_str() {
-                                Some(path) => {
-                                    cmd_sign_crt.args(&["-extfile", path, "-extensions", "SAN"])
-                                }
-                                None => {
-                                    return Err(Error::new(
-                                        ErrorKind::Other,
-                                        "Could not find path to temporary SANS file.",
-                                    ))
-                                }
-                            };
-                        }
-                        Err(e) => {
-                            return Err(Error::new(ErrorKind::Other, e));
-                        }
-                    }
-                }
-                Err(e) => return Err(e),
-            }
+/**
+ * Resumes the certificate watchdog, the renewal check is ran within `WATCHDOG_POLL_INTERVAL` seconds.
+ * `requested_by` describes who resumed the watchdog and is logged.
+ */
+pub fn resume_watchdog(requested_by: &str) -> Result<(), Error> {
+    match fs::remove_file([BASE_DIRECTORY, WATCHDOG_PAUSE_FILE].concat()) {
+        Ok(_) => {
+            warn!("Certificate watchdog resumed by {}.", requested_by);
+            Ok(())
         }
-
-        cmd_sign_crt.args(&["-days", &cert.main_certificate.duration.to_string()]);
-        if ca.encrypted {
-            cmd_sign_crt.args(&["-passin", &["pass:", &ca.passphrase].concat()]);
+        Err(e) if e.kind() == ErrorKind::NotFound => {
+            info!("Certificate watchdog is not paused.");
+            Ok(())
         }
-    } else {
-        return Err(Error::new(
-            ErrorKind::NotFound,
-            "Could not find CA certificate settings.",
-        ));
+        Err(e) => Err(e),
     }
+}
 
-    match cmd_csr.output() {
-        Ok(res) => {
-            debug!("Generating a CSR for signing with a CA certificate...");
-            // OpenSSL command output is on stderr
-            debug!("Command output: {}", String::from_utf8_lossy(&res.stderr));
+/**
+ * Returns who paused the certificate watchdog (and when), or `None` if it isn't paused.
+ */
+pub fn watchdog_paused_by() -> Option<String> {
+    fs::read_to_string([BASE_DIRECTORY, WATCHDOG_PAUSE_FILE].concat()).ok()
+}
+
+/**
+ * Regenerates every certificate in `certificates` - the CA (and intermediate CAs) first if the certificate is CA-signed, then the main certificate.
+ * A failing certificate doesn't stop the rest from being regenerated, the result of each one is returned with its component name.
+ *
+ * The caller has to hold the renewal lock (`lock_renewal()`) until the new key passphrases are saved,
+ *     so the watchdog can't renew any of them in the meantime.
+ */
+pub fn regenerate_all_certificates(
+    runner: &dyn CommandRunner,
+    certificates: &mut [CertificateSettings],
+) -> RegenerationResults {
+    certificates
+        .iter_mut()
+        .map(|cert| {
+            let cert_type = if cert.cert_authority.is_some() { "ca" } else { "main" };
+            let result = regenerate_certificate(runner, cert, cert_type);
+
+            if let Err(e) = &result {
+                error!("Could not regenerate the certificates of component: {}. {}", &cert.component_name, e);
+            }
+
+            (cert.component_name.to_owned(), result)
+        })
+        .collect()
+}
+
+/**
+ * Replaces the key and certificate of `cert` right away, regardless of their age.
+ * If `cert_type` is 'ca', the CA and the intermediate CAs are regenerated first and the main certificate is then signed with the new chain.
+ * The new key passphrases and dates issued are updated in `cert`, auxiliary paths and bundles are repopulated.
+ * The replaced keys and certificates are kept as `<path>.bak` (see `backup_certificate_files()`).
+ *
+ * The caller has to hold the renewal lock (`lock_renewal()`) until the new key passphrases are saved,
+ *     otherwise the watchdog could renew the certificate again with the passphrases still in the settings file.
+ */
+pub fn regenerate_certificate(
+    runner: &dyn CommandRunner,
+    cert: &mut CertificateSettings,
+    cert_type: &str,
+) -> Result<(), Error> {
+    if cert_type == "ca" {
+        if let Some(ca) = cert.cert_authority.as_mut() {
+            backup_certificate_files(&ca.main_paths)?;
+            ca.passphrase = generate_ca(runner, &cert.component_name, ca, &cert.passphrase_policy, false)?;
+            ca.date_issued = get_date_issued(&ca.main_paths.cert).map(|date| date.to_string());
+        } else {
+            return Err(Error::new(
+                ErrorKind::NotFound,
+                "Could not find a CA certificate for that component",
+            ));
         }
-        Err(e) => return Err(e),
-    }
 
-    match cmd_sign_crt.output() {
-        Ok(res) => {
-            debug!(
-                "Signed certificate with a CA for component: {}.",
-                &cert.component_name
-            );
-            // OpenSSL command output is on stderr
-            debug!("Command output: \n{}", String::from_utf8_lossy(&res.stderr));
+        // The intermediate CAs were signed by the old CA
+        for index in 0..cert.intermediate_cas.len() {
+            let issuer = intermediate_issuer(cert, index).cloned().unwrap();
+            let ca = &mut cert.intermediate_cas[index];
+
+            backup_certificate_files(&ca.main_paths)?;
+            ca.passphrase = generate_intermediate_ca(
+                runner,
+                &cert.component_name,
+                ca,
+                &issuer,
+                &cert.passphrase_policy,
+                false,
+            )?;
+            ca.date_issued = get_date_issued(&ca.main_paths.cert).map(|date| date.to_string());
         }
-        Err(e) => return Err(e),
     }
 
-    if let Err(e) = fs::remove_file(csr_temp_path) {
-        error!("Could not remove the CSR file. {}", e);
-    }
+    backup_certificate_files(&cert.main_certificate.main_paths)?;
+    cert.main_certificate.passphrase = generate_certificate(runner, cert, false)?;
+    cert.main_certificate.date_issued =
+        get_date_issued(&cert.main_certificate.main_paths.cert).map(|date| date.to_string());
+
+    export_certificate_bundles(runner, cert)?;
+
+    warn!(
+        "Renewed the {} certificate of component: {}.",
+        cert_type, &cert.component_name
+    );
 
     Ok(())
 }
 
 /**
- * Generates a CSR (Certificate Signing Request) with the `signing_key`, `subj`, `signing_key_encrypted`, `passphrase` function parameters.
- * The CSR is saved to the same path as the signing key, with the extension `.csr`.
- * The CSR is then signed with the `cert_duration`, `signing_key` and the generated certificate is saved to the path in `crt_path`.
- * CSR file is deleted if the certificate was signed successfully.
+ * Creates the renewal lock file, waiting up to `RENEWAL_LOCK_TIMEOUT` if it's held by someone else.
+ * A lock older than `RENEWAL_LOCK_STALE` is considered abandoned and is taken over.
+ * The lock is released when the returned `RenewalLock` is dropped.
  */
-fn gen_csr_sign_with_key(
-    component_name: &str,
-    signing_key: &str,
-    signing_key_encrypted: bool,
-    subj: &str,
-    passphrase: &str,
-    cert_duration: i64,
-    crt_path: &str,
-) -> Result<(), Error> {
-    let csr_temp_path = if signing_key.contains('.') {
-        [&signing_key.split('.').take(1).collect::<String>(), ".csr"].concat()
-    } else {
-        return Err(Error::new(
-            ErrorKind::Other,
-            "Signing key path does not end with a file extension. Path: {}"
+pub fn lock_renewal() -> Result<RenewalLock, Error> {
+    let path = [BASE_DIRECTORY, RENEWAL_LOCK_FILE].concat();
+    let started = SystemTime::now();
+
+    loop {
+        match fs::OpenOptions::new().write(true).create_new(true).open(&path) {
+            Ok(_) => return Ok(RenewalLock { path }),
+            Err(e) if e.kind() == ErrorKind::AlreadyExists => {
+                let lock_age = fs::metadata(&path)
+                    .and_then(|meta| meta.modified())
+                    .ok()
+                    .and_then(|modified| modified.elapsed().ok());
+
+                if matches!(lock_age, Some(age) if age > RENEWAL_LOCK_STALE) {
+                    warn!("Removing a stale certificate renewal lock.");
+                    fs::remove_file(&path)?;
+                    continue;
+                }
+            }
+            Err(e) => return Err(e),
+        }
+
+        if started.elapsed().unwrap_or_default() > RENEWAL_LOCK_TIMEOUT {
+            return Err(Error::other("Timed out waiting for the certificate renewal lock."));
+        }
+
+        thread::sleep(Duration::from_secs(1));
+    }
+}
+
+/**
+ * Replaces `certificates` with the ones in the settings file, so certificates added, removed or renewed
+ *     outside of the watchdog (e.g. through the CLI) are picked up.
+ * The dates issued are read from the certificate files, certificates whose files can't be read are skipped.
+ * If the settings file can't be loaded, `certificates` are left as they are.
+ */
+fn refresh_certificates(certificates: &mut Vec<CertificateSettings>) {
+    let saved_certificates = match settings::init() {
+        Ok(settings) => settings.certificates,
+        Err(_) => return,
+    };
+
+    *certificates = saved_certificates
+        .into_iter()
+        .filter_map(|mut cert| {
+            if let Some(ca) = cert.cert_authority.as_mut() {
+                if let Some(date) = get_date_issued(&ca.main_paths.cert) {
+                    ca.date_issued = Some(date.to_string());
+                } else {
+                    error!(
+                        "Could not determine the CA certificate issue date. Skipping certificate..."
+                    );
+                    return None;
+                }
+            }
+
+            for ca in &mut cert.intermediate_cas {
+                if let Some(date) = get_date_issued(&ca.main_paths.cert) {
+                    ca.date_issued = Some(date.to_string());
+                } else {
+                    error!("Could not determine the intermediate CA certificate issue date. Skipping certificate...");
+                    return None;
+                }
+            }
+
+            if let Some(date) = get_date_issued(&cert.main_certificate.main_paths.cert) {
+                cert.main_certificate.date_issued = Some(date.to_string());
+                Some(cert)
+            } else {
+                error!("Could not determine the certificate issue date. Skipping certificate...");
+                None
+            }
+        })
+        .collect();
+}
+
+/**
+ * Writes the certificate chain file and exports the PKCS#12 bundle of the main certificate.
+ * Each of them is skipped if its path is not set.
+ */
+fn export_certificate_bundles(runner: &dyn CommandRunner, cert: &CertificateSettings) -> Result<(), Error> {
+    if !cert.main_certificate.chain_path.is_empty() {
+        write_certificate_chain(cert)?;
+    }
+
+    if !cert.main_certificate.pkcs12_path.is_empty() {
+        export_pkcs12(runner, cert)?;
+    }
+
+    Ok(())
+}
+
+/**
+ * Concatenates the main certificate and the CA chain (if the certificate is CA-signed)
+ *     into the chain file at `cert.main_certificate.chain_path`.
+ */
+fn write_certificate_chain(cert: &CertificateSettings) -> Result<(), Error> {
+    let mut chain = fs::read_to_string(&cert.main_certificate.main_paths.cert)?;
+
+    let ca_chain = read_ca_chain(cert)?;
+    if !ca_chain.is_empty() {
+        if !chain.ends_with('\n') {
+            chain.push('\n');
+        }
+        chain.push_str(&ca_chain);
+    }
+
+    fs::write(&cert.main_certificate.chain_path, chain)?;
+
+    debug!(
+        "Wrote the certificate chain for component: {}.",
+        &cert.component_name
+    );
+
+    Ok(())
+}
+
+/**
+ * Returns the certificates of the CAs the main certificate chains up to, concatenated in order from
+ *     its issuer (the last intermediate CA) to the root CA.
+ * Returns an empty string if the certificate is self-signed.
+ */
+fn read_ca_chain(cert: &CertificateSettings) -> Result<String, Error> {
+    let mut chain = String::new();
+
+    if let Some(ca) = &cert.cert_authority {
+        for ca_cert in cert.intermediate_cas.iter().rev().chain(std::iter::once(ca)) {
+            if !chain.is_empty() && !chain.ends_with('\n') {
+                chain.push('\n');
+            }
+            chain.push_str(&fs::read_to_string(&ca_cert.main_paths.cert)?);
+        }
+    }
+
+    Ok(chain)
+}
+
+/**
+ * Bundles the main certificate key, the main certificate and the CA chain (if the certificate is CA-signed)
+ *     into a PKCS#12 file at `cert.main_certificate.pkcs12_path`, protected with `cert.main_certificate.pkcs12_passphrase`.
+ * The key passphrase is taken from `cert.main_certificate.passphrase`, so it has to be up-to-date when calling this.
+ */
+fn export_pkcs12(runner: &dyn CommandRunner, cert: &CertificateSettings) -> Result<(), Error> {
+    let mut command = Command::new("openssl");
+    command.args(["pkcs12", "-export"]);
+    command.args(["-inkey", &cert.main_certificate.main_paths.key]);
+    command.args(["-in", &cert.main_certificate.main_paths.cert]);
+
+    // OpenSSL takes the whole CA chain from a single file, it has to live until the command is ran
+    let mut ca_chain_file = None;
+    if cert.cert_authority.is_some() {
+        let file = runner.temp_file(&read_ca_chain(cert)?)?;
+        command.arg("-certfile").arg(file.path());
+        ca_chain_file = Some(file);
+    }
+    command.args(["-name", &cert.component_name]);
+    command.args(["-out", &cert.main_certificate.pkcs12_path]);
+    command.args(["-passout", &["pass:", &cert.main_certificate.pkcs12_passphrase].concat()]);
+    if cert.main_certificate.encrypted {
+        command.args(["-passin", &["pass:", &cert.main_certificate.passphrase].concat()]);
+    }
+
+    let result = run_openssl(
+        runner,
+        &mut command,
+        &format!("Could not export the PKCS#12 bundle. Component: {}", &cert.component_name),
+    );
+    drop(ca_chain_file);
+    result?;
+
+    debug!(
+        "Exported the PKCS#12 bundle for component: {}.",
+        &cert.component_name
+    );
+
+    Ok(())
+}
+
+/**
+ * Generates a CSR (Certificate Signing Request) with the info from the `cert.main_certificate` struct.
+ * That CSR is saved to the same path as the main certificate key, with the extension `.csr`.
+ * The CSR is then signed with the CA (Certificate Authority) returned by `leaf_issuer()`, the last intermediate CA or `cert.cert_authority`.
+ * The CSR file is removed after the certificate has been successfully signed.
+ */
+fn gen_csr_sign_with_ca(
+    runner: &dyn CommandRunner,
+    cert: &CertificateSettings,
+    main_key_passphrase: &str,
+) -> Result<(), Error> {
+    let key_path = &cert.main_certificate.main_paths.key;
+
+    let csr_temp_path = if key_path.contains('.') {
+        [&key_path.split('.').take(1).collect::<String>(), ".csr"].concat()
+    } else {
+        return Err(Error::new(
+            ErrorKind::Other,
+            "Main certificate key path does not end with a file extension.",
+        ));
+    };
+
+    let mut cmd_csr = Command::new("openssl");
+    cmd_csr.arg("req");
+    cmd_csr.args(&["-out", &csr_temp_path]);
+    cmd_csr.args(&["-key", &cert.main_certificate.main_paths.key]);
+    cmd_csr.arg("-new");
+    cmd_csr.args(&["-subj", &cert.main_certificate.subj]);
+    if cert.main_certificate.encrypted {
+        cmd_csr.args(&["-passin", &["pass:", main_key_passphrase].concat()]);
+    }
+
+    let mut cmd_sign_crt;
+    // Has to live until the certificate is signed
+    let mut san_file = None;
+    if let Some(ca) = leaf_issuer(cert) {
+        cmd_sign_crt = Command::new("openssl");
+        cmd_sign_crt.arg("x509");
+        cmd_sign_crt.arg("-req");
+        cmd_sign_crt.args(&["-in", &csr_temp_path]);
+        cmd_sign_crt.args(&["-CA", &ca.main_paths.cert]);
+        cmd_sign_crt.args(&["-CAkey", &ca.main_paths.key]);
+        cmd_sign_crt.arg("-CAcreateserial");
+        cmd_sign_crt.args(&["-out", &cert.main_certificate.main_paths.cert]);
+
+        if !cert.main_certificate.service_ips.is_empty() {
+            let san_ips = &cert.main_certificate.service_ips.join(",");
+            /*for ip in &cert.main_certificate.service_ips {
+                if !san_ips.is_empty() {
+                    san_ips = format!("{},", san_ips);
+                }
+                san_ips = format!("{}IP:{}", san_ips, ip)
+            }*/
+
+            let sans = format!("\n[SAN]\nsubjectAltName={}", san_ips);
+
+            let file = runner.temp_file(&sans)?;
+            cmd_sign_crt.arg("-extfile").arg(file.path());
+            cmd_sign_crt.args(["-extensions", "SAN"]);
+            san_file = Some(file);
+        }
+
+        cmd_sign_crt.args(&["-days", &cert.main_certificate.duration.to_string()]);
+        if ca.encrypted {
+            cmd_sign_crt.args(&["-passin", &["pass:", &ca.passphrase].concat()]);
+        }
+    } else {
+        return Err(Error::new(
+            ErrorKind::NotFound,
+            "Could not find CA certificate settings.",
+        ));
+    }
+
+    debug!("Generating a CSR for signing with a CA certificate...");
+    run_openssl(
+        runner,
+        &mut cmd_csr,
+        &format!("Could not generate the certificate CSR. Component: {}", &cert.component_name),
+    )?;
+
+    run_openssl(
+        runner,
+        &mut cmd_sign_crt,
+        &format!("Could not sign the certificate with the CA. Component: {}", &cert.component_name),
+    )?;
+    drop(san_file);
+    debug!(
+        "Signed certificate with a CA for component: {}.",
+        &cert.component_name
+    );
+
+    // The CSR was never written on a dry run
+    match fs::remove_file(csr_temp_path) {
+        Err(e) if e.kind() != ErrorKind::NotFound => error!("Could not remove the CSR file. {}", e),
+        _ => {}
+    }
+
+    Ok(())
+}
+
+/**
+ * Generates a CSR (Certificate Signing Request) with the `signing_key`, `subj`, `signing_key_encrypted`, `passphrase` function parameters.
+ * The CSR is saved to the same path as the signing key, with the extension `.csr`.
+ * The CSR is then signed with the `cert_duration`, `signing_key` and the generated certificate is saved to the path in `crt_path`.
+ * CSR file is deleted if the certificate was signed successfully.
+ */
+#[allow(clippy::too_many_arguments)]
+fn gen_csr_sign_with_key(
+    runner: &dyn CommandRunner,
+    component_name: &str,
+    signing_key: &str,
+    signing_key_encrypted: bool,
+    subj: &str,
+    passphrase: &str,
+    cert_duration: i64,
+    crt_path: &str,
+) -> Result<(), Error> {
+    let csr_temp_path = if signing_key.contains('.') {
+        [&signing_key.split('.').take(1).collect::<String>(), ".csr"].concat()
+    } else {
+        return Err(Error::new(
+            ErrorKind::Other,
+            "Signing key path does not end with a file extension. Path: {}"
                 .replace("{}", signing_key),
         ));
     };
@@ -630,29 +1252,26 @@ fn gen_csr_sign_with_key(
         sign_csr.args(&["-passin", &["pass:", passphrase].concat()]);
     }
 
-    match csr.output() {
-        Ok(res) => {
-            debug!("Generating a CSR for signing with a key...");
-            // OpenSSL command output is on stderr
-            debug!("Command output: {}", String::from_utf8_lossy(&res.stderr));
-        }
-        Err(e) => return Err(e),
-    }
-
-    match sign_csr.output() {
-        Ok(res) => {
-            debug!(
-                "Signed certificate using key for component: {}.",
-                component_name
-            );
-            // OpenSSL command output is on stderr
-            debug!("Command output: {}", String::from_utf8_lossy(&res.stderr));
-        }
-        Err(e) => return Err(e),
-    }
+    debug!("Generating a CSR for signing with a key...");
+    run_openssl(
+        runner,
+        &mut csr,
+        &format!("Could not generate the certificate CSR. Component: {}", component_name),
+    )?;
+
+    run_openssl(
+        runner,
+        &mut sign_csr,
+        &format!("Could not sign the certificate with its key. Component: {}", component_name),
+    )?;
+    debug!(
+        "Signed certificate using key for component: {}.",
+        component_name
+    );
 
-    if let Err(e) = fs::remove_file(csr_temp_path) {
-        error!("Could not remove the CSR file. {}", e);
+    match fs::remove_file(csr_temp_path) {
+        Err(e) if e.kind() != ErrorKind::NotFound => error!("Could not remove the CSR file. {}", e),
+        _ => {}
     }
 
     Ok(())
@@ -664,8 +1283,10 @@ fn gen_csr_sign_with_key(
  * Parameter `component_name` is just used for logging messages.
  */
 pub fn generate_ca(
+    runner: &dyn CommandRunner,
     component_name: &str,
     ca_config: &CACertificate,
+    passphrase_policy: &PassphrasePolicy,
     just_populate_aux: bool,
 ) -> Result<String, Error> {
     let mut passphrase = String::new();
@@ -688,7 +1309,7 @@ pub fn generate_ca(
         command.args(&["-subj", &ca_config.subj]);
 
         if ca_config.encrypted {
-            match rand_passphrase() {
+            match rand_passphrase(passphrase_policy) {
                 Some(pass) => passphrase = pass,
                 None => {
                     return Err(Error::new(
@@ -701,36 +1322,543 @@ pub fn generate_ca(
             command.args(&["-passout", &["pass:", &passphrase].concat()]);
         }
 
-        match command.output() {
-            Ok(res) => {
-                debug!("Generated a CA for component: {}.", component_name);
-                // OpenSSL command output is on stderr
-                debug!("Command output: {}", String::from_utf8_lossy(&res.stderr));
+        run_openssl(
+            runner,
+            &mut command,
+            &format!("Could not generate the CA. Component: {}", component_name),
+        )?;
+        debug!("Generated a CA for component: {}.", component_name);
+    }
+
+    populate_ca_aux_paths(runner, ca_config)?;
+
+    Ok(passphrase)
+}
+
+/**
+ * Generates an intermediate CA with the info in the `ca_config` function parameter, its certificate is signed with the `issuer` CA.
+ * If the `just_populate_aux` function parameter is set to true, generation will be skipped but the crt/key will be copied over to the auxiliary paths.
+ * The generated key passphrase is returned, it is empty if the key is not encrypted or `just_populate_aux` is true.
+ */
+pub fn generate_intermediate_ca(
+    runner: &dyn CommandRunner,
+    component_name: &str,
+    ca_config: &CACertificate,
+    issuer: &CACertificate,
+    passphrase_policy: &PassphrasePolicy,
+    just_populate_aux: bool,
+) -> Result<String, Error> {
+    let mut passphrase = String::new();
+
+    if !just_populate_aux {
+        debug!("Generating an intermediate CA for component: {}", component_name);
+
+        let csr_path = get_csr_path(&ca_config.main_paths.key)?;
+
+        let mut command = Command::new("openssl");
+        command.args(["req", "-new"]);
+
+        if !ca_config.encrypted {
+            command.arg("-nodes");
+        }
+
+        command.args(["-keyout", &ca_config.main_paths.key]);
+        command.args(["-out", &csr_path]);
+        command.args(["-subj", &ca_config.subj]);
+
+        if ca_config.encrypted {
+            match rand_passphrase(passphrase_policy) {
+                Some(pass) => passphrase = pass,
+                None => {
+                    return Err(Error::other("Could not generate a random passphrase."))
+                }
             }
-            Err(e) => return Err(e),
+
+            command.args(["-passout", &["pass:", &passphrase].concat()]);
+        }
+
+        run_openssl(runner, &mut command, "Could not generate the intermediate CA key and CSR.")?;
+
+        sign_intermediate_ca(runner, component_name, &csr_path, ca_config, issuer)?;
+    }
+
+    populate_ca_aux_paths(runner, ca_config)?;
+
+    Ok(passphrase)
+}
+
+/**
+ * Signs a new certificate for the intermediate CA in `ca_config` with its existing key, using the `issuer` CA.
+ * The key passphrase is taken from `ca_config.passphrase`, so it has to be up-to-date when calling this.
+ * The new certificate is copied over to the auxiliary paths.
+ */
+fn renew_intermediate_ca(
+    runner: &dyn CommandRunner,
+    component_name: &str,
+    ca_config: &CACertificate,
+    issuer: &CACertificate,
+) -> Result<(), Error> {
+    let csr_path = get_csr_path(&ca_config.main_paths.key)?;
+
+    let mut command = Command::new("openssl");
+    command.args(["req", "-new"]);
+    command.args(["-key", &ca_config.main_paths.key]);
+    command.args(["-out", &csr_path]);
+    command.args(["-subj", &ca_config.subj]);
+    if ca_config.encrypted {
+        command.args(["-passin", &["pass:", &ca_config.passphrase].concat()]);
+    }
+
+    run_openssl(runner, &mut command, "Could not generate the intermediate CA CSR.")?;
+
+    sign_intermediate_ca(runner, component_name, &csr_path, ca_config, issuer)?;
+
+    populate_ca_aux_paths(runner, ca_config)
+}
+
+/**
+ * Signs the intermediate CA CSR at `csr_path` with the `issuer` CA, adding the `INTERMEDIATE_CA_EXTENSIONS`.
+ * The certificate is saved to the main path of `ca_config` and the CSR file is removed.
+ */
+fn sign_intermediate_ca(
+    runner: &dyn CommandRunner,
+    component_name: &str,
+    csr_path: &str,
+    ca_config: &CACertificate,
+    issuer: &CACertificate,
+) -> Result<(), Error> {
+    let extfile = runner.temp_file(INTERMEDIATE_CA_EXTENSIONS)?;
+
+    let mut command = Command::new("openssl");
+    command.args(["x509", "-req"]);
+    command.args(["-in", csr_path]);
+    command.args(["-CA", &issuer.main_paths.cert]);
+    command.args(["-CAkey", &issuer.main_paths.key]);
+    command.arg("-CAcreateserial");
+    command.args(["-days", &ca_config.duration.to_string()]);
+    command.arg("-extfile").arg(extfile.path());
+    command.args(["-extensions", "INTERMEDIATE_CA"]);
+    command.args(["-out", &ca_config.main_paths.cert]);
+    if issuer.encrypted {
+        command.args(["-passin", &["pass:", &issuer.passphrase].concat()]);
+    }
+
+    run_openssl(runner, &mut command, "Could not sign the intermediate CA certificate.")?;
+
+    debug!(
+        "Signed an intermediate CA certificate for component: {}.",
+        component_name
+    );
+
+    match fs::remove_file(csr_path) {
+        Err(e) if e.kind() != ErrorKind::NotFound => error!("Could not remove the CSR file. {}", e),
+        _ => {}
+    }
+
+    Ok(())
+}
+
+/**
+ * Runs the openssl `command`, logging its output.
+ * Returns an error with `failure_msg` if openssl exits with a non-zero code.
+ */
+fn run_openssl(runner: &dyn CommandRunner, command: &mut Command, failure_msg: &str) -> Result<(), Error> {
+    let res = runner.output(command)?;
+
+    // OpenSSL command output is on stderr
+    debug!("Command output: {}", String::from_utf8_lossy(&res.stderr));
+
+    if !res.status.success() {
+        return Err(Error::other([failure_msg, " ", &failure_reason(&res)].concat()));
+    }
+
+    Ok(())
+}
+
+/**
+ * Returns the path a CSR for the key at `key_path` is temporarily saved to, the key path with the extension `.csr`.
+ */
+fn get_csr_path(key_path: &str) -> Result<String, Error> {
+    if key_path.contains('.') {
+        Ok([&key_path.split('.').take(1).collect::<String>(), ".csr"].concat())
+    } else {
+        Err(Error::other("Key path does not end with a file extension. Path: {}".replace("{}", key_path)))
+    }
+}
+
+/**
+ * Runs `openssl version` and returns its output (e.g. "OpenSSL 1.1.1f  31 Mar 2020").
+ * Returns an error stating that certificates cannot be managed if the binary is missing or fails to run.
+ */
+pub fn check_openssl(runner: &dyn CommandRunner) -> Result<String, Error> {
+    let mut command = Command::new("openssl");
+    command.arg("version");
+
+    match runner.output(&mut command) {
+        Ok(res) if res.status.success() => {
+            Ok(String::from_utf8_lossy(&res.stdout).trim().to_owned())
         }
+        Ok(res) => Err(Error::other(
+            format!(
+                "openssl binary failed to run, certificates cannot be managed. {}",
+                String::from_utf8_lossy(&res.stderr).trim()
+            ),
+        )),
+        Err(e) if e.kind() == ErrorKind::NotFound => Err(Error::new(
+            ErrorKind::NotFound,
+            "openssl binary not found, certificates cannot be managed.",
+        )),
+        Err(e) => Err(Error::other(format!("openssl binary failed to run, certificates cannot be managed. {}", e))),
+    }
+}
+
+/**
+ * Checks that the certificate and key on `paths` exist, that the certificate can be parsed and isn't expired
+ *     and that the key (decrypted with `passphrase` if `encrypted`) pairs with it.
+ * A certificate that exists but fails a check is logged as corrupt, the caller is expected to regenerate it like a missing one.
+ *
+ * Returns an error of kind `PermissionDenied` if the key can't be decrypted with the `passphrase`.
+ *     The key material is fine, only the passphrase is wrong or lost, so it must not be regenerated.
+ */
+fn is_certificate_intact(
+    runner: &dyn CommandRunner,
+    paths: &CertificatePaths,
+    encrypted: bool,
+    passphrase: &str,
+    component_name: &str,
+) -> Result<bool, Error> {
+    if fs::metadata(&paths.cert).is_err() || fs::metadata(&paths.key).is_err() {
+        return Ok(false);
+    }
+
+    match check_certificate_integrity(runner, paths, encrypted, passphrase) {
+        Ok(()) => Ok(true),
+        Err(e) if e.kind() == ErrorKind::PermissionDenied => Err(Error::new(
+            ErrorKind::PermissionDenied,
+            format!(
+                "Key '{}' of component {} can't be decrypted, it will not be regenerated. {}",
+                &paths.key, component_name, e
+            ),
+        )),
+        Err(e) => {
+            warn!(
+                "Certificate '{}' of component {} is corrupt, it will be regenerated. {}",
+                &paths.cert, component_name, e
+            );
+            Ok(false)
+        }
+    }
+}
+
+/**
+ * Copies the key and certificate on `paths` to `<path>.bak`, so the key material a regeneration replaces can be recovered.
+ * Files that don't exist are skipped.
+ */
+fn backup_certificate_files(paths: &CertificatePaths) -> Result<(), Error> {
+    for path in &[&paths.key, &paths.cert] {
+        if fs::metadata(path).is_ok() {
+            let backup_path = [path.as_str(), CERTIFICATE_BACKUP_EXTENSION].concat();
+            fs::copy(path, &backup_path).map_err(|e| {
+                Error::new(e.kind(), format!("Could not back up '{}' before regenerating it. {}", path, e))
+            })?;
+            warn!("Backed up '{}' to '{}'.", path, backup_path);
+        }
+    }
+
+    Ok(())
+}
+
+/**
+ * Returns an error describing why the certificate/key on `paths` can't be used.
+ * The certificate is parsed and its expiry checked with `openssl x509 -checkend`, then its public key is compared with the one derived from the key.
+ */
+fn check_certificate_integrity(
+    runner: &dyn CommandRunner,
+    paths: &CertificatePaths,
+    encrypted: bool,
+    passphrase: &str,
+) -> Result<(), Error> {
+    let mut cert_cmd = Command::new("openssl");
+    cert_cmd.args(["x509", "-noout", "-pubkey", "-checkend", "0"]);
+    cert_cmd.args(["-in", &paths.cert]);
+
+    let cert_res = runner.output(&mut cert_cmd)?;
+    if !cert_res.status.success() {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            "The certificate can't be parsed or has expired.",
+        ));
+    }
+
+    let mut key_cmd = Command::new("openssl");
+    key_cmd.args(["pkey", "-pubout"]);
+    key_cmd.args(["-in", &paths.key]);
+    // Never prompt for a passphrase, an unencrypted key ignores this
+    key_cmd.args(["-passin", &["pass:", if encrypted { passphrase } else { "" }].concat()]);
+
+    let key_res = runner.output(&mut key_cmd)?;
+    if !key_res.status.success() {
+        // An encrypted key that fails to load has the wrong passphrase, not broken key material
+        let key_is_encrypted = fs::read_to_string(&paths.key)
+            .map(|contents| contents.contains("ENCRYPTED"))
+            .unwrap_or(false);
+
+        if key_is_encrypted {
+            return Err(Error::new(
+                ErrorKind::PermissionDenied,
+                "The passphrase of the key is wrong or missing.",
+            ));
+        }
+
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            "The key can't be parsed.",
+        ));
+    }
+
+    // `-checkend` prints its verdict after the public key
+    let cert_pub_key = String::from_utf8_lossy(&cert_res.stdout);
+    let key_pub_key = String::from_utf8_lossy(&key_res.stdout);
+
+    if !cert_pub_key.starts_with(key_pub_key.as_ref()) {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            "The key does not pair with the certificate.",
+        ));
+    }
+
+    Ok(())
+}
+
+/**
+ * Returns the SHA256 fingerprints of the CA, intermediate CA and main certificates of every certificate in `certificates`.
+ * A certificate that can't be read or parsed is listed with the error instead of a fingerprint.
+ */
+pub fn certificate_fingerprints(certificates: &[CertificateSettings]) -> Vec<CertificateFingerprint> {
+    let mut fingerprints: Vec<CertificateFingerprint> = Vec::new();
+
+    for cert in certificates {
+        let mut paths: Vec<(&str, &str)> = Vec::new();
+        if let Some(ca) = &cert.cert_authority {
+            paths.push(("ca", &ca.main_paths.cert));
+        }
+        for intermediate in &cert.intermediate_cas {
+            paths.push(("intermediate", &intermediate.main_paths.cert));
+        }
+        paths.push(("main", &cert.main_certificate.main_paths.cert));
+
+        for (crt_type, path) in paths {
+            let fingerprint = certificate_fingerprint(path);
+
+            fingerprints.push(CertificateFingerprint {
+                component_name: cert.component_name.to_owned(),
+                crt_type: crt_type.to_owned(),
+                path: path.to_owned(),
+                fingerprint: fingerprint.as_ref().ok().cloned(),
+                error: fingerprint.err().map(|e| e.to_string()),
+            });
+        }
+    }
+
+    fingerprints
+}
+
+/**
+ * Returns every file the `certificate` writes with a description of what it's used for (e.g. "main key").
+ * These are the key and certificate paths of its CA, intermediate CAs and main certificate (auxiliary paths included),
+ *     the certificate chain and the PKCS#12 bundle. Empty paths aren't written, they're left out.
+ */
+fn certificate_file_paths(certificate: &CertificateSettings) -> Vec<(String, String)> {
+    let mut paths: Vec<(String, String)> = Vec::new();
+
+    // Empty key/cert paths are skipped when copying, nothing is written there
+    let mut push_path = |path: &str, description: String| {
+        if !path.is_empty() {
+            paths.push((path.to_owned(), description));
+        }
+    };
+    let mut push_paths = |crt_type: &str, main_paths: &CertificatePaths, auxiliary_paths: &[CertificatePaths]| {
+        push_path(&main_paths.key, [crt_type, " key"].concat());
+        push_path(&main_paths.cert, [crt_type, " cert"].concat());
+        for aux in auxiliary_paths {
+            push_path(&aux.key, [crt_type, " auxiliary key"].concat());
+            push_path(&aux.cert, [crt_type, " auxiliary cert"].concat());
+        }
+    };
+
+    if let Some(ca) = &certificate.cert_authority {
+        push_paths("ca", &ca.main_paths, &ca.auxiliary_paths);
+    }
+    for (index, intermediate) in certificate.intermediate_cas.iter().enumerate() {
+        push_paths(
+            &format!("intermediate ca {}", index + 1),
+            &intermediate.main_paths,
+            &intermediate.auxiliary_paths,
+        );
     }
+    let main = &certificate.main_certificate;
+    push_paths("main", &main.main_paths, &main.auxiliary_paths);
 
+    // Empty chain and PKCS#12 paths mean the files aren't written
+    if !main.chain_path.is_empty() {
+        paths.push((main.chain_path.to_owned(), String::from("chain")));
+    }
+    if !main.pkcs12_path.is_empty() {
+        paths.push((main.pkcs12_path.to_owned(), String::from("pkcs12 bundle")));
+    }
+
+    paths
+}
+
+/**
+ * Finds the files written by more than one of the `certificates` (or written twice by the same one),
+ *     the renewal of one of them would silently overwrite the file of the other.
+ * Paths are compared with repeated separators and `.` components removed.
+ *
+ * Returns a message naming the path and both users of every collision.
+ */
+pub fn find_certificate_path_collisions(certificates: &[CertificateSettings]) -> Vec<String> {
+    let mut users: BTreeMap<PathBuf, String> = BTreeMap::new();
+    let mut collisions: Vec<String> = Vec::new();
+
+    for certificate in certificates {
+        for (path, usage) in certificate_file_paths(certificate) {
+            let user = format!("{} ({})", certificate.component_name, usage);
+            let normalized: PathBuf = Path::new(&path).components().collect();
+
+            match users.get(&normalized) {
+                Some(first_user) => collisions.push(format!(
+                    "'{}' is used by both {} and {}.",
+                    path, first_user, user
+                )),
+                None => {
+                    users.insert(normalized, user);
+                }
+            }
+        }
+    }
+
+    collisions
+}
+
+/**
+ * Returns the SHA256 fingerprint of the first certificate in the PEM file on `path`,
+ *     formatted like `openssl x509 -fingerprint -sha256` does ("AB:CD:...").
+ *
+ * Returns an error of kind `InvalidData` if the file holds no certificate.
+ */
+pub fn certificate_fingerprint(path: &str) -> Result<String, Error> {
+    let contents = fs::read_to_string(path)?;
+
+    let der = decode_pem_certificates(&contents)?
+        .into_iter()
+        .next()
+        .ok_or_else(|| Error::new(ErrorKind::InvalidData, "The file holds no PEM certificate."))?;
+
+    Ok(digest(&SHA256, &der)
+        .as_ref()
+        .iter()
+        .map(|byte| format!("{:02X}", byte))
+        .collect::<Vec<String>>()
+        .join(":"))
+}
+
+/**
+ * Decodes the DER bodies of the PEM certificates in `contents`, in the order they appear.
+ * Anything outside of the certificate blocks (e.g. keys or comments) is ignored.
+ *
+ * Returns an error of kind `InvalidData` if a certificate block is unterminated or isn't an ASN.1 sequence.
+ */
+pub fn decode_pem_certificates(contents: &str) -> Result<Vec<Vec<u8>>, Error> {
+    let mut certificates: Vec<Vec<u8>> = Vec::new();
+
+    for block in contents.split(PEM_CERTIFICATE_BEGIN).skip(1) {
+        let der = match block.find(PEM_CERTIFICATE_END) {
+            Some(end) => {
+                let body: String = block[..end].chars().filter(|c| !c.is_whitespace()).collect();
+                BASE64.decode(body.as_bytes()).ok()
+            }
+            None => None,
+        };
+
+        // Every X.509 certificate is an ASN.1 SEQUENCE
+        match der {
+            Some(der) if der.first() == Some(&0x30) => certificates.push(der),
+            _ => {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    "A certificate can't be parsed.",
+                ))
+            }
+        }
+    }
+
+    Ok(certificates)
+}
+
+/**
+ * Returns the CA that signs the main certificate of `cert`, the last intermediate CA if there are any.
+ * Returns `None` if the certificate is self-signed.
+ */
+fn leaf_issuer(cert: &CertificateSettings) -> Option<&CACertificate> {
+    cert.cert_authority
+        .as_ref()
+        .map(|ca| cert.intermediate_cas.last().unwrap_or(ca))
+}
+
+/**
+ * Returns the CA that signs the intermediate CA at `index`, the CA of `cert` for the first one and the previous intermediate CA for the rest.
+ */
+fn intermediate_issuer(cert: &CertificateSettings, index: usize) -> Option<&CACertificate> {
+    if index == 0 {
+        cert.cert_authority.as_ref()
+    } else {
+        cert.intermediate_cas.get(index - 1)
+    }
+}
+
+/**
+ * Returns `true` if the certificate issued on `date_issued` is older than (`duration` - 10) days.
+ * Returns `false` if `date_issued` can't be parsed.
+ */
+fn needs_renewal(date_issued: &str, duration: i64) -> bool {
+    match NaiveDateTime::parse_from_str(date_issued, "%Y-%m-%d %H:%M:%S") {
+        Ok(parsed_date) => {
+            let difference_in_days = chrono::Utc::now()
+                .naive_local()
+                .signed_duration_since(parsed_date)
+                .num_days();
+
+            difference_in_days >= (duration - 10)
+        }
+        Err(_) => false,
+    }
+}
+
+/**
+ * Copies the CA crt/key from the main paths of `ca_config` to its auxiliary paths with the `runner` (see `CommandRunner::copy_file()`).
+ */
+fn populate_ca_aux_paths(runner: &dyn CommandRunner, ca_config: &CACertificate) -> Result<(), Error> {
     for path in &ca_config.auxiliary_paths {
         // Check if any path is empty, if it is; skip the copy so we don't get errors
         // If we fail at copying anywhere, we return Err
 
         if !ca_config.main_paths.key.is_empty() && !path.key.is_empty() {
-            if let Err(e) = fs::copy(&ca_config.main_paths.key, &path.key) {
+            if let Err(e) = runner.copy_file(&ca_config.main_paths.key, &path.key) {
                 let msg = format!("Failed to copy CA key to auxiliary path. {}", e);
                 return Err(Error::new(ErrorKind::Other, msg));
             }
         }
 
         if !ca_config.main_paths.cert.is_empty() && !path.cert.is_empty() {
-            if let Err(e) = fs::copy(&ca_config.main_paths.cert, &path.cert) {
+            if let Err(e) = runner.copy_file(&ca_config.main_paths.cert, &path.cert) {
                 let msg = format!("Failed to copy CA certificate to auxiliary path. {}", e);
                 return Err(Error::new(ErrorKind::Other, msg));
             }
         }
     }
 
-    Ok(passphrase)
+    Ok(())
 }
 
 /**
@@ -761,18 +1889,189 @@ fn get_date_issued(file_path: &str) -> Option<NaiveDateTime> {
 }
 
 /**
- * Generates a random passphrase from the provided character set.
- * Returns `None` if generation failed.
+ * Generates a random passphrase of `policy.length` from the `policy.charset` character set.
+ * Returns `None` if generation failed or the policy is invalid.
  */
-fn rand_passphrase() -> Option<String> {
+fn rand_passphrase(policy: &PassphrasePolicy) -> Option<String> {
+    if validate_passphrase_policy(policy).is_err() {
+        return None;
+    }
+
+    let charset: Vec<char> = policy.charset.chars().collect();
+
     let mut rand_generator = thread_rng();
-    (0..PASSPHRASE_LENGTH)
+    (0..policy.length)
         .map(|_| {
-            Some(if let Some(ch) = CHARSET.choose(&mut rand_generator) {
-                *ch as char
+            Some(if let Some(ch) = charset.choose(&mut rand_generator) {
+                *ch
             } else {
                 return None;
             })
         })
         .collect()
 }
+
+/**
+ * Checks that `algorithm` is a key algorithm we can generate keys with.
+ * Supported values are `rsa:<bits>`, `ec:<curve>` (with a curve from `SUPPORTED_EC_CURVES`) and `ed25519`.
+ * Returns an error listing the supported values if it isn't.
+ */
+pub fn validate_algorithm(algorithm: &str) -> Result<(), Error> {
+    let is_valid = if let Some(bits) = algorithm.strip_prefix("rsa:") {
+        match bits.parse::<u32>() {
+            Ok(bits) => (MIN_RSA_KEY_LENGTH..=MAX_RSA_KEY_LENGTH).contains(&bits),
+            Err(_) => false,
+        }
+    } else if let Some(curve) = algorithm.strip_prefix("ec:") {
+        SUPPORTED_EC_CURVES.contains(&curve)
+    } else {
+        algorithm == "ed25519"
+    };
+
+    if !is_valid {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            format!(
+                "Unsupported key algorithm '{}'. Supported algorithms: 'rsa:<{}-{}>', 'ec:<{}>', 'ed25519'.",
+                algorithm,
+                MIN_RSA_KEY_LENGTH,
+                MAX_RSA_KEY_LENGTH,
+                SUPPORTED_EC_CURVES.join("|")
+            ),
+        ));
+    }
+
+    Ok(())
+}
+
+/**
+ * Returns the `openssl req` arguments for generating a new key with `algorithm`.
+ * OpenSSL expects a parameter file after `ec:`, so EC curves are passed as a key option instead.
+ */
+fn get_newkey_args(algorithm: &str) -> Vec<String> {
+    if let Some(curve) = algorithm.strip_prefix("ec:") {
+        vec![
+            String::from("-newkey"),
+            String::from("ec"),
+            String::from("-pkeyopt"),
+            ["ec_paramgen_curve:", curve].concat(),
+        ]
+    } else {
+        vec![String::from("-newkey"), algorithm.to_owned()]
+    }
+}
+
+/**
+ * Returns the `openssl genpkey` arguments for generating a key with a non-RSA `algorithm` (`ec:<curve>` or `ed25519`).
+ */
+fn get_genpkey_args(algorithm: &str) -> Vec<String> {
+    if let Some(curve) = algorithm.strip_prefix("ec:") {
+        vec![
+            String::from("-algorithm"),
+            String::from("EC"),
+            String::from("-pkeyopt"),
+            ["ec_paramgen_curve:", curve].concat(),
+        ]
+    } else {
+        vec![String::from("-algorithm"), algorithm.to_owned()]
+    }
+}
+
+/**
+ * Checks that passphrases can be generated with the `policy`.
+ * Returns an error if the length is 0 or the charset is empty.
+ */
+pub fn validate_passphrase_policy(policy: &PassphrasePolicy) -> Result<(), Error> {
+    if policy.length == 0 {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            "Passphrase length needs to be bigger than 0.",
+        ));
+    }
+
+    if policy.charset.is_empty() {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            "Passphrase charset can not be empty.",
+        ));
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::command_runner::FakeCommandRunner;
+    use crate::settings::structs::MainCertificate;
+
+    fn self_signed_certificate() -> CertificateSettings {
+        CertificateSettings {
+            component_name: String::from("blackbox"),
+            algorithm: String::from("rsa:2048"),
+            main_certificate: MainCertificate {
+                duration: 365,
+                key_len: 2048,
+                subj: String::from("/CN=blackbox"),
+                main_paths: CertificatePaths {
+                    key: String::from("/tmp/neco-test/blackbox.key"),
+                    cert: String::from("/tmp/neco-test/blackbox.crt"),
+                },
+                ..Default::default()
+            },
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn generate_certificate_fails_if_openssl_fails() {
+        let runner = FakeCommandRunner::default().respond(1, "", "req: unable to write the key\n");
+
+        let e = generate_certificate(&runner, &self_signed_certificate(), false).unwrap_err();
+
+        assert!(e.to_string().contains("Could not generate the self-signed certificate"), "{}", e);
+        assert!(e.to_string().contains("unable to write the key"), "{}", e);
+    }
+
+    #[test]
+    fn generate_certificate_fails_if_signing_with_the_ca_fails() {
+        let certificate = CertificateSettings {
+            cert_authority: Some(CACertificate {
+                subj: String::from("/CN=blackbox CA"),
+                main_paths: CertificatePaths {
+                    key: String::from("/tmp/neco-test/ca.key"),
+                    cert: String::from("/tmp/neco-test/ca.crt"),
+                },
+                ..Default::default()
+            }),
+            ..self_signed_certificate()
+        };
+        // Key and CSR succeed, signing fails
+        let runner = FakeCommandRunner::default()
+            .respond(0, "", "")
+            .respond(0, "", "")
+            .respond(1, "", "");
+
+        let e = generate_certificate(&runner, &certificate, false).unwrap_err();
+
+        assert!(e.to_string().contains("Could not sign the certificate with the CA"), "{}", e);
+        assert_eq!(runner.commands().len(), 3);
+    }
+
+    #[test]
+    fn generate_certificate_uses_the_algorithm_for_ca_signed_keys() {
+        let certificate = CertificateSettings {
+            algorithm: String::from("ec:prime256v1"),
+            cert_authority: Some(CACertificate::default()),
+            ..self_signed_certificate()
+        };
+        let runner = FakeCommandRunner::default();
+
+        generate_certificate(&runner, &certificate, false).unwrap();
+
+        assert_eq!(
+            runner.commands()[0],
+            "openssl genpkey -algorithm EC -pkeyopt ec_paramgen_curve:prime256v1 -out /tmp/neco-test/blackbox.key"
+        );
+    }
+}
@@ -21,3 +21,16 @@ pub struct CertRenewal {
     pub crt_type: String,
     pub crt_data: String,
 }
+
+/**
+ * SHA256 fingerprint of one of the certificates of a component, `crt_type` is "ca", "intermediate" or "main".
+ * If the certificate can't be read or parsed, `fingerprint` is `None` and `error` says why.
+ */
+#[derive(Serialize, Debug)]
+pub struct CertificateFingerprint {
+    pub component_name: String,
+    pub crt_type: String,
+    pub path: String,
+    pub fingerprint: Option<String>,
+    pub error: Option<String>,
+}
@@ -0,0 +1,58 @@
+use std::path::{Path, PathBuf};
+
+use sysinfo::{Disks, System};
+
+use crate::BASE_DIRECTORY;
+
+/**
+ * Resources of the host NECO runs on, reported so failed updates can be correlated with disk or memory pressure.
+ * Sizes are in bytes, the uptime is in seconds.
+ */
+#[derive(Serialize, Debug)]
+pub struct HostMetrics {
+    pub disk_mount_point: Option<String>, // Filesystem `BASE_DIRECTORY` is on, `None` if it couldn't be found
+    pub disk_total: Option<u64>,
+    pub disk_free: Option<u64>, // Available to NECO, excludes the space reserved for root
+    pub memory_total: u64,
+    pub memory_free: u64,
+    pub memory_available: u64, // Free memory plus the memory that can be reclaimed (e.g. caches)
+    pub load_average: [f64; 3], // 1, 5 and 15 minutes
+    pub uptime: u64,
+}
+
+/**
+ * Gathers the free disk space of the filesystem `BASE_DIRECTORY` is on, the memory usage, the load average and the uptime.
+ */
+pub fn collect() -> HostMetrics {
+    let mut system = System::new();
+    system.refresh_memory();
+
+    let load_average = System::load_average();
+
+    let disks = Disks::new_with_refreshed_list();
+    let base_directory = Path::new(BASE_DIRECTORY)
+        .canonicalize()
+        .unwrap_or_else(|_| PathBuf::from(BASE_DIRECTORY));
+
+    // The filesystem mounted closest to the base directory holds it
+    let disk = disks
+        .list()
+        .iter()
+        .filter(|disk| base_directory.starts_with(disk.mount_point()))
+        .max_by_key(|disk| disk.mount_point().as_os_str().len());
+
+    if disk.is_none() {
+        warn!("Could not find the filesystem of '{}'.", BASE_DIRECTORY);
+    }
+
+    HostMetrics {
+        disk_mount_point: disk.map(|disk| disk.mount_point().to_string_lossy().to_string()),
+        disk_total: disk.map(|disk| disk.total_space()),
+        disk_free: disk.map(|disk| disk.available_space()),
+        memory_total: system.total_memory(),
+        memory_free: system.free_memory(),
+        memory_available: system.available_memory(),
+        load_average: [load_average.one, load_average.five, load_average.fifteen],
+        uptime: System::uptime(),
+    }
+}
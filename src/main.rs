@@ -3,17 +3,22 @@
 // #![deny(clippy::pedantic)]
 // #![deny(clippy::all)]
 
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, VecDeque};
 use std::env;
 use std::sync::atomic::{AtomicBool, Ordering};
 //use std::sync::mpsc::{channel, Receiver, Sender};
 use std::sync::Mutex;
+use std::time::Instant;
 
 use paho_mqtt as mqtt;
 
+use nix::sys::signal::{signal, SigHandler, Signal};
+use nix::unistd::geteuid;
+
 use lazy_static::lazy_static;
 
-use clap::{App, Arg, SubCommand};
+use clap::{App, Arg, ArgMatches, SubCommand};
+use serde_json::json;
 
 #[macro_use]
 extern crate log;
@@ -23,7 +28,10 @@ use env_logger;
 extern crate serde_derive;
 
 mod version_control;
-use crate::version_control::{find_leftover_updates, init_component_versions};
+use crate::version_control::{
+    find_leftover_updates, in_maintenance_window, init_component_versions, install_staged_updates,
+    purge_stale_temp_files, MAINTENANCE_WINDOW_CHECK_INTERVAL, VERSION_DRIFT_CHECK_INTERVAL,
+};
 use version_control::structs::UpdateManifest;
 
 mod mqtt_connection;
@@ -36,31 +44,70 @@ mod encryption_certificates;
 
 mod remote_management;
 
+mod command_runner;
+
+mod preflight;
+
+mod audit;
+
+mod host_metrics;
+
+mod cli_output;
+use cli_output::CliOutcome;
+
 lazy_static! {
     static ref SETTINGS: Mutex<settings::structs::Settings> = Mutex::default();
     static ref UPDATE_COMPONENTS: Mutex<Vec<settings::structs::UpdateComponent>> = Mutex::default();
     static ref COMPONENT_VERSIONS: Mutex<BTreeMap<String, String>> = Mutex::default();
     //static ref COMPONENT_MQTT_OWN_TOPIC: Mutex<String> = Mutex::default();
     static ref UPDATE_MANIFEST: Mutex<Option<UpdateManifest>> = Mutex::default();
+    static ref SETTINGS_FILE_OVERRIDE: Mutex<Option<String>> = Mutex::default();
+    static ref EXTERNAL_INTERFACE_QUEUE: Mutex<VecDeque<String>> = Mutex::default();
+    static ref STARTUP_TIME: Instant = Instant::now();
+    static ref CERTIFICATES: Mutex<Vec<settings::structs::CertificateSettings>> = Mutex::default();
+    // Hash of the last audit log entry, loaded from the audit log on the first write
+    static ref AUDIT_LOG: Mutex<Option<String>> = Mutex::default();
+    // When each rate limited command type was last accepted from the External Interface
+    static ref COMMAND_LAST_ACCEPTED: Mutex<BTreeMap<String, Instant>> = Mutex::default();
 }
 
 const APP_NAME: &str = "NeutronCommunicator";
 const APP_VERSION: &str = env!("CARGO_PKG_VERSION");
 const BASE_DIRECTORY: &str = "/etc/NeutronCommunicator/";
 
+// Defaults for the `neutron_server` settings entry
 const NEUTRON_SERVER_IP: &str = "127.0.0.1";
-const NEUTRON_SERVER_PORT: &str = ":8002";
+const NEUTRON_SERVER_PORT: u16 = 8002;
 #[cfg(feature = "SECURE")]
-const NEUTRON_SERVER_PROTOCOL: &str = "https://";
+const NEUTRON_SERVER_USE_TLS: bool = true;
 #[cfg(feature = "INSECURE")]
-const NEUTRON_SERVER_PROTOCOL: &str = "http://";
+const NEUTRON_SERVER_USE_TLS: bool = false;
 
 static RESTART_NECO: AtomicBool = AtomicBool::new(false);
+// Set/cleared by the connection callbacks of the respective mqtt client
+static COMPONENT_MQTT_CONNECTED: AtomicBool = AtomicBool::new(false);
+static NEUTRON_MQTT_CONNECTED: AtomicBool = AtomicBool::new(false);
+// Set by the certificate watchdog when it renews the client certificate of the component mqtt client,
+//     or when the reloaded settings change the connection options
+static RECONNECT_COMPONENT_MQTT: AtomicBool = AtomicBool::new(false);
+// Set by the SIGHUP handler, the main loop reloads the settings
+static RELOAD_SETTINGS: AtomicBool = AtomicBool::new(false);
+// Set once an encrypted settings file or passphrase store was loaded, they're never saved in plaintext afterwards
+//     unless settings encryption is disabled with `settings::decrypt_settings()`
+static SETTINGS_ENCRYPTED: AtomicBool = AtomicBool::new(false);
 
 fn main() {
+    // Uptime is measured from here
+    lazy_static::initialize(&STARTUP_TIME);
+
     check_if_root();
     process_cli_args();
 
+    // An upgraded NECO that keeps failing to start is rolled back to the previous binary
+    if version_control::recovery::check_upgrade() {
+        std::process::exit(1);
+    }
+
     // Try to load the settings file
     let settings = if let Ok(res) = settings::init() {
         // Save Settings struct to a static ref
@@ -92,17 +139,59 @@ fn main() {
         std::process::exit(1);
     };
 
-    // Check for unfinished updates
-    find_leftover_updates(&settings.update_components);
+    // Missing binaries would otherwise only show up as failures in the middle of an update or a renewal
+    if !preflight::run(&settings.update_components) {
+        error!("Required external binaries are missing. Exiting...");
+        std::process::exit(1);
+    }
+
+    // Updates are downloaded into the temporary folder, it can be outside of the base directory
+    match version_control::check_temp_folder(&settings.update_files) {
+        Ok(temp_folder) => debug!("Temporary update folder: '{}'.", temp_folder),
+        Err(e) => {
+            error!("{} Exiting...", e);
+            std::process::exit(1);
+        }
+    }
+
+    if settings.sandbox.enabled {
+        warn!(
+            "SANDBOX MODE! Updates are installed into '{}', commands and scripts are skipped and components are not restarted.",
+            settings.sandbox.dir
+        );
+    }
+
+    let unverified_components = version_control::get_unverified_components(&settings.update_components);
+    if !unverified_components.is_empty() {
+        warn!(
+            "CHECKSUM VERIFICATION IS DISABLED for: {}. Their update packages are installed without being verified!",
+            unverified_components.into_iter().collect::<Vec<String>>().join(", ")
+        );
+    }
+
+    // Check for unfinished updates, they're kept for when updates are resumed
+    if settings.updates_paused {
+        warn!("UPDATES ARE PAUSED! No update manifests will be requested and no updates will be installed until they're resumed.");
+    } else {
+        find_leftover_updates(
+            &settings.update_components,
+            &settings.update_files,
+            &settings.maintenance_window,
+            version_control::get_sandbox_root(&settings).as_deref(),
+        );
+    }
+
+    // Reclaim the disk space taken by updates that were interrupted
+    purge_stale_temp_files(&settings.update_files);
 
     info!("Neutron Communicator::Startup V{}", APP_VERSION);
     println!();
 
     let component_mqtt =
-        mqtt_connection::init_component_mqtt(&settings.component_mqtt_client).unwrap();
+        mqtt_connection::init_component_mqtt(&settings.component_mqtt_client, &settings.certificates).unwrap();
 
     // let neutron_mqtt =
-    //     mqtt_connection::init_neutron_mqtt(&settings.neutron_mqtt_client).unwrap();
+    //     mqtt_connection::init_neutron_mqtt(&settings.neutron_server, &settings.neutron_mqtt_client).unwrap();
 
 
     let mut cert_watchdog_thread: Option<std::thread::JoinHandle<()>> = None;
@@ -117,12 +206,65 @@ fn main() {
     /*warn!("VERSIONS: {:?}", COMPONENT_VERSIONS.lock().unwrap());
     info!("{:?}", t_update_manifest);*/
 
+    // We made it through the startup, the backup of the previous binary is no longer needed for recovery
+    version_control::recovery::confirm_upgrade();
+
+    // `kill -HUP` reloads the settings file, the same as the `ReloadSettings` command
+    if let Err(e) = unsafe { signal(Signal::SIGHUP, SigHandler::Handler(request_settings_reload)) } {
+        error!("Could not install the SIGHUP handler. {}", e);
+    }
+
+    let mut last_drift_check = Instant::now();
+    // The staged updates are installed when the maintenance window opens, startup already installed them if it's open
+    let mut last_window_check = Instant::now();
+    let mut maintenance_window_open = in_maintenance_window(&settings.maintenance_window);
+
     loop {
         std::thread::sleep(std::time::Duration::from_secs(1));
         if RESTART_NECO.load(Ordering::SeqCst) {
             warn!("Restarting NECO. Breaking loop in main...");
             break;
         }
+        if last_drift_check.elapsed() >= VERSION_DRIFT_CHECK_INTERVAL {
+            last_drift_check = Instant::now();
+            mqtt_connection::component_mqtt::send_version_drift(&component_mqtt, &None, false);
+        }
+        if RELOAD_SETTINGS.swap(false, Ordering::SeqCst) {
+            info!("SIGHUP received. Reloading settings...");
+            if let Err(e) = settings::reload() {
+                error!("Could not reload the settings. {}", e);
+            }
+        }
+        // The settings can be reloaded, the window is read from the current ones
+        if last_window_check.elapsed() >= MAINTENANCE_WINDOW_CHECK_INTERVAL {
+            last_window_check = Instant::now();
+            let window_open = match SETTINGS.lock() {
+                Ok(current_settings) => in_maintenance_window(&current_settings.maintenance_window),
+                Err(_) => {
+                    error!("Could not lock SETTINGS mutex.");
+                    maintenance_window_open
+                }
+            };
+            if window_open && !maintenance_window_open {
+                install_staged_updates(&component_mqtt);
+            }
+            maintenance_window_open = window_open;
+        }
+        if RECONNECT_COMPONENT_MQTT.swap(false, Ordering::SeqCst) {
+            // Cloned, the mutex must not be held while the client disconnects
+            let current_settings = SETTINGS.lock().map(|current_settings| current_settings.clone());
+
+            if let Ok(current_settings) = current_settings {
+                info!("Component backhaul connection options changed. Reconnecting to component backhaul...");
+                mqtt_connection::reconnect_component_mqtt(
+                    &component_mqtt,
+                    &current_settings.component_mqtt_client,
+                    &current_settings.certificates,
+                );
+            } else {
+                error!("Could not lock SETTINGS mutex. Not reconnecting to component backhaul.");
+            }
+        }
     }
 
     /*
@@ -140,22 +282,36 @@ fn main() {
     }
 }
 
+/**
+ * SIGHUP handler, only sets `RELOAD_SETTINGS` (nothing that isn't async-signal-safe can run here), the main loop does the reload.
+ */
+extern "C" fn request_settings_reload(_signal: i32) {
+    RELOAD_SETTINGS.store(true, Ordering::SeqCst);
+}
+
 /**
  * Checks if app is root.
  * If the app is not root, makes sure the user knows that some functions will not work.
  */
 fn check_if_root() {
-    if let Ok(user) = env::var("USER") {
-        if user == "root" {
-            return;
-        }
+    if is_root() {
+        return;
     }
 
     eprintln!("This application needs to be ran as root. Some functions WILL fail.");
 }
 
+/**
+ * Returns `true` if the app is ran as root (effective user id 0).
+ * `$USER` isn't checked, any user can set it to anything.
+ */
+fn is_root() -> bool {
+    geteuid().is_root()
+}
+
 /**
  * Processes the command-line arguments provided on app start.
+ * If a subcommand was given, it's ran and the app exits with the exit code of its outcome (see `cli_output`).
  */
 fn process_cli_args() {
     let matches = App::new("Neutron Communicator")
@@ -170,7 +326,66 @@ fn process_cli_args() {
                 .possible_values(&["info", "warn", "debug", "trace"])
                 .default_value("info"),
         )
-        .subcommand(SubCommand::with_name("gen_settings").about("Generate default settings file."))
+        .arg(
+            Arg::with_name("output")
+                .long("output")
+                .value_name("FORMAT")
+                .help("Output format of the subcommands. 'json' prints a single result object with the status, message and data.")
+                .possible_values(&["text", "json"])
+                .default_value("text")
+                .global(true),
+        )
+        .arg(
+            Arg::with_name("config")
+                .long("config")
+                .value_name("FILE")
+                .help("Use an alternate settings file instead of the default one.")
+                .takes_value(true)
+                .global(true),
+        )
+        .subcommand(SubCommand::with_name("gen_settings").about("Generate default settings file.")
+                    .arg(Arg::with_name("force")
+                            .long("force")
+                            .help("Overwrite an existing settings file. The old file is kept as 'settings.json.bak'."))
+                    )
+        .subcommand(SubCommand::with_name("show_settings").about("Show the settings with the passwords and passphrases redacted.")
+                    .arg(Arg::with_name("reveal")
+                            .long("reveal")
+                            .help("Show the passwords and passphrases in full. Requires root."))
+                    )
+        .subcommand(SubCommand::with_name("encrypt_settings").about("Encrypt the settings file and the passphrase store with the key file next to the settings file ('settings.key'). A random key file is generated if there isn't one.")
+                    .arg(Arg::with_name("rekey")
+                            .long("rekey")
+                            .help("Generate a new key file and re-encrypt with it. The old key file is kept as 'settings.key.bak'."))
+                    )
+        .subcommand(SubCommand::with_name("decrypt_settings").about("Decrypt the settings file and the passphrase store and disable settings encryption. The key file is kept as 'settings.key.bak'."))
+        .subcommand(SubCommand::with_name("check_connectivity").about("Check if the MQTT brokers and the Neutron server API can be reached with the configured credentials."))
+        .subcommand(SubCommand::with_name("preflight").about("Check if the external binaries NECO depends on (openssl, systemctl, docker...) are installed."))
+        .subcommand(SubCommand::with_name("preview_updates").about("Show the updates available for this device (versions, sizes, changelogs) without installing them."))
+        .subcommand(SubCommand::with_name("validate_recipe").about("Check a recipe file for problems without installing anything.")
+                    .arg(Arg::with_name("path")
+                            .value_name("FILE")
+                            .help("Path to the recipe file.")
+                            .required(true))
+                    .arg(Arg::with_name("extracted_dir")
+                            .long("extracted_dir")
+                            .value_name("DIR")
+                            .help("Folder the update package is extracted to, the files the recipe references are looked up in it. Defaults to the folder of the recipe.")
+                            .takes_value(true))
+                    )
+        .subcommand(SubCommand::with_name("test_update").about("Download the available updates and install them into a sandbox folder instead of the live system. Commands and scripts are not run, components are not restarted.")
+                    .arg(Arg::with_name("sandbox")
+                            .long("sandbox")
+                            .value_name("DIR")
+                            .help("Folder the updates are installed into, the copy destinations are recreated under it.")
+                            .takes_value(true)
+                            .required(true))
+                    .arg(Arg::with_name("component")
+                            .value_name("COMPONENT")
+                            .help("Only test the updates of these components. (default: every tracked component)")
+                            .multiple(true))
+                    )
+        .subcommand(SubCommand::with_name("build_info").about("Show the version, compiled features and update branch of this NECO."))
         .subcommand(SubCommand::with_name("neutron_credentials").about("Set the Neutron server credentials.")
                     .arg(Arg::with_name("neutron_username")
                             .long("neutron_user")
@@ -289,6 +504,37 @@ fn process_cli_args() {
                             .help("Command for restarting the component container/service.")
                             .takes_value(true)
                             .required(true))
+                    .arg(Arg::with_name("branch")
+                            .long("branch")
+                            .short("b")
+                            .value_name("STRING")
+                            .help("Update branch of this component. Overrides the global update branch.")
+                            .takes_value(true)
+                            .required(false))
+                    .arg(Arg::with_name("group")
+                            .long("group")
+                            .value_name("STRING")
+                            .help("Group of this component. Group members can be queried and restarted together.")
+                            .takes_value(true)
+                            .required(false))
+                    .arg(Arg::with_name("health_check")
+                            .long("health_check")
+                            .value_name("STRING")
+                            .help("Command ran after the component is restarted by an update. A non-zero exit code fails the update.")
+                            .takes_value(true)
+                            .required(false))
+                    .arg(Arg::with_name("health_check_grace_period")
+                            .long("health_check_grace_period")
+                            .value_name("SECONDS")
+                            .help("Time to wait after the restart before checking the component health. Default: 10")
+                            .takes_value(true)
+                            .required(false))
+                    .arg(Arg::with_name("restart_retries")
+                            .long("restart_retries")
+                            .value_name("COUNT")
+                            .help("How many times the restart is re-issued while the component isn't healthy after an update. Default: 0")
+                            .takes_value(true)
+                            .required(false))
                     )
                 .subcommand(SubCommand::with_name("remove").about("Remove an update component.")
                     .arg(Arg::with_name("name")
@@ -323,6 +569,31 @@ fn process_cli_args() {
                             .number_of_values(2)
                             .required(true))
                     )
+        .subcommand(SubCommand::with_name("renew_certificate").about("Immediately replace the key and certificate of the specified certificate/component.")
+                    .arg(Arg::with_name("component_name")
+                            .long("name")
+                            .value_name("STRING")
+                            .help("Specify the name of the component the certificate belongs to.")
+                            .takes_value(true)
+                            .required(true))
+                    .arg(Arg::with_name("certificate_type")
+                            .long("type")
+                            .value_name("TYPE")
+                            .help("Specify the type of certificate you want to renew. Renewing the CA also renews the main certificate.")
+                            .possible_values(&["ca", "main"])
+                            .default_value("main"))
+                    )
+        .subcommand(SubCommand::with_name("regenerate_all_certificates").about("Immediately replace the keys and certificates (CAs included) of every certificate, e.g. after a key compromise.")
+                    )
+        .subcommand(SubCommand::with_name("cert_watchdog").about("Pause/resume the certificate watchdog of the running NECO, e.g. during certificate maintenance.")
+                    .arg(Arg::with_name("action")
+                            .value_name("ACTION")
+                            .help("Pause or resume the watchdog, or show if it's paused.")
+                            .possible_values(&["pause", "resume", "status"])
+                            .required(true))
+                    )
+        .subcommand(SubCommand::with_name("cert_fingerprints").about("Show the SHA256 fingerprints of the managed certificates (CAs included), e.g. for pinning.")
+                    )
         .subcommand(SubCommand::with_name("add_certificate").about("Add a new certificate for generation/tracking. (Use with no subcommand generates a self-signed certificate)")
                     .subcommand(SubCommand::with_name("ca-signed").about("Generate a CA-signed certificate.")
                                 .arg(Arg::with_name("ca_not_encrypted")
@@ -358,6 +629,13 @@ fn process_cli_args() {
                                         .help("Path to the CA certificate file. THE PATH MUST END WITH A FILE EXTENSION!")
                                         .takes_value(true)
                                         .required(true))
+                                .arg(Arg::with_name("intermediate_ca")
+                                        .long("intermediate_ca")
+                                        .value_names(&["DAYS", "SUBJ", "KEY_FILE", "CERT_FILE"])
+                                        .help("Add an intermediate CA to the chain, can be repeated. The first one is signed by the CA, the last one signs the certificate. Its key is encrypted unless --ca_not_encrypted is specified. THE PATHS MUST END WITH A FILE EXTENSION!")
+                                        .takes_value(true)
+                                        .multiple(true)
+                                        .number_of_values(4))
                                 )
                     .arg(Arg::with_name("component_name")
                             .long("name")
@@ -368,12 +646,16 @@ fn process_cli_args() {
                     .arg(Arg::with_name("algorithm")
                             .long("algorithm")
                             .value_name("ALGORITHM")
-                            .help("Specify the algorithm for key generation.")
+                            .help("Specify the algorithm for key generation. ('rsa:<bits>', 'ec:<prime256v1|secp384r1|secp521r1>' or 'ed25519')")
                             .takes_value(true)
                             .default_value("rsa:2048"))
                     .arg(Arg::with_name("key_not_encrypted")
                             .long("not_encrypted")
                             .help("If specified, the key will not be encrypted with a randomly-generated passphrase."))
+                    .arg(Arg::with_name("dry_run")
+                            .long("dry_run")
+                            .alias("dry-run")
+                            .help("Only show the openssl commands (and SAN extension files) that would be ran. No keys or certificates are created and the settings are not changed."))
                     .arg(Arg::with_name("certificate_duration")
                             .long("certificate_duration")
                             .value_name("DAYS")
@@ -383,6 +665,7 @@ fn process_cli_args() {
                     .arg(Arg::with_name("key_length")
                             .long("key_length")
                             .value_name("SIZE")
+                            .help("Key length of a CA-signed certificate with an RSA algorithm, other algorithms ignore it.")
                             .takes_value(true)
                             .default_value("2048"))
                     .arg(Arg::with_name("cert_parameters")
@@ -412,204 +695,736 @@ fn process_cli_args() {
                             .number_of_values(1)
                             .use_delimiter(true)
                             .required(true))
+                    .arg(Arg::with_name("chain_file")
+                            .long("chain_file")
+                            .value_name("FILE")
+                            .help("If specified, the certificate followed by the CA chain is also written to this file.")
+                            .takes_value(true))
+                    .arg(Arg::with_name("pkcs12_file")
+                            .long("pkcs12_file")
+                            .value_name("FILE")
+                            .help("If specified, the key, certificate and CA chain are also exported to this PKCS#12 bundle.")
+                            .takes_value(true))
+                    .arg(Arg::with_name("pkcs12_passphrase")
+                            .long("pkcs12_passphrase")
+                            .value_name("STRING")
+                            .help("Passphrase of the PKCS#12 bundle.")
+                            .takes_value(true)
+                            .requires("pkcs12_file"))
+                    .arg(Arg::with_name("passphrase_length")
+                            .long("passphrase_length")
+                            .value_name("LENGTH")
+                            .help("Length of the generated key passphrases. (default: 20)")
+                            .takes_value(true))
+                    .arg(Arg::with_name("passphrase_charset")
+                            .long("passphrase_charset")
+                            .value_name("CHARACTERS")
+                            .help("Characters the generated key passphrases are made of. (default: alphanumeric)")
+                            .takes_value(true))
                     )
         .get_matches();
 
     init_logging(matches.value_of("verbosity").unwrap());
 
-    //if let Some(cmd) = matches.subcommand_matches("gen_settings") {
-    if matches.subcommand_matches("gen_settings").is_some() {
-        match settings::write_default() {
-            Ok(path) => info!("Default settings file generated. File Path: {}", path),
-            Err(e) => {
-                error!("Could not write default settings to disk. {}", e);
-                std::process::exit(1);
-            }
+    // The override has to be set before any subcommand loads or saves the settings file
+    if let Some(config_path) = matches.value_of("config") {
+        if let Ok(mut settings_file) = SETTINGS_FILE_OVERRIDE.lock() {
+            *settings_file = Some(config_path.to_owned());
         }
-        std::process::exit(0);
-    }
-
-    if let Some(cmd) = matches.subcommand_matches("neutron_credentials") {
-        if let Ok(settings_struct) = settings::init() {
-            if let Err(e) = settings::mqtt_connection::save_neutron_creds (
-                settings_struct,
-                cmd.value_of("neutron_username").unwrap(),
-                cmd.value_of("mqtt_username").unwrap(),
-                cmd.value_of("mqtt_password").unwrap(),
-            ) {
-                error!("{}", e);
-                std::process::exit(1);
+    }
+
+    let format = cli_output::OutputFormat::from_arg(matches.value_of("output"));
+
+    let outcome = match matches.subcommand() {
+        ("gen_settings", Some(cmd)) => cli_gen_settings(cmd),
+        ("show_settings", Some(cmd)) => cli_show_settings(cmd),
+        ("encrypt_settings", Some(cmd)) => cli_encrypt_settings(cmd),
+        ("decrypt_settings", Some(_)) => cli_decrypt_settings(),
+        ("check_connectivity", Some(_)) => cli_check_connectivity(),
+        ("preflight", Some(_)) => cli_preflight(),
+        ("build_info", Some(_)) => cli_build_info(),
+        ("preview_updates", Some(_)) => cli_preview_updates(),
+        ("validate_recipe", Some(cmd)) => cli_validate_recipe(cmd),
+        ("test_update", Some(cmd)) => cli_test_update(cmd),
+        ("neutron_credentials", Some(cmd)) => cli_neutron_credentials(cmd),
+        ("comp_backhaul_credentials", Some(cmd)) => cli_comp_backhaul_credentials(cmd),
+        ("update_component", Some(cmd)) => cli_update_component(cmd),
+        ("add_cert_aux_paths", Some(cmd)) => cli_add_cert_aux_paths(cmd),
+        ("renew_certificate", Some(cmd)) => cli_renew_certificate(cmd),
+        ("regenerate_all_certificates", Some(_)) => cli_regenerate_all_certificates(),
+        ("cert_watchdog", Some(cmd)) => cli_cert_watchdog(cmd),
+        ("cert_fingerprints", Some(_)) => cli_cert_fingerprints(),
+        ("add_certificate", Some(cmd)) => cli_add_certificate(cmd),
+        // No subcommand, start NECO
+        _ => return,
+    };
+
+    cli_output::exit(format, outcome);
+}
+
+fn cli_gen_settings(cmd: &ArgMatches) -> CliOutcome {
+    match settings::write_default(cmd.is_present("force")) {
+        Ok(path) => CliOutcome::success(&format!("Default settings file generated. File Path: {}", path))
+            .with_data(json!({ "path": path })),
+        Err(e) => CliOutcome::failure(
+            cli_output::exit_code_for(&e),
+            &format!("Could not write default settings to disk. {}", e),
+        ),
+    }
+}
+
+fn cli_show_settings(cmd: &ArgMatches) -> CliOutcome {
+    let mut settings_struct = match settings::init() {
+        Ok(settings_struct) => settings_struct,
+        Err(_) => return CliOutcome::settings_error(),
+    };
+
+    // NECO is added to the update components on load, it's not part of the settings file
+    settings_struct.update_components.retain(|x| x.name != APP_NAME);
+
+    if cmd.is_present("reveal") {
+        if !is_root() {
+            return CliOutcome::failure(cli_output::EXIT_PERMISSION_DENIED, "Revealing the settings requires root.");
+        }
+    } else {
+        settings::redact_secrets(&mut settings_struct);
+    }
+
+    match serde_json::to_value(&settings_struct) {
+        Ok(json) => CliOutcome::success("")
+            .with_text(serde_json::to_string_pretty(&json).unwrap_or_default())
+            .with_data(json),
+        Err(e) => CliOutcome::failure(
+            cli_output::EXIT_FAILURE,
+            &format!("Could not convert the settings to JSON. {}", e),
+        ),
+    }
+}
+
+fn cli_encrypt_settings(cmd: &ArgMatches) -> CliOutcome {
+    if !is_root() {
+        return CliOutcome::failure(cli_output::EXIT_PERMISSION_DENIED, "Encrypting the settings requires root.");
+    }
+
+    match settings::encrypt_settings(cmd.is_present("rekey")) {
+        Ok(key_path) => CliOutcome::success(&format!("Settings encrypted. Key file: {}", key_path))
+            .with_data(json!({ "key_path": key_path })),
+        Err(e) => CliOutcome::failure(
+            cli_output::exit_code_for(&e),
+            &format!("Could not encrypt the settings. {}", e),
+        ),
+    }
+}
+
+fn cli_decrypt_settings() -> CliOutcome {
+    if !is_root() {
+        return CliOutcome::failure(cli_output::EXIT_PERMISSION_DENIED, "Decrypting the settings requires root.");
+    }
+
+    match settings::decrypt_settings() {
+        Ok(backup_path) => CliOutcome::success(&format!("Settings decrypted. Key file backed up to: {}", backup_path))
+            .with_data(json!({ "key_backup_path": backup_path })),
+        Err(e) => CliOutcome::failure(
+            cli_output::exit_code_for(&e),
+            &format!("Could not decrypt the settings. {}", e),
+        ),
+    }
+}
+
+fn cli_check_connectivity() -> CliOutcome {
+    let settings_struct = match settings::init() {
+        Ok(settings_struct) => settings_struct,
+        Err(_) => return CliOutcome::settings_error(),
+    };
+
+    let mut checks = vec![
+        (
+            String::from("Component MQTT broker"),
+            mqtt_connection::check_component_mqtt(&settings_struct.component_mqtt_client, &settings_struct.certificates),
+        ),
+        (
+            String::from("Neutron MQTT broker"),
+            mqtt_connection::check_neutron_mqtt(
+                &settings_struct.neutron_server,
+                &settings_struct.neutron_mqtt_client,
+            ),
+        ),
+    ];
+    checks.extend(
+        version_control::check_neutron_api(&settings_struct)
+            .into_iter()
+            .map(|(endpoint, result)| (["Neutron API ", &endpoint].concat(), result)),
+    );
+
+    let mut lines: Vec<String> = Vec::new();
+    let mut results: Vec<serde_json::Value> = Vec::new();
+    for (check, result) in &checks {
+        match result {
+            Ok(_) => lines.push(format!("[PASS] {}", check)),
+            Err(e) => lines.push(format!("[FAIL] {} - {}", check, e)),
+        }
+        results.push(json!({ "check": check, "passed": result.is_ok(), "error": result.as_ref().err() }));
+    }
+
+    let outcome = if checks.iter().any(|(_, result)| result.is_err()) {
+        CliOutcome::failure(cli_output::EXIT_CHECK_FAILED, "Some connectivity checks failed.")
+    } else {
+        CliOutcome::success("")
+    };
+
+    outcome.with_text(lines.join("\n")).with_data(json!(results))
+}
+
+fn cli_preflight() -> CliOutcome {
+    let settings_struct = match settings::init() {
+        Ok(settings_struct) => settings_struct,
+        Err(_) => return CliOutcome::settings_error(),
+    };
+
+    let mut failed = false;
+    let mut lines: Vec<String> = Vec::new();
+    let mut results: Vec<serde_json::Value> = Vec::new();
+    for check in preflight::probe(&settings_struct.update_components) {
+        let status = match &check.path {
+            Some(path) => {
+                lines.push(format!("[PASS] {} ({})", check.name, path.display()));
+                "pass"
             }
-        } else {
-            std::process::exit(1)
+            None if check.required || check.used_by_components => {
+                lines.push(format!("[FAIL] {} - not found, needed for {}", check.name, check.purpose));
+                failed = true;
+                "fail"
+            }
+            None => {
+                lines.push(format!("[WARN] {} - not found, needed for {}", check.name, check.purpose));
+                "warn"
+            }
+        };
+
+        results.push(json!({
+            "binary": check.name,
+            "status": status,
+            "path": check.path.as_ref().map(|path| path.display().to_string()),
+            "purpose": check.purpose,
+        }));
+    }
+
+    let outcome = if failed {
+        CliOutcome::failure(cli_output::EXIT_CHECK_FAILED, "Required external binaries are missing.")
+    } else {
+        CliOutcome::success("")
+    };
+
+    outcome.with_text(lines.join("\n")).with_data(json!(results))
+}
+
+fn cli_build_info() -> CliOutcome {
+    let settings_struct = match settings::init() {
+        Ok(settings_struct) => settings_struct,
+        Err(_) => return CliOutcome::settings_error(),
+    };
+
+    match version_control::get_build_info(&settings_struct.update_branch, None) {
+        Ok(json) => CliOutcome::success("")
+            .with_data(serde_json::from_str(&json).unwrap_or_default())
+            .with_text(json),
+        Err(e) => CliOutcome::failure(cli_output::EXIT_FAILURE, &format!("Could not get build info. {}", e)),
+    }
+}
+
+fn cli_preview_updates() -> CliOutcome {
+    let settings_struct = match settings::init() {
+        Ok(settings_struct) => settings_struct,
+        Err(_) => return CliOutcome::settings_error(),
+    };
+
+    let component_versions = init_component_versions(&settings_struct.update_components);
+
+    let update_manifest = match version_control::fetch_update_manifest(&settings_struct, &component_versions) {
+        Ok(Some(update_manifest)) => update_manifest,
+        Ok(None) => {
+            return CliOutcome::success("No updates were found.")
+                .with_data(json!({ "installed": component_versions, "updates": {} }))
         }
+        Err(e) => return CliOutcome::from_error(&e),
+    };
 
-        info!("Neutron configuration successfully saved.");
-        std::process::exit(0);
-    }
-
-    if let Some(cmd) = matches.subcommand_matches("comp_backhaul_credentials") {
-        if let Ok(settings_struct) = settings::init() {
-            if let Err(e) = settings::mqtt_connection::save_component_creds(
-                settings_struct,
-                cmd.value_of("ip_address").unwrap(),
-                cmd.value_of("port").unwrap(),
-                cmd.value_of("username").unwrap(),
-                cmd.value_of("password").unwrap(),
-                cmd.value_of("ca_file").unwrap(),
-            ) {
-                error!("{}", e);
-                std::process::exit(1);
+    let mut lines: Vec<String> = Vec::new();
+    for (component, updates) in &update_manifest.list {
+        lines.push(format!(
+            "{} (installed: {})",
+            component,
+            component_versions.get(component).map(String::as_str).unwrap_or("-")
+        ));
+
+        for update in updates {
+            lines.push(format!(
+                "  {} - {}{}",
+                update.version,
+                update.file_size.as_deref().unwrap_or("unknown size"),
+                if update.chainlink { ", chainlink" } else { "" }
+            ));
+            for changelog_line in update.changelog.lines() {
+                lines.push(["    ", changelog_line].concat());
             }
-        } else {
-            std::process::exit(1)
         }
+    }
+
+    CliOutcome::success("")
+        .with_text(lines.join("\n"))
+        .with_data(json!({ "installed": component_versions, "updates": update_manifest.list }))
+}
 
-        info!("Component backhaul configuration successfully saved.");
-        std::process::exit(0);
+fn cli_validate_recipe(cmd: &ArgMatches) -> CliOutcome {
+    let recipe_path = cmd.value_of("path").unwrap();
+
+    match version_control::validate_recipe(recipe_path, cmd.value_of("extracted_dir")) {
+        Ok(problems) if problems.is_empty() => {
+            CliOutcome::success("Recipe is valid.").with_data(json!({ "problems": problems }))
+        }
+        Ok(problems) => CliOutcome::failure(
+            cli_output::EXIT_CHECK_FAILED,
+            &format!("Recipe has {} problem(s).", problems.len()),
+        )
+        .with_text(problems.join("\n"))
+        .with_data(json!({ "problems": problems })),
+        Err(e) => CliOutcome::from_error(&e),
     }
+}
 
-    if let Some(cmd) = matches.subcommand_matches("update_component") {
-        if let Some(cmd_add) = cmd.subcommand_matches("add") {
-            if let Ok(settings_struct) = settings::init() {
-                let mut component = settings::structs::UpdateComponent::default();
+fn cli_test_update(cmd: &ArgMatches) -> CliOutcome {
+    let settings_struct = match settings::init() {
+        Ok(settings_struct) => settings_struct,
+        Err(_) => return CliOutcome::settings_error(),
+    };
 
-                if let Some(container_name) = cmd_add.value_of("container_name") {
-                    component.container_name = Some(container_name.to_owned());
-                } else if let Some(service_name) = cmd_add.value_of("service_name"){
-                    component.service_name = Some(service_name.to_owned());
-                } else {
-                    error!("Neither container name or service name weren't specified.");
-                    std::process::exit(1);
-                }
+    let sandbox_root = cmd.value_of("sandbox").unwrap();
+    let components: Vec<String> = cmd
+        .values_of("component")
+        .map(|values| values.map(str::to_owned).collect())
+        .unwrap_or_default();
 
-                component.name = cmd_add.value_of("name").unwrap().to_owned();
-                component.version_file_path = cmd_add.value_of("version_file_path").unwrap().to_owned();
-                component.permission_user = cmd_add.value_of("owner").unwrap().to_owned();
-                component.permission_group = cmd_add.value_of("owner_group").unwrap().to_owned();
-                component.file_permissions = cmd_add.value_of("permissions").unwrap().to_owned();
+    let report = match version_control::test_update(&settings_struct, &components, sandbox_root) {
+        Ok(Some(report)) => report,
+        Ok(None) => return CliOutcome::success("No updates were found."),
+        Err(e) => return CliOutcome::from_error(&e),
+    };
 
-                component.restart_command = cmd_add.value_of("restart_command").unwrap().to_owned();
+    let lines: Vec<String> = report
+        .entries
+        .iter()
+        .map(|entry| match &entry.destination {
+            Some(destination) => format!("{} {}: {} -> {}", entry.component, entry.instruction, entry.source, destination),
+            None => format!("{} {} (skipped): {}", entry.component, entry.instruction, entry.source),
+        })
+        .collect();
+
+    let outcome = if report.failed_components.is_empty() {
+        CliOutcome::success("Updates installed into the sandbox.")
+    } else {
+        CliOutcome::failure(
+            cli_output::EXIT_CHECK_FAILED,
+            &format!("Some components failed to install into the sandbox: {}", report.failed_components.join(", ")),
+        )
+    };
+
+    outcome
+        .with_text(lines.join("\n"))
+        .with_data(serde_json::to_value(&report).unwrap_or_default())
+}
 
-                if let Err(e) = settings::update_components::add_update_component (
-                    settings_struct,
-                    component,
-                ) {
-                    error!("{}", e);
-                    std::process::exit(1);
+fn cli_neutron_credentials(cmd: &ArgMatches) -> CliOutcome {
+    let settings_struct = match settings::init() {
+        Ok(settings_struct) => settings_struct,
+        Err(_) => return CliOutcome::settings_error(),
+    };
+
+    match settings::mqtt_connection::save_neutron_creds(
+        settings_struct,
+        cmd.value_of("neutron_username").unwrap(),
+        cmd.value_of("mqtt_username").unwrap(),
+        cmd.value_of("mqtt_password").unwrap(),
+    ) {
+        Ok(_) => CliOutcome::success("Neutron configuration successfully saved."),
+        Err(e) => CliOutcome::from_error(&e),
+    }
+}
+
+fn cli_comp_backhaul_credentials(cmd: &ArgMatches) -> CliOutcome {
+    let settings_struct = match settings::init() {
+        Ok(settings_struct) => settings_struct,
+        Err(_) => return CliOutcome::settings_error(),
+    };
+
+    match settings::mqtt_connection::save_component_creds(
+        settings_struct,
+        cmd.value_of("ip_address").unwrap(),
+        cmd.value_of("port").unwrap(),
+        cmd.value_of("username").unwrap(),
+        cmd.value_of("password").unwrap(),
+        cmd.value_of("ca_file").unwrap(),
+    ) {
+        Ok(_) => CliOutcome::success("Component backhaul configuration successfully saved."),
+        Err(e) => CliOutcome::from_error(&e),
+    }
+}
+
+fn cli_update_component(cmd: &ArgMatches) -> CliOutcome {
+    if let Some(cmd_add) = cmd.subcommand_matches("add") {
+        let settings_struct = match settings::init() {
+            Ok(settings_struct) => settings_struct,
+            Err(_) => return CliOutcome::settings_error(),
+        };
+
+        let mut component = settings::structs::UpdateComponent::default();
+
+        if let Some(container_name) = cmd_add.value_of("container_name") {
+            component.container_name = Some(container_name.to_owned());
+        } else if let Some(service_name) = cmd_add.value_of("service_name"){
+            component.service_name = Some(service_name.to_owned());
+        } else {
+            return CliOutcome::failure(
+                cli_output::EXIT_INVALID_INPUT,
+                "Neither container name or service name weren't specified.",
+            );
+        }
+
+        component.name = cmd_add.value_of("name").unwrap().to_owned();
+        component.version_file_path = cmd_add.value_of("version_file_path").unwrap().to_owned();
+        component.permission_user = cmd_add.value_of("owner").unwrap().to_owned();
+        component.permission_group = cmd_add.value_of("owner_group").unwrap().to_owned();
+        component.file_permissions = cmd_add.value_of("permissions").unwrap().to_owned();
+
+        component.restart_command = cmd_add.value_of("restart_command").unwrap().to_owned();
+        component.branch = cmd_add.value_of("branch").map(std::borrow::ToOwned::to_owned);
+        component.group = cmd_add.value_of("group").map(std::borrow::ToOwned::to_owned);
+        component.health_check = cmd_add.value_of("health_check").map(std::borrow::ToOwned::to_owned);
+
+        if let Some(grace_period) = cmd_add.value_of("health_check_grace_period") {
+            match grace_period.parse::<u64>() {
+                Ok(grace_period) => component.health_check_grace_period = Some(grace_period),
+                Err(e) => {
+                    return CliOutcome::failure(
+                        cli_output::EXIT_INVALID_INPUT,
+                        &format!("Invalid health check grace period. {}", e),
+                    )
                 }
-            } else {
-                std::process::exit(1)
             }
+        }
 
-            info!("Update component successfully added.");
-        } else if let Some(cmd_remove) = cmd.subcommand_matches("remove") {
-            if let Ok(settings_struct) = settings::init() {
-                if let Err(e) = settings::update_components::remove_update_component (
-                    settings_struct,
-                    cmd_remove.value_of("name").unwrap(),
-                ) {
-                    error!("{}", e);
-                    std::process::exit(1);
+        if let Some(restart_retries) = cmd_add.value_of("restart_retries") {
+            match restart_retries.parse::<u32>() {
+                Ok(restart_retries) => component.restart_retries = Some(restart_retries),
+                Err(e) => {
+                    return CliOutcome::failure(
+                        cli_output::EXIT_INVALID_INPUT,
+                        &format!("Invalid restart retry count. {}", e),
+                    )
                 }
-            } else {
-                std::process::exit(1)
             }
+        }
+
+        let name = component.name.to_owned();
+        match settings::update_components::add_update_component(settings_struct, component) {
+            Ok(_) => CliOutcome::success("Update component successfully added.").with_data(json!({ "name": name })),
+            Err(e) => CliOutcome::from_error(&e),
+        }
+    } else if let Some(cmd_remove) = cmd.subcommand_matches("remove") {
+        let settings_struct = match settings::init() {
+            Ok(settings_struct) => settings_struct,
+            Err(_) => return CliOutcome::settings_error(),
+        };
 
-            info!("Update component successfully removed.");
+        let name = cmd_remove.value_of("name").unwrap();
+        match settings::update_components::remove_update_component(settings_struct, name) {
+            Ok(_) => CliOutcome::success("Update component successfully removed.").with_data(json!({ "name": name })),
+            Err(e) => CliOutcome::from_error(&e),
         }
-        std::process::exit(0);
+    } else {
+        CliOutcome::success("")
     }
+}
 
-    if let Some(cmd) = matches.subcommand_matches("add_cert_aux_paths") {
-        if let Ok(settings_struct) = settings::init() {
-            if let Err(e) = settings::encryption_certificates::append_cert_aux_paths(
-                settings_struct,
-                cmd.value_of("component_name").unwrap(),
-                cmd.value_of("certificate_type").unwrap(),
-                cmd.values_of("paths")
-                    .unwrap()
-                    .collect::<Vec<&str>>()
-                    .as_slice(),
-            ) {
-                error!("{}", e);
-                std::process::exit(1);
+fn cli_add_cert_aux_paths(cmd: &ArgMatches) -> CliOutcome {
+    let settings_struct = match settings::init() {
+        Ok(settings_struct) => settings_struct,
+        Err(_) => return CliOutcome::settings_error(),
+    };
+
+    match settings::encryption_certificates::append_cert_aux_paths(
+        settings_struct,
+        cmd.value_of("component_name").unwrap(),
+        cmd.value_of("certificate_type").unwrap(),
+        cmd.values_of("paths")
+            .unwrap()
+            .collect::<Vec<&str>>()
+            .as_slice(),
+    ) {
+        Ok(_) => CliOutcome::success("Certificates generated and paths added to certificate auxiliary path list."),
+        Err(e) => CliOutcome::from_error(&e),
+    }
+}
+
+fn cli_renew_certificate(cmd: &ArgMatches) -> CliOutcome {
+    let settings_struct = match settings::init() {
+        Ok(settings_struct) => settings_struct,
+        Err(_) => return CliOutcome::settings_error(),
+    };
+
+    match settings::encryption_certificates::force_certificate_renewal(
+        settings_struct,
+        cmd.value_of("component_name").unwrap(),
+        cmd.value_of("certificate_type").unwrap(),
+    ) {
+        Ok(_) => CliOutcome::success("Certificate successfully renewed."),
+        Err(e) => CliOutcome::from_error(&e),
+    }
+}
+
+fn cli_regenerate_all_certificates() -> CliOutcome {
+    let results = match settings::init() {
+        Ok(settings_struct) => match settings::encryption_certificates::regenerate_certificates(settings_struct) {
+            Ok(results) => results,
+            Err(e) => return CliOutcome::from_error(&e),
+        },
+        Err(_) => return CliOutcome::settings_error(),
+    };
+
+    let mut failed = 0;
+    let mut lines: Vec<String> = Vec::new();
+    let mut certificates: Vec<serde_json::Value> = Vec::new();
+    for (component_name, result) in &results {
+        match result {
+            Ok(()) => lines.push(format!("{}: regenerated", component_name)),
+            Err(e) => {
+                lines.push(format!("{}: failed ({})", component_name, e));
+                failed += 1;
             }
-        } else {
-            std::process::exit(1)
         }
+        certificates.push(json!({
+            "component": component_name,
+            "regenerated": result.is_ok(),
+            "error": result.as_ref().err().map(|e| e.to_string()),
+        }));
+    }
+
+    let outcome = if failed > 0 {
+        CliOutcome::failure(
+            cli_output::EXIT_CHECK_FAILED,
+            &format!("{} of {} certificates could not be regenerated.", failed, results.len()),
+        )
+    } else {
+        CliOutcome::success("All certificates successfully regenerated.")
+    };
 
-        info!("Certificates generated and paths added to certificate auxiliary path list.");
-        std::process::exit(0);
+    outcome.with_text(lines.join("\n")).with_data(json!(certificates))
+}
+
+fn cli_cert_watchdog(cmd: &ArgMatches) -> CliOutcome {
+    // Logged with the pause/resume for auditing
+    let requested_by = format!(
+        "CLI (user: {})",
+        env::var("SUDO_USER").or_else(|_| env::var("USER")).unwrap_or_else(|_| String::from("unknown"))
+    );
+
+    let result = match cmd.value_of("action").unwrap() {
+        "pause" => encryption_certificates::pause_watchdog(&requested_by),
+        "resume" => encryption_certificates::resume_watchdog(&requested_by),
+        _ => Ok(()),
+    };
+
+    if let Err(e) = result {
+        return CliOutcome::from_error(&e);
     }
 
-    if let Some(cmd) = matches.subcommand_matches("add_certificate") {
-        let mut cert = settings::structs::CertificateSettings {
-            component_name: cmd.value_of("component_name").unwrap().to_owned(),
-            algorithm: cmd.value_of("algorithm").unwrap().to_owned(),
-            cert_authority: None,
-            main_certificate: settings::structs::MainCertificate {
-                encrypted: !cmd.is_present("key_not_encrypted"),
-                duration: cmd
-                    .value_of("certificate_duration")
-                    .unwrap()
-                    .parse()
-                    .unwrap(),
-                key_len: cmd.value_of("key_length").unwrap().parse().unwrap(),
-                subj: cmd.value_of("cert_parameters").unwrap().to_owned(),
-                main_paths: settings::structs::CertificatePaths {
-                    key: cmd.value_of("key_file").unwrap().to_owned(),
-                    cert: cmd.value_of("certificate_file").unwrap().to_owned(),
-                },
-                auxiliary_paths: Vec::new(),
-                service_ips: cmd
-                    .values_of("service_ips")
-                    .unwrap()
-                    .map(std::borrow::ToOwned::to_owned)
-                    .collect(),
-                date_issued: None,
-                passphrase: String::new(),
+    let paused_by = encryption_certificates::watchdog_paused_by();
+    let outcome = CliOutcome::success("").with_data(json!({ "paused": paused_by.is_some(), "paused_by": paused_by }));
+
+    if cmd.value_of("action") == Some("status") {
+        outcome.with_text(match paused_by {
+            Some(paused_by) => format!("Paused by {}", paused_by),
+            None => String::from("Running"),
+        })
+    } else {
+        outcome
+    }
+}
+
+fn cli_cert_fingerprints() -> CliOutcome {
+    let settings_struct = match settings::init() {
+        Ok(settings_struct) => settings_struct,
+        Err(_) => return CliOutcome::settings_error(),
+    };
+
+    let fingerprints = encryption_certificates::certificate_fingerprints(&settings_struct.certificates);
+
+    let mut failed = 0;
+    let mut lines: Vec<String> = Vec::new();
+    for cert in &fingerprints {
+        match (&cert.fingerprint, &cert.error) {
+            (Some(fingerprint), _) => lines.push(format!(
+                "{} ({}): {}  {}",
+                cert.component_name, cert.crt_type, fingerprint, cert.path
+            )),
+            (None, error) => {
+                lines.push(format!(
+                    "{} ({}): failed ({})  {}",
+                    cert.component_name,
+                    cert.crt_type,
+                    error.as_deref().unwrap_or_default(),
+                    cert.path
+                ));
+                failed += 1;
+            }
+        }
+    }
+
+    let outcome = if failed > 0 {
+        CliOutcome::failure(
+            cli_output::EXIT_CHECK_FAILED,
+            &format!("{} of {} certificates could not be fingerprinted.", failed, fingerprints.len()),
+        )
+    } else {
+        CliOutcome::success("")
+    };
+
+    outcome.with_text(lines.join("\n")).with_data(json!(fingerprints))
+}
+
+fn cli_add_certificate(cmd: &ArgMatches) -> CliOutcome {
+    let duration = match cmd.value_of("certificate_duration").unwrap().parse() {
+        Ok(duration) => duration,
+        Err(e) => return CliOutcome::failure(cli_output::EXIT_INVALID_INPUT, &format!("Invalid certificate duration. {}", e)),
+    };
+    let key_len = match cmd.value_of("key_length").unwrap().parse() {
+        Ok(key_len) => key_len,
+        Err(e) => return CliOutcome::failure(cli_output::EXIT_INVALID_INPUT, &format!("Invalid key length. {}", e)),
+    };
+
+    let mut cert = settings::structs::CertificateSettings {
+        component_name: cmd.value_of("component_name").unwrap().to_owned(),
+        algorithm: cmd.value_of("algorithm").unwrap().to_owned(),
+        cert_authority: None,
+        intermediate_cas: Vec::new(),
+        main_certificate: settings::structs::MainCertificate {
+            encrypted: !cmd.is_present("key_not_encrypted"),
+            duration,
+            key_len,
+            subj: cmd.value_of("cert_parameters").unwrap().to_owned(),
+            main_paths: settings::structs::CertificatePaths {
+                key: cmd.value_of("key_file").unwrap().to_owned(),
+                cert: cmd.value_of("certificate_file").unwrap().to_owned(),
             },
-        };
+            auxiliary_paths: Vec::new(),
+            service_ips: cmd
+                .values_of("service_ips")
+                .unwrap()
+                .map(std::borrow::ToOwned::to_owned)
+                .collect(),
+            date_issued: None,
+            passphrase: String::new(),
+            chain_path: cmd.value_of("chain_file").unwrap_or_default().to_owned(),
+            pkcs12_path: cmd.value_of("pkcs12_file").unwrap_or_default().to_owned(),
+            pkcs12_passphrase: cmd.value_of("pkcs12_passphrase").unwrap_or_default().to_owned(),
+        },
+        passphrase_policy: settings::structs::PassphrasePolicy::default(),
+    };
 
-        if let Some(ca_signed) = cmd.subcommand_matches("ca-signed") {
-            info!("Generating a CA-Signed certificate.");
+    if let Some(length) = cmd.value_of("passphrase_length") {
+        match length.parse() {
+            Ok(length) => cert.passphrase_policy.length = length,
+            Err(e) => {
+                return CliOutcome::failure(cli_output::EXIT_INVALID_INPUT, &format!("Invalid passphrase length. {}", e))
+            }
+        }
+    }
+    if let Some(charset) = cmd.value_of("passphrase_charset") {
+        cert.passphrase_policy.charset = charset.to_owned();
+    }
 
-            cert.cert_authority = Some(settings::structs::CACertificate {
-                encrypted: !ca_signed.is_present("ca_not_encrypted"),
-                duration: ca_signed
-                    .value_of("ca_certificate_duration")
+    if let Some(ca_signed) = cmd.subcommand_matches("ca-signed") {
+        info!("Generating a CA-Signed certificate.");
+
+        let ca_duration = match ca_signed.value_of("ca_certificate_duration").unwrap().parse() {
+            Ok(duration) => duration,
+            Err(e) => {
+                return CliOutcome::failure(
+                    cli_output::EXIT_INVALID_INPUT,
+                    &format!("Invalid CA certificate duration. {}", e),
+                )
+            }
+        };
+
+        cert.cert_authority = Some(settings::structs::CACertificate {
+            encrypted: !ca_signed.is_present("ca_not_encrypted"),
+            duration: ca_duration,
+            extensions: ca_signed.value_of("ca_extensions").unwrap().to_owned(),
+            subj: ca_signed.value_of("ca_cert_parameters").unwrap().to_owned(),
+            main_paths: settings::structs::CertificatePaths {
+                key: ca_signed.value_of("ca_key_file").unwrap().to_owned(),
+                cert: ca_signed
+                    .value_of("ca_certificate_file")
                     .unwrap()
-                    .parse()
-                    .unwrap(),
-                extensions: ca_signed.value_of("ca_extensions").unwrap().to_owned(),
-                subj: ca_signed.value_of("ca_cert_parameters").unwrap().to_owned(),
-                main_paths: settings::structs::CertificatePaths {
-                    key: ca_signed.value_of("ca_key_file").unwrap().to_owned(),
-                    cert: ca_signed
-                        .value_of("ca_certificate_file")
-                        .unwrap()
-                        .to_owned(),
-                },
-                auxiliary_paths: Vec::new(),
-                date_issued: None,
-                passphrase: String::new(),
-            });
-        } else {
-            info!("Generating a Self-Signed certificate.");
-        }
+                    .to_owned(),
+            },
+            auxiliary_paths: Vec::new(),
+            date_issued: None,
+            passphrase: String::new(),
+        });
 
-        if let Ok(settings_struct) = settings::init() {
-            if let Err(e) =  settings::encryption_certificates::add_certificate(settings_struct, cert) {
-                error!("{}", e);
-                std::process::exit(1);
+        if let Some(values) = ca_signed.values_of("intermediate_ca") {
+            let values: Vec<&str> = values.collect();
+
+            for intermediate_ca in values.chunks(4) {
+                let duration = match intermediate_ca[0].parse() {
+                    Ok(duration) => duration,
+                    Err(e) => {
+                        return CliOutcome::failure(
+                            cli_output::EXIT_INVALID_INPUT,
+                            &format!("Invalid intermediate CA certificate duration '{}'. {}", intermediate_ca[0], e),
+                        )
+                    }
+                };
+
+                cert.intermediate_cas.push(settings::structs::CACertificate {
+                    encrypted: !ca_signed.is_present("ca_not_encrypted"),
+                    duration,
+                    extensions: String::new(),
+                    subj: intermediate_ca[1].to_owned(),
+                    main_paths: settings::structs::CertificatePaths {
+                        key: intermediate_ca[2].to_owned(),
+                        cert: intermediate_ca[3].to_owned(),
+                    },
+                    auxiliary_paths: Vec::new(),
+                    date_issued: None,
+                    passphrase: String::new(),
+                });
             }
-        } else {
-            std::process::exit(1);
         }
+    } else {
+        info!("Generating a Self-Signed certificate.");
+    }
+
+    let settings_struct = match settings::init() {
+        Ok(settings_struct) => settings_struct,
+        Err(_) => return CliOutcome::settings_error(),
+    };
+
+    let component_name = cert.component_name.to_owned();
+
+    if cmd.is_present("dry_run") {
+        return match settings::encryption_certificates::plan_certificate(&settings_struct, cert) {
+            Ok(commands) => CliOutcome::success("Dry run, no keys or certificates were created.")
+                .with_text(commands.join("\n"))
+                .with_data(json!({ "component": component_name, "commands": commands })),
+            Err(e) => CliOutcome::from_error(&e),
+        };
+    }
 
-        info!("New certificate is successfully registered and generated.");
-        std::process::exit(0);
+    match settings::encryption_certificates::add_certificate(settings_struct, cert) {
+        Ok(_) => CliOutcome::success("New certificate is successfully registered and generated.")
+            .with_data(json!({ "component": component_name })),
+        Err(e) => CliOutcome::from_error(&e),
     }
 }
 
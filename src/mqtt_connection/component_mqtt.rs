@@ -1,15 +1,49 @@
 //use crate::encryption_certificates::structs::CertRenewal;
+use crate::audit;
+use crate::host_metrics;
+use crate::settings;
+use crate::encryption_certificates::{certificate_fingerprints, pause_watchdog, resume_watchdog, watchdog_paused_by};
 use crate::mqtt::{message, AsyncClient, Message};
+use crate::settings::update_components::set_updates_paused;
 use crate::version_control::{
-    get_component_log, get_component_states, request_update_manifest, update_download_and_install,
+    get_build_info, get_component_log, get_component_states, read_install_log, reconcile_component_versions,
+    request_update_manifest, restart_component, restart_group, truncate_log, update_download_and_install,
+    updates_paused,
 };
 // use crate::COMPONENT_MQTT_OWN_TOPIC;
+use crate::{
+    CERTIFICATES, COMMAND_LAST_ACCEPTED, COMPONENT_MQTT_CONNECTED, EXTERNAL_INTERFACE_QUEUE, SETTINGS, STARTUP_TIME,
+    UPDATE_MANIFEST,
+};
+use std::sync::atomic::Ordering;
+use std::time::{Duration, Instant};
 use serde_json::from_str as from_json;
+use serde_json::json;
 
 use super::component_structs::{Command, CommandType};
+use super::connection_failure_reason;
 
 const RECONNECT_TIMEOUT: u64 = 2500;
+const OFFLINE_QUEUE_LIMIT: usize = 100;
+const DEFAULT_MAX_MESSAGE_SIZE: usize = 1024 * 1024;
 const ROOT_EXTERNAL_INTERFACE_TOPIC: &str = "external_interface";
+// Appended to `external_interface/<client id>/` for the retained connection state, see `connection_state()`
+const CONNECTION_STATE_SUBTOPIC: &str = "Connection";
+// Message types that can be published retained (see `publish_retained_copy()`), the others are replies or transient
+const RETAINABLE_MESSAGES: [CommandType; 4] = [
+    CommandType::ComponentStates,
+    CommandType::BuildInfo,
+    CommandType::Changelogs,
+    CommandType::State,
+];
+// Minimum seconds between two accepted commands of the type, for commands that hit Neutron or run docker/systemctl
+const COMMAND_RATE_LIMITS: [(CommandType, u64); 5] = [
+    (CommandType::RefreshUpdateManifest, 60),
+    (CommandType::StartUpdateDownloadAndInstall, 60),
+    (CommandType::ComponentStates, 5),
+    (CommandType::ComponentLog, 5),
+    (CommandType::RestartGroup, 10),
+];
 pub const ROOT_NECO_TOPIC: &str = "neutron_communicators";
 // const ROOT_TOPIC_ALL: &str = "neutron_communicators/#";
 
@@ -28,10 +62,20 @@ pub fn payload_callback(cli: &AsyncClient, msg: Option<message::Message>) {
         //if topic_split.len() == 1 && topic_split[0] == ROOT_TOPIC {
         let mqtt_cli = cli.clone();
         match from_json(&msg.payload_str()) {
-            Ok(result) => process_command(&mqtt_cli, &result),
+            Ok(result) => process_command(&mqtt_cli, msg.topic(), &result),
             Err(e) => {
                 error!("Could not parse command struct.");
                 debug!("{}", e);
+
+                audit::record(
+                    "component",
+                    msg.topic(),
+                    "-",
+                    None,
+                    &audit::fingerprint(&msg.payload_str()),
+                    &["rejected: ", &e.to_string()].concat(),
+                );
+                send_nack(&mqtt_cli, &msg.payload_str(), &e.to_string());
             }
         }
         // std::thread::spawn(move || {
@@ -53,6 +97,7 @@ pub fn payload_callback(cli: &AsyncClient, msg: Option<message::Message>) {
  */
 pub fn connection_success(cli: &AsyncClient, _msgid: u16) {
     info!("Backhaul broker connection succeeded.");
+    COMPONENT_MQTT_CONNECTED.store(true, Ordering::SeqCst);
 
     cli.subscribe(ROOT_NECO_TOPIC, 1);
 
@@ -66,7 +111,11 @@ pub fn connection_success(cli: &AsyncClient, _msgid: u16) {
         1,
     );
 
-    send_component_states(cli);
+    cli.publish(connection_state(true, cli.inner.client_id.to_str().unwrap_or_default()));
+
+    flush_offline_queue(cli);
+
+    send_component_states(cli, None);
     // cli.subscribe(ROOT_TOPIC_ALL, 1);
 }
 
@@ -74,7 +123,12 @@ pub fn connection_success(cli: &AsyncClient, _msgid: u16) {
  * `OnConnectionFail` mqtt callback.
  */
 pub fn connection_failure(cli: &AsyncClient, _msgid: u16, rc: i32) {
-    debug!("Connection attempt failed with error code {}.", rc);
+    COMPONENT_MQTT_CONNECTED.store(false, Ordering::SeqCst);
+    debug!(
+        "Connection attempt failed with error code {}. {}",
+        rc,
+        connection_failure_reason(rc)
+    );
 
     std::thread::sleep(std::time::Duration::from_millis(RECONNECT_TIMEOUT));
     cli.reconnect_with_callbacks(connection_success, connection_failure);
@@ -85,6 +139,7 @@ pub fn connection_failure(cli: &AsyncClient, _msgid: u16, rc: i32) {
  */
 pub fn connection_lost(cli: &AsyncClient) {
     error!("Connection lost. Reconnecting...");
+    COMPONENT_MQTT_CONNECTED.store(false, Ordering::SeqCst);
 
     std::thread::sleep(std::time::Duration::from_millis(RECONNECT_TIMEOUT));
     cli.reconnect_with_callbacks(connection_success, connection_failure);
@@ -92,79 +147,813 @@ pub fn connection_lost(cli: &AsyncClient) {
 
 /**
  * Executes the command type the main node issued to us and passes the data of the command to the matched function.
+ * The command and its outcome are recorded in the audit log. Commands whose handler reports a result are
+ *     audited as `succeeded`/`failed`, the rest as `handled` and command types we don't act on as `ignored`.
  */
-fn process_command(mqtt_client: &AsyncClient, cmd: &Command) {
-    match cmd.command {
-        CommandType::RefreshUpdateManifest => request_update_manifest(&mqtt_client),
+fn process_command(mqtt_client: &AsyncClient, topic: &str, cmd: &Command) {
+    let command = format!("{:?}", cmd.command);
+
+    if let Some(wait) = rate_limit(cmd.command) {
+        let state = format!(
+            "Command {} dropped, it was received too often. Try again in {} seconds.",
+            command, wait
+        );
+        warn!("{}", state);
+        send_state(mqtt_client, &state, &cmd.request);
+
+        audit::record("component", topic, &command, cmd.request.as_deref(), &cmd.data, "rate limited");
+        return;
+    }
+
+    send_ack(mqtt_client, cmd);
+
+    let outcome = match cmd.command {
+        CommandType::RefreshUpdateManifest => {
+            // Kept until the External Interface starts the install
+            let update_manifest = request_update_manifest(mqtt_client, &cmd.request);
+            if let Ok(mut manifest) = UPDATE_MANIFEST.lock() {
+                *manifest = update_manifest;
+            } else {
+                error!("Couldn't lock and set UPDATE_MANIFEST mutex.");
+            }
+            None
+        }
         CommandType::StartUpdateDownloadAndInstall => {
-            send_update_started(&mqtt_client);
-            update_download_and_install(&mqtt_client);
+            install_update_manifest(mqtt_client, &cmd.request);
+            None
+        }
+        CommandType::ComponentStates => {
+            // The command data optionally names the component group to report
+            let group = Some(cmd.data.as_str()).filter(|group| !group.is_empty());
+            send_component_states(mqtt_client, group);
+            None
+        }
+        CommandType::ComponentLog => {
+            send_component_log(mqtt_client, &cmd.data, &cmd.request);
+            None
+        }
+        CommandType::RestartComponent => Some(send_restart_result(mqtt_client, &cmd.data, &cmd.request)),
+        CommandType::RestartGroup => Some(send_group_restart_result(mqtt_client, &cmd.data, &cmd.request)),
+        CommandType::BuildInfo => {
+            send_build_info(mqtt_client, &cmd.request);
+            None
+        }
+        CommandType::PauseCertWatchdog | CommandType::ResumeCertWatchdog => {
+            Some(send_watchdog_result(mqtt_client, cmd))
         }
-        CommandType::ComponentStates => send_component_states(mqtt_client),
-        CommandType::ComponentLog => send_component_log(mqtt_client, &cmd.data),
-        _ => {}
+        CommandType::PauseUpdates | CommandType::ResumeUpdates => {
+            Some(send_updates_pause_result(mqtt_client, cmd))
+        }
+        CommandType::VersionDrift => {
+            send_version_drift(mqtt_client, &cmd.request, true);
+            None
+        }
+        CommandType::HostMetrics => {
+            send_host_metrics(mqtt_client, &cmd.request);
+            None
+        }
+        CommandType::ReloadSettings => Some(send_reload_settings_result(mqtt_client, &cmd.request)),
+        CommandType::CertificateFingerprints => {
+            send_certificate_fingerprints(mqtt_client, &cmd.request);
+            None
+        }
+        _ => {
+            audit::record("component", topic, &command, cmd.request.as_deref(), &cmd.data, "ignored");
+            return;
+        }
+    };
+
+    let outcome = match outcome {
+        Some(true) => "succeeded",
+        Some(false) => "failed",
+        None => "handled",
+    };
+    audit::record("component", topic, &command, cmd.request.as_deref(), &cmd.data, outcome);
+}
+
+/**
+ * Downloads and installs the updates in the manifest fetched by the last `RefreshUpdateManifest` command.
+ * Once the updates were installed, the manifest is cleared so the same updates aren't downloaded again.
+ * The state updates carry the `request` id of the install command, not the one of the manifest refresh.
+ *
+ * Mutex `UPDATE_MANIFEST` is locked momentarily.
+ */
+fn install_update_manifest(client: &AsyncClient, request: &Option<String>) {
+    let update_manifest = if let Ok(manifest) = UPDATE_MANIFEST.lock() {
+        manifest.clone()
+    } else {
+        error!("Could not lock UPDATE_MANIFEST mutex.");
+        return;
+    };
+
+    match update_manifest {
+        Some(update_manifest) => {
+            send_update_started(client, request);
+
+            if update_download_and_install(client, update_manifest, request) {
+                if let Ok(mut manifest) = UPDATE_MANIFEST.lock() {
+                    *manifest = None;
+                }
+            }
+        }
+        None => warn!("Cannot download and install - update manifest is empty."),
+    }
+}
+
+/**
+ * Checks the `command_type` against `COMMAND_RATE_LIMITS` and marks it as accepted if it's not limited.
+ * Returns the seconds left until the command type is accepted again, or `None` if the command can be processed.
+ *
+ * Mutex `COMMAND_LAST_ACCEPTED` is locked momentarily.
+ */
+fn rate_limit(command_type: CommandType) -> Option<u64> {
+    let min_interval = COMMAND_RATE_LIMITS
+        .iter()
+        .find(|(limited, _)| *limited == command_type)
+        .map(|(_, seconds)| Duration::from_secs(*seconds))?;
+
+    if let Ok(mut last_accepted) = COMMAND_LAST_ACCEPTED.lock() {
+        let key = format!("{:?}", command_type);
+
+        if let Some(accepted) = last_accepted.get(&key) {
+            let elapsed = accepted.elapsed();
+            if elapsed < min_interval {
+                // Rounded up, so the reported wait is never too short
+                return Some((min_interval - elapsed).as_secs() + 1);
+            }
+        }
+
+        last_accepted.insert(key, Instant::now());
+    } else {
+        error!("Could not lock COMMAND_LAST_ACCEPTED mutex. Not rate limiting the command.");
+    }
+
+    None
+}
+
+/**
+ * Publishes an acknowledgement to the `External Interface` topic, telling it that the command was received and parsed.
+ * The acknowledgement contains the command type and the correlation id of the received command.
+ */
+fn send_ack(client: &AsyncClient, cmd: &Command) {
+    let mut ack = Command::new(CommandType::Ack, &format!("{:?}", cmd.command));
+    ack.request = cmd.request.clone();
+
+    if let Some(command) = ack.to_json() {
+        publish_external(client, command);
+    }
+}
+
+/**
+ * Publishes a negative acknowledgement to the `External Interface` topic when a received command could not be parsed.
+ * If the payload is valid JSON carrying a `request` key, its value is used as the correlation id.
+ */
+fn send_nack(client: &AsyncClient, payload: &str, reason: &str) {
+    let mut nack = Command::new(CommandType::Nack, reason);
+    nack.request = from_json::<serde_json::Value>(payload)
+        .ok()
+        .and_then(|json| json["request"].as_str().map(str::to_owned));
+
+    if let Some(command) = nack.to_json() {
+        publish_external(client, command);
     }
 }
 
 /**
  * Responds to the `External Interface` topic.
  * We publish a payload containing a list of components (& their states) that this NECO is in charge for.
+ * If `group` is set, only the members of that component group are listed.
+ * If the payload is bigger than the maximum message size, the component list is split over multiple messages,
+ *     each carrying a `sequence` object with the `index` of the message and the `total` number of messages.
  */
-fn send_component_states(client: &AsyncClient) {
-    match get_component_states() {
+fn send_component_states(client: &AsyncClient, group: Option<&str>) {
+    match get_component_states(group) {
         Ok(json) => {
-            if let Some(command) = Command::new(CommandType::ComponentStates, &json).to_string() {
-                let msg = Message::new(ROOT_EXTERNAL_INTERFACE_TOPIC, command, 1);
-                client.publish(msg);
+            let max_size = get_max_message_size();
+
+            if let Some(command) = Command::new(CommandType::ComponentStates, &json).to_json() {
+                if command.len() <= max_size {
+                    // Group reports are partial, they must not replace the retained states of every component
+                    if group.is_none() {
+                        publish_retained_copy(client, CommandType::ComponentStates, &command);
+                    }
+                    publish_external(client, command);
+                    return;
+                }
+            }
+
+            match chunk_component_states(&json, max_size) {
+                Some(chunks) => {
+                    warn!(
+                        "Component states exceed the maximum message size ({} bytes), sending them in {} messages.",
+                        max_size,
+                        chunks.len()
+                    );
+                    debug!("Split component states are not published retained.");
+
+                    for chunk in chunks {
+                        if let Some(command) = Command::new(CommandType::ComponentStates, &chunk).to_json() {
+                            publish_external(client, command);
+                        }
+                    }
+                }
+                None => error!(
+                    "Could not split the component states to fit the maximum message size ({} bytes).",
+                    max_size
+                ),
             }
         }
         Err(e) => error!("Could not send component states. {}", e),
     }
 }
 
+/**
+ * Splits the component states in `json` into multiple component states payloads, each with a part of the component list.
+ * Every payload, once wrapped in a command, fits into `max_size` bytes.
+ *
+ * Returns `None` if the states can't be parsed or a single component doesn't fit into `max_size`.
+ */
+fn chunk_component_states(json: &str, max_size: usize) -> Option<Vec<String>> {
+    let mut states: serde_json::Value = from_json(json).ok()?;
+    let components = states["components"].as_array()?.to_owned();
+
+    // Size of a payload with the components and the biggest sequence object we could add
+    let payload_size = |states: &mut serde_json::Value, components: &[serde_json::Value]| {
+        states["components"] = json!(components);
+        states["sequence"] = json!({"index": usize::MAX, "total": usize::MAX});
+        Command::new(CommandType::ComponentStates, &states.to_string())
+            .to_json()
+            .map_or(usize::MAX, |command| command.len())
+    };
+
+    let mut chunks: Vec<Vec<serde_json::Value>> = Vec::new();
+    let mut chunk: Vec<serde_json::Value> = Vec::new();
+
+    for component in components {
+        chunk.push(component);
+
+        if payload_size(&mut states, &chunk) > max_size {
+            let component = chunk.pop()?;
+            if chunk.is_empty() {
+                return None;
+            }
+
+            chunks.push(chunk);
+            chunk = vec![component];
+        }
+    }
+    chunks.push(chunk);
+
+    let total = chunks.len();
+    Some(
+        chunks
+            .into_iter()
+            .enumerate()
+            .map(|(index, chunk)| {
+                states["components"] = json!(chunk);
+                states["sequence"] = json!({"index": index, "total": total});
+                states.to_string()
+            })
+            .collect(),
+    )
+}
+
 /**
  * Responds to the `External Interface` topic.
  * Returns the component log (can be a service or a container component).
+ * If the payload is bigger than the maximum message size, the oldest part of the log is cut off.
+ * The reply carries the correlation id of the request.
  */
-fn send_component_log(client: &AsyncClient, data: &str) {
+fn send_component_log(client: &AsyncClient, data: &str, request: &Option<String>) {
     match get_component_log(data) {
+        Ok(json) => publish_log(client, CommandType::ComponentLog, json, request),
+        Err(e) => error!("Could not send component log. {}", e),
+    }
+}
+
+/**
+ * Publishes the log of the last install of the component to the `External Interface` topic.
+ * If the payload is bigger than the maximum message size, the oldest part of the log is cut off.
+ */
+pub fn send_install_log(client: &AsyncClient, component_name: &str) {
+    match read_install_log(component_name) {
+        Ok(log) => {
+            let json = json!({ "component": component_name, "data": log }).to_string();
+            publish_log(client, CommandType::InstallLog, json, &None);
+        }
+        Err(e) => error!("Could not send the install log of component {}. {}", component_name, e),
+    }
+}
+
+/**
+ * Publishes a reply carrying a log in its `data` field to the `External Interface` topic.
+ * The oldest part of the log is cut off until the payload fits the maximum message size.
+ */
+fn publish_log(client: &AsyncClient, command_type: CommandType, mut json: String, request: &Option<String>) {
+    let max_size = get_max_message_size();
+    let mut truncated = false;
+
+    loop {
+        let mut log = Command::new(command_type, &json);
+        log.request = request.clone();
+
+        let command = match log.to_json() {
+            Some(command) => command,
+            None => return,
+        };
+
+        if command.len() <= max_size {
+            if truncated {
+                warn!(
+                    "Log exceeds the maximum message size ({} bytes), sending a truncated log.",
+                    max_size
+                );
+            }
+
+            publish_external(client, command);
+            return;
+        }
+
+        // Every byte cut off the log shrinks the payload by at least a byte
+        match shrink_component_log(&json, command.len() - max_size) {
+            Some(shrunk) => {
+                json = shrunk;
+                truncated = true;
+            }
+            None => {
+                error!(
+                    "Could not truncate the log to fit the maximum message size ({} bytes).",
+                    max_size
+                );
+                return;
+            }
+        }
+    }
+}
+
+/**
+ * Cuts at least `excess` bytes off the log in the log reply `json`.
+ * Returns `None` if the reply can't be parsed or there is no log left to cut.
+ */
+fn shrink_component_log(json: &str, excess: usize) -> Option<String> {
+    let mut reply: serde_json::Value = from_json(json).ok()?;
+    let log = reply["data"].as_str()?.to_owned();
+
+    let max_log_size = log.len().checked_sub(excess)?;
+    if max_log_size == 0 {
+        return None;
+    }
+
+    let truncated_log = truncate_log(log.to_owned(), max_log_size);
+    if truncated_log.len() >= log.len() {
+        return None;
+    }
+
+    reply["data"] = json!(truncated_log);
+    Some(reply.to_string())
+}
+
+/**
+ * Returns the maximum size of a message we publish, from the component mqtt client settings.
+ */
+fn get_max_message_size() -> usize {
+    if let Ok(settings) = SETTINGS.lock() {
+        settings
+            .component_mqtt_client
+            .max_message_size
+            .unwrap_or(DEFAULT_MAX_MESSAGE_SIZE)
+    } else {
+        error!("Could not lock SETTINGS mutex.");
+        DEFAULT_MAX_MESSAGE_SIZE
+    }
+}
+
+/**
+ * Responds to the `External Interface` topic.
+ * Restarts the component named in `component_name` and replies with the outcome.
+ * The reply carries the correlation id of the request.
+ *
+ * Returns `true` if the component was restarted.
+ */
+fn send_restart_result(client: &AsyncClient, component_name: &str, request: &Option<String>) -> bool {
+    let (success, result) = match restart_component(component_name) {
+        Ok(_) => (true, json!({"component": component_name, "success": true, "msg": "Component restarted."})),
+        Err(e) => {
+            error!("Could not restart component. {}", e);
+            (false, json!({"component": component_name, "success": false, "msg": e.to_string()}))
+        }
+    };
+
+    let mut reply = Command::new(CommandType::RestartComponent, &result.to_string());
+    reply.request = request.clone();
+
+    if let Some(command) = reply.to_json() {
+        publish_external(client, command);
+    }
+
+    success
+}
+
+/**
+ * Responds to the `External Interface` topic.
+ * Restarts every member of the component group named in `group` and replies with the outcome of each restart.
+ * The group restart is successful only if every member restarted successfully.
+ * The reply carries the correlation id of the request.
+ *
+ * Returns `true` if every member of the group was restarted.
+ */
+fn send_group_restart_result(client: &AsyncClient, group: &str, request: &Option<String>) -> bool {
+    let result = match restart_group(group) {
+        Ok(results) => {
+            let members: Vec<serde_json::Value> = results
+                .iter()
+                .map(|(component, result)| match result {
+                    Ok(_) => json!({"component": component, "success": true, "msg": "Component restarted."}),
+                    Err(e) => {
+                        error!("Could not restart component. {}", e);
+                        json!({"component": component, "success": false, "msg": e.to_string()})
+                    }
+                })
+                .collect();
+
+            json!({
+                "group": group,
+                "success": results.iter().all(|(_, result)| result.is_ok()),
+                "components": members,
+            })
+        }
+        Err(e) => {
+            error!("Could not restart component group. {}", e);
+            json!({"group": group, "success": false, "msg": e.to_string(), "components": []})
+        }
+    };
+
+    let mut reply = Command::new(CommandType::RestartGroup, &result.to_string());
+    reply.request = request.clone();
+
+    if let Some(command) = reply.to_json() {
+        publish_external(client, command);
+    }
+
+    result["success"].as_bool().unwrap_or_default()
+}
+
+/**
+ * Responds to the `External Interface` topic.
+ * Pauses or resumes the certificate watchdog, depending on the command type, and replies with the outcome.
+ * The command data can describe who is requesting it, it's logged with the pause/resume.
+ * The reply carries the correlation id of the request.
+ *
+ * Returns `true` if the watchdog was paused/resumed.
+ */
+fn send_watchdog_result(client: &AsyncClient, cmd: &Command) -> bool {
+    let requested_by = format!(
+        "External Interface (request: {}, requester: {})",
+        cmd.request.as_deref().unwrap_or("-"),
+        if cmd.data.is_empty() { "-" } else { &cmd.data }
+    );
+
+    let pause = cmd.command == CommandType::PauseCertWatchdog;
+    let result = if pause {
+        pause_watchdog(&requested_by)
+    } else {
+        resume_watchdog(&requested_by)
+    };
+
+    // The reply reports the state the watchdog ended up in
+    let paused = watchdog_paused_by().is_some();
+    let result = match result {
+        Ok(_) => json!({"paused": paused, "success": true, "msg": ""}),
+        Err(e) => {
+            error!("Could not pause/resume the certificate watchdog. {}", e);
+            json!({"paused": paused, "success": false, "msg": e.to_string()})
+        }
+    };
+
+    let mut reply = Command::new(
+        if pause { CommandType::PauseCertWatchdog } else { CommandType::ResumeCertWatchdog },
+        &result.to_string(),
+    );
+    reply.request = cmd.request.clone();
+
+    if let Some(command) = reply.to_json() {
+        publish_external(client, command);
+    }
+
+    result["success"].as_bool().unwrap_or_default()
+}
+
+/**
+ * Pauses or resumes update installs (depending on the command type) and responds to the `External Interface` topic with the result.
+ * The reply carries the correlation id of the request.
+ *
+ * Returns `true` if updates were paused/resumed.
+ */
+fn send_updates_pause_result(client: &AsyncClient, cmd: &Command) -> bool {
+    let pause = cmd.command == CommandType::PauseUpdates;
+
+    let result = match set_updates_paused(pause) {
+        Ok(_) => {
+            warn!(
+                "Updates {} by the External Interface (request: {}).",
+                if pause { "paused" } else { "resumed" },
+                cmd.request.as_deref().unwrap_or("-")
+            );
+            json!({"paused": pause, "success": true, "msg": ""})
+        }
+        Err(e) => {
+            error!("Could not pause/resume updates. {}", e);
+            json!({"paused": updates_paused(), "success": false, "msg": e.to_string()})
+        }
+    };
+
+    let mut reply = Command::new(
+        if pause { CommandType::PauseUpdates } else { CommandType::ResumeUpdates },
+        &result.to_string(),
+    );
+    reply.request = cmd.request.clone();
+
+    if let Some(command) = reply.to_json() {
+        publish_external(client, command);
+    }
+
+    result["success"].as_bool().unwrap_or_default()
+}
+
+/**
+ * Reloads the settings file (see `settings::reload()`) and responds to the `External Interface` topic with the result,
+ *     including the changed settings that need a restart to take effect.
+ * The reply carries the correlation id of the request.
+ *
+ * Returns `true` if the settings were reloaded.
+ */
+fn send_reload_settings_result(client: &AsyncClient, request: &Option<String>) -> bool {
+    let result = match settings::reload() {
+        Ok(restart_required) => json!({"restart_required": restart_required, "success": true, "msg": ""}),
+        Err(e) => {
+            error!("Could not reload the settings. {}", e);
+            json!({"restart_required": [], "success": false, "msg": e.to_string()})
+        }
+    };
+
+    let mut reply = Command::new(CommandType::ReloadSettings, &result.to_string());
+    reply.request = request.clone();
+
+    if let Some(command) = reply.to_json() {
+        publish_external(client, command);
+    }
+
+    result["success"].as_bool().unwrap_or_default()
+}
+
+/**
+ * Responds to the `External Interface` topic.
+ * Reports the NECO version, compiled features, active update branch and uptime.
+ * The reply carries the correlation id of the request.
+ */
+fn send_build_info(client: &AsyncClient, request: &Option<String>) {
+    let update_branch = if let Ok(settings) = SETTINGS.lock() {
+        settings.update_branch.to_owned()
+    } else {
+        error!("Could not lock SETTINGS mutex.");
+        return;
+    };
+
+    match get_build_info(&update_branch, Some(STARTUP_TIME.elapsed().as_secs())) {
         Ok(json) => {
-            if let Some(command) = Command::new(CommandType::ComponentLog, &json).to_string() {
-                let msg = Message::new(ROOT_EXTERNAL_INTERFACE_TOPIC, command, 1);
-                client.publish(msg);
+            let mut info = Command::new(CommandType::BuildInfo, &json);
+            info.request = request.clone();
+
+            if let Some(command) = info.to_json() {
+                publish_retained_copy(client, CommandType::BuildInfo, &command);
+                publish_external(client, command);
             }
         }
-        Err(e) => error!("Could not send component log. {}", e),
+        Err(e) => error!("Could not send build info. {}", e),
+    }
+}
+
+/**
+ * Re-reads the component version files (see `reconcile_component_versions()`) and publishes the components whose
+ *     version drifted to the `External Interface` topic.
+ * If no component drifted, nothing is published unless `always_send` is set (the External Interface asked for it).
+ */
+pub fn send_version_drift(client: &AsyncClient, request: &Option<String>, always_send: bool) {
+    let drifts = reconcile_component_versions();
+
+    if drifts.is_empty() && !always_send {
+        return;
+    }
+
+    match serde_json::to_string(&drifts) {
+        Ok(json) => {
+            let mut drift = Command::new(CommandType::VersionDrift, &json);
+            drift.request = request.clone();
+
+            if let Some(command) = drift.to_json() {
+                publish_external(client, command);
+            }
+        }
+        Err(e) => error!("Could not convert the version drift report to JSON. {}", e),
+    }
+}
+
+/**
+ * Responds to the `External Interface` topic with the SHA256 fingerprints of the managed certificates,
+ *     so the server can record them and notice unexpected certificate changes.
+ * The reply carries the correlation id of the request.
+ *
+ * Mutex `CERTIFICATES` is locked momentarily.
+ */
+fn send_certificate_fingerprints(client: &AsyncClient, request: &Option<String>) {
+    let certificates = if let Ok(certificates) = CERTIFICATES.lock() {
+        certificates.clone()
+    } else {
+        error!("Could not lock CERTIFICATES mutex.");
+        return;
+    };
+
+    match serde_json::to_string(&certificate_fingerprints(&certificates)) {
+        Ok(json) => {
+            let mut fingerprints = Command::new(CommandType::CertificateFingerprints, &json);
+            fingerprints.request = request.clone();
+
+            if let Some(command) = fingerprints.to_json() {
+                publish_external(client, command);
+            }
+        }
+        Err(e) => error!("Could not convert the certificate fingerprints to JSON. {}", e),
+    }
+}
+
+/**
+ * Responds to the `External Interface` topic with the disk, memory, load and uptime metrics of the host.
+ * The reply carries the correlation id of the request.
+ */
+fn send_host_metrics(client: &AsyncClient, request: &Option<String>) {
+    match serde_json::to_string(&host_metrics::collect()) {
+        Ok(json) => {
+            let mut metrics = Command::new(CommandType::HostMetrics, &json);
+            metrics.request = request.clone();
+
+            if let Some(command) = metrics.to_json() {
+                publish_external(client, command);
+            }
+        }
+        Err(e) => error!("Could not convert the host metrics to JSON. {}", e),
     }
 }
 
 /**
  * Publishes the state to the `External Interface` topic.
+ * `request` is the correlation id of the command that triggered the state change, `None` for unsolicited states.
  */
-pub fn send_state(client: &AsyncClient, state: &str) {
-    if let Some(command) = Command::new(CommandType::State, state).to_string() {
-        let msg = Message::new(ROOT_EXTERNAL_INTERFACE_TOPIC, command, 1);
-        client.publish(msg);
+pub fn send_state(client: &AsyncClient, state: &str, request: &Option<String>) {
+    let mut state = Command::new(CommandType::State, state);
+    state.request = request.clone();
+
+    if let Some(command) = state.to_json() {
+        publish_retained_copy(client, CommandType::State, &command);
+        publish_external(client, command);
     }
 }
 
 /**
- * Publishes the concatenated changelogs to the `External Interface` topic.
+ * Publishes the changelogs (JSON, grouped by component) to the `External Interface` topic.
  */
 pub fn send_changelogs(client: &AsyncClient, changelogs: &str) {
-    if let Some(command) = Command::new(CommandType::Changelogs, changelogs).to_string() {
-        let msg = Message::new(ROOT_EXTERNAL_INTERFACE_TOPIC, command, 1);
-        client.publish(msg);
+    if let Some(command) = Command::new(CommandType::Changelogs, changelogs).to_json() {
+        publish_retained_copy(client, CommandType::Changelogs, &command);
+        publish_external(client, command);
     }
 }
 
+/**
+ * Returns the retained `State` message announcing if the NECO with the `client_id` is connected to the backhaul.
+ * The offline variant is used as the last-will so the `External Interface` learns when we drop off the backhaul.
+ * It's published on the `external_interface/<client id>/Connection` topic, so every NECO on the broker keeps its own retained state.
+ */
+pub fn connection_state(online: bool, client_id: &str) -> Message {
+    let state = if online { "Online" } else { "Offline" };
+
+    Message::new_retained(
+        [ROOT_EXTERNAL_INTERFACE_TOPIC, "/", client_id, "/", CONNECTION_STATE_SUBTOPIC].concat(),
+        Command::new(CommandType::State, state).to_json().unwrap_or_default(),
+        1,
+    )
+}
+
 /**
  * Sends a command telling the WebInterface that the updating procedure has started.
  */
-fn send_update_started(client: &AsyncClient) {
-    if let Some(command) = Command::new(CommandType::UpdateStarted, "").to_string() {
-        let msg = Message::new(ROOT_EXTERNAL_INTERFACE_TOPIC, command, 1);
-        client.publish(msg);
+fn send_update_started(client: &AsyncClient, request: &Option<String>) {
+    let mut started = Command::new(CommandType::UpdateStarted, "");
+    started.request = request.clone();
+
+    if let Some(command) = started.to_json() {
+        publish_external(client, command);
+    }
+}
+
+/**
+ * Publishes the payload to the `External Interface` topic.
+ * If we're disconnected from the backhaul, the payload is queued and published by `flush_offline_queue()` once we reconnect.
+ * The queue holds at most `OFFLINE_QUEUE_LIMIT` messages, the oldest one is dropped when the limit is exceeded.
+ *
+ * Mutex `EXTERNAL_INTERFACE_QUEUE` is locked momentarily.
+ */
+fn publish_external(client: &AsyncClient, payload: String) {
+    if client.is_connected() {
+        client.publish(Message::new(ROOT_EXTERNAL_INTERFACE_TOPIC, payload, 1));
+        return;
+    }
+
+    if let Ok(mut queue) = EXTERNAL_INTERFACE_QUEUE.lock() {
+        if queue.len() >= OFFLINE_QUEUE_LIMIT {
+            queue.pop_front();
+            warn!("Offline message queue is full. Dropped the oldest message.");
+        }
+
+        queue.push_back(payload);
+        debug!("Backhaul disconnected. Message queued for publishing.");
+    } else {
+        error!("Could not lock EXTERNAL_INTERFACE_QUEUE mutex. Message dropped.");
+    }
+}
+
+/**
+ * Warns about the `retained_messages` from the settings that aren't one of the `RETAINABLE_MESSAGES`, they are never retained.
+ */
+pub fn check_retained_messages(retained_messages: &[String]) {
+    for message_type in retained_messages {
+        if !RETAINABLE_MESSAGES.iter().any(|x| &format!("{:?}", x) == message_type) {
+            warn!(
+                "Message type '{}' can't be published retained. Retainable message types: {:?}",
+                message_type, RETAINABLE_MESSAGES
+            );
+        }
+    }
+}
+
+/**
+ * If the `command_type` is one of the `retained_messages` in the component mqtt client settings, the payload is also
+ *     published retained on the `external_interface/<client id>/<message type>` topic.
+ * Every message type has its own retained topic, so they don't replace each other (or the retained connection state).
+ * Nothing is published while we're disconnected, the states are sent again once we reconnect.
+ *
+ * Mutex `SETTINGS` is locked momentarily.
+ */
+fn publish_retained_copy(client: &AsyncClient, command_type: CommandType, payload: &str) {
+    if !RETAINABLE_MESSAGES.contains(&command_type) || !client.is_connected() {
+        return;
+    }
+
+    let message_type = format!("{:?}", command_type);
+
+    let retained = if let Ok(settings) = SETTINGS.lock() {
+        settings
+            .component_mqtt_client
+            .retained_messages
+            .iter()
+            .any(|x| x == &message_type)
+    } else {
+        error!("Could not lock SETTINGS mutex.");
+        false
+    };
+
+    if retained {
+        let topic = [
+            ROOT_EXTERNAL_INTERFACE_TOPIC,
+            "/",
+            client.inner.client_id.to_str().unwrap_or_default(),
+            "/",
+            &message_type,
+        ]
+        .concat();
+
+        client.publish(Message::new_retained(topic, payload, 1));
+    }
+}
+
+/**
+ * Publishes every message queued while we were disconnected, in the order they were queued.
+ *
+ * Mutex `EXTERNAL_INTERFACE_QUEUE` is locked momentarily.
+ */
+fn flush_offline_queue(client: &AsyncClient) {
+    let queued: Vec<String> = if let Ok(mut queue) = EXTERNAL_INTERFACE_QUEUE.lock() {
+        queue.drain(..).collect()
+    } else {
+        error!("Could not lock EXTERNAL_INTERFACE_QUEUE mutex.");
+        return;
+    };
+
+    if !queued.is_empty() {
+        info!("Publishing {} queued message(s).", queued.len());
+    }
+
+    for payload in queued {
+        client.publish(Message::new(ROOT_EXTERNAL_INTERFACE_TOPIC, payload, 1));
     }
 }
 
@@ -1,6 +1,6 @@
-use serde_json::to_string;
+use super::structs;
 
-#[derive(Debug, Serialize, Deserialize, PartialEq)]
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq)]
 pub enum CommandType {
     RefreshUpdateManifest,         // Received on ROOT_NECO_TOPIC
     StartUpdateDownloadAndInstall, // Received on <self> NECO topic
@@ -10,35 +10,25 @@ pub enum CommandType {
 
     ComponentStates, // Sends to ROOT_EXTERNAL_INTERFACE, received on ROOT_NECO_TOPIC
     ComponentLog,    // Sends to ROOT_EXTERNAL_INTERFACE, received on <self> NECO topic
+    RestartComponent, // Sends to ROOT_EXTERNAL_INTERFACE, received on <self> NECO topic
+    RestartGroup,     // Sends to ROOT_EXTERNAL_INTERFACE, received on <self> NECO topic
+    BuildInfo,        // Sends to ROOT_EXTERNAL_INTERFACE, received on <self> NECO topic
+    PauseCertWatchdog,  // Sends to ROOT_EXTERNAL_INTERFACE, received on <self> NECO topic
+    ResumeCertWatchdog, // Sends to ROOT_EXTERNAL_INTERFACE, received on <self> NECO topic
+    PauseUpdates,       // Sends to ROOT_EXTERNAL_INTERFACE, received on ROOT_NECO_TOPIC and <self> NECO topic
+    ResumeUpdates,      // Sends to ROOT_EXTERNAL_INTERFACE, received on ROOT_NECO_TOPIC and <self> NECO topic
+    InstallLog,         // Sends to ROOT_EXTERNAL_INTERFACE
+    VersionDrift,       // Sends to ROOT_EXTERNAL_INTERFACE, received on ROOT_NECO_TOPIC and <self> NECO topic
+    HostMetrics,        // Sends to ROOT_EXTERNAL_INTERFACE, received on ROOT_NECO_TOPIC and <self> NECO topic
+    ReloadSettings,     // Sends to ROOT_EXTERNAL_INTERFACE, received on ROOT_NECO_TOPIC and <self> NECO topic
+    CertificateFingerprints, // Sends to ROOT_EXTERNAL_INTERFACE, received on ROOT_NECO_TOPIC and <self> NECO topic
+
+    Ack,  // Sends to ROOT_EXTERNAL_INTERFACE
+    Nack, // Sends to ROOT_EXTERNAL_INTERFACE
 
     // This is not needed right now
     // Probably going to be used for communication between NECOs
     //CertRenewal,                  // Sends to ROOT_NECO_TOPIC
 }
 
-#[derive(Debug, Serialize, Deserialize)]
-pub struct Command {
-    pub command: CommandType,
-    pub data: String,
-}
-
-impl Command {
-    pub fn new(command: CommandType, data: &str) -> Self {
-        Self {
-            command,
-            data: data.to_owned(),
-        }
-    }
-
-    /**
-     * Converts the `Command` struct to a JSON formatted string.
-     * If the conversion fails, an error message is printed and `None` is returned.
-     */
-    pub fn to_string(&self) -> Option<String> {
-        match to_string(self) {
-            Ok(res) => return Some(res),
-            Err(e) => error!("Could not convert command to string. Command: {:?} | Err: {}", self.command, e),
-        }
-        None
-    }
-}
+pub type Command = structs::Command<CommandType>;
@@ -1,8 +1,19 @@
-use crate::mqtt::{AsyncClient, ConnectOptionsBuilder, SslOptionsBuilder, MQTT_VERSION_3_1_1};
+use std::fs::read_to_string;
+use std::io::{Error, ErrorKind};
+use std::sync::atomic::Ordering;
+use std::time::Duration;
 
-use crate::settings::structs::{ComponentMqttClient, NeutronMqttClient};
+use crate::mqtt::{
+    AsyncClient, ConnectOptions, ConnectOptionsBuilder, SslOptions, SslOptionsBuilder, MQTT_VERSION_3_1_1,
+};
 
-use crate::NEUTRON_SERVER_IP;
+use crate::encryption_certificates::decode_pem_certificates;
+use crate::settings::passphrases;
+use crate::settings::structs::{
+    CertificatePaths, CertificateSettings, ComponentMqttClient, MqttClientCertificate, NeutronMqttClient,
+    NeutronServer, TlsPolicy,
+};
+use crate::COMPONENT_MQTT_CONNECTED;
 
 pub mod component_mqtt;
 mod component_structs;
@@ -11,35 +22,50 @@ mod neutron_mqtt;
 // We only export this
 pub use neutron_mqtt::own_topic_out;
 pub mod neutron_structs;
+mod structs;
+
+const CHECK_TIMEOUT: Duration = Duration::from_secs(10);
+// Appended to the client id so the check doesn't take over the session of a running NECO
+const CHECK_CLIENT_ID_SUFFIX: &str = "_connectivity_check";
+const DISCONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+// Defaults of the component backhaul `tls_policy` settings
+pub const DEFAULT_MIN_TLS_VERSION: &str = "1.2";
+pub const DEFAULT_CIPHER_SUITES: &str = "ECDHE-ECDSA-AES256-GCM-SHA384:ECDHE-RSA-AES256-GCM-SHA384:\
+ECDHE-ECDSA-CHACHA20-POLY1305:ECDHE-RSA-CHACHA20-POLY1305:ECDHE-ECDSA-AES128-GCM-SHA256:ECDHE-RSA-AES128-GCM-SHA256";
+// Removes every cipher usable before TLS 1.2 from an OpenSSL cipher list
+const PRE_TLS_1_2_EXCLUSION: &str = ":!SSLv3:!TLSv1";
 
 /**
  * Initiates the connection to the component backhaul network MQTT broker
+ * The client certificate (if configured) is looked up in `certificates`, see `get_client_certificate()`.
  * If connection is successful; returns `Some<AsyncClient>`
  * If we fail to instantiate `AsyncClient`; returns `None`
  */
-pub fn init_component_mqtt(mqtt_config: &ComponentMqttClient) -> Option<AsyncClient> {
+pub fn init_component_mqtt(
+    mqtt_config: &ComponentMqttClient,
+    certificates: &[CertificateSettings],
+) -> Option<AsyncClient> {
     info!("Connecting to component backhaul...");
     let mqtt_address = format!("ssl://{}:{}", mqtt_config.ip, mqtt_config.port);
 
-    match AsyncClient::new((mqtt_address.as_str(), mqtt_config.username.as_str() /*Clientid*/)) {
+    let conn_opts = match component_connect_options(mqtt_config, certificates) {
+        Ok(conn_opts) => conn_opts,
+        Err(e) => {
+            error!("{}", e);
+            return None;
+        }
+    };
+
+    component_mqtt::check_retained_messages(&mqtt_config.retained_messages);
+
+    let client_id = get_client_id(&mqtt_config.username, &mqtt_config.client_id);
+
+    match AsyncClient::new((mqtt_address.as_str(), client_id)) {
         Ok(mut client) => {
             client.set_connection_lost_callback(component_mqtt::connection_lost);
             client.set_message_callback(component_mqtt::payload_callback);
 
-            let ssl = SslOptionsBuilder::new()
-                .trust_store(&mqtt_config.cafile)
-                .finalize();
-
-            let conn_opts = ConnectOptionsBuilder::new()
-                .keep_alive_interval(std::time::Duration::from_secs(30))
-                .mqtt_version(MQTT_VERSION_3_1_1)
-                .clean_session(true)
-                .ssl_options(ssl)
-                .user_name(mqtt_config.username.to_owned())
-                .password(mqtt_config.password.to_owned())
-                //.will_message(web_interface::wi_announce_blackbox(&cli, false))
-                .finalize();
-
             // Make the connection to the broker
             client.connect_with_callbacks(
                 conn_opts,
@@ -58,20 +84,200 @@ pub fn init_component_mqtt(mqtt_config: &ComponentMqttClient) -> Option<AsyncCli
 }
 
 /**
+ * Disconnects from the component backhaul broker and connects again with freshly built connect options,
+ *     so the broker is presented the renewed client certificate.
+ */
+pub fn reconnect_component_mqtt(
+    client: &AsyncClient,
+    mqtt_config: &ComponentMqttClient,
+    certificates: &[CertificateSettings],
+) {
+    let conn_opts = match component_connect_options(mqtt_config, certificates) {
+        Ok(conn_opts) => conn_opts,
+        Err(e) => {
+            error!("Not reconnecting to component backhaul. {}", e);
+            return;
+        }
+    };
+
+    if let Err(e) = client.disconnect(None).wait_for(DISCONNECT_TIMEOUT) {
+        warn!("Could not disconnect from component backhaul. {}", e);
+    }
+    COMPONENT_MQTT_CONNECTED.store(false, Ordering::SeqCst);
+
+    client.connect_with_callbacks(
+        conn_opts,
+        component_mqtt::connection_success,
+        component_mqtt::connection_failure,
+    );
+}
+
+/**
+ * Returns `true` if `cert` is the managed certificate the component backhaul connection presents to the broker,
+ *     or if its main certificate is on the configured client certificate path.
+ */
+pub fn is_component_client_certificate(mqtt_config: &ComponentMqttClient, cert: &CertificateSettings) -> bool {
+    match &mqtt_config.client_certificate {
+        Some(client_cert) => match &client_cert.managed_certificate {
+            Some(name) => name == &cert.component_name,
+            None => client_cert.cert == cert.main_certificate.main_paths.cert,
+        },
+        None => false,
+    }
+}
+
+/**
+ * Builds the component backhaul connect options, the password file and the client certificate key passphrase are read here.
+ */
+fn component_connect_options(
+    mqtt_config: &ComponentMqttClient,
+    certificates: &[CertificateSettings],
+) -> Result<ConnectOptions, Error> {
+    let password = get_password(&mqtt_config.password, &mqtt_config.password_file).map_err(|e| {
+        Error::new(e.kind(), format!("Could not read the component mqtt password file. {}", e))
+    })?;
+
+    let ssl = component_ssl_options(mqtt_config, certificates)?;
+
+    Ok(ConnectOptionsBuilder::new()
+        .keep_alive_interval(std::time::Duration::from_secs(30))
+        .mqtt_version(MQTT_VERSION_3_1_1)
+        .clean_session(!mqtt_config.persistent_session)
+        .ssl_options(ssl)
+        .user_name(mqtt_config.username.to_owned())
+        .password(password)
+        .will_message(component_mqtt::connection_state(
+            false,
+            get_client_id(&mqtt_config.username, &mqtt_config.client_id),
+        ))
+        .finalize())
+}
+
+/**
+ * Builds the component backhaul TLS options, with the client certificate if one is configured.
+ * The CA file is checked first, see `check_ca_file()`.
+ * The ciphers are restricted by the `tls_policy`, see `get_cipher_list()`.
+ */
+fn component_ssl_options(
+    mqtt_config: &ComponentMqttClient,
+    certificates: &[CertificateSettings],
+) -> Result<SslOptions, Error> {
+    check_ca_file(&mqtt_config.cafile)?;
+
+    let mut ssl = SslOptionsBuilder::new();
+    ssl.trust_store(&mqtt_config.cafile)
+        .enabled_cipher_suites(&get_cipher_list(&mqtt_config.tls_policy)?);
+
+    if let Some(client_cert) = &mqtt_config.client_certificate {
+        let (paths, passphrase) = get_client_certificate(client_cert, certificates)?;
+
+        ssl.key_store(&paths.cert).private_key(&paths.key);
+        if let Some(passphrase) = passphrase {
+            ssl.private_key_password(&passphrase);
+        }
+    }
+
+    Ok(ssl.finalize())
+}
+
+/**
+ * Returns the OpenSSL cipher list enforcing the `tls_policy`.
+ * The bundled MQTT library can't set the minimum protocol version, so TLS 1.2 is enforced by removing every cipher
+ *     usable before TLS 1.2 from the list. A broker that only speaks TLS 1.0/1.1 has no cipher in common with us.
+ *
+ * Returns an error of kind `InvalidInput` if the minimum version can't be enforced.
+ */
+fn get_cipher_list(tls_policy: &TlsPolicy) -> Result<String, Error> {
+    let cipher_suites = if tls_policy.cipher_suites.is_empty() {
+        "DEFAULT"
+    } else {
+        &tls_policy.cipher_suites
+    };
+
+    match tls_policy.min_version.as_str() {
+        "1.2" => Ok([cipher_suites, PRE_TLS_1_2_EXCLUSION].concat()),
+        "1.0" => Ok(cipher_suites.to_owned()),
+        version => Err(Error::new(
+            ErrorKind::InvalidInput,
+            format!("Minimum TLS version '{}' can't be enforced. Supported: '1.2', '1.0'.", version),
+        )),
+    }
+}
+
+/**
+ * Returns the certificate and key paths of the client certificate, paired with the key passphrase if the key is encrypted.
+ * A managed certificate is looked up by its component name in `certificates`, its passphrase is taken from the passphrase store.
  *
+ * Returns an error of kind `NotFound` if the managed certificate doesn't exist.
  */
-pub fn init_neutron_mqtt(mqtt_config: &NeutronMqttClient) -> Option<AsyncClient> {
+fn get_client_certificate(
+    client_cert: &MqttClientCertificate,
+    certificates: &[CertificateSettings],
+) -> Result<(CertificatePaths, Option<String>), Error> {
+    if let Some(name) = &client_cert.managed_certificate {
+        let cert = certificates
+            .iter()
+            .find(|cert| &cert.component_name == name)
+            .ok_or_else(|| {
+                Error::new(
+                    ErrorKind::NotFound,
+                    format!("Client certificate '{}' is not a managed certificate.", name),
+                )
+            })?;
+
+        let passphrase = if cert.main_certificate.encrypted {
+            passphrases::lookup(name, "main").or_else(|| Some(cert.main_certificate.passphrase.to_owned()))
+        } else {
+            None
+        };
+
+        return Ok((cert.main_certificate.main_paths.clone(), passphrase));
+    }
+
+    let passphrase = match &client_cert.key_passphrase_file {
+        Some(_) => Some(get_password("", &client_cert.key_passphrase_file).map_err(|e| {
+            Error::new(e.kind(), format!("Could not read the client certificate key passphrase file. {}", e))
+        })?),
+        None => None,
+    };
+
+    Ok((
+        CertificatePaths {
+            cert: client_cert.cert.to_owned(),
+            key: client_cert.key.to_owned(),
+        },
+        passphrase,
+    ))
+}
+
+/**
+ * Initiates the connection to the Neutron server MQTT broker.
+ * The broker host and whether TLS is used are taken from the `neutron_server` settings.
+ */
+pub fn init_neutron_mqtt(
+    server: &NeutronServer,
+    mqtt_config: &NeutronMqttClient,
+) -> Option<AsyncClient> {
     info!("Connecting to neutron server...");
 
-    #[cfg(feature = "INSECURE")]
-    warn!("Using an insecure Neutron MQTT port!");
-    #[cfg(feature = "INSECURE")]
-    let mqtt_address = format!("tcp://{}:1883", NEUTRON_SERVER_IP);
+    let mqtt_address = if server.use_tls {
+        format!("ssl://{}:1883", server.host)
+    } else {
+        warn!("Using an insecure Neutron MQTT port!");
+        format!("tcp://{}:1883", server.host)
+    };
 
-    #[cfg(feature = "SECURE")]
-    let mqtt_address = format!("ssl://{}:1883", NEUTRON_SERVER_IP);
+    let password = match get_password(&mqtt_config.password, &mqtt_config.password_file) {
+        Ok(pass) => pass,
+        Err(e) => {
+            error!("Could not read the neutron mqtt password file. {}", e);
+            return None;
+        }
+    };
 
-    match AsyncClient::new((&*mqtt_address, &*mqtt_config.username /*Clientid*/)) {
+    let client_id = get_client_id(&mqtt_config.username, &mqtt_config.client_id);
+
+    match AsyncClient::new((mqtt_address.as_str(), client_id)) {
         Ok(mut client) => {
             client.set_connection_lost_callback(neutron_mqtt::connection_lost);
             client.set_message_callback(neutron_mqtt::payload_callback);
@@ -83,10 +289,10 @@ pub fn init_neutron_mqtt(mqtt_config: &NeutronMqttClient) -> Option<AsyncClient>
             let conn_opts = ConnectOptionsBuilder::new()
                 .keep_alive_interval(std::time::Duration::from_secs(30))
                 .mqtt_version(MQTT_VERSION_3_1_1)
-                .clean_session(true)
+                .clean_session(!mqtt_config.persistent_session)
                 // .ssl_options(ssl)
                 .user_name(mqtt_config.username.to_owned())
-                .password(mqtt_config.password.to_owned())
+                .password(password)
                 .will_message(neutron_mqtt::send_state(false, client.inner.client_id.to_str().unwrap_or_default()))
                 .finalize();
 
@@ -106,3 +312,155 @@ pub fn init_neutron_mqtt(mqtt_config: &NeutronMqttClient) -> Option<AsyncClient>
         }
     }
 }
+
+/**
+ * Tries to connect to the component backhaul broker with the configured credentials and disconnects right after.
+ *
+ * Returns `Err` with the reason if the connection could not be made.
+ */
+pub fn check_component_mqtt(
+    mqtt_config: &ComponentMqttClient,
+    certificates: &[CertificateSettings],
+) -> Result<(), String> {
+    let mqtt_address = format!("ssl://{}:{}", mqtt_config.ip, mqtt_config.port);
+
+    let password = get_password(&mqtt_config.password, &mqtt_config.password_file)
+        .map_err(|e| format!("Could not read the password file. {}", e))?;
+
+    let ssl = component_ssl_options(mqtt_config, certificates).map_err(|e| e.to_string())?;
+
+    let conn_opts = ConnectOptionsBuilder::new()
+        .mqtt_version(MQTT_VERSION_3_1_1)
+        .clean_session(true)
+        .ssl_options(ssl)
+        .user_name(mqtt_config.username.to_owned())
+        .password(password)
+        .finalize();
+
+    check_mqtt_connection(
+        &mqtt_address,
+        get_client_id(&mqtt_config.username, &mqtt_config.client_id),
+        conn_opts,
+    )
+}
+
+/**
+ * Tries to connect to the Neutron server broker with the configured credentials and disconnects right after.
+ *
+ * Returns `Err` with the reason if the connection could not be made.
+ */
+pub fn check_neutron_mqtt(
+    server: &NeutronServer,
+    mqtt_config: &NeutronMqttClient,
+) -> Result<(), String> {
+    let protocol = if server.use_tls { "ssl" } else { "tcp" };
+    let mqtt_address = format!("{}://{}:1883", protocol, server.host);
+
+    let password = get_password(&mqtt_config.password, &mqtt_config.password_file)
+        .map_err(|e| format!("Could not read the password file. {}", e))?;
+
+    let conn_opts = ConnectOptionsBuilder::new()
+        .mqtt_version(MQTT_VERSION_3_1_1)
+        .clean_session(true)
+        .user_name(mqtt_config.username.to_owned())
+        .password(password)
+        .finalize();
+
+    check_mqtt_connection(
+        &mqtt_address,
+        get_client_id(&mqtt_config.username, &mqtt_config.client_id),
+        conn_opts,
+    )
+}
+
+/**
+ * Connects to the broker at `mqtt_address`, waiting at most `CHECK_TIMEOUT`, then disconnects.
+ */
+fn check_mqtt_connection(
+    mqtt_address: &str,
+    client_id: &str,
+    conn_opts: ConnectOptions,
+) -> Result<(), String> {
+    let client_id = [client_id, CHECK_CLIENT_ID_SUFFIX].concat();
+
+    let client = AsyncClient::new((mqtt_address, client_id.as_str())).map_err(|e| e.to_string())?;
+
+    client
+        .connect(conn_opts)
+        .wait_for(CHECK_TIMEOUT)
+        .map_err(|e| e.to_string())?;
+
+    if let Err(e) = client.disconnect(None).wait_for(CHECK_TIMEOUT) {
+        debug!("Could not disconnect after the connectivity check. {}", e);
+    }
+
+    Ok(())
+}
+
+/**
+ * Returns the contents of the `password_file`, without the trailing newlines, if the path is set.
+ * If it isn't set, the inline `password` is returned.
+ */
+pub fn get_password(password: &str, password_file: &Option<String>) -> Result<String, Error> {
+    if let Some(path) = password_file {
+        let contents = read_to_string(path)?;
+        return Ok(contents.trim_end_matches(&['\n', '\r'][..]).to_owned());
+    }
+
+    Ok(password.to_owned())
+}
+
+/**
+ * Checks that the CA file on `path` exists and holds at least one PEM certificate (see `decode_pem_certificates()`).
+ * The MQTT library only reports a generic SSL failure when the trust store can't be loaded, this tells the user why.
+ *
+ * Returns an error of kind `NotFound` if the file doesn't exist and `InvalidData` if it holds no usable certificate.
+ */
+pub fn check_ca_file(path: &str) -> Result<(), Error> {
+    let contents = read_to_string(path).map_err(|e| {
+        Error::new(e.kind(), format!("Could not read the CA file '{}'. {}", path, e))
+    })?;
+
+    let certificates = decode_pem_certificates(&contents).map_err(|e| {
+        Error::new(e.kind(), format!("The CA file '{}' holds a certificate that can't be parsed.", path))
+    })?;
+
+    if certificates.is_empty() {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            format!("The CA file '{}' holds no PEM certificate.", path),
+        ));
+    }
+
+    Ok(())
+}
+
+/**
+ * Returns the configured `client_id`, or the `username` if it isn't set.
+ */
+pub fn get_client_id<'a>(username: &'a str, client_id: &'a Option<String>) -> &'a str {
+    client_id.as_deref().unwrap_or(username)
+}
+
+/**
+ * Returns a human-readable description of the return code passed to the `OnConnectionFail` callbacks.
+ * Negative codes come from the MQTT client library, positive ones are the CONNACK codes the broker refused us with.
+ */
+pub fn connection_failure_reason(rc: i32) -> &'static str {
+    match rc {
+        -1 => "Generic failure (is the broker reachable?)",
+        -2 => "Persistence error",
+        -3 => "Client disconnected",
+        -8 => "Bad connect options structure",
+        -13 => "SSL is not supported by the MQTT library",
+        -14 => "Bad protocol prefix in the server URI",
+        -15 => "Bad MQTT option",
+        -16 => "MQTT version not supported by the client",
+        1 => "Unacceptable protocol version",
+        2 => "Client identifier rejected",
+        3 => "Server unavailable",
+        4 => "Bad username or password",
+        5 => "Not authorized",
+        _ => "Unknown error",
+    }
+}
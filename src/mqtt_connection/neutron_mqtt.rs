@@ -1,8 +1,12 @@
 use crate::mqtt::{message, AsyncClient, Message};
 use serde_json::from_str as from_json;
 
+use super::connection_failure_reason;
+use crate::audit;
 use super::neutron_structs::{Command, CommandType};
 use crate::remote_management::start_ssh_server;
+use crate::NEUTRON_MQTT_CONNECTED;
+use std::sync::atomic::Ordering;
 
 // This topic is read-only (subscribe only)
 const ROOT_TOPIC: &str = "LSOC/communicators";
@@ -16,10 +20,19 @@ pub fn payload_callback(cli: &AsyncClient, msg: Option<message::Message>) {
 
         let mqtt_cli = cli.clone();
         match from_json(&msg.payload_str()) {
-            Ok(result) => process_command(&mqtt_cli, &result),
+            Ok(result) => process_command(&mqtt_cli, msg.topic(), &result),
             Err(e) => {
                 error!("Could not parse command struct.");
                 debug!("{}", e);
+
+                audit::record(
+                    "neutron",
+                    msg.topic(),
+                    "-",
+                    None,
+                    &audit::fingerprint(&msg.payload_str()),
+                    &["rejected: ", &e.to_string()].concat(),
+                );
             }
         }
     }
@@ -30,6 +43,7 @@ pub fn payload_callback(cli: &AsyncClient, msg: Option<message::Message>) {
  */
 pub fn connection_success(cli: &AsyncClient, _msgid: u16) {
     info!("Neutron Server connection succeeded.");
+    NEUTRON_MQTT_CONNECTED.store(true, Ordering::SeqCst);
 
     cli.subscribe(ROOT_TOPIC, 1);
 
@@ -48,7 +62,12 @@ pub fn connection_success(cli: &AsyncClient, _msgid: u16) {
  * `OnConnectionFail` mqtt callback.
  */
 pub fn connection_failure(cli: &AsyncClient, _msgid: u16, rc: i32) {
-    debug!("Connection attempt failed with error code {}.", rc);
+    NEUTRON_MQTT_CONNECTED.store(false, Ordering::SeqCst);
+    debug!(
+        "Connection attempt failed with error code {}. {}",
+        rc,
+        connection_failure_reason(rc)
+    );
 
     std::thread::sleep(std::time::Duration::from_millis(RECONNECT_TIMEOUT));
     cli.reconnect_with_callbacks(connection_success, connection_failure);
@@ -59,6 +78,7 @@ pub fn connection_failure(cli: &AsyncClient, _msgid: u16, rc: i32) {
  */
 pub fn connection_lost(cli: &AsyncClient) {
     error!("Connection lost. Reconnecting...");
+    NEUTRON_MQTT_CONNECTED.store(false, Ordering::SeqCst);
 
     std::thread::sleep(std::time::Duration::from_millis(RECONNECT_TIMEOUT));
     cli.reconnect_with_callbacks(connection_success, connection_failure);
@@ -66,17 +86,24 @@ pub fn connection_lost(cli: &AsyncClient) {
 
 /**
  * Executes the command type the main node issued to us and passes the data of the command to the matched function.
+ * The command and its outcome are recorded in the audit log, the SSH key of a remote management request is recorded as a fingerprint.
  */
-fn process_command(mqtt_client: &AsyncClient, cmd: &Command) {
+fn process_command(mqtt_client: &AsyncClient, topic: &str, cmd: &Command) {
+    let command = format!("{:?}", cmd.command);
+
     match cmd.command {
-        CommandType::RemoteManagement => start_ssh_server(mqtt_client, &cmd.data),
+        CommandType::RemoteManagement => {
+            let outcome = if start_ssh_server(mqtt_client, &cmd.data) { "succeeded" } else { "failed" };
+            audit::record("neutron", topic, &command, None, &audit::fingerprint(&cmd.data), outcome);
+        }
         CommandType::UpdateInstall => {
             //TODO
             // Fetch the Update Manifest
             // Start UpdateDownloadAndInstall
+            audit::record("neutron", topic, &command, None, &cmd.data, "ignored");
         },
-        CommandType::MQTTServerCA => {},
-        _ => {}
+        CommandType::MQTTServerCA => audit::record("neutron", topic, &command, None, &cmd.data, "ignored"),
+        _ => audit::record("neutron", topic, &command, None, &cmd.data, "ignored"),
     }
 }
 
@@ -93,7 +120,7 @@ pub fn send_state(state: bool, client_id: &str) -> Message {
 
     Message::new(
         own_topic_out(client_id),
-        Command::new(cmd_type, "").to_string().unwrap_or_default(),
+        Command::new(cmd_type, "").to_json().unwrap_or_default(),
         1,
     )
 }
@@ -1,4 +1,4 @@
-use serde_json::to_string;
+use super::structs;
 
 #[derive(Debug, Serialize, Deserialize, PartialEq)]
 pub enum CommandType {
@@ -11,29 +11,4 @@ pub enum CommandType {
     MQTTServerCA                // <UNIMPLEMENTED> Received on global topic
 }
 
-#[derive(Debug, Serialize, Deserialize)]
-pub struct Command {
-    pub command: CommandType,
-    pub data: String,
-}
-
-impl Command {
-    pub fn new(command: CommandType, data: &str) -> Self {
-        Self {
-            command,
-            data: data.to_owned(),
-        }
-    }
-
-    /**
-     * Converts the `Command` struct to a JSON formatted string.
-     * If the conversion fails, an error message is printed and `None` is returned.
-     */
-    pub fn to_string(&self) -> Option<String> {
-        match to_string(self) {
-            Ok(res) => return Some(res),
-            Err(e) => error!("Could not convert command to string. Command: {:?} | Err: {}", self.command, e),
-        }
-        None
-    }
-}
+pub type Command = structs::Command<CommandType>;
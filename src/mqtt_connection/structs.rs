@@ -0,0 +1,39 @@
+use std::fmt::Debug;
+
+use serde::Serialize;
+use serde_json::to_string;
+
+/**
+ * The envelope shared by the component and neutron commands.
+ * `T` is the command type enum of the connection the command is sent over.
+ * `request` is an optional correlation id, replies to a command carry the id of the command they respond to.
+ */
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Command<T> {
+    pub command: T,
+    pub data: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub request: Option<String>,
+}
+
+impl<T: Serialize + Debug> Command<T> {
+    pub fn new(command: T, data: &str) -> Self {
+        Self {
+            command,
+            data: data.to_owned(),
+            request: None,
+        }
+    }
+
+    /**
+     * Converts the `Command` struct to a JSON formatted string.
+     * If the conversion fails, an error message is printed and `None` is returned.
+     */
+    pub fn to_json(&self) -> Option<String> {
+        match to_string(self) {
+            Ok(res) => return Some(res),
+            Err(e) => error!("Could not convert command to string. Command: {:?} | Err: {}", self.command, e),
+        }
+        None
+    }
+}
@@ -0,0 +1,98 @@
+use std::env;
+use std::fs;
+use std::os::unix::fs::PermissionsExt;
+use std::path::PathBuf;
+
+use crate::settings::structs::UpdateComponent;
+
+// (binary, what it is used for, if NECO can't work without it)
+// `systemctl` and `docker` are only needed if an update component is a service or a container
+const EXTERNAL_TOOLS: [(&str, &str, bool); 7] = [
+    ("sh", "running recipe, restart and health check commands", true),
+    ("openssl", "certificate management", true),
+    ("systemctl", "service states, logs and restarts", false),
+    ("docker", "container states, logs and restarts", false),
+    ("chmod", "recipe scripts", false),
+    ("chown", "recipe scripts", false),
+    ("curl", "recipe scripts", false),
+];
+
+pub struct ToolCheck {
+    pub name: &'static str,
+    pub purpose: &'static str,
+    pub required: bool,
+    pub used_by_components: bool, // A configured update component needs it
+    pub path: Option<PathBuf>, // `None` if the binary was not found
+}
+
+/**
+ * Looks up every binary in `EXTERNAL_TOOLS` on the PATH.
+ * `systemctl` and `docker` are marked as used by the components if any of the `update_components` is a service or a container, respectively.
+ */
+pub fn probe(update_components: &[UpdateComponent]) -> Vec<ToolCheck> {
+    let has_services = update_components.iter().any(|comp| comp.service_name.is_some());
+    let has_containers = update_components.iter().any(|comp| comp.container_name.is_some());
+
+    EXTERNAL_TOOLS
+        .iter()
+        .map(|(name, purpose, required)| ToolCheck {
+            name,
+            purpose,
+            required: *required,
+            used_by_components: match *name {
+                "systemctl" => has_services,
+                "docker" => has_containers,
+                _ => false,
+            },
+            path: find_in_path(name),
+        })
+        .collect()
+}
+
+/**
+ * Runs `probe()` and logs a summary of the available binaries, missing optional ones are logged as warnings
+ *     and missing required ones (or ones a component needs) as errors.
+ *
+ * Returns `false` if a required binary is missing.
+ */
+pub fn run(update_components: &[UpdateComponent]) -> bool {
+    info!("Checking for external binaries...");
+
+    let checks = probe(update_components);
+    let mut available: Vec<&str> = Vec::new();
+    let mut all_required_found = true;
+
+    for check in &checks {
+        if check.path.is_some() {
+            available.push(check.name);
+        } else if check.required {
+            error!("Required binary '{}' not found. Needed for: {}.", check.name, check.purpose);
+            all_required_found = false;
+        } else if check.used_by_components {
+            error!(
+                "Binary '{}' not found, but an update component needs it. Needed for: {}.",
+                check.name, check.purpose
+            );
+        } else {
+            warn!("Optional binary '{}' not found. Needed for: {}.", check.name, check.purpose);
+        }
+    }
+
+    info!("Available binaries: {}", available.join(", "));
+
+    all_required_found
+}
+
+/**
+ * Returns the path of the first executable file named `name` in the directories of the PATH environment variable.
+ */
+fn find_in_path(name: &str) -> Option<PathBuf> {
+    let path_var = env::var_os("PATH")?;
+
+    env::split_paths(&path_var)
+        .map(|dir| dir.join(name))
+        .find(|candidate| match fs::metadata(candidate) {
+            Ok(metadata) => metadata.is_file() && metadata.permissions().mode() & 0o111 != 0,
+            Err(_) => false,
+        })
+}
@@ -1,27 +1,81 @@
 use std::fs::File;
 use std::io::{Error, ErrorKind, Write};
+use std::net::IpAddr;
 use std::process::Command;
+use std::time::Duration;
+
+use data_encoding::{BASE64, BASE64_NOPAD};
+use ring::digest::{digest, SHA256};
+
+use crate::command_runner::{failure_reason, CommandRunner, SystemCommandRunner};
 
 use crate::mqtt::{AsyncClient, Message};
 use crate::mqtt_connection::neutron_structs::{Command as NeutronCommand, CommandType};
-use crate::mqtt_connection::own_topic_out;
+use crate::mqtt_connection::{get_client_id, own_topic_out};
+use crate::version_control::http::{HttpClient, ReqwestClient};
+use crate::SETTINGS;
 
 const SSH_FOLDER_PATH: &str = "/root/.ssh";
 const AUTHORIZED_KEY_FILE: &str = "authorized_keys";
 const CMD_SSH_SERVICE_RESTART: &str = "systemctl restart sshd";
+// IP-echo services, tried in order until one responds with a valid IP address
+const WAN_IP_SERVICES: [&str; 2] = ["https://api.ipify.org", "https://icanhazip.com"];
+const WAN_IP_TIMEOUT: Duration = Duration::from_secs(10);
 
 /** WHEN THIS GETS STABILIZED -> REMOVE THE AUTOMATIC KEY IMPLEMENTATION FROM THE INSTALLATION **/
 
 /** This should be called on NEUS to generate the key pair: 'ssh-keygen -a 100 -t ed25519' **/
 
 /**
+ * Installs the public key Neutron sent us, restarts the SSH service and reports our WAN IP back to Neutron.
+ * Nothing is done if remote management is disabled in the settings or the key isn't in the
+ *     `remote_management_authorized_keys` allowlist (when the allowlist is not empty).
+ *
+ * Returns `true` if the key was installed and our WAN IP was sent to Neutron.
  *
+ * Mutex `SETTINGS` is locked momentarily.
  */
-pub fn start_ssh_server(mqtt: &AsyncClient, pub_key: &str) {
-    match get_wan_ip() {
+pub fn start_ssh_server(mqtt: &AsyncClient, pub_key: &str) -> bool {
+    let (enabled, authorized_keys, http_proxy, device_id) = if let Ok(settings) = SETTINGS.lock() {
+        (
+            settings.remote_management_enabled,
+            settings.remote_management_authorized_keys.to_owned(),
+            settings.http_proxy.to_owned(),
+            get_client_id(&settings.component_mqtt_client.username, &settings.component_mqtt_client.client_id)
+                .to_owned(),
+        )
+    } else {
+        error!("Could not lock SETTINGS mutex. Ignoring the remote management request.");
+        return false;
+    };
+
+    if !enabled {
+        warn!("Received a remote management request, but remote management is disabled. Ignoring...");
+        return false;
+    }
+
+    if !authorized_keys.is_empty() {
+        if let Err(e) = check_key_authorized(pub_key, &authorized_keys) {
+            warn!("Ignoring the remote management request. {}", e);
+            return false;
+        }
+    }
+
+    let runner = SystemCommandRunner;
+
+    // Same client as the update requests, so the lookup goes through the proxy
+    let http_client = match ReqwestClient::with_timeout(&http_proxy, &device_id, WAN_IP_TIMEOUT) {
+        Ok(client) => client,
+        Err(e) => {
+            error!("Could not create the HTTP client. {}", e);
+            return false;
+        }
+    };
+
+    match get_wan_ip(&http_client) {
         Ok(ip) => {
-            let cmd = NeutronCommand::new(CommandType::RemoteManagement, &ip)
-                .to_string()
+            let cmd = NeutronCommand::new(CommandType::RemoteManagement, &ip.to_string())
+                .to_json()
                 .unwrap_or_default();
             let ip_msg = Message::new(
                 own_topic_out(mqtt.inner.client_id.to_str().unwrap_or_default()),
@@ -29,12 +83,13 @@ pub fn start_ssh_server(mqtt: &AsyncClient, pub_key: &str) {
                 1,
             );
 
-            match set_pub_key(pub_key) {
+            match set_pub_key(&runner, pub_key) {
                 Ok(_) => {
-                    if let Err(e) = restart_ssh_service() {
+                    if let Err(e) = restart_ssh_service(&runner) {
                         error!("Failed to restart the SSH service. {}", e);
                     } else {
                         mqtt.publish(ip_msg);
+                        return true;
                     }
                 }
                 Err(e) => error!("Failed to set public SSH key. {}", e),
@@ -42,12 +97,57 @@ pub fn start_ssh_server(mqtt: &AsyncClient, pub_key: &str) {
         }
         Err(e) => error!("Could not get WAN IP address. {}", e),
     }
+
+    false
+}
+
+/**
+ * Checks that every key in `pub_key` (one per line, OpenSSH format) has its fingerprint in `authorized_keys`.
+ */
+fn check_key_authorized(pub_key: &str, authorized_keys: &[String]) -> Result<(), Error> {
+    let keys: Vec<&str> = pub_key.lines().filter(|line| !line.trim().is_empty()).collect();
+
+    if keys.is_empty() {
+        return Err(Error::new(ErrorKind::InvalidInput, "No public key received."));
+    }
+
+    for key in keys {
+        match key_fingerprint(key) {
+            Some(fingerprint) => {
+                if !authorized_keys.iter().any(|x| x == &fingerprint) {
+                    return Err(Error::new(
+                        ErrorKind::PermissionDenied,
+                        format!("Public key is not authorized. Fingerprint: {}", fingerprint),
+                    ));
+                }
+            }
+            None => {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    "Could not parse the received public key.",
+                ))
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/**
+ * Calculates the OpenSSH SHA256 fingerprint ("SHA256:<unpadded base64>") of a public key line ("<type> <base64 key> [comment]").
+ * Returns `None` if the key part is missing or isn't valid base64.
+ */
+fn key_fingerprint(pub_key: &str) -> Option<String> {
+    let key_data = pub_key.split_whitespace().nth(1)?;
+    let key_blob = BASE64.decode(key_data.as_bytes()).ok()?;
+
+    Some(["SHA256:", &BASE64_NOPAD.encode(digest(&SHA256, &key_blob).as_ref())].concat())
 }
 
 /**
  *
  */
-fn set_pub_key(pub_key: &str) -> Result<(), Error> {
+fn set_pub_key(runner: &dyn CommandRunner, pub_key: &str) -> Result<(), Error> {
     let auth_file_path = [SSH_FOLDER_PATH, "/", AUTHORIZED_KEY_FILE].concat();
     match File::create(&auth_file_path) {
         Ok(mut file) => {
@@ -63,13 +163,10 @@ fn set_pub_key(pub_key: &str) -> Result<(), Error> {
         "chmod 700 {} && chmod 600 {}",
         SSH_FOLDER_PATH, &auth_file_path
     );
-    match Command::new("sh").arg("-c").arg(cmd).output() {
+    match runner.output(Command::new("sh").arg("-c").arg(cmd)) {
         Ok(res) => {
-            if !res.stderr.is_empty() {
-                return Err(Error::new(
-                    ErrorKind::Other,
-                    String::from_utf8_lossy(&res.stderr),
-                ));
+            if !res.status.success() {
+                return Err(Error::other(failure_reason(&res)));
             }
         }
         Err(e) => return Err(e),
@@ -81,18 +178,11 @@ fn set_pub_key(pub_key: &str) -> Result<(), Error> {
 /**
  *
  */
-fn restart_ssh_service() -> Result<(), Error> {
-    match Command::new("sh")
-        .arg("-c")
-        .arg(CMD_SSH_SERVICE_RESTART)
-        .output()
-    {
+fn restart_ssh_service(runner: &dyn CommandRunner) -> Result<(), Error> {
+    match runner.output(Command::new("sh").arg("-c").arg(CMD_SSH_SERVICE_RESTART)) {
         Ok(res) => {
-            if !res.stderr.is_empty() {
-                return Err(Error::new(
-                    ErrorKind::Other,
-                    String::from_utf8_lossy(&res.stderr),
-                ));
+            if !res.status.success() {
+                return Err(Error::other(failure_reason(&res)));
             }
         }
         Err(e) => return Err(e),
@@ -102,24 +192,43 @@ fn restart_ssh_service() -> Result<(), Error> {
 }
 
 /**
+ * Asks the `WAN_IP_SERVICES` for our public IP address through the `http_client`, moving on to the next service if one fails.
+ * The client should time out after `WAN_IP_TIMEOUT`.
  *
+ * Returns an error of kind `InvalidData` if a service responded with something that isn't an IP address,
+ *     `TimedOut` if the last service didn't respond in time, or the error of the last service that failed otherwise.
  */
-fn get_wan_ip() -> Result<String, Error> {
-    match Command::new("sh")
-        .arg("-c")
-        .arg("curl https://api.ipify.org")
-        .output()
-    {
-        Ok(res) => {
-            if !res.stderr.is_empty() {
-                return Err(Error::new(
-                    ErrorKind::Other,
-                    String::from_utf8_lossy(&res.stderr),
-                ));
+fn get_wan_ip(http_client: &dyn HttpClient) -> Result<IpAddr, Error> {
+    let mut last_error = Error::new(ErrorKind::NotFound, "No IP-echo service configured.");
+
+    for service in WAN_IP_SERVICES.iter() {
+        let result = match http_client.get(service) {
+            Ok(mut response) => {
+                if response.is_success() {
+                    match response.text() {
+                        Ok(body) => body.trim().parse::<IpAddr>().map_err(|_| {
+                            Error::new(
+                                ErrorKind::InvalidData,
+                                format!("Response is not an IP address. Service: {}", service),
+                            )
+                        }),
+                        Err(e) => Err(e),
+                    }
+                } else {
+                    Err(Error::other(format!("Service responded with {}. Service: {}", response.status, service)))
+                }
             }
+            Err(e) => Err(e),
+        };
 
-            Ok(String::from_utf8_lossy(&res.stdout).into())
+        match result {
+            Ok(ip) => return Ok(ip),
+            Err(e) => {
+                warn!("Could not get the WAN IP address. {}", e);
+                last_error = e;
+            }
         }
-        Err(e) => Err(e),
     }
+
+    Err(last_error)
 }
@@ -1,7 +1,15 @@
 use std::io::{Error, ErrorKind};
+use std::net::IpAddr;
 
 use super::{save_to_file, structs};
-use crate::encryption_certificates::{generate_ca, generate_certificate};
+use crate::command_runner::{CommandRunner, DryRunCommandRunner, SystemCommandRunner};
+use crate::encryption_certificates::{
+    check_openssl, find_certificate_path_collisions, generate_ca, generate_certificate, RegenerationResults, generate_intermediate_ca,
+    lock_renewal,
+    regenerate_all_certificates, regenerate_certificate,
+    validate_algorithm,
+    validate_passphrase_policy,
+};
 use crate::SETTINGS;
 
 /**
@@ -35,6 +43,7 @@ pub fn save_certificates(certificates: Vec<structs::CertificateSettings>) -> Res
  *     main certificate depending on `cert_type` ('ca' or 'main'). Then it triggers the certificate generators for populating the auxiliary paths.
  * Returns an error if the certificate struct does not contain a CA certificate but it is specified in the `cert_type` parameter.
  * Returns an error if no certificate struct contains the component name specified in the `component_name` parameter.
+ * Returns an error if the auxiliary paths are already used by a certificate (see `check_path_collisions()`).
  */
 pub fn append_cert_aux_paths(
     mut settings: structs::Settings,
@@ -42,6 +51,24 @@ pub fn append_cert_aux_paths(
     cert_type: &str,
     aux_paths: &[&str],
 ) -> Result<(), Error> {
+    // Check the new paths before anything is generated into them
+    let mut new_certificates = settings.certificates.clone();
+    if let Some(cert) = new_certificates.iter_mut().find(|cert| cert.component_name == component_name) {
+        let paths = structs::CertificatePaths {
+            key: aux_paths[0].to_owned(),
+            cert: aux_paths[1].to_owned(),
+        };
+
+        if cert_type == "ca" {
+            if let Some(ca) = cert.cert_authority.as_mut() {
+                ca.auxiliary_paths.push(paths);
+            }
+        } else {
+            cert.main_certificate.auxiliary_paths.push(paths);
+        }
+    }
+    check_path_collisions(&settings.certificates, &new_certificates)?;
+
     let mut failed_counter = 0;
 
     for cert in &mut settings.certificates {
@@ -53,7 +80,7 @@ pub fn append_cert_aux_paths(
                         cert: aux_paths[1].to_owned(),
                     });
 
-                    if let Err(e) = generate_ca(component_name, ca, true) {
+                    if let Err(e) = generate_ca(&SystemCommandRunner, component_name, ca, &cert.passphrase_policy, true) {
                         return Err(Error::new(ErrorKind::Other, e));
                     }
                 } else {
@@ -70,7 +97,7 @@ pub fn append_cert_aux_paths(
                         cert: aux_paths[1].to_owned(),
                     });
 
-                if let Err(e) = generate_certificate(&cert, true) {
+                if let Err(e) = generate_certificate(&SystemCommandRunner, cert, true) {
                     return Err(Error::new(ErrorKind::Other, e));
                 }
             }
@@ -92,12 +119,51 @@ pub fn append_cert_aux_paths(
 /**
  * Takes the certificate in the `certificate` parameter and inserts it into the certificates vector in the settings, `settings` parameter, struct.
  * If a certificate with the same `component_name` already exists, we return an error.
- * If we didn't error-out, we go into generating the actual certificates.
+ * If openssl can't be ran, the key algorithm is not supported, any of the `service_ips` is not a valid SAN entry or the passphrase policy is invalid, we return an error.
+ * If any of its files is already used by another certificate (or twice by itself), we return an error.
+ * If we didn't error-out, we go into generating the actual certificates (see `generate_new_certificate()`).
  */
 pub fn add_certificate(
     mut settings: structs::Settings,
     mut certificate: structs::CertificateSettings,
 ) -> Result<(), Error> {
+    generate_new_certificate(&SystemCommandRunner, &settings, &mut certificate)?;
+
+    settings.certificates.push(certificate);
+
+    save_to_file(settings)
+}
+
+/**
+ * Checks the `certificate` like `add_certificate()` does, but instead of generating it,
+ *     returns the openssl commands that would be ran (see `DryRunCommandRunner`).
+ * No keys or certificates are created and the settings are not saved.
+ */
+pub fn plan_certificate(
+    settings: &structs::Settings,
+    mut certificate: structs::CertificateSettings,
+) -> Result<Vec<String>, Error> {
+    let runner = DryRunCommandRunner::default();
+    generate_new_certificate(&runner, settings, &mut certificate)?;
+
+    Ok(runner.commands())
+}
+
+/**
+ * Checks the `certificate` against the `settings`, then generates its CA, intermediate CAs and main certificate with the `runner`.
+ * The generated key passphrases are set in the `certificate`.
+ */
+fn generate_new_certificate(
+    runner: &dyn CommandRunner,
+    settings: &structs::Settings,
+    certificate: &mut structs::CertificateSettings,
+) -> Result<(), Error> {
+    validate_algorithm(&certificate.algorithm)?;
+    // The openssl binary has to be usable even on a dry run
+    check_openssl(&SystemCommandRunner)?;
+    validate_service_ips(&certificate.main_certificate.service_ips)?;
+    validate_passphrase_policy(&certificate.passphrase_policy)?;
+
     if settings
         .certificates
         .iter()
@@ -110,10 +176,16 @@ pub fn add_certificate(
         ));
     }
 
+    let mut new_certificates = settings.certificates.clone();
+    new_certificates.push(certificate.clone());
+    check_path_collisions(&settings.certificates, &new_certificates)?;
+
     if certificate.cert_authority.is_some() {
         match generate_ca(
+            runner,
             &certificate.component_name,
             &certificate.cert_authority.clone().unwrap(),
+            &certificate.passphrase_policy,
             false,
         ) {
             Ok(passphrase) => certificate.cert_authority.as_mut().unwrap().passphrase = passphrase,
@@ -121,12 +193,177 @@ pub fn add_certificate(
         }
     }
 
-    match generate_certificate(&certificate, false) {
+    // Each intermediate CA is signed by the one before it, the first one by the CA
+    for index in 0..certificate.intermediate_cas.len() {
+        let issuer = if index == 0 {
+            certificate.cert_authority.clone()
+        } else {
+            certificate.intermediate_cas.get(index - 1).cloned()
+        };
+
+        let issuer = issuer.ok_or_else(|| {
+            Error::new(
+                ErrorKind::InvalidInput,
+                "Intermediate CAs need a CA certificate to be signed with.",
+            )
+        })?;
+
+        match generate_intermediate_ca(
+            runner,
+            &certificate.component_name,
+            &certificate.intermediate_cas[index],
+            &issuer,
+            &certificate.passphrase_policy,
+            false,
+        ) {
+            Ok(passphrase) => certificate.intermediate_cas[index].passphrase = passphrase,
+            Err(e) => return Err(Error::other(e)),
+        }
+    }
+
+    match generate_certificate(runner, certificate, false) {
         Ok(passphrase) => certificate.main_certificate.passphrase = passphrase,
         Err(e) => return Err(Error::new(ErrorKind::Other, e)),
     }
 
-    settings.certificates.push(certificate);
+    Ok(())
+}
+
+/**
+ * Searches the certificates vector for the one matching the component name and renews it immediately,
+ *     replacing the key and certificate (CA or main depending on `cert_type` ('ca' or 'main')).
+ * The new key passphrases are saved to the settings file, the renewal lock is held until they are.
+ * Returns an error if no certificate struct contains the component name specified in the `component_name` parameter.
+ */
+pub fn force_certificate_renewal(
+    mut settings: structs::Settings,
+    component_name: &str,
+    cert_type: &str,
+) -> Result<(), Error> {
+    let _renewal_lock = lock_renewal()?;
+
+    if let Some(cert) = settings
+        .certificates
+        .iter_mut()
+        .find(|cert| cert.component_name == component_name)
+    {
+        regenerate_certificate(&SystemCommandRunner, cert, cert_type)?;
+    } else {
+        return Err(Error::new(
+            ErrorKind::NotFound,
+            "Could not find a certificate with that component name.",
+        ));
+    }
 
     save_to_file(settings)
 }
+
+/**
+ * Regenerates the keys and certificates of every certificate in the settings, CAs included, regardless of their age.
+ * The new key passphrases are saved to the settings file, even if some of the certificates failed to regenerate.
+ * The renewal lock is held until they are saved.
+ * Returns the result of each certificate with its component name.
+ */
+pub fn regenerate_certificates(
+    mut settings: structs::Settings,
+) -> Result<RegenerationResults, Error> {
+    let _renewal_lock = lock_renewal()?;
+
+    let results = regenerate_all_certificates(&SystemCommandRunner, &mut settings.certificates);
+
+    save_to_file(settings)?;
+
+    Ok(results)
+}
+
+/**
+ * Compares the path collisions of the `certificates` before and after a change (`new_certificates`).
+ * Collisions that were already in the settings file are left alone, they're warned about on startup.
+ *
+ * Returns an error of kind `AlreadyExists` naming the path and the certificate already using it, if the change adds a collision.
+ */
+fn check_path_collisions(
+    certificates: &[structs::CertificateSettings],
+    new_certificates: &[structs::CertificateSettings],
+) -> Result<(), Error> {
+    let existing = find_certificate_path_collisions(certificates);
+
+    match find_certificate_path_collisions(new_certificates)
+        .into_iter()
+        .find(|collision| !existing.contains(collision))
+    {
+        Some(collision) => Err(Error::new(
+            ErrorKind::AlreadyExists,
+            format!("Certificate path is already in use. {}", collision),
+        )),
+        None => Ok(()),
+    }
+}
+
+/**
+ * Checks that every entry of `service_ips` is a SAN entry openssl accepts.
+ * Entries have to start with `IP:` followed by a valid IPv4/IPv6 address or `DNS:` followed by a host name.
+ * Returns an error naming the first malformed entry.
+ */
+fn validate_service_ips(service_ips: &[String]) -> Result<(), Error> {
+    for entry in service_ips {
+        let is_valid = if let Some(ip) = entry.strip_prefix("IP:") {
+            ip.parse::<IpAddr>().is_ok()
+        } else if let Some(host) = entry.strip_prefix("DNS:") {
+            !host.is_empty()
+                && host
+                    .chars()
+                    .all(|ch| ch.is_ascii_alphanumeric() || ch == '-' || ch == '.' || ch == '*')
+        } else {
+            false
+        };
+
+        if !is_valid {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!(
+                    "Invalid service IP '{}'. Entries must be 'IP:<address>' or 'DNS:<host name>'.",
+                    entry
+                ),
+            ));
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entries(values: &[&str]) -> Vec<String> {
+        values.iter().map(|x| x.to_string()).collect()
+    }
+
+    #[test]
+    fn validate_service_ips_accepts_ip_entries() {
+        assert!(validate_service_ips(&entries(&["IP:127.0.0.1", "IP:192.168.1.10", "IP:::1", "IP:fe80::1"])).is_ok());
+    }
+
+    #[test]
+    fn validate_service_ips_accepts_dns_entries() {
+        assert!(validate_service_ips(&entries(&["DNS:localhost", "DNS:blackbox.example.com", "DNS:*.example.com"])).is_ok());
+        assert!(validate_service_ips(&[]).is_ok());
+    }
+
+    #[test]
+    fn validate_service_ips_rejects_bad_addresses() {
+        for entry in &["IP:256.0.0.1", "IP:localhost", "IP:", "DNS:", "DNS:bad host", "DNS:host;rm"] {
+            let e = validate_service_ips(&entries(&["IP:127.0.0.1", entry])).unwrap_err();
+            assert_eq!(e.kind(), ErrorKind::InvalidInput, "{}", entry);
+            assert!(e.to_string().contains(entry), "{}", e);
+        }
+    }
+
+    #[test]
+    fn validate_service_ips_rejects_entries_without_a_prefix() {
+        for entry in &["127.0.0.1", "localhost", "ip:127.0.0.1", "URI:https://example.com"] {
+            assert!(validate_service_ips(&entries(&[entry])).is_err(), "{}", entry);
+        }
+    }
+}
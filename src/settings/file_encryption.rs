@@ -0,0 +1,241 @@
+use std::fs::{metadata, read, rename, set_permissions, OpenOptions, Permissions};
+use std::io::{Error, ErrorKind, Write};
+use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+use std::path::Path;
+use std::sync::atomic::Ordering;
+
+use data_encoding::BASE64;
+use ring::aead::{Aad, LessSafeKey, Nonce, UnboundKey, AES_256_GCM, NONCE_LEN};
+use ring::hkdf::{Salt, HKDF_SHA256};
+use ring::rand::{SecureRandom, SystemRandom};
+
+use super::get_settings_location;
+use crate::SETTINGS_ENCRYPTED;
+
+const KEY_FILE: &str = "settings.key";
+const KEY_FILE_MODE: u32 = 0o600;
+// Permission bits of the group and others, the key file must have none of them
+const KEY_FILE_FORBIDDEN_MODE: u32 = 0o077;
+const KEY_BACKUP_EXTENSION: &str = ".bak";
+const GENERATED_KEY_LENGTH: usize = 32;
+const SALT_LENGTH: usize = 16;
+// First line of an encrypted file, followed by the base64 encoded salt, nonce and ciphertext
+const ENCRYPTED_HEADER: &str = "NECO-ENCRYPTED-V1";
+const KEY_INFO: &[u8] = b"NeutronCommunicator settings";
+
+/**
+ * Returns the path of the settings key file, it is kept in the same folder as the settings file.
+ * It can be a symlink, e.g. to a secret unsealed from the TPM into a tmpfs on boot.
+ */
+pub fn get_key_location() -> String {
+    let settings_loc = get_settings_location();
+
+    match Path::new(&settings_loc).parent() {
+        Some(dir) => dir.join(KEY_FILE).to_string_lossy().into_owned(),
+        None => KEY_FILE.to_owned(),
+    }
+}
+
+/**
+ * Settings encryption is enabled while the key file exists.
+ */
+pub fn is_enabled() -> bool {
+    Path::new(&get_key_location()).exists()
+}
+
+/**
+ * Reads the secret from the key file, its whole contents are the secret.
+ *
+ * Returns an error of kind `PermissionDenied` if the group or others have any permissions on the key file.
+ */
+fn read_secret() -> Result<Vec<u8>, Error> {
+    let key_loc = get_key_location();
+
+    let mode = metadata(&key_loc)
+        .map_err(|e| Error::new(e.kind(), format!("Could not read the settings key file '{}'. {}", key_loc, e)))?
+        .permissions()
+        .mode();
+    if mode & KEY_FILE_FORBIDDEN_MODE != 0 {
+        return Err(Error::new(
+            ErrorKind::PermissionDenied,
+            format!(
+                "Settings key file '{}' is accessible by other users (mode {:o}), it must only be accessible by its owner (600).",
+                key_loc,
+                mode & 0o777
+            ),
+        ));
+    }
+
+    let secret = read(&key_loc).map_err(|e| {
+        Error::new(e.kind(), format!("Could not read the settings key file '{}'. {}", key_loc, e))
+    })?;
+
+    if secret.is_empty() {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            format!("Settings key file '{}' is empty.", key_loc),
+        ));
+    }
+
+    Ok(secret)
+}
+
+/**
+ * Derives the AES-256-GCM key of a file from the `secret` in the key file and the `salt` of the file (HKDF-SHA256).
+ */
+fn derive_key(secret: &[u8], salt: &[u8]) -> Result<LessSafeKey, Error> {
+    let prk = Salt::new(HKDF_SHA256, salt).extract(secret);
+    let info = [KEY_INFO];
+    let okm = prk
+        .expand(&info, &AES_256_GCM)
+        .map_err(|_| Error::other("Could not derive the settings encryption key."))?;
+
+    Ok(LessSafeKey::new(UnboundKey::from(okm)))
+}
+
+/**
+ * Encrypts the `contents` of the settings file or the passphrase store if settings encryption is enabled (see `is_enabled()`),
+ *     otherwise they're returned as they are.
+ * Every call uses a new random salt and nonce.
+ *
+ * Returns an error of kind `NotFound` if encrypted files were loaded (`SETTINGS_ENCRYPTED`) but the key file is gone,
+ *     e.g. a dangling symlink. The files are only saved in plaintext again through `settings::decrypt_settings()`.
+ */
+pub fn seal(contents: &str) -> Result<String, Error> {
+    if !is_enabled() {
+        if SETTINGS_ENCRYPTED.load(Ordering::SeqCst) {
+            return Err(Error::new(
+                ErrorKind::NotFound,
+                format!(
+                    "The settings were loaded encrypted, but the settings key file '{}' is missing. Refusing to save them in plaintext.",
+                    get_key_location()
+                ),
+            ));
+        }
+
+        return Ok(contents.to_owned());
+    }
+
+    let secret = read_secret()?;
+    let rng = SystemRandom::new();
+
+    let mut salt = [0u8; SALT_LENGTH];
+    let mut nonce = [0u8; NONCE_LEN];
+    rng.fill(&mut salt)
+        .and_then(|_| rng.fill(&mut nonce))
+        .map_err(|_| Error::other("Could not generate the encryption nonce."))?;
+
+    let mut in_out = contents.as_bytes().to_vec();
+    derive_key(&secret, &salt)?
+        .seal_in_place_append_tag(
+            Nonce::assume_unique_for_key(nonce),
+            Aad::from(ENCRYPTED_HEADER.as_bytes()),
+            &mut in_out,
+        )
+        .map_err(|_| Error::other("Could not encrypt the settings."))?;
+
+    let sealed = [&salt[..], &nonce[..], &in_out[..]].concat();
+
+    Ok([ENCRYPTED_HEADER, "\n", &BASE64.encode(&sealed), "\n"].concat())
+}
+
+/**
+ * Decrypts `contents` sealed by `seal()` with the key file. Plaintext `contents` are returned as they are,
+ *     so files saved before the settings were encrypted still load.
+ * Once something was decrypted, `seal()` refuses to save in plaintext.
+ *
+ * Returns an error of kind `InvalidData` if the contents can't be decrypted (wrong key or a damaged file).
+ */
+pub fn open(contents: &str) -> Result<String, Error> {
+    let encoded = match contents.strip_prefix(ENCRYPTED_HEADER) {
+        Some(encoded) => encoded.trim(),
+        None => return Ok(contents.to_owned()),
+    };
+
+    let secret = read_secret()?;
+
+    let sealed = BASE64
+        .decode(encoded.as_bytes())
+        .map_err(|e| Error::new(ErrorKind::InvalidData, format!("Encrypted file is damaged. {}", e)))?;
+
+    if sealed.len() < SALT_LENGTH + NONCE_LEN {
+        return Err(Error::new(ErrorKind::InvalidData, "Encrypted file is damaged."));
+    }
+
+    let (salt, rest) = sealed.split_at(SALT_LENGTH);
+    let (nonce, ciphertext) = rest.split_at(NONCE_LEN);
+    let nonce = Nonce::try_assume_unique_for_key(nonce)
+        .map_err(|_| Error::new(ErrorKind::InvalidData, "Encrypted file is damaged."))?;
+
+    let mut in_out = ciphertext.to_vec();
+    let plaintext = derive_key(&secret, salt)?
+        .open_in_place(nonce, Aad::from(ENCRYPTED_HEADER.as_bytes()), &mut in_out)
+        .map_err(|_| {
+            Error::new(
+                ErrorKind::InvalidData,
+                "Could not decrypt the file. The settings key doesn't match or the file is damaged.",
+            )
+        })?;
+
+    let plaintext = String::from_utf8(plaintext.to_vec()).map_err(|e| Error::new(ErrorKind::InvalidData, e))?;
+
+    SETTINGS_ENCRYPTED.store(true, Ordering::SeqCst);
+
+    Ok(plaintext)
+}
+
+/**
+ * Allows the settings to be saved in plaintext again after encrypted ones were loaded (see `seal()`).
+ * Only meant for disabling settings encryption.
+ */
+pub fn allow_plaintext() {
+    SETTINGS_ENCRYPTED.store(false, Ordering::SeqCst);
+}
+
+/**
+ * Writes a new random secret to the key file, only readable by its owner (root).
+ * An existing key file is moved to `<key file>.bak` first, it's still needed if re-encrypting with the new key fails.
+ *
+ * Returns the key file path.
+ */
+pub fn generate_key() -> Result<String, Error> {
+    let mut secret = [0u8; GENERATED_KEY_LENGTH];
+    SystemRandom::new()
+        .fill(&mut secret)
+        .map_err(|_| Error::other("Could not generate the settings key."))?;
+
+    backup_key()?;
+
+    let key_loc = get_key_location();
+
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(KEY_FILE_MODE)
+        .open(&key_loc)?;
+
+    // The mode is only applied when creating the file, the umask may have masked it
+    set_permissions(&key_loc, Permissions::from_mode(KEY_FILE_MODE))?;
+
+    file.write_all(BASE64.encode(&secret).as_bytes())?;
+
+    Ok(key_loc)
+}
+
+/**
+ * Moves the key file to `<key file>.bak`, which disables settings encryption for the files saved afterwards.
+ *
+ * Returns the path of the backed up key file, `None` if there is no key file.
+ */
+pub fn backup_key() -> Result<Option<String>, Error> {
+    let key_loc = get_key_location();
+
+    if !Path::new(&key_loc).exists() {
+        return Ok(None);
+    }
+
+    let backup_loc = [key_loc.as_str(), KEY_BACKUP_EXTENSION].concat();
+    rename(&key_loc, &backup_loc)?;
+
+    Ok(Some(backup_loc))
+}
@@ -1,15 +1,27 @@
-use std::{fs::File, io::prelude::Read, io::Error, io::ErrorKind, io::Write, path::Path};
+use std::{fs::copy, fs::File, io::prelude::Read, io::Error, io::ErrorKind, io::Write, path::Path};
 
 use serde_json::from_str;
 
-use crate::{APP_NAME, BASE_DIRECTORY};
+use std::sync::atomic::Ordering;
+
+use crate::encryption_certificates::reload as reload_certificates;
+use crate::mqtt_connection::get_client_id;
+use crate::version_control::{init_component_versions, validate_update_files};
+use crate::{
+    APP_NAME, BASE_DIRECTORY, COMPONENT_VERSIONS, RECONNECT_COMPONENT_MQTT, SETTINGS, SETTINGS_FILE_OVERRIDE,
+    UPDATE_COMPONENTS,
+};
 
 pub mod encryption_certificates;
+pub mod file_encryption;
 pub mod mqtt_connection;
+pub mod passphrases;
 pub mod update_components;
 pub mod structs;
 
 const SETTINGS_FILE: &str = "settings.json";
+const SETTINGS_BACKUP_EXTENSION: &str = ".bak";
+const REDACTED_VALUE: &str = "****";
 
 /**
  * Checks if the settings file exists.
@@ -37,13 +49,102 @@ pub fn init() -> Result<structs::Settings, ()> {
     }
 }
 
+/**
+ * Loads the settings file again and replaces `SETTINGS`, `UPDATE_COMPONENTS` and `COMPONENT_VERSIONS` with it,
+ *     then hands the certificates to the certificate watchdog (see `crate::encryption_certificates::reload()`).
+ * If the component mqtt client settings changed, the component backhaul reconnects with them (`RECONNECT_COMPONENT_MQTT`).
+ * The broker address and the client id can't change without a restart, the running client keeps using the old ones.
+ *
+ * Returns the changed settings that need a NECO restart to take effect.
+ *
+ * Mutexes `SETTINGS`, `UPDATE_COMPONENTS`, `COMPONENT_VERSIONS` are locked momentarily.
+ */
+pub fn reload() -> Result<Vec<String>, Error> {
+    let new_settings =
+        init().map_err(|_| Error::new(ErrorKind::InvalidData, "Could not load the settings file."))?;
+
+    let old_mqtt_client = if let Ok(mut settings) = SETTINGS.lock() {
+        std::mem::replace(&mut *settings, new_settings.clone()).component_mqtt_client
+    } else {
+        return Err(Error::other("Could not lock SETTINGS mutex."));
+    };
+
+    if let Ok(mut update_components) = UPDATE_COMPONENTS.lock() {
+        *update_components = new_settings.update_components.clone();
+    } else {
+        return Err(Error::other("Could not lock UPDATE_COMPONENTS mutex."));
+    }
+
+    let component_versions = init_component_versions(&new_settings.update_components);
+    if let Ok(mut versions) = COMPONENT_VERSIONS.lock() {
+        *versions = component_versions;
+    } else {
+        return Err(Error::other("Could not lock COMPONENT_VERSIONS mutex."));
+    }
+
+    let new_mqtt_client = &new_settings.component_mqtt_client;
+    let mut restart_required: Vec<String> = Vec::new();
+
+    if old_mqtt_client.ip != new_mqtt_client.ip || old_mqtt_client.port != new_mqtt_client.port {
+        restart_required.push(String::from("component_mqtt_client.ip/port"));
+    }
+    if get_client_id(&old_mqtt_client.username, &old_mqtt_client.client_id)
+        != get_client_id(&new_mqtt_client.username, &new_mqtt_client.client_id)
+    {
+        restart_required.push(String::from("component_mqtt_client.client_id"));
+    }
+
+    if restart_required.is_empty() && old_mqtt_client != *new_mqtt_client {
+        info!("Component mqtt client settings changed. Reconnecting to component backhaul...");
+        RECONNECT_COMPONENT_MQTT.store(true, Ordering::SeqCst);
+    }
+
+    if let Err(e) = reload_certificates(&new_settings.certificates) {
+        error!("Could not reload the certificates. {}", e);
+    }
+
+    if restart_required.is_empty() {
+        info!("Settings reloaded.");
+    } else {
+        warn!(
+            "Settings reloaded. These changes need a restart to take effect: {}",
+            restart_required.join(", ")
+        );
+    }
+
+    Ok(restart_required)
+}
+
 /**
  * Converts the settings struct default() output to JSON and saves it to disk.
- * If the file already exits it is truncated.
+ * If the file already exists, an error is returned unless `force` is set.
+ *     When forcing, the existing file (and the passphrase store) is first copied to `<file>.bak` and then truncated.
  * If the path is invalid it returns an error.
  * Returns settings file path if successful.
  */
-pub fn write_default() -> Result<String, Error> {
+pub fn write_default(force: bool) -> Result<String, Error> {
+    let settings_loc = get_settings_location();
+
+    if Path::new(&settings_loc).exists() {
+        if !force {
+            return Err(Error::new(
+                ErrorKind::AlreadyExists,
+                "Settings file already exists. Use '--force' to overwrite it.",
+            ));
+        }
+
+        let backup_loc = [&settings_loc, SETTINGS_BACKUP_EXTENSION].concat();
+        copy(&settings_loc, &backup_loc)?;
+
+        warn!("Existing settings file backed up to: '{}'", backup_loc);
+
+        // The key passphrases would be lost with the settings, keep them too
+        let store_loc = passphrases::get_store_location();
+        if Path::new(&store_loc).exists() {
+            copy(&store_loc, [&store_loc, SETTINGS_BACKUP_EXTENSION].concat())?;
+        }
+    }
+
     info!("Generating default settings file...");
 
     if let Err(e) = save_to_file(structs::Settings::default()) {
@@ -55,8 +156,11 @@ pub fn write_default() -> Result<String, Error> {
 
 /**
  * Tries to load the JSON settings file from the `get_settings_location()` function and parse it.
+ * An encrypted settings file is decrypted first (see `file_encryption::open()`).
  * If we're successful at parsing the file, we then add NECO to the `update_components` array in the
  *     settings struct so that we can include ourselves when searching for updates.
+ * Key passphrases of the certificates are filled in from the passphrase store, an unloadable store fails the load.
+ * Invalid `update_files` settings fail the load too (see `crate::version_control::validate_update_files()`).
  *
  * Returns `Ok(structs::Settings)` if successful.
  */
@@ -76,9 +180,14 @@ fn load_settings() -> Result<structs::Settings, Error> {
         Err(e) => return Err(e),
     }
 
+    let contents = file_encryption::open(&contents)?;
+
     if let Ok(json) = from_str(&contents) {
         let mut settings: structs::Settings = json;
 
+        validate_update_files(&settings.update_files)?;
+        passphrases::fill_passphrases(&mut settings.certificates)?;
+
         settings.update_components.push(structs::UpdateComponent {
             name: APP_NAME.to_owned(),
             version_file_path: String::new(),
@@ -88,6 +197,12 @@ fn load_settings() -> Result<structs::Settings, Error> {
             container_name: None,
             service_name: Some(String::from("neutroncommunicator.service")),
             restart_command: String::new(),
+            branch: None,
+            group: None,
+            health_check: None,
+            health_check_grace_period: None,
+            restart_retries: None,
+            skip_verification: false,
         });
 
         return Ok(settings);
@@ -99,10 +214,84 @@ fn load_settings() -> Result<structs::Settings, Error> {
     ))
 }
 
+/**
+ * Encrypts the settings file and the passphrase store (see `file_encryption`).
+ * If there's no key file yet, a random one is generated. An existing key file is used as it is,
+ *     unless `rekey` is set, then a new one is generated and the old one is kept as `<key file>.bak`.
+ * Files that are already encrypted are decrypted with the current key first.
+ *
+ * Returns the key file path.
+ */
+pub fn encrypt_settings(rekey: bool) -> Result<String, Error> {
+    let settings = load_settings()?;
+
+    let key_loc = if rekey || !file_encryption::is_enabled() {
+        file_encryption::generate_key()?
+    } else {
+        file_encryption::get_key_location()
+    };
+
+    save_to_file(settings)?;
+
+    Ok(key_loc)
+}
+
+/**
+ * Decrypts the settings file and the passphrase store, then disables settings encryption
+ *     by moving the key file to `<key file>.bak` (see `file_encryption::backup_key()`).
+ *
+ * Returns the path of the backed up key file.
+ */
+pub fn decrypt_settings() -> Result<String, Error> {
+    if !file_encryption::is_enabled() {
+        return Err(Error::new(ErrorKind::InvalidInput, "Settings encryption is not enabled."));
+    }
+
+    let settings = load_settings()?;
+
+    let backup_loc = file_encryption::backup_key()?.unwrap_or_default();
+    file_encryption::allow_plaintext();
+
+    save_to_file(settings)?;
+
+    Ok(backup_loc)
+}
+
+/**
+ * Replaces every non-empty secret in `settings` (mqtt and proxy passwords, key and PKCS#12 passphrases) with `REDACTED_VALUE`.
+ * Used for showing the settings without exposing the secrets.
+ */
+pub fn redact_secrets(settings: &mut structs::Settings) {
+    let redact = |secret: &mut String| {
+        if !secret.is_empty() {
+            *secret = REDACTED_VALUE.to_owned();
+        }
+    };
+
+    redact(&mut settings.neutron_mqtt_client.password);
+    redact(&mut settings.component_mqtt_client.password);
+    if let Some(http_proxy) = settings.http_proxy.as_mut() {
+        redact(&mut http_proxy.password);
+    }
+
+    for cert in &mut settings.certificates {
+        if let Some(ca) = cert.cert_authority.as_mut() {
+            redact(&mut ca.passphrase);
+        }
+        for ca in &mut cert.intermediate_cas {
+            redact(&mut ca.passphrase);
+        }
+        redact(&mut cert.main_certificate.passphrase);
+        redact(&mut cert.main_certificate.pkcs12_passphrase);
+    }
+}
+
 /**
  * Converts the struct `structs::Settings` to JSON and then saves the data to the path given by `get_settings_location()`.
  *
  * This function also removes the `NECO` entry in the `update_components` vector as it is added on startup and there is no need for it to be saved.
+ * Key passphrases are moved to the passphrase store (`passphrases::store_passphrases()`) instead of being saved to the settings file.
+ * If settings encryption is enabled, the file is encrypted (see `file_encryption::seal()`).
  */
 fn save_to_file(mut settings: structs::Settings) -> Result<(), Error> {
     let settings_loc = get_settings_location();
@@ -116,10 +305,13 @@ fn save_to_file(mut settings: structs::Settings) -> Result<(), Error> {
         settings.update_components.remove(index);
     }
 
+    // Key passphrases are kept out of the settings file
+    passphrases::store_passphrases(&mut settings.certificates)?;
+
     // Convert to json
     let json_settings;
     match serde_json::to_string_pretty(&settings) {
-        Ok(json) => json_settings = json,
+        Ok(json) => json_settings = file_encryption::seal(&json)?,
         Err(e) => return Err(Error::new(ErrorKind::Other, e)),
     }
 
@@ -137,8 +329,137 @@ fn save_to_file(mut settings: structs::Settings) -> Result<(), Error> {
 }
 
 /**
- * Concatenates the `BASE_DIRECTORY` `SETTINGS_FILE` to create the path of the settings file.
+ * Returns the settings file path given with the `--config` argument, if there is one.
+ * Otherwise concatenates the `BASE_DIRECTORY` `SETTINGS_FILE` to create the path of the settings file.
+ *
+ * Mutex `SETTINGS_FILE_OVERRIDE` is locked momentarily.
  */
 fn get_settings_location() -> String {
+    if let Ok(settings_file) = SETTINGS_FILE_OVERRIDE.lock() {
+        if let Some(path) = settings_file.as_ref() {
+            return path.to_owned();
+        }
+    }
+
     [BASE_DIRECTORY, SETTINGS_FILE].concat()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /**
+     * Settings with every kind of entry filled in, certificates with key passphrases included.
+     */
+    fn populated_settings() -> structs::Settings {
+        let paths = |name: &str| structs::CertificatePaths {
+            key: ["/etc/neco-test/", name, ".key"].concat(),
+            cert: ["/etc/neco-test/", name, ".crt"].concat(),
+        };
+
+        let component = structs::UpdateComponent {
+            name: String::from("BlackBox"),
+            version_file_path: String::from("/etc/BlackBox/blackbox.version"),
+            permission_user: String::from("root"),
+            permission_group: String::from("root"),
+            file_permissions: String::from("700"),
+            service_name: Some(String::from("blackbox.service")),
+            restart_command: String::from("systemctl restart blackbox.service"),
+            branch: Some(String::from("beta")),
+            health_check_grace_period: Some(5),
+            ..Default::default()
+        };
+
+        let certificate = structs::CertificateSettings {
+            component_name: String::from("BlackBox"),
+            algorithm: String::from("rsa"),
+            cert_authority: Some(structs::CACertificate {
+                encrypted: true,
+                duration: 3650,
+                subj: String::from("/CN=BlackBox CA"),
+                main_paths: paths("ca"),
+                passphrase: String::from("ca-pass"),
+                ..Default::default()
+            }),
+            intermediate_cas: vec![structs::CACertificate {
+                encrypted: true,
+                duration: 1825,
+                subj: String::from("/CN=BlackBox Intermediate"),
+                main_paths: paths("intermediate"),
+                passphrase: String::from("intermediate-pass"),
+                ..Default::default()
+            }],
+            main_certificate: structs::MainCertificate {
+                encrypted: true,
+                duration: 365,
+                key_len: 2048,
+                subj: String::from("/CN=blackbox"),
+                main_paths: paths("main"),
+                auxiliary_paths: vec![paths("aux")],
+                service_ips: vec![String::from("IP:127.0.0.1"), String::from("DNS:localhost")],
+                passphrase: String::from("main-pass"),
+                pkcs12_path: String::from("/etc/neco-test/main.p12"),
+                pkcs12_passphrase: String::from("p12-pass"),
+                ..Default::default()
+            },
+            passphrase_policy: structs::PassphrasePolicy::default(),
+        };
+
+        structs::Settings {
+            neutron_account_username: String::from("tester"),
+            update_components: vec![component],
+            certificates: vec![certificate],
+            neutron_mirrors: vec![String::from("mirror.example.com")],
+            update_files: structs::UpdateFiles {
+                temp_root: Some(String::from("/var/lib/neco")),
+                ..Default::default()
+            },
+            updates_paused: true,
+            http_proxy: Some(structs::HttpProxy {
+                url: String::from("http://proxy.example.com:3128"),
+                username: Some(String::from("proxy")),
+                password: String::from("proxy-pass"),
+                password_file: None,
+            }),
+            maintenance_window: Some(structs::MaintenanceWindow {
+                start: String::from("22:00"),
+                end: String::from("04:00"),
+            }),
+            max_log_lines: Some(500),
+            max_log_size: Some(64 * 1024),
+            hash_buffer_size: Some(4096),
+            ..Default::default()
+        }
+    }
+
+    // The only test pointing `SETTINGS_FILE_OVERRIDE` somewhere else, tests run in parallel
+    #[test]
+    fn settings_survive_a_save_and_load() {
+        let dir = tempfile::tempdir().unwrap();
+        let settings_loc = dir.path().join(SETTINGS_FILE);
+        *SETTINGS_FILE_OVERRIDE.lock().unwrap() = Some(settings_loc.to_string_lossy().into_owned());
+
+        let settings = populated_settings();
+        save_to_file(settings.clone()).unwrap();
+        let contents = std::fs::read_to_string(&settings_loc).unwrap();
+        let mut loaded = load_settings().unwrap();
+
+        *SETTINGS_FILE_OVERRIDE.lock().unwrap() = None;
+
+        // Neither NECO nor the key passphrases end up in the file
+        let saved: structs::Settings = serde_json::from_str(&contents).unwrap();
+        assert!(saved.update_components.iter().all(|x| x.name != APP_NAME));
+        assert!(saved.certificates.iter().all(|x| x.main_certificate.passphrase.is_empty()));
+
+        // NECO is added to the update components on load
+        let neco = loaded.update_components.pop().unwrap();
+        assert_eq!(neco.name, APP_NAME);
+
+        // The key passphrases come back from the passphrase store
+        assert_eq!(loaded.certificates, settings.certificates);
+        assert_eq!(
+            serde_json::to_value(&loaded).unwrap(),
+            serde_json::to_value(&settings).unwrap()
+        );
+    }
+}
@@ -1,6 +1,7 @@
 use std::io::Error;
 
 use super::{save_to_file, structs};
+use crate::mqtt_connection::check_ca_file;
 
 /**
  * Sets the Neutron account settings and saves them to file.
@@ -20,6 +21,7 @@ pub fn save_neutron_creds(
 
 /**
  * Sets the component backhaul server credentials and saves them to file.
+ * Nothing is saved if the CA file doesn't exist or holds no certificate, see `check_ca_file()`.
  */
 pub fn save_component_creds(
     mut settings: structs::Settings,
@@ -29,6 +31,8 @@ pub fn save_component_creds(
     password: &str,
     ca_path: &str,
 ) -> Result<(), Error> {
+    check_ca_file(ca_path)?;
+
     settings.component_mqtt_client.ip = ip.to_owned();
     settings.component_mqtt_client.port = port.to_owned();
     settings.component_mqtt_client.username = username.to_owned();
@@ -0,0 +1,173 @@
+use std::collections::BTreeMap;
+use std::fs::{read_to_string, set_permissions, OpenOptions, Permissions};
+use std::io::{Error, ErrorKind, Write};
+use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+use std::path::Path;
+
+use super::{file_encryption, get_settings_location, structs::CertificateSettings};
+
+const PASSPHRASE_FILE: &str = "passphrases.json";
+const PASSPHRASE_FILE_MODE: u32 = 0o600;
+
+/**
+ * Returns the path of the passphrase store, it is kept in the same folder as the settings file.
+ */
+pub fn get_store_location() -> String {
+    let settings_loc = get_settings_location();
+
+    match Path::new(&settings_loc).parent() {
+        Some(dir) => dir.join(PASSPHRASE_FILE).to_string_lossy().into_owned(),
+        None => PASSPHRASE_FILE.to_owned(),
+    }
+}
+
+/**
+ * Returns the key under which the passphrase of the `cert_type` ('ca', 'main', 'pkcs12' or `intermediate_key()`) of `component_name` is stored.
+ */
+fn store_key(component_name: &str, cert_type: &str) -> String {
+    [component_name, "/", cert_type].concat()
+}
+
+/**
+ * Returns the `cert_type` the passphrase of the intermediate CA at `index` (in `intermediate_cas`) is stored under.
+ */
+pub fn intermediate_key(index: usize) -> String {
+    format!("intermediate{}", index)
+}
+
+/**
+ * Loads the passphrase store, decrypting it if it's encrypted (see `file_encryption`).
+ * If the store doesn't exist, an empty store is returned.
+ *
+ * Returns an error if the store exists but can't be read, decrypted or parsed. The store is rewritten from scratch
+ *     when the settings are saved, so treating it as empty would erase every passphrase in it.
+ */
+fn load_store() -> Result<BTreeMap<String, String>, Error> {
+    let contents = match read_to_string(get_store_location()) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(BTreeMap::new()),
+        Err(e) => return Err(Error::new(e.kind(), format!("Could not read the passphrase store. {}", e))),
+    };
+
+    let contents = file_encryption::open(&contents)
+        .map_err(|e| Error::new(e.kind(), format!("Could not decrypt the passphrase store. {}", e)))?;
+
+    serde_json::from_str(&contents)
+        .map_err(|e| Error::new(ErrorKind::InvalidData, format!("Could not parse the passphrase store. {}", e)))
+}
+
+/**
+ * Returns the stored passphrase of the `cert_type` ('ca', 'main' or `intermediate_key()`) key of the component named `component_name`.
+ * `None` if there is none or the store can't be loaded.
+ */
+pub fn lookup(component_name: &str, cert_type: &str) -> Option<String> {
+    match load_store() {
+        Ok(mut store) => store.remove(&store_key(component_name, cert_type)),
+        Err(e) => {
+            error!("{}", e);
+            None
+        }
+    }
+}
+
+/**
+ * Fills in the key (and PKCS#12 bundle) passphrases of `certificates` from the passphrase store.
+ * Passphrases that are still in the settings file (saved before the store existed) are kept as they are,
+ *     they are moved to the store the next time the settings are saved.
+ *
+ * Returns an error if the store exists but can't be loaded (see `load_store()`).
+ */
+pub fn fill_passphrases(certificates: &mut [CertificateSettings]) -> Result<(), Error> {
+    let store = load_store()?;
+
+    for cert in certificates {
+        if let Some(ca) = cert.cert_authority.as_mut() {
+            if ca.passphrase.is_empty() {
+                if let Some(pass) = store.get(&store_key(&cert.component_name, "ca")) {
+                    ca.passphrase = pass.to_owned();
+                }
+            }
+        }
+
+        for (index, ca) in cert.intermediate_cas.iter_mut().enumerate() {
+            if ca.passphrase.is_empty() {
+                if let Some(pass) = store.get(&store_key(&cert.component_name, &intermediate_key(index))) {
+                    ca.passphrase = pass.to_owned();
+                }
+            }
+        }
+
+        if cert.main_certificate.passphrase.is_empty() {
+            if let Some(pass) = store.get(&store_key(&cert.component_name, "main")) {
+                cert.main_certificate.passphrase = pass.to_owned();
+            }
+        }
+
+        if cert.main_certificate.pkcs12_passphrase.is_empty() {
+            if let Some(pass) = store.get(&store_key(&cert.component_name, "pkcs12")) {
+                cert.main_certificate.pkcs12_passphrase = pass.to_owned();
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/**
+ * Moves the key (and PKCS#12 bundle) passphrases of `certificates` to the passphrase store, leaving them empty in the structs.
+ * The store is rewritten from scratch so passphrases of removed certificates don't linger in it.
+ * The store file is only readable by its owner (root) and encrypted like the settings file (see `file_encryption::seal()`).
+ */
+pub fn store_passphrases(certificates: &mut [CertificateSettings]) -> Result<(), Error> {
+    let mut store: BTreeMap<String, String> = BTreeMap::new();
+
+    for cert in certificates {
+        if let Some(ca) = cert.cert_authority.as_mut() {
+            if !ca.passphrase.is_empty() {
+                store.insert(store_key(&cert.component_name, "ca"), ca.passphrase.split_off(0));
+            }
+        }
+
+        for (index, ca) in cert.intermediate_cas.iter_mut().enumerate() {
+            if !ca.passphrase.is_empty() {
+                store.insert(
+                    store_key(&cert.component_name, &intermediate_key(index)),
+                    ca.passphrase.split_off(0),
+                );
+            }
+        }
+
+        if !cert.main_certificate.passphrase.is_empty() {
+            store.insert(
+                store_key(&cert.component_name, "main"),
+                cert.main_certificate.passphrase.split_off(0),
+            );
+        }
+
+        if !cert.main_certificate.pkcs12_passphrase.is_empty() {
+            store.insert(
+                store_key(&cert.component_name, "pkcs12"),
+                cert.main_certificate.pkcs12_passphrase.split_off(0),
+            );
+        }
+    }
+
+    let json = match serde_json::to_string_pretty(&store) {
+        Ok(json) => file_encryption::seal(&json)?,
+        Err(e) => return Err(Error::other(e)),
+    };
+
+    let store_loc = get_store_location();
+
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(PASSPHRASE_FILE_MODE)
+        .open(&store_loc)?;
+
+    // The mode is only applied when creating the file
+    set_permissions(&store_loc, Permissions::from_mode(PASSPHRASE_FILE_MODE))?;
+
+    file.write_all(json.as_bytes())
+}
@@ -1,3 +1,11 @@
+use crate::encryption_certificates::{CHARSET, PASSPHRASE_LENGTH};
+use crate::mqtt_connection::{DEFAULT_CIPHER_SUITES, DEFAULT_MIN_TLS_VERSION};
+use crate::version_control::{
+    DEFAULT_MAX_DOWNLOAD_SIZE, DEFAULT_MAX_PACKAGE_SIZE, DEFAULT_SANDBOX_DIR, EXTRACTED_FOLDER_SUFFIX,
+    LEFTOVER_UPDATES_FILE, RECIPE_FILENAME, TEMP_UPDATE_FOLDER,
+};
+use crate::{NEUTRON_SERVER_IP, NEUTRON_SERVER_PORT, NEUTRON_SERVER_USE_TLS};
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Settings {
     pub neutron_account_username: String,
@@ -7,21 +15,158 @@ pub struct Settings {
     pub update_branch: String,
     pub update_components: Vec<UpdateComponent>,
     pub certificates: Vec<CertificateSettings>,
+    #[serde(default)]
+    pub neutron_server: NeutronServer,
+    // Fallback Neutron servers (e.g. "https://mirror.example.com:8002"), tried in order after the primary one
+    #[serde(default)]
+    pub neutron_mirrors: Vec<String>,
+    // Neutron can only start the SSH server for remote management if this is enabled
+    #[serde(default)]
+    pub remote_management_enabled: bool,
+    // OpenSSH fingerprints ("SHA256:...") of the public keys Neutron may install, any key is accepted if empty
+    #[serde(default)]
+    pub remote_management_authorized_keys: Vec<String>,
+    #[serde(default)]
+    pub update_files: UpdateFiles,
+    // Kill switch for rollouts, while set no update manifests are requested and no updates are installed
+    #[serde(default)]
+    pub updates_paused: bool,
+    // Base64 Ed25519 public key NECO update packages have to be signed with, NECO is not upgraded if this is not set
+    #[serde(default)]
+    pub neco_update_public_key: Option<String>,
+    // Publish the install log of every installed component to the External Interface once the install completes
+    #[serde(default)]
+    pub publish_install_logs: bool,
+    #[serde(default)]
+    pub download_limits: DownloadLimits,
+    // Manifest requests and update downloads go through this proxy, direct connections are used if `None`
+    #[serde(default)]
+    pub http_proxy: Option<HttpProxy>,
+    // Updates are downloaded and verified right away, but only the NECO upgrade is installed outside of this window,
+    //     the other updates are staged until it opens. Updates are installed immediately if `None`
+    #[serde(default)]
+    pub maintenance_window: Option<MaintenanceWindow>,
+    #[serde(default)]
+    pub sandbox: InstallSandbox,
+    // Upper bound of the log lines a component log request can fetch, larger requests are clamped to it.
+    //     `DEFAULT_MAX_LOG_LINES` is used if `None`
+    #[serde(default)]
+    pub max_log_lines: Option<u32>,
+    // Upper bound in bytes of the log sent in a component log reply, older lines are cut off.
+    //     `DEFAULT_MAX_LOG_SIZE` is used if `None` or 0
+    #[serde(default)]
+    pub max_log_size: Option<u32>,
+    // Bytes read at once while hashing downloaded update packages, `DEFAULT_HASH_BUFFER_SIZE` is used if `None` or 0
+    #[serde(default)]
+    pub hash_buffer_size: Option<u32>,
+}
+
+// Names of the files and folders used while downloading and installing updates
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct UpdateFiles {
+    pub temp_folder: String, // Relative to the `temp_root`
+    // Absolute path of the folder the `temp_folder` is created in, e.g. a data partition with room for the downloads.
+    //     The base directory is used if `None`
+    #[serde(default)]
+    pub temp_root: Option<String>,
+    pub leftover_updates_file: String, // Saved in the temp folder
+    pub recipe_filename: String, // Looked up in the root of every extracted update
+    pub extracted_folder_suffix: String, // Appended to the update archive path to get the extraction folder
+}
+
+// Update downloads are aborted once they grow past these sizes
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct DownloadLimits {
+    pub max_package_size: u64, // Bytes, a single update package
+    pub max_total_size: u64, // Bytes, all update packages downloaded for one install
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct HttpProxy {
+    pub url: String, // e.g. "http://proxy.example.com:3128"
+    pub username: Option<String>, // If `None`, no credentials are sent to the proxy
+    #[serde(default)]
+    pub password: String,
+    pub password_file: Option<String>, // If set, the password is read from this file and `password` is ignored
+}
+
+// For testing NECO, while enabled the updates are installed into `dir` instead of the live system:
+//     the copy destinations are recreated under it, commands and scripts are skipped and components are not restarted
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct InstallSandbox {
+    pub enabled: bool,
+    pub dir: String,
+}
+
+// Daily, in local time. If `end` is before `start` the window crosses midnight
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct MaintenanceWindow {
+    pub start: String, // "HH:MM", e.g. "22:00"
+    pub end: String, // "HH:MM", e.g. "04:00"
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct NeutronServer {
+    pub host: String,
+    pub port: u16,
+    pub use_tls: bool, // Defaults to `true` for SECURE builds and `false` for INSECURE builds
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone, Default)]
 pub struct NeutronMqttClient {
     pub username: String,
     pub password: String,
+    pub password_file: Option<String>, // If set, the password is read from this file and `password` is ignored
+    pub client_id: Option<String>, // Also used in our topics, defaults to `username` if `None`
+    // See `ComponentMqttClient::persistent_session`
+    #[serde(default)]
+    pub persistent_session: bool,
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
 pub struct ComponentMqttClient {
     pub ip: String,
     pub port: String,
     pub username: String,
     pub password: String,
+    pub password_file: Option<String>, // If set, the password is read from this file and `password` is ignored
+    pub client_id: Option<String>, // Also used in our topics and as our id in the component states, defaults to `username` if `None`
+    // Connect without a clean session, so the broker queues the QoS 1 commands sent to us while we're offline and
+    //     delivers them once we reconnect. The session is keyed on the client id, it has to stay the same.
+    // Unlike retained messages (only the last one per topic is kept), every queued command is delivered, including
+    //     stale ones, e.g. an update trigger sent hours before the reconnect is still executed.
+    #[serde(default)]
+    pub persistent_session: bool,
     pub cafile: String,
+    pub max_message_size: Option<usize>, // Bytes, bigger payloads are truncated/split. Defaults to 1MB if `None`
+    // Message types ("ComponentStates", "BuildInfo", "Changelogs", "State") also published as retained on
+    //     "external_interface/<client id>/<message type>", so late subscribers get the last one right away
+    #[serde(default)]
+    pub retained_messages: Vec<String>,
+    // Presented to the broker for mutual TLS, only the username/password are used if `None`
+    #[serde(default)]
+    pub client_certificate: Option<MqttClientCertificate>,
+    #[serde(default)]
+    pub tls_policy: TlsPolicy,
+}
+
+// A broker that can't meet the policy fails the handshake, the connection is never downgraded
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct TlsPolicy {
+    pub min_version: String, // "1.2" or "1.0"
+    pub cipher_suites: String, // OpenSSL cipher list for TLS 1.2 and older, TLS 1.3 always uses the OpenSSL defaults
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct MqttClientCertificate {
+    // Component name of a certificate in `certificates`, its main certificate and key are presented and
+    //     the connection is re-established every time the certificate watchdog renews it
+    pub managed_certificate: Option<String>,
+    #[serde(default)]
+    pub cert: String, // Used if `managed_certificate` is `None`
+    #[serde(default)]
+    pub key: String, // Used if `managed_certificate` is `None`
+    pub key_passphrase_file: Option<String>, // Needed if `key` is encrypted, managed keys use their stored passphrase
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone, Default)]
@@ -35,6 +180,14 @@ pub struct UpdateComponent {
     pub service_name: Option<String>,
     // Before removing this, make the recipe processor work without this field
     pub restart_command: String,
+    pub branch: Option<String>, // Overrides the global `update_branch` for this component
+    pub group: Option<String>, // Components sharing a group can have their states, logs and restarts requested together
+    pub health_check: Option<String>, // Ran after the component is restarted by an update, a non-zero exit code fails the update
+    pub health_check_grace_period: Option<u64>, // Seconds to wait after the restart before checking, defaults to 10 if `None`
+    pub restart_retries: Option<u32>, // Restarts re-issued while the health check fails, defaults to 0 if `None`
+    // Development only, the package checksum of this component is not verified. Ignored for NECO itself
+    #[serde(default)]
+    pub skip_verification: bool,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
@@ -42,7 +195,19 @@ pub struct CertificateSettings {
     pub component_name: String,
     pub algorithm: String,
     pub cert_authority: Option<CACertificate>, // If this is `None`, we assume the cert is self-signed
+    // Ordered from the one signed by `cert_authority` to the one that signs the main certificate, needs a `cert_authority`
+    // Their `extensions` are ignored, intermediate CAs always get the CA extensions NECO signs them with
+    #[serde(default)]
+    pub intermediate_cas: Vec<CACertificate>,
     pub main_certificate: MainCertificate,
+    #[serde(default)]
+    pub passphrase_policy: PassphrasePolicy, // Used for generating the key passphrases of the CA and main certificate
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct PassphrasePolicy {
+    pub length: u16,
+    pub charset: String,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
@@ -54,20 +219,28 @@ pub struct CACertificate {
     pub main_paths: CertificatePaths,
     pub auxiliary_paths: Vec<CertificatePaths>,
     pub date_issued: Option<String>, // This is used for transferring the date between threads, renewed every enc_cert init
-    pub passphrase: String,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub passphrase: String, // Kept in the passphrase store, looked up by the component name when loading settings
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
 pub struct MainCertificate {
     pub encrypted: bool,
     pub duration: i64,
-    pub key_len: i64, // If the cert is CA signed, we use this instead of the `algorithm` key in `CertificateSettings`
+    pub key_len: i64, // If the cert is CA signed and the `algorithm` is RSA, we use this instead of its key length
     pub subj: String,
     pub main_paths: CertificatePaths,
     pub auxiliary_paths: Vec<CertificatePaths>,
     pub service_ips: Vec<String>,
     pub date_issued: Option<String>, // This is used for transferring the date between threads, renewed every enc_cert init
-    pub passphrase: String,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub passphrase: String, // Kept in the passphrase store, looked up by the component name when loading settings
+    #[serde(default)]
+    pub chain_path: String, // If empty, no certificate chain file is written
+    #[serde(default)]
+    pub pkcs12_path: String, // If empty, no PKCS#12 bundle is exported
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub pkcs12_passphrase: String, // Kept in the passphrase store, like the key passphrases
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
@@ -120,6 +293,79 @@ impl Default for Settings {
                 // },
             ],
             certificates: vec![],
+            neutron_server: NeutronServer::default(),
+            neutron_mirrors: vec![],
+            remote_management_enabled: false,
+            remote_management_authorized_keys: vec![],
+            update_files: UpdateFiles::default(),
+            updates_paused: false,
+            neco_update_public_key: None,
+            publish_install_logs: false,
+            download_limits: DownloadLimits::default(),
+            http_proxy: None,
+            maintenance_window: None,
+            sandbox: InstallSandbox::default(),
+            max_log_lines: None,
+            max_log_size: None,
+            hash_buffer_size: None,
+        }
+    }
+}
+
+impl Default for DownloadLimits {
+    fn default() -> Self {
+        Self {
+            max_package_size: DEFAULT_MAX_PACKAGE_SIZE,
+            max_total_size: DEFAULT_MAX_DOWNLOAD_SIZE,
+        }
+    }
+}
+
+impl Default for InstallSandbox {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            dir: String::from(DEFAULT_SANDBOX_DIR),
+        }
+    }
+}
+
+impl Default for TlsPolicy {
+    fn default() -> Self {
+        Self {
+            min_version: String::from(DEFAULT_MIN_TLS_VERSION),
+            cipher_suites: String::from(DEFAULT_CIPHER_SUITES),
+        }
+    }
+}
+
+impl Default for NeutronServer {
+    fn default() -> Self {
+        Self {
+            host: String::from(NEUTRON_SERVER_IP),
+            port: NEUTRON_SERVER_PORT,
+            use_tls: NEUTRON_SERVER_USE_TLS,
+        }
+    }
+}
+
+impl Default for UpdateFiles {
+    fn default() -> Self {
+        Self {
+            temp_folder: String::from(TEMP_UPDATE_FOLDER),
+            temp_root: None,
+            leftover_updates_file: String::from(LEFTOVER_UPDATES_FILE),
+            recipe_filename: String::from(RECIPE_FILENAME),
+            extracted_folder_suffix: String::from(EXTRACTED_FOLDER_SUFFIX),
+        }
+    }
+}
+
+impl Default for PassphrasePolicy {
+    fn default() -> Self {
+        Self {
+            length: PASSPHRASE_LENGTH,
+            charset: String::from_utf8_lossy(CHARSET).into_owned(),
         }
     }
 }
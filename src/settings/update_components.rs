@@ -1,15 +1,28 @@
 use std::io::{Error, ErrorKind};
 
-use super::{save_to_file, structs};
+use super::{load_settings, save_to_file, structs};
+use crate::version_control::parse_file_permissions;
+use crate::SETTINGS;
 
 /**
  * Appends the provided component to the update component vector which is then saved to file.
  * Only components with unique names get saved.
+ * Components without a container or a service name are rejected since we can't track their state.
+ * Components whose `file_permissions` is not a valid octal mode are rejected.
  */
 pub fn add_update_component(
     mut settings: structs::Settings,
     component: structs::UpdateComponent,
 ) -> Result<(), Error> {
+    if component.container_name.is_none() && component.service_name.is_none() {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            "An update component needs a container name or a service name.",
+        ));
+    }
+
+    parse_file_permissions(&component.file_permissions)?;
+
     let exists: bool = settings
         .update_components
         .iter()
@@ -54,3 +67,25 @@ pub fn remove_update_component(
 
     save_to_file(settings)
 }
+
+/**
+ * Sets the `updates_paused` flag in the settings file, so the pause survives restarts, then in the settings mutex.
+ * The flag is flipped in the settings loaded from the file, changes made to the file since startup aren't overwritten
+ *     with the ones in the mutex.
+ *
+ * Mutex `SETTINGS` is locked for the duration.
+ */
+pub fn set_updates_paused(paused: bool) -> Result<(), Error> {
+    if let Ok(mut settings) = SETTINGS.lock() {
+        let mut file_settings = load_settings()?;
+        file_settings.updates_paused = paused;
+
+        save_to_file(file_settings)?;
+
+        settings.updates_paused = paused;
+
+        Ok(())
+    } else {
+        Err(Error::other("Could not lock settings mutex."))
+    }
+}
@@ -0,0 +1,151 @@
+use std::io::{Error, ErrorKind, Read};
+use std::time::Duration;
+
+use reqwest::header::{HeaderMap, HeaderValue, USER_AGENT};
+
+use crate::mqtt_connection::get_password;
+use crate::settings::structs::HttpProxy;
+use crate::{APP_NAME, APP_VERSION};
+
+// Identifies the NECO a request came from in the server logs, the credentials are only in the URL
+const DEVICE_ID_HEADER: &str = "X-Device-Id";
+
+/**
+ * Response to a request made through an `HttpClient`.
+ */
+pub struct HttpResponse {
+    pub status: u16,
+    pub content_length: Option<u64>, // `None` if the server didn't send a `Content-Length` header
+    pub body: Box<dyn Read>,
+}
+
+impl HttpResponse {
+    /**
+     * Returns `true` if the status is 2xx.
+     */
+    pub fn is_success(&self) -> bool {
+        (200..300).contains(&self.status)
+    }
+
+    /**
+     * Returns `true` if the status is 5xx or 429, the server may serve the same request once it recovers.
+     */
+    pub fn is_transient_error(&self) -> bool {
+        self.status >= 500 || self.status == 429
+    }
+
+    /**
+     * Reads the whole body as text.
+     */
+    pub fn text(&mut self) -> Result<String, Error> {
+        let mut text = String::new();
+        self.body.read_to_string(&mut text)?;
+
+        Ok(text)
+    }
+}
+
+/**
+ * HTTP access to the Neutron server.
+ * The update flow only talks to the server through this trait, so the server can be swapped for a mock.
+ */
+pub trait HttpClient {
+    fn get(&self, url: &str) -> Result<HttpResponse, Error>;
+    fn post_json(&self, url: &str, body: &serde_json::Value) -> Result<HttpResponse, Error>;
+}
+
+/**
+ * `HttpClient` used at runtime, requests are made with `reqwest`.
+ */
+pub struct ReqwestClient {
+    client: reqwest::Client,
+}
+
+impl ReqwestClient {
+    /**
+     * Builds the client, every request is routed through the `proxy` if it's set.
+     * The proxy credentials are only sent if the proxy `username` is set.
+     * Every request carries the `User-Agent: NeutronCommunicator/<version>` and the `X-Device-Id: <device_id>` headers.
+     */
+    pub fn new(proxy: &Option<HttpProxy>, device_id: &str) -> Result<Self, Error> {
+        Self::build(proxy, device_id, None)
+    }
+
+    /**
+     * Like `new()`, but every request times out after `timeout`, returning an error of kind `TimedOut`.
+     */
+    pub fn with_timeout(proxy: &Option<HttpProxy>, device_id: &str, timeout: Duration) -> Result<Self, Error> {
+        Self::build(proxy, device_id, Some(timeout))
+    }
+
+    fn build(proxy: &Option<HttpProxy>, device_id: &str, timeout: Option<Duration>) -> Result<Self, Error> {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            USER_AGENT,
+            HeaderValue::from_str(&[APP_NAME, "/", APP_VERSION].concat()).map_err(Error::other)?,
+        );
+        headers.insert(
+            DEVICE_ID_HEADER,
+            HeaderValue::from_str(device_id)
+                .map_err(|e| Error::new(ErrorKind::InvalidInput, format!("Invalid device id. {}", e)))?,
+        );
+
+        let mut builder = reqwest::Client::builder().default_headers(headers);
+
+        if let Some(proxy) = proxy {
+            let mut reqwest_proxy = reqwest::Proxy::all(&proxy.url)
+                .map_err(|e| Error::new(ErrorKind::InvalidInput, format!("Invalid proxy URL. {}", e)))?;
+
+            if let Some(username) = &proxy.username {
+                let password = get_password(&proxy.password, &proxy.password_file).map_err(|e| {
+                    Error::new(e.kind(), format!("Could not read the proxy password file. {}", e))
+                })?;
+                reqwest_proxy = reqwest_proxy.basic_auth(username, &password);
+            }
+
+            builder = builder.proxy(reqwest_proxy);
+        }
+
+        if let Some(timeout) = timeout {
+            builder = builder.timeout(timeout);
+        }
+
+        let client = builder.build().map_err(Error::other)?;
+
+        Ok(Self { client })
+    }
+}
+
+impl HttpClient for ReqwestClient {
+    fn get(&self, url: &str) -> Result<HttpResponse, Error> {
+        to_http_response(self.client.get(url).send())
+    }
+
+    fn post_json(&self, url: &str, body: &serde_json::Value) -> Result<HttpResponse, Error> {
+        to_http_response(self.client.post(url).json(body).send())
+    }
+}
+
+/**
+ * Converts the result of a `reqwest` request into an `HttpResponse`.
+ * The `reqwest` error is only logged in debug, the returned error only carries its cause.
+ */
+fn to_http_response(result: Result<reqwest::Response, reqwest::Error>) -> Result<HttpResponse, Error> {
+    match result {
+        Ok(response) => Ok(HttpResponse {
+            status: response.status().as_u16(),
+            content_length: response.content_length(),
+            body: Box::new(response),
+        }),
+        Err(e) => {
+            // Error message is written in debug because it contains sensitive information
+            debug!("{}", e);
+
+            // Keep only the cause, the error itself contains the url with the credentials
+            let cause = std::error::Error::source(&e)
+                .map_or_else(|| String::from("Request failed."), |cause| cause.to_string());
+            let kind = if e.is_timeout() { ErrorKind::TimedOut } else { ErrorKind::Other };
+            Err(Error::new(kind, cause))
+        }
+    }
+}
@@ -0,0 +1,74 @@
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{Error, Read, Write};
+
+use crate::BASE_DIRECTORY;
+
+// This lives outside of the temporary folder, it has to survive the temporary folder purges
+const INSTALLED_UPDATES_FILE: &str = "installed_updates.json";
+
+/**
+ * Loads the ledger of installed update versions.
+ * The ledger maps the component name to the list of versions installed for that component.
+ *
+ * Returns an empty `BTreeMap` if the ledger doesn't exist or cannot be parsed.
+ */
+pub fn load() -> BTreeMap<String, Vec<String>> {
+    let mut contents = String::new();
+
+    match File::open(get_ledger_path()) {
+        Ok(mut file) => {
+            if let Err(e) = file.read_to_string(&mut contents) {
+                warn!("Could not read the installed updates ledger. {}", e);
+                return BTreeMap::new();
+            }
+        }
+        Err(_) => return BTreeMap::new(),
+    }
+
+    match serde_json::from_str(&contents) {
+        Ok(ledger) => ledger,
+        Err(e) => {
+            warn!("Could not parse the installed updates ledger. {}", e);
+            BTreeMap::new()
+        }
+    }
+}
+
+/**
+ * Checks if the `version` of the component is recorded as installed in the `ledger`.
+ */
+pub fn is_installed(ledger: &BTreeMap<String, Vec<String>>, component: &str, version: &str) -> bool {
+    match ledger.get(component) {
+        Some(versions) => versions.iter().any(|x| x == version),
+        None => false,
+    }
+}
+
+/**
+ * Records the `versions` as installed for the component and saves the ledger to disk.
+ *
+ * Returns `Ok(())` if successful.
+ */
+pub fn record(component: &str, versions: &[String]) -> Result<(), Error> {
+    let mut ledger = load();
+
+    let installed = ledger.entry(component.to_owned()).or_default();
+    for version in versions {
+        if !installed.contains(version) {
+            installed.push(version.to_owned());
+        }
+    }
+
+    let mut file = File::create(get_ledger_path())?;
+    file.write_all(serde_json::to_string(&ledger)?.as_bytes())?;
+
+    Ok(())
+}
+
+/**
+ * Concatenates the `BASE_DIRECTORY` and `INSTALLED_UPDATES_FILE`.
+ */
+fn get_ledger_path() -> String {
+    [BASE_DIRECTORY, INSTALLED_UPDATES_FILE].concat()
+}
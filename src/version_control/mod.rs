@@ -1,32 +1,83 @@
 #![allow(clippy::bool_comparison)]
 
-use std::collections::BTreeMap;
-use std::fs::{create_dir, create_dir_all, remove_dir_all, remove_file, File};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs::{create_dir_all, read_dir, remove_dir_all, remove_file, set_permissions, File, Permissions};
 use std::io::{copy, Error, ErrorKind, Read, Write};
+use std::os::unix::fs::{symlink, PermissionsExt};
+use std::path::{Component, Path, PathBuf};
 use std::process::Command;
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+
+use chrono::{Local, NaiveDateTime, NaiveTime};
+
+use flate2::read::GzDecoder;
+use xz2::read::XzDecoder;
+
+use semver::Version;
 
 use serde_json;
 use serde_json::json;
 
 use crate::mqtt::AsyncClient;
 
-use crate::mqtt_connection::component_mqtt::{send_changelogs, send_state};
-use crate::settings::structs::UpdateComponent;
+use crate::mqtt_connection::{get_client_id, get_password};
+
+use http::{HttpClient, HttpResponse, ReqwestClient};
+
+use crate::command_runner::{failure_reason, CommandRunner, SystemCommandRunner};
+
+use crate::mqtt_connection::component_mqtt::{send_changelogs, send_install_log, send_state, send_version_drift};
+use crate::settings::structs::{
+    DownloadLimits, MaintenanceWindow, NeutronServer, Settings, UpdateComponent, UpdateFiles,
+};
 
 use crate::{
-    APP_NAME, APP_VERSION, BASE_DIRECTORY, COMPONENT_VERSIONS,
-    NEUTRON_SERVER_IP, NEUTRON_SERVER_PORT, NEUTRON_SERVER_PROTOCOL,
-    SETTINGS, UPDATE_COMPONENTS, UPDATE_MANIFEST,
+    APP_NAME, APP_VERSION, BASE_DIRECTORY, COMPONENT_MQTT_CONNECTED, COMPONENT_VERSIONS,
+    NEUTRON_MQTT_CONNECTED,
+    SETTINGS, UPDATE_COMPONENTS,
 };
 
+pub mod http;
+mod ledger;
 mod recipe_processor;
+pub mod recovery;
 mod security;
+// Used for validating the update component settings
+pub use security::parse_file_permissions;
+pub use recipe_processor::{read_install_log, validate_recipe};
 pub mod structs;
 
-const TEMP_UPDATE_FOLDER: &str = ".vc-temp/version_control/";
+use structs::ComponentState;
+
+// Defaults of the `update_files` settings
+pub const TEMP_UPDATE_FOLDER: &str = ".vc-temp/version_control/";
+// `test_update()` downloads here, so it never touches the updates the running NECO has staged
+const TEST_UPDATE_TEMP_FOLDER: &str = ".vc-temp/test_update/";
+const TEMP_FOLDER_PROBE_FILE: &str = ".write-check";
 //const ABS_TEMP_UPDATE_FOLDER: &'static str = format!("{}{}", BASE_DIRECTORY, TEMP_UPDATE_FOLDER);
-const LEFTOVER_UPDATES_FILE: &str = "unfinished_updates.json";
-const RECIPE_FILENAME: &str = "recipe.json";
+pub const LEFTOVER_UPDATES_FILE: &str = "unfinished_updates.json";
+pub const RECIPE_FILENAME: &str = "recipe.json";
+pub const EXTRACTED_FOLDER_SUFFIX: &str = "-extracted";
+// File type bits of the unix mode of a zip entry, and their value for a symlink
+const S_IFMT: u32 = 0o170000;
+const S_IFLNK: u32 = 0o120000;
+// Defaults of the `download_limits` settings
+pub const DEFAULT_MAX_PACKAGE_SIZE: u64 = 1024 * 1024 * 1024;
+pub const DEFAULT_MAX_DOWNLOAD_SIZE: u64 = 4 * 1024 * 1024 * 1024;
+pub const DEFAULT_SANDBOX_DIR: &str = "/home/system/.neco_test_dir/";
+const STALE_TEMP_THRESHOLD: Duration = Duration::from_secs(24 * 60 * 60);
+const DEFAULT_LOG_LINES: u32 = 500;
+const DEFAULT_MAX_LOG_LINES: u32 = 5000;
+const DEFAULT_HASH_BUFFER_SIZE: usize = 64 * 1024;
+const DEFAULT_MAX_LOG_SIZE: u32 = 256 * 1024;
+const LOG_TRUNCATED_MARKER: &str = "[... log truncated ...]\n";
+pub const VERSION_DRIFT_CHECK_INTERVAL: Duration = Duration::from_secs(60 * 60);
+pub const MAINTENANCE_WINDOW_CHECK_INTERVAL: Duration = Duration::from_secs(60);
+// Transient manifest request failures are retried after 2, 4 and 8 seconds
+const MANIFEST_RETRIES: u32 = 3;
+const MANIFEST_RETRY_DELAY: Duration = Duration::from_secs(2);
+const UPDATE_REPORT_PATH: &str = "/api/versioncontrol/report";
 
 /**
  * Goes through the components list and opens each version file, the contents of the
@@ -34,13 +85,80 @@ const RECIPE_FILENAME: &str = "recipe.json";
  * `BTreeMap` always contains NECOs version.
  */
 pub fn init_component_versions(components: &[UpdateComponent]) -> BTreeMap<String, String> {
+    info!("Initializing component versions...");
+
+    let versions = read_component_versions(components);
+
+    info!("Loaded versions: {:?}", versions);
+    info!("Component versions loaded.");
+
+    versions
+}
+
+/**
+ * Re-reads the version file of every update component and compares it to the version recorded in `COMPONENT_VERSIONS`.
+ * Drifted versions (manual deploys, failed updates) are logged and `COMPONENT_VERSIONS` is replaced with the
+ *     versions from the version files, so the next update manifest request is made for the deployed versions.
+ *
+ * Returns the components whose version drifted.
+ *
+ * Mutexes `UPDATE_COMPONENTS`, `COMPONENT_VERSIONS` are locked momentarily.
+ */
+pub fn reconcile_component_versions() -> Vec<structs::VersionDrift> {
+    let components = if let Ok(components) = UPDATE_COMPONENTS.lock() {
+        components.clone()
+    } else {
+        error!("Could not lock UPDATE_COMPONENTS mutex.");
+        return Vec::new();
+    };
+
+    let actual_versions = read_component_versions(&components);
+
+    let mut versions = if let Ok(versions) = COMPONENT_VERSIONS.lock() {
+        versions
+    } else {
+        error!("Could not lock COMPONENT_VERSIONS mutex.");
+        return Vec::new();
+    };
+
+    let mut names: Vec<&String> = versions.keys().chain(actual_versions.keys()).collect();
+    names.sort();
+    names.dedup();
+
+    let drifts: Vec<structs::VersionDrift> = names
+        .into_iter()
+        .filter(|name| versions.get(*name) != actual_versions.get(*name))
+        .map(|name| structs::VersionDrift {
+            component: name.to_owned(),
+            recorded: versions.get(name).cloned(),
+            actual: actual_versions.get(name).cloned(),
+        })
+        .collect();
+
+    for drift in &drifts {
+        warn!(
+            "Version of component {} drifted. Recorded: {}, version file: {}.",
+            drift.component,
+            drift.recorded.as_deref().unwrap_or("unknown"),
+            drift.actual.as_deref().unwrap_or("unknown")
+        );
+    }
+
+    *versions = actual_versions;
+
+    drifts
+}
+
+/**
+ * Reads the version file of every component in `components`, components whose version file can't be read are left out.
+ * The returned `BTreeMap` always contains NECOs version.
+ */
+fn read_component_versions(components: &[UpdateComponent]) -> BTreeMap<String, String> {
     let mut versions: BTreeMap<String, String> = BTreeMap::new();
 
     // The updater is always present in the versions BTreeMap
     versions.insert(APP_NAME.to_owned(), APP_VERSION.to_owned());
 
-    info!("Initializing component versions...");
-
     for component in components {
         // This will prevent trying to fetch version file for NECO
         // This is needed because we're inserting permission data into the 'UpdateComponent' vector
@@ -74,152 +192,319 @@ pub fn init_component_versions(components: &[UpdateComponent]) -> BTreeMap<Strin
         }
     }
 
-    info!("Loaded versions: {:?}", versions);
-    info!("Component versions loaded.");
-
     versions
 }
 
 /**
- * Requests the update manifest from `Neutron Update Server` for the configured components.
- * When update manifest is received it is then parsed. If we succeed at parsing, the parsed
- *     update manifest is set by locking a mutex.
+ * Returns the `max_log_lines` setting, `DEFAULT_MAX_LOG_LINES` if it isn't set.
+ * If the settings mutex can't be locked, `DEFAULT_LOG_LINES` is returned.
+ */
+fn max_log_lines() -> u32 {
+    if let Ok(settings) = SETTINGS.lock() {
+        settings.max_log_lines.unwrap_or(DEFAULT_MAX_LOG_LINES)
+    } else {
+        error!("Could not lock SETTINGS mutex.");
+        DEFAULT_LOG_LINES
+    }
+}
+
+/**
+ * Returns the `max_log_size` setting in bytes, `DEFAULT_MAX_LOG_SIZE` if it isn't set or is 0.
+ * If the settings mutex can't be locked, `DEFAULT_MAX_LOG_SIZE` is returned.
+ */
+fn max_log_size() -> usize {
+    if let Ok(settings) = SETTINGS.lock() {
+        match settings.max_log_size {
+            Some(size) if size > 0 => size as usize,
+            _ => DEFAULT_MAX_LOG_SIZE as usize,
+        }
+    } else {
+        error!("Could not lock SETTINGS mutex.");
+        DEFAULT_MAX_LOG_SIZE as usize
+    }
+}
+
+/**
+ * Returns the `updates_paused` settings flag.
+ * If the settings mutex can't be locked, updates are treated as paused.
+ */
+pub fn updates_paused() -> bool {
+    if let Ok(settings) = SETTINGS.lock() {
+        settings.updates_paused
+    } else {
+        error!("Could not lock SETTINGS mutex.");
+        true
+    }
+}
+
+/**
+ * Requests the update manifest from `Neutron Update Server` for the configured components (see `fetch_update_manifest()`).
+ * The caller decides if the manifest is kept for `update_download_and_install()`.
  *
  * NOTICE: Sends the changelogs through the component backhaul if there were update found.
- * NOTICE: Sends state updates through the component backhaul.
+ * NOTICE: Sends state updates through the component backhaul, they carry the `request` id of the triggering command.
  *
- * Mutexes `SETTINGS`, `COMPONENT_VERSIONS`, `UPDATE_MANIFEST` are locked momentarily.
- */
-pub fn request_update_manifest(
-    mqtt_client: &AsyncClient, /*neutron_acc_user: &str,
-                               mosquitto_client_user: &str,
-                               mosquitto_client_pass: &str,
-                               app_name: &str,
-                               update_branch: &str,*/
-) /*-> Option<structs::UpdateManifest>*/
-{
+ * Returns `None` if updates are paused, there are no updates or the request failed.
+ *
+ * Mutexes `SETTINGS`, `COMPONENT_VERSIONS` are locked momentarily.
+ */
+pub fn request_update_manifest(mqtt_client: &AsyncClient, request: &Option<String>) -> Option<structs::UpdateManifest> {
+    if updates_paused() {
+        warn!("Updates are paused. Skipping the update manifest request.");
+        send_state(mqtt_client, "Updates are paused.", request);
+        return None;
+    }
+
+    // The manifest is requested for the versions that are actually deployed
+    send_version_drift(mqtt_client, &None, false);
+
     debug!("Requesting update manifest...");
 
-    send_state(mqtt_client, "Looking for updates...");
+    send_state(mqtt_client, "Looking for updates...", request);
 
-    // Get variables from Settings struct
-    let neutron_acc_user;
-    let mosquitto_client_user;
-    let mosquitto_client_pass;
-    let app_name;
-    let update_branch;
-    if let Ok(settings) = SETTINGS.lock() {
-        neutron_acc_user = settings.neutron_account_username.to_owned();
-        mosquitto_client_user = settings.neutron_mqtt_client.username.to_owned();
-        mosquitto_client_pass = settings.neutron_mqtt_client.password.to_owned();
-        app_name = settings.application_name.to_owned();
-        update_branch = settings.update_branch.to_owned();
+    let settings = if let Ok(settings) = SETTINGS.lock() {
+        settings.clone()
     } else {
         error!("Could not lock SETTINGS mutex.");
-        return;
-    }
+        return None;
+    };
 
-    // Get component names from Vec<Settings::UpdateComponent> Settings struct
-    // Get component versions from Vec<Settings::UpdateComponent> Settings struct
-    let components: Vec<String>;
-    let versions: Vec<String>;
-    if let Ok(comp_ver) = COMPONENT_VERSIONS.lock() {
-        components = comp_ver.keys().cloned().collect();
-        versions = comp_ver.values().cloned().collect();
+    let component_versions = if let Ok(comp_ver) = COMPONENT_VERSIONS.lock() {
+        comp_ver.clone()
     } else {
         error!("Could not acquire COMPONENT_VERSIONS mutex.");
-        return;
+        return None;
+    };
+
+    let update_manifest = match fetch_update_manifest(&settings, &component_versions) {
+        Ok(Some(update_manifest)) => update_manifest,
+        Ok(None) => {
+            send_state(mqtt_client, "No updates were found.", request);
+            return None;
+        }
+        Err(e) => {
+            error!("{}", e);
+            send_state(mqtt_client, &e.to_string(), request);
+            return None;
+        }
+    };
+
+    send_state(mqtt_client, "Found updates.", request);
+
+    // Prepare the changelogs and send them
+    match build_changelogs(&update_manifest) {
+        Ok(changelogs) => send_changelogs(mqtt_client, &changelogs),
+        Err(e) => error!("Could not convert the changelogs to JSON. {}", e),
     }
 
-    if components.is_empty() || versions.is_empty() {
-        warn!("Could not request update manifest with no components/versions loaded.");
+    Some(update_manifest)
+}
 
-        if let Ok(mut manifest) = UPDATE_MANIFEST.lock() {
-            *manifest = None;
-            return;
-        }
+/**
+ * Requests the update manifest for the components in `component_versions` from the Neutron server (or its mirrors),
+ *     using the credentials, branches and endpoints in `settings`.
+ * The updates of every component are sorted from oldest to newest (see `sort_updates()`).
+ * Nothing is stored, the caller decides what to do with the manifest.
+ * Transient failures (see `request_manifest()`) are retried `MANIFEST_RETRIES` times with an exponential backoff,
+ *     "no updates" and the other failures are returned right away.
+ *
+ * Returns `Ok(None)` if there are no updates, `Err` with a message fit for the `External Interface` if the request failed.
+ */
+pub fn fetch_update_manifest(
+    settings: &Settings,
+    component_versions: &BTreeMap<String, String>,
+) -> Result<Option<structs::UpdateManifest>, Error> {
+    let mosquitto_client_pass = get_password(
+        &settings.neutron_mqtt_client.password,
+        &settings.neutron_mqtt_client.password_file,
+    )
+    .map_err(|e| {
+        Error::new(
+            e.kind(),
+            format!("Could not read the neutron mqtt password file. {}", e),
+        )
+    })?;
+
+    // Get component names and versions
+    let components: Vec<String> = component_versions.keys().cloned().collect();
+    let versions: Vec<String> = component_versions.values().cloned().collect();
+
+    if components.is_empty() || versions.is_empty() {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            "Could not request update manifest with no components/versions loaded.",
+        ));
     }
 
-    let url = format!(
-        "{protocol}{host}{port}/api/versioncontrol?neutronuser={neutron_username}&username={mqtt_username}&password={mqtt_password}&application={app}&branch={branch}&components={component_list}&versions={version_list}",
-        protocol = NEUTRON_SERVER_PROTOCOL,
-        host = NEUTRON_SERVER_IP,
-        port = NEUTRON_SERVER_PORT,
-        neutron_username = neutron_acc_user,
-        mqtt_username = mosquitto_client_user,
-        mqtt_password = mosquitto_client_pass,
-        app = app_name,
-        branch = update_branch,
+    // Branch of every component, in the same order as the component list
+    let branches: Vec<String> = components
+        .iter()
+        .map(|name| get_component_branch(&settings.update_components, name, &settings.update_branch))
+        .collect();
+
+    let url_path = format!(
+        "/api/versioncontrol?{auth}&branch={branch}&components={component_list}&versions={version_list}&branches={branch_list}",
+        auth = neutron_auth_query(
+            &settings.neutron_account_username,
+            &settings.neutron_mqtt_client.username,
+            &mosquitto_client_pass,
+            &settings.application_name
+        ),
+        branch = settings.update_branch,
         component_list = components.join(","),
-        version_list = versions.join(",")
+        version_list = versions.join(","),
+        branch_list = branches.join(",")
     );
 
-    match reqwest::get(&url) {
-        Ok(mut req) => {
-            if let Ok(txt) = req.text() {
-                let response: serde_json::Value = serde_json::from_str(&txt).unwrap_or_default();
-
-                if response["result"] == true {
-                    if response["msg"]["manifest"] != json!({})
-                        && response["msg"]["manifest"] != serde_json::Value::Null
-                    {
-                        // Acquire the mutex lock, set the update manifest and exit the function
-                        if let Ok(mut manifest) = UPDATE_MANIFEST.lock() {
-                            *manifest =
-                                serde_json::from_value(response["msg"]["manifest"].to_owned()).ok();
-
-                            send_state(mqtt_client, "Found updates.");
-
-                            // Prepare the changelogs and send them
-                            let upds: Vec<structs::Update> = manifest
-                                .clone()
-                                .unwrap()
-                                .list
-                                .values()
-                                .cloned()
-                                .flatten()
-                                //.flat_map(|updates| updates)
-                                .collect();
-                            let changelogs: String = upds
-                                .iter()
-                                .map(|update| {
-                                    [update.changelog.to_owned(), "\r\n\r\n".to_owned()].concat()
-                                })
-                                .rev()
-                                .collect();
-
-                            send_changelogs(mqtt_client, &changelogs);
-
-                            return;
-                        } else {
-                            error!("Couldn't lock and set UPDATE_MANIFEST mutex.");
-                        }
+    let endpoints = get_neutron_endpoints(&settings.neutron_server, &settings.neutron_mirrors);
 
-                    //return serde_json::from_value(response["msg"]["manifest"].to_owned()).ok();
-                    } else {
-                        send_state(mqtt_client, "No updates were found.");
-                    }
-                } else if response["msg"] == serde_json::Value::Null {
-                    error!("Update manifest response empty.");
+    let http_client = ReqwestClient::new(
+        &settings.http_proxy,
+        get_client_id(&settings.component_mqtt_client.username, &settings.component_mqtt_client.client_id),
+    )?;
 
-                    send_state(mqtt_client, "Update manifest response empty.");
-                } else {
-                    error!("Server -> {}", response["msg"].as_str().unwrap_or_default());
-                }
+    let mut attempt = 0;
+    loop {
+        match request_manifest(&http_client, &endpoints, &url_path) {
+            Err(e) if e.kind() == ErrorKind::Interrupted && attempt < MANIFEST_RETRIES => {
+                let delay = MANIFEST_RETRY_DELAY * 2u32.pow(attempt);
+                attempt += 1;
+
+                warn!(
+                    "{} Retrying in {} seconds ({}/{})...",
+                    e,
+                    delay.as_secs(),
+                    attempt,
+                    MANIFEST_RETRIES
+                );
+                std::thread::sleep(delay);
             }
+            result => return result,
         }
-        Err(e) => {
-            send_state(mqtt_client, "Could not reach Neutron server.");
-            warn!("Could not reach Neutron server.");
-            debug!("{}", e);
+    }
+}
+
+/**
+ * Requests the update manifest on `url_path` from the `endpoints` (see `fetch_from_endpoints()`) and parses it.
+ *
+ * Returns `Ok(None)` if there are no updates.
+ * Returns an `Err` of kind `Interrupted` if the failure is transient and the request can be retried:
+ *     the server responded with 5xx/429, with an empty response or with `"retry": true` (e.g. while it's warming up).
+ * Returns an `Err` of kind `InvalidData` if the response isn't JSON, the raw response is logged at the debug level.
+ */
+fn request_manifest(
+    http_client: &dyn HttpClient,
+    endpoints: &[String],
+    url_path: &str,
+) -> Result<Option<structs::UpdateManifest>, Error> {
+    let (endpoint, mut req) = fetch_from_endpoints(http_client, endpoints, url_path).map_err(|e| {
+        debug!("{}", e);
+        if e.kind() == ErrorKind::Interrupted {
+            Error::new(e.kind(), "Neutron server is not ready.")
+        } else {
+            Error::new(e.kind(), "Could not reach Neutron server.")
         }
+    })?;
+    info!("Update manifest served by '{}'.", endpoint);
+
+    let body = req.text()?;
+    if body.trim().is_empty() {
+        return Err(Error::new(ErrorKind::Interrupted, "Update manifest response empty."));
+    }
+
+    let response: serde_json::Value = serde_json::from_str(&body).map_err(|e| {
+        debug!("Unparseable update manifest response: {}", body);
+        Error::new(
+            ErrorKind::InvalidData,
+            format!("Neutron server returned an unparseable response. {}", e),
+        )
+    })?;
+
+    if response["result"] == true {
+        if response["msg"]["manifest"] == json!({}) || response["msg"]["manifest"] == serde_json::Value::Null {
+            return Ok(None);
+        }
+
+        let mut update_manifest: structs::UpdateManifest =
+            serde_json::from_value(response["msg"]["manifest"].to_owned())
+                .map_err(|e| Error::new(ErrorKind::InvalidData, format!("Could not parse the update manifest. {}", e)))?;
+
+        // Everything after this relies on the updates being sorted from oldest to newest
+        sort_updates(&mut update_manifest);
+
+        Ok(Some(update_manifest))
+    } else if response["retry"] == true {
+        Err(Error::new(
+            ErrorKind::Interrupted,
+            format!("Server -> {}", response["msg"].as_str().unwrap_or("Try again later.")),
+        ))
+    } else if response["msg"] == serde_json::Value::Null {
+        Err(Error::new(ErrorKind::Interrupted, "Update manifest response empty."))
+    } else {
+        Err(Error::other(format!("Server -> {}", response["msg"].as_str().unwrap_or_default())))
     }
+}
+
+/**
+ * Sorts the updates of every component in `update_manifest` by their semantic version, from oldest to newest.
+ * Components with an update version that isn't a valid semantic version are removed from the manifest,
+ *     we can't know in which order their updates have to be applied.
+ */
+fn sort_updates(update_manifest: &mut structs::UpdateManifest) {
+    update_manifest.list.retain(|component, updates| {
+        let mut versioned_updates: Vec<(Version, structs::Update)> = Vec::new();
+
+        for update in updates.drain(..) {
+            match Version::parse(&update.version) {
+                Ok(version) => versioned_updates.push((version, update)),
+                Err(e) => {
+                    error!(
+                        "Invalid update version, skipping the component updates. Component: {}, Version: {}. {}",
+                        component, &update.version, e
+                    );
+                    return false;
+                }
+            }
+        }
 
-    if let Ok(mut manifest) = UPDATE_MANIFEST.lock() {
-        *manifest = None;
+        versioned_updates.sort_by(|a, b| a.0.cmp(&b.0));
+        updates.extend(versioned_updates.into_iter().map(|(_, update)| update));
+
+        true
+    });
+}
+
+/**
+ * Groups the changelogs of the updates in `update_manifest` by component, in the order they're listed in the manifest.
+ * The legacy `text` form contains all changelogs, newest first, separated by empty lines.
+ *
+ * Returns the changelogs as a JSON-formatted `String`.
+ */
+fn build_changelogs(update_manifest: &structs::UpdateManifest) -> Result<String, serde_json::Error> {
+    let mut changelogs = structs::Changelogs::default();
+
+    for (component, updates) in &update_manifest.list {
+        changelogs.components.insert(
+            component.to_owned(),
+            updates
+                .iter()
+                .map(|update| structs::Changelog {
+                    version: update.version.to_owned(),
+                    changelog: update.changelog.to_owned(),
+                })
+                .collect(),
+        );
     }
 
-    //None
+    changelogs.text = update_manifest
+        .list
+        .values()
+        .flatten()
+        .map(|update| [update.changelog.to_owned(), "\r\n\r\n".to_owned()].concat())
+        .rev()
+        .collect();
+
+    serde_json::to_string(&changelogs)
 }
 
 /**
@@ -227,29 +512,30 @@ pub fn request_update_manifest(
  *     checks if there are any NECO updates, if there are, install them
  *     (call to `get_recipes()` and `recipe_processor::cook()`) first and add
  *     others to the leftover update file.
+ * Outside of the maintenance window, only the NECO update is installed, the others are staged (see `stage_updates()`).
+ * The outcome of every component that failed to download or was installed is reported back to the Neutron server
+ *     (see `report_update_results()`). Staged updates and the updates left over for after the NECO upgrade are not reported.
  *
- * NOTICE: Sends state updates through the component backhaul.
- * NOTICE: The `update manifest` has to be correctly version sorted for this function to do its job correctly.
- * NOTICE: At the end of the function, we set the `UPDATE_MANIFEST` to `None` to prevent installation of already-installed updates.
+ * NOTICE: Sends state updates through the component backhaul, they carry the `request` id of the triggering command.
+ * NOTICE: The `update manifest` has to be correctly version sorted for this function to do its job correctly (see `sort_updates()`).
  *
- * Mutexes `UPDATE_MANIFEST`, `SETTINGS`, `UPDATE_COMPONENTS` are locked momentarily.
+ * Returns `true` once the updates in `update_manifest` were installed (successfully or not),
+ *     the caller should discard the manifest to prevent installation of already-installed updates.
+ *
+ * Mutexes `SETTINGS`, `UPDATE_COMPONENTS` are locked momentarily.
  */
-pub fn update_download_and_install(mqtt_client: &AsyncClient) {
+pub fn update_download_and_install(
+    mqtt_client: &AsyncClient,
+    update_manifest: structs::UpdateManifest,
+    request: &Option<String>,
+) -> bool {
     // info!("Starting update download & install.");
     // info!("UM: {:?}", &update_manifest.list);
 
-    // Get update manifest
-    let update_manifest: structs::UpdateManifest;
-    if let Ok(manifest_option) = UPDATE_MANIFEST.lock() {
-        if let Some(manifest) = manifest_option.clone() {
-            update_manifest = manifest;
-        } else {
-            warn!("Cannot download and install - update manifest is empty.");
-            return;
-        }
-    } else {
-        error!("Could not lock UPDATE_MANIFEST mutex.");
-        return;
+    if updates_paused() {
+        warn!("Updates are paused. Not installing any updates.");
+        send_state(mqtt_client, "Updates are paused. Not installing any updates.", request);
+        return false;
     }
 
     // Set variables from the Settings struct
@@ -258,15 +544,45 @@ pub fn update_download_and_install(mqtt_client: &AsyncClient) {
     let mosquitto_client_pass;
     let app_name;
     let update_branch;
+    let endpoints;
+    let update_files;
+    let neco_public_key;
+    let publish_install_logs;
+    let download_limits;
+    let http_proxy;
+    let device_id;
+    let maintenance_window;
+    let sandbox_root;
+    let hash_buffer_size;
     if let Ok(settings) = SETTINGS.lock() {
         neutron_acc_user = settings.neutron_account_username.to_owned();
         mosquitto_client_user = settings.neutron_mqtt_client.username.to_owned();
-        mosquitto_client_pass = settings.neutron_mqtt_client.password.to_owned();
+        mosquitto_client_pass = match get_password(
+            &settings.neutron_mqtt_client.password,
+            &settings.neutron_mqtt_client.password_file,
+        ) {
+            Ok(pass) => pass,
+            Err(e) => {
+                error!("Could not read the neutron mqtt password file. {}", e);
+                return false;
+            }
+        };
         app_name = settings.application_name.to_owned();
         update_branch = settings.update_branch.to_owned();
+        endpoints = get_neutron_endpoints(&settings.neutron_server, &settings.neutron_mirrors);
+        update_files = settings.update_files.to_owned();
+        neco_public_key = settings.neco_update_public_key.to_owned();
+        publish_install_logs = settings.publish_install_logs;
+        download_limits = settings.download_limits.to_owned();
+        http_proxy = settings.http_proxy.to_owned();
+        device_id = get_client_id(&settings.component_mqtt_client.username, &settings.component_mqtt_client.client_id)
+            .to_owned();
+        maintenance_window = settings.maintenance_window.to_owned();
+        sandbox_root = get_sandbox_root(&settings);
+        hash_buffer_size = get_hash_buffer_size(&settings);
     } else {
         error!("Could not lock SETTINGS mutex.");
-        return;
+        return false;
     }
 
     // Get permission presets from Settings::UpdateComponents struct
@@ -275,36 +591,100 @@ pub fn update_download_and_install(mqtt_client: &AsyncClient) {
         permission_presets = permissions.clone();
     } else {
         error!("Could not lock UPDATE_COMPONENTS mutex.");
-        return;
+        return false;
     }
 
+    let http_client = match ReqwestClient::new(&http_proxy, &device_id) {
+        Ok(client) => client,
+        Err(e) => {
+            error!("Could not create the HTTP client. {}", e);
+            send_state(mqtt_client, "Could not create the HTTP client. Check the proxy settings.", request);
+            return false;
+        }
+    };
+
+    // Branch every component is downloaded from
+    let component_branches: BTreeMap<String, String> = update_manifest
+        .list
+        .keys()
+        .map(|name| {
+            (
+                name.to_owned(),
+                get_component_branch(&permission_presets, name, &update_branch),
+            )
+        })
+        .collect();
+
+    // Version every component is updated to, reported to the Neutron server with the outcome
+    let target_versions: BTreeMap<String, String> = update_manifest
+        .list
+        .iter()
+        .filter_map(|(name, updates)| updates.last().map(|update| (name.to_owned(), update.version.to_owned())))
+        .collect();
+
+    let auth_query = neutron_auth_query(
+        &neutron_acc_user,
+        &mosquitto_client_user,
+        &mosquitto_client_pass,
+        &app_name,
+    );
+
     // Start downloading and verifying
 
-    send_state(mqtt_client, "Starting update download & install.");
+    send_state(mqtt_client, "Starting update download & install.", request);
+
+    let neco_upgrade_requested = update_manifest.list.contains_key(APP_NAME);
 
     // Contains path to the update archive and a server-side calculated checksum for the archive
     let verified_updates: BTreeMap<String, Vec<String>> = dload_and_verify_updates(
+        Some(mqtt_client),
+        &http_client,
         update_manifest,
-        &neutron_acc_user,
-        &mosquitto_client_user,
-        &mosquitto_client_pass,
-        &app_name,
-        &update_branch,
+        &auth_query,
+        &component_branches,
+        &get_unverified_components(&permission_presets),
+        &endpoints,
+        &update_files,
+        neco_public_key.as_deref(),
+        &download_limits,
+        hash_buffer_size,
+        request,
     );
 
     // info!("VERIFIED: {:?}", &verified_updates);
 
+    let mut update_results: Vec<structs::UpdateResult> = target_versions
+        .iter()
+        .filter(|(name, _)| !verified_updates.contains_key(*name))
+        .map(|(name, version)| structs::UpdateResult {
+            component: name.to_owned(),
+            version: version.to_owned(),
+            success: false,
+            error: Some(String::from("The update could not be downloaded and verified.")),
+        })
+        .collect();
+
+    if neco_upgrade_requested && !verified_updates.contains_key(APP_NAME) {
+        error!("NECO update package could not be verified. NECO will not be upgraded.");
+        send_state(
+            mqtt_client,
+            "The updater package could not be verified (checksum/signature). The updater will not be upgraded.",
+            request,
+        );
+    }
+
     // If downloading updates fail, just return, we don't need to waste cpu cycles on an empty list
     if verified_updates.is_empty() {
-        return;
+        report_update_results(&http_client, &endpoints, &auth_query, &update_results);
+        return false;
     }
 
-    send_state(mqtt_client, "Updates downloaded and verified. Unpacking...");
+    send_state(mqtt_client, "Updates downloaded and verified. Unpacking...", request);
 
     info!("Unpacking updates...");
 
     // Returns component name with a vector of file paths that have been extracted
-    let mut inflated_updates: BTreeMap<String, Vec<String>> = unpack_updates(verified_updates);
+    let mut inflated_updates: BTreeMap<String, Vec<String>> = unpack_updates(verified_updates, &update_files);
     // info!("INFLATED: {:?}", inflated_updates);
 
     // NOTICE: THIS WILL SKIP UPDATING NECO IF WE'RE DEBUGGING
@@ -312,8 +692,28 @@ pub fn update_download_and_install(mqtt_client: &AsyncClient) {
     //     inflated_updates.remove(APP_NAME);
     // }
 
+    // Upgrading NECO doesn't interrupt the components, it's not held back by the maintenance window
+    if !in_maintenance_window(&maintenance_window) {
+        let neco_update = inflated_updates.remove(APP_NAME);
+
+        if !inflated_updates.is_empty() {
+            stage_updates(mqtt_client, &inflated_updates, &update_files, request);
+            inflated_updates.clear();
+        }
+
+        match neco_update {
+            Some(paths) => {
+                inflated_updates.insert(APP_NAME.to_owned(), paths);
+            }
+            None => {
+                report_update_results(&http_client, &endpoints, &auth_query, &update_results);
+                return true;
+            }
+        }
+    }
+
     let cookbook: Vec<serde_json::Value> = if inflated_updates.contains_key(APP_NAME) {
-        send_state(mqtt_client, "Upgrading updater...");
+        send_state(mqtt_client, "Upgrading updater...", request);
         info!("Starting NECO upgrade...");
 
         let mut neco_updates: BTreeMap<String, Vec<String>> = BTreeMap::new();
@@ -324,65 +724,550 @@ pub fn update_download_and_install(mqtt_client: &AsyncClient) {
         inflated_updates.remove(APP_NAME);
 
         if !inflated_updates.is_empty() {
-            if save_leftover_updates(&inflated_updates).is_err() {
+            if add_leftover_updates(&inflated_updates, &update_files).is_err() {
                 error!("Failed to save unfinished update list.");
                 warn!("Automatic resuming will not happen, start the update search manually after NECO upgrade.");
-                send_state(mqtt_client, "Failed to save the unfinished update list. Start the update search manually after the updater upgrade.");
+                send_state(mqtt_client, "Failed to save the unfinished update list. Start the update search manually after the updater upgrade.", request);
             } else {
                 info!("Other updates will be installed after upgrading NECO.");
                 send_state(
                     mqtt_client,
                     "Other updates will be installed after updater is upgraded.",
+                    request,
                 );
             }
         }
 
-        get_recipes(neco_updates, &permission_presets)
+        get_recipes(neco_updates, &permission_presets, &update_files)
     } else {
         info!("Fetching recipes...");
 
-        get_recipes(inflated_updates, &permission_presets)
+        get_recipes(inflated_updates, &permission_presets, &update_files)
     };
 
     // info!("Cookbook: {:#}", serde_json::to_string(&cookbook).unwrap());
 
-    info!("Updating component(s)...");
-    send_state(mqtt_client, "Updating component(s)...");
+    info!("Updating component(s)...");
+    send_state(mqtt_client, "Updating component(s)...", request);
+
+    // Start cooking
+    let failed_components = recipe_processor::cook(&SystemCommandRunner, &cookbook, sandbox_root.as_deref());
+
+    if publish_install_logs {
+        for component in &cookbook {
+            if let Some(component_name) = component["component"].as_str() {
+                send_install_log(mqtt_client, component_name);
+            }
+        }
+    }
+
+    for component in &cookbook {
+        if let Some(component_name) = component["component"].as_str() {
+            let success = !failed_components.iter().any(|name| name == component_name);
+            update_results.push(structs::UpdateResult {
+                component: component_name.to_owned(),
+                version: target_versions.get(component_name).cloned().unwrap_or_default(),
+                success,
+                error: if success { None } else { Some(String::from("The update could not be installed.")) },
+            });
+        }
+    }
+    report_update_results(&http_client, &endpoints, &auth_query, &update_results);
+
+    if failed_components.is_empty() {
+        info!("Update download & install complete.");
+        send_state(mqtt_client, "Update download & install complete.", request);
+    } else if failed_components.iter().any(|name| name == APP_NAME) {
+        send_state(
+            mqtt_client,
+            "The updater upgrade failed the sanity check and was aborted, the updater was not restarted. Please contact the support team.",
+            request,
+        );
+    } else {
+        send_state(
+            mqtt_client,
+            "Some components failed to install. Please contact the support team.",
+            request,
+        );
+    }
+
+    //info!("Cleaning up vc-temp folder.");
+    //remove_dir_all(TMP_ROOT).is_ok();
+
+    true
+}
+
+/**
+ * Downloads, verifies and unpacks the updates available for the `components` (every tracked component if empty)
+ *     and cooks them into the `sandbox_root` folder instead of the live system (see `recipe_processor::cook_in_sandbox()`).
+ * The updates are downloaded to their own temporary folder, which is removed afterwards.
+ *
+ * Returns `Ok(None)` if there are no updates, otherwise the names of the components that failed
+ *     and what was copied where.
+ */
+pub fn test_update(
+    settings: &Settings,
+    components: &[String],
+    sandbox_root: &str,
+) -> Result<Option<structs::SandboxReport>, Error> {
+    let mut component_versions = init_component_versions(&settings.update_components);
+
+    if let Some(component) = components.iter().find(|x| !component_versions.contains_key(*x)) {
+        return Err(Error::new(
+            ErrorKind::NotFound,
+            format!("Component '{}' is not tracked.", component),
+        ));
+    }
+    if !components.is_empty() {
+        component_versions.retain(|name, _| components.contains(name));
+    }
+
+    let update_manifest = match fetch_update_manifest(settings, &component_versions)? {
+        Some(update_manifest) => update_manifest,
+        None => return Ok(None),
+    };
+
+    let mosquitto_client_pass = get_password(
+        &settings.neutron_mqtt_client.password,
+        &settings.neutron_mqtt_client.password_file,
+    )?;
+    let http_client = ReqwestClient::new(
+        &settings.http_proxy,
+        get_client_id(&settings.component_mqtt_client.username, &settings.component_mqtt_client.client_id),
+    )?;
+
+    let component_branches: BTreeMap<String, String> = update_manifest
+        .list
+        .keys()
+        .map(|name| {
+            (
+                name.to_owned(),
+                get_component_branch(&settings.update_components, name, &settings.update_branch),
+            )
+        })
+        .collect();
+
+    let mut update_files = settings.update_files.to_owned();
+    update_files.temp_folder = String::from(TEST_UPDATE_TEMP_FOLDER);
+
+    let verified_updates = dload_and_verify_updates(
+        None,
+        &http_client,
+        update_manifest,
+        &neutron_auth_query(
+            &settings.neutron_account_username,
+            &settings.neutron_mqtt_client.username,
+            &mosquitto_client_pass,
+            &settings.application_name,
+        ),
+        &component_branches,
+        &get_unverified_components(&settings.update_components),
+        &get_neutron_endpoints(&settings.neutron_server, &settings.neutron_mirrors),
+        &update_files,
+        settings.neco_update_public_key.as_deref(),
+        &settings.download_limits,
+        get_hash_buffer_size(settings),
+        &None,
+    );
+
+    if verified_updates.is_empty() {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            "None of the updates could be downloaded and verified.",
+        ));
+    }
+
+    let inflated_updates = unpack_updates(verified_updates, &update_files);
+    let cookbook = get_recipes(inflated_updates, &settings.update_components, &update_files);
+
+    info!("Cooking the updates into the sandbox '{}'...", sandbox_root);
+    let report = recipe_processor::cook_in_sandbox(&SystemCommandRunner, &cookbook, sandbox_root);
+
+    if let Err(e) = remove_temp_dir(&update_files, &get_temp_folder_path(&update_files)) {
+        warn!("Could not remove the temporary test update folder. {}", e);
+    }
+
+    Ok(Some(report))
+}
+
+/**
+ * Returns `true` if the local time is inside the maintenance `window`, or if there is no window.
+ * An invalid window is logged and treated as closed, so the staged updates are kept rather than installed at any time.
+ */
+pub fn in_maintenance_window(window: &Option<MaintenanceWindow>) -> bool {
+    let window = match window {
+        Some(window) => window,
+        None => return true,
+    };
+
+    let (start, end) = match (
+        NaiveTime::parse_from_str(&window.start, "%H:%M"),
+        NaiveTime::parse_from_str(&window.end, "%H:%M"),
+    ) {
+        (Ok(start), Ok(end)) => (start, end),
+        _ => {
+            error!(
+                "Invalid maintenance window '{}' - '{}', expected 'HH:MM'. Staged updates will not be installed.",
+                window.start, window.end
+            );
+            return false;
+        }
+    };
+
+    let now = Local::now().time();
+
+    if start <= end {
+        now >= start && now < end
+    } else {
+        now >= start || now < end
+    }
+}
+
+/**
+ * Adds the unpacked `updates` to the leftover update manifest, they're installed once the maintenance window opens.
+ * An update staged earlier for the same component is replaced by the new one.
+ *
+ * NOTICE: Sends state updates through the component backhaul, they carry the `request` id of the triggering command.
+ */
+fn stage_updates(
+    mqtt_client: &AsyncClient,
+    updates: &BTreeMap<String, Vec<String>>,
+    update_files: &UpdateFiles,
+    request: &Option<String>,
+) {
+    let staged_updates = match add_leftover_updates(updates, update_files) {
+        Ok(staged_updates) => staged_updates,
+        Err(e) => {
+            error!("Failed to save the staged update list. {}", e);
+            send_state(
+                mqtt_client,
+                "Failed to stage the updates. Start the update search again during the maintenance window.",
+                request,
+            );
+            return;
+        }
+    };
+
+    info!(
+        "Updates staged, awaiting the maintenance window. Components: {:?}",
+        staged_updates.keys().collect::<Vec<&String>>()
+    );
+    send_state(mqtt_client, "Updates staged, awaiting the maintenance window.", request);
+}
+
+/**
+ * Installs the staged updates if the maintenance window is open (see `find_leftover_updates()`).
+ * This is meant to be called when the maintenance window opens, the updates that fail to install are retried the next time.
+ *
+ * NOTICE: Sends state updates through the component backhaul.
+ *
+ * Mutexes `SETTINGS`, `UPDATE_COMPONENTS` are locked momentarily.
+ */
+pub fn install_staged_updates(mqtt_client: &AsyncClient) {
+    if updates_paused() {
+        return;
+    }
+
+    let (maintenance_window, update_files, sandbox_root) = if let Ok(settings) = SETTINGS.lock() {
+        (
+            settings.maintenance_window.to_owned(),
+            settings.update_files.to_owned(),
+            get_sandbox_root(&settings),
+        )
+    } else {
+        error!("Could not lock SETTINGS mutex.");
+        return;
+    };
+
+    let permission_presets: Vec<UpdateComponent> = if let Ok(permissions) = UPDATE_COMPONENTS.lock() {
+        permissions.clone()
+    } else {
+        error!("Could not lock UPDATE_COMPONENTS mutex.");
+        return;
+    };
+
+    let leftover_updates_file = [get_temp_folder_path(&update_files), update_files.leftover_updates_file.to_owned()].concat();
+    if !in_maintenance_window(&maintenance_window) || !Path::new(&leftover_updates_file).exists() {
+        return;
+    }
+
+    info!("Maintenance window open. Installing staged updates...");
+    send_state(mqtt_client, "Maintenance window open. Installing staged updates...", &None);
+
+    find_leftover_updates(
+        &permission_presets,
+        &update_files,
+        &maintenance_window,
+        sandbox_root.as_deref(),
+    );
+
+    if Path::new(&leftover_updates_file).exists() {
+        send_state(
+            mqtt_client,
+            "Some staged updates failed to install, they will be retried in the next maintenance window. Please contact the support team.",
+            &None,
+        );
+    } else {
+        send_state(mqtt_client, "Staged updates installed.", &None);
+    }
+}
+
+/**
+ * Returns the folder the updates are installed into if the install sandbox is enabled in the `settings`.
+ */
+pub fn get_sandbox_root(settings: &Settings) -> Option<String> {
+    if settings.sandbox.enabled {
+        Some(settings.sandbox.dir.to_owned())
+    } else {
+        None
+    }
+}
+
+/**
+ * Returns the `hash_buffer_size` from the `settings`, `DEFAULT_HASH_BUFFER_SIZE` if it isn't set or is 0.
+ */
+fn get_hash_buffer_size(settings: &Settings) -> usize {
+    match settings.hash_buffer_size {
+        Some(size) if size > 0 => size as usize,
+        _ => DEFAULT_HASH_BUFFER_SIZE,
+    }
+}
+
+/**
+ * Returns the names of the components whose package checksums are not verified (`skip_verification`).
+ * NECO itself is never in the list, its packages are always verified.
+ */
+pub fn get_unverified_components(update_components: &[UpdateComponent]) -> BTreeSet<String> {
+    update_components
+        .iter()
+        .filter(|x| x.skip_verification && x.name != APP_NAME)
+        .map(|x| x.name.to_owned())
+        .collect()
+}
+
+/**
+ * Returns the update branch of the component named `component_name`.
+ * If the component doesn't override the branch, the global `default_branch` is returned.
+ */
+fn get_component_branch(
+    update_components: &[UpdateComponent],
+    component_name: &str,
+    default_branch: &str,
+) -> String {
+    update_components
+        .iter()
+        .find(|x| x.name == component_name)
+        .and_then(|x| x.branch.to_owned())
+        .unwrap_or_else(|| default_branch.to_owned())
+}
+
+/**
+ * Sends an authenticated update manifest request for NECO itself to every Neutron server endpoint.
+ * An endpoint passes if it is reachable, responds with a success status and accepts our credentials.
+ *
+ * Returns the endpoints paired with the outcome of their check.
+ */
+pub fn check_neutron_api(settings: &Settings) -> Vec<(String, Result<(), String>)> {
+    let password = match get_password(
+        &settings.neutron_mqtt_client.password,
+        &settings.neutron_mqtt_client.password_file,
+    ) {
+        Ok(pass) => pass,
+        Err(e) => {
+            return vec![(
+                String::from("Neutron API"),
+                Err(format!("Could not read the password file. {}", e)),
+            )]
+        }
+    };
+
+    let url_path = format!(
+        "/api/versioncontrol?{}&branch={}&components={}&versions={}",
+        neutron_auth_query(
+            &settings.neutron_account_username,
+            &settings.neutron_mqtt_client.username,
+            &password,
+            &settings.application_name
+        ),
+        settings.update_branch,
+        APP_NAME,
+        APP_VERSION
+    );
+
+    let device_id = get_client_id(&settings.component_mqtt_client.username, &settings.component_mqtt_client.client_id);
+    let http_client = match ReqwestClient::new(&settings.http_proxy, device_id) {
+        Ok(client) => client,
+        Err(e) => return vec![(String::from("Neutron API"), Err(format!("Could not create the HTTP client. {}", e)))],
+    };
+
+    get_neutron_endpoints(&settings.neutron_server, &settings.neutron_mirrors)
+        .into_iter()
+        .map(|endpoint| {
+            let result = match fetch_from_endpoints(&http_client, &[endpoint.to_owned()], &url_path) {
+                Ok((_, mut response)) => {
+                    let txt = response.text().unwrap_or_default();
+                    let response: serde_json::Value = serde_json::from_str(&txt).unwrap_or_default();
+
+                    if response["result"] == true {
+                        Ok(())
+                    } else {
+                        Err(format!(
+                            "Server -> {}",
+                            response["msg"].as_str().unwrap_or("Unexpected response.")
+                        ))
+                    }
+                }
+                Err(e) => Err(e.to_string()),
+            };
+
+            (endpoint, result)
+        })
+        .collect()
+}
+
+/**
+ * Returns the Neutron server endpoints in the order they should be tried.
+ * The primary Neutron server (`server`) is always first, followed by the configured `mirrors`.
+ */
+fn get_neutron_endpoints(server: &NeutronServer, mirrors: &[String]) -> Vec<String> {
+    let protocol = if server.use_tls { "https://" } else { "http://" };
+
+    let mut endpoints = vec![format!("{}{}:{}", protocol, server.host, server.port)];
+
+    endpoints.extend(
+        mirrors
+            .iter()
+            .map(|mirror| mirror.trim_end_matches('/').to_owned()),
+    );
+
+    endpoints
+}
+
+/**
+ * Requests `url_path` from every endpoint in `endpoints` through `http_client` until one of them responds with a success status.
+ * Endpoints that can't be reached or respond with a non-2xx status are skipped.
+ *
+ * Returns the endpoint that served the request and its response,
+ *     or the error of the last endpoint if none of them succeeded (of kind `Interrupted` if it responded with 5xx/429).
+ */
+fn fetch_from_endpoints(
+    http_client: &dyn HttpClient,
+    endpoints: &[String],
+    url_path: &str,
+) -> Result<(String, HttpResponse), Error> {
+    let mut last_error = Error::other("No Neutron server endpoints configured.");
+
+    for endpoint in endpoints {
+        match http_client.get(&[endpoint, url_path].concat()) {
+            Ok(response) => {
+                if response.is_success() {
+                    return Ok((endpoint.to_owned(), response));
+                }
+
+                warn!(
+                    "Neutron server endpoint '{}' responded with {}. Trying the next one...",
+                    endpoint, response.status
+                );
+                // The caller may retry if the last endpoint is only temporarily unavailable
+                let kind = if response.is_transient_error() { ErrorKind::Interrupted } else { ErrorKind::Other };
+                last_error = Error::new(kind, format!("Endpoint responded with {}.", response.status));
+            }
+            Err(e) => {
+                warn!(
+                    "Could not reach Neutron server endpoint '{}'. Trying the next one...",
+                    endpoint
+                );
+                last_error = e;
+            }
+        }
+    }
+
+    Err(last_error)
+}
+
+/**
+ * Posts the `results` of an update to the `UPDATE_REPORT_PATH` of the first endpoint in `endpoints` that accepts them.
+ * Nothing is sent if there are no results. The update already happened, so failing to report it is only logged.
+ */
+fn report_update_results(
+    http_client: &dyn HttpClient,
+    endpoints: &[String],
+    auth_query: &str,
+    results: &[structs::UpdateResult],
+) {
+    if results.is_empty() {
+        return;
+    }
+
+    let url_path = [UPDATE_REPORT_PATH, "?", auth_query].concat();
+    let body = json!({ "results": results });
 
-    // Start cooking
-    if recipe_processor::cook(&cookbook) {
-        info!("Update download & install complete.");
-        send_state(mqtt_client, "Update download & install complete.");
-    } else {
-        send_state(
-            mqtt_client,
-            "Some components failed to install. Please contact the support team.",
-        );
+    for endpoint in endpoints {
+        match http_client.post_json(&[endpoint, url_path.as_str()].concat(), &body) {
+            Ok(response) if response.is_success() => {
+                info!("Update results reported to '{}'.", endpoint);
+                return;
+            }
+            Ok(response) => warn!(
+                "Neutron server endpoint '{}' responded to the update report with {}. Trying the next one...",
+                endpoint, response.status
+            ),
+            Err(e) => warn!(
+                "Could not report the update results to Neutron server endpoint '{}'. {}",
+                endpoint, e
+            ),
+        }
     }
 
-    // Remove the update manifest so we don't download the same updates again
-    if let Ok(mut manifest_option) = UPDATE_MANIFEST.lock() {
-        *manifest_option = None;
-    }
+    error!("Could not report the update results to the Neutron server.");
+}
 
-    //info!("Cleaning up vc-temp folder.");
-    //remove_dir_all(TMP_ROOT).is_ok();
+/**
+ * Returns the query string parameters every request to the Neutron server has to carry to authenticate.
+ */
+fn neutron_auth_query(
+    neutron_acc_user: &str,
+    mosquitto_client_user: &str,
+    mosquitto_client_pass: &str,
+    app_name: &str,
+) -> String {
+    format!(
+        "neutronuser={}&username={}&password={}&application={}",
+        neutron_acc_user, mosquitto_client_user, mosquitto_client_pass, app_name
+    )
+}
+
+/**
+ * Returns `true` if `version` is a newer semantic version than `current`.
+ * An empty or unparseable `current` is older than any `version`, an unparseable `version` is never newer.
+ */
+fn is_newer_version(version: &str, current: &str) -> bool {
+    match (Version::parse(version), Version::parse(current)) {
+        (Ok(version), Ok(current)) => version > current,
+        (Ok(_), Err(_)) => true,
+        (Err(_), _) => current.is_empty(),
+    }
 }
 
 /**
- * Fetches the recipes from the `update_paths.value()`(Vec) and groups them into
+ * Fetches the recipes (named `update_files.recipe_filename`) from the `update_paths.value()`(Vec) and groups them into
  *     component updates which then becomes a cookbook.
  * The cookbook is an array of components that have updates pending for installation.
  *     Each component has an `updates` key (array).
+ * Recipes of versions the installed updates ledger already contains are skipped so an update is never installed twice.
+ * Components with a `copy` instruction whose file permissions are not a valid octal mode are skipped.
  *
  * Returns `Vec<>` containing every component that has updates pending.
  */
 fn get_recipes(
     update_paths: BTreeMap<String, Vec<String>>,
     permission_presets: &[UpdateComponent],
+    update_files: &UpdateFiles,
 ) -> Vec<serde_json::Value> {
     let mut cookbook: Vec<serde_json::Value> = Vec::new();
 
+    let installed_updates = ledger::load();
+
     for component in update_paths {
         // Extract the component permissions for this component
         let component_perms: Vec<&UpdateComponent> = permission_presets
@@ -397,11 +1282,19 @@ fn get_recipes(
         component_in_vec["component"] = serde_json::value::Value::String(component.0.to_owned());
         component_in_vec["restart_command"] =
             serde_json::value::Value::String(component_perms[0].restart_command.to_owned());
+        component_in_vec["health_check"] = json!({
+            "command": component_perms[0].health_check,
+            "grace_period": component_perms[0].health_check_grace_period,
+            "restart_retries": component_perms[0].restart_retries,
+            "container_name": component_perms[0].container_name,
+        });
 
         let mut restart_comp = false;
 
-        // Since the updates are ordered by version, from old to new, we can set `final_version` on
-        //     every iter and get the last version by the end
+        // Set if a copy instruction has invalid file permissions, the component is skipped
+        let mut invalid_permissions = false;
+
+        // The newest version out of all the recipes
         let mut final_version = String::new();
         // This is going to contain all the updates we are able to extract from the paths for that component
         let mut recipes: Vec<serde_json::Value> = Vec::new();
@@ -409,7 +1302,7 @@ fn get_recipes(
         // For every recipe path in a recipe vector
         for recipe_path in component.1 {
             // Open the recipe at the `recipe_path` and try to parse it
-            match File::open([&recipe_path, RECIPE_FILENAME].concat()) {
+            match File::open([recipe_path.as_str(), &update_files.recipe_filename].concat()) {
                 Ok(mut file) => {
                     let mut recipe = String::new();
                     match file.read_to_string(&mut recipe) {
@@ -417,6 +1310,19 @@ fn get_recipes(
                             if let Ok(recipe_json) = serde_json::from_str(&recipe) {
                                 let parsed_json: Vec<serde_json::Value> = recipe_json;
 
+                                let recipe_version = parsed_json
+                                    .iter()
+                                    .find_map(|instruction| instruction["version"].as_str());
+                                if let Some(version) = recipe_version {
+                                    if ledger::is_installed(&installed_updates, &component.0, version) {
+                                        warn!(
+                                            "Update already installed, skipping. Component: {}, Version: {}",
+                                            &component.0, version
+                                        );
+                                        continue;
+                                    }
+                                }
+
                                 // For every command block in a recipe
                                 for mut instruction in parsed_json {
                                     if instruction["restart"] == true {
@@ -428,17 +1334,24 @@ fn get_recipes(
                                     instruction["absolute_update_path"] =
                                         serde_json::value::Value::String(recipe_path.to_string());
 
-                                    // This will work because the recipes are ordered from oldest to newest
-                                    if instruction["version"] != serde_json::Value::Null {
-                                        final_version = instruction["version"]
-                                            .as_str()
-                                            .unwrap_or_default()
-                                            .to_string();
+                                    // Keep the newest version, regardless of the order the recipes come in
+                                    if let Some(version) = instruction["version"].as_str() {
+                                        if is_newer_version(version, &final_version) {
+                                            final_version = version.to_string();
+                                        }
                                     }
 
+                                    // A copy_dir instruction only sets permissions if it specifies any of them,
+                                    //     otherwise the copied files keep their modes and stay root-owned
+                                    let copy_dir_permissions = instruction["type"] == "copy_dir"
+                                        && ["permission_user", "permission_group", "file_permissions", "dir_permissions"]
+                                            .iter()
+                                            .any(|key| instruction[*key] != serde_json::Value::Null);
+
                                     // Check if permission overrides exist for the copy command
                                     // If they don't, insert the ones from settings for that component
-                                    if instruction["type"] == "copy" && !component_perms.is_empty()
+                                    if (instruction["type"] == "copy" || copy_dir_permissions)
+                                        && !component_perms.is_empty()
                                     {
                                         if instruction["permission_user"] == serde_json::Value::Null
                                         {
@@ -467,6 +1380,22 @@ fn get_recipes(
                                         }
                                     }
 
+                                    if instruction["type"] == "copy" || copy_dir_permissions {
+                                        if let Err(e) = security::parse_file_permissions(
+                                            instruction["file_permissions"].as_str().unwrap_or_default(),
+                                        ) {
+                                            error!("{} Component: {}, Recipe: '{}'", e, &component.0, &recipe_path);
+                                            invalid_permissions = true;
+                                        }
+                                    }
+
+                                    if let Some(dir_permissions) = instruction["dir_permissions"].as_str() {
+                                        if let Err(e) = security::parse_file_permissions(dir_permissions) {
+                                            error!("{} Component: {}, Recipe: '{}'", e, &component.0, &recipe_path);
+                                            invalid_permissions = true;
+                                        }
+                                    }
+
                                     // Add instruction to recipes
                                     recipes.push(instruction);
                                 }
@@ -488,6 +1417,11 @@ fn get_recipes(
             }
         }
 
+        if invalid_permissions {
+            error!("Recipes contain invalid file permissions for component: {}. Skipping component...", &component.0);
+            continue;
+        }
+
         if final_version.is_empty() {
             error!("Could not find any version numbers in recipes for component: {}. Skipping component...", &component.0);
             continue;
@@ -505,61 +1439,180 @@ fn get_recipes(
 }
 
 /**
- * Unzipps the downloaded update files so that they can be further processed.
- * Files are unzipped to a folder named `<zipfile-name>-extracted` and if it was
- *     successful, the zip file is removed.
+ * The archive formats update packages can come in.
+ */
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum ArchiveType {
+    Zip,
+    TarGz,
+    TarXz,
+}
+
+/**
+ * Determines the archive type of the file on the `file_path` by reading its magic bytes.
+ * We can't rely on the extension since the update files are saved under their version name.
  *
- * NOTICE: The client needs to have `unzip` installed for this function to work.
+ * Returns `None` if the file can't be read or the format isn't supported.
+ */
+fn detect_archive_type(file_path: &str) -> Option<ArchiveType> {
+    let mut magic = [0; 6];
+    let mut file = File::open(file_path).ok()?;
+    file.read_exact(&mut magic).ok()?;
+
+    if magic.starts_with(b"PK\x03\x04") {
+        Some(ArchiveType::Zip)
+    } else if magic.starts_with(&[0x1f, 0x8b]) {
+        Some(ArchiveType::TarGz)
+    } else if magic == [0xfd, b'7', b'z', b'X', b'Z', 0x00] {
+        Some(ArchiveType::TarXz)
+    } else {
+        None
+    }
+}
+
+/**
+ * Extracts the downloaded update files so that they can be further processed.
+ * The archive type (zip, tar.gz, tar.xz) is detected from the file contents.
+ * Files are extracted to a folder named `<archive-name><update_files.extracted_folder_suffix>` and if it was
+ *     successful, the archive is removed.
  *
  * Returns `BTreeMap` with component name as the key and the extracted folder path
  *     as the value if successful.
  */
 fn unpack_updates(
     verified_updates: BTreeMap<String, Vec<String>>,
+    update_files: &UpdateFiles,
 ) -> BTreeMap<String, Vec<String>> {
     let mut inflated_updates: BTreeMap<String, Vec<String>> = BTreeMap::new();
 
     for component in verified_updates {
-        let mut unzipped_updates: Vec<String> = Vec::new();
+        let mut unpacked_updates: Vec<String> = Vec::new();
 
         // For every update in the vector of a component
         for update in component.1 {
-            let extracted_folder_name = [&update, "-extracted"].concat();
-
-            match Command::new("unzip")
-                .arg(&update)
-                .arg("-d")
-                .arg(&extracted_folder_name)
-                .output()
-            {
-                Ok(res) => {
-                    // Check if error output is empty, if it's not, stdout error and skip this loop count
-                    if !String::from_utf8_lossy(&res.stderr).is_empty() {
-                        error!(
-                            "Could not extract update zip-file. {}",
-                            String::from_utf8_lossy(&res.stderr)
-                        );
-                        continue;
-                    }
+            let extracted_folder_name = [update.as_str(), &update_files.extracted_folder_suffix].concat();
+
+            let archive_type = match detect_archive_type(&update) {
+                Some(archive_type) => archive_type,
+                None => {
+                    error!("Unsupported update archive format. {}", &update);
+                    continue;
                 }
-                Err(e) => error!("Could not execute 'unzip'. Is it installed? {}", e),
+            };
+
+            // If extracting failed, print the error and skip this loop count
+            if let Err(e) = extract_archive(&update, archive_type, &extracted_folder_name) {
+                error!("Could not extract update archive. {} {}", &update, e);
+                continue;
             }
 
             // If we're here, that means that we have no critical errors
 
             if remove_file(&update).ok().is_none() {
-                warn!("Could not remove extracted zip file. {}", &update);
+                warn!("Could not remove extracted archive. {}", &update);
             }
 
             // Push the extracted update path to vec
-            unzipped_updates.push([&extracted_folder_name, "/"].concat());
+            unpacked_updates.push([&extracted_folder_name, "/"].concat());
         }
-        inflated_updates.insert(component.0, unzipped_updates);
+        inflated_updates.insert(component.0, unpacked_updates);
     }
 
     inflated_updates
 }
 
+/**
+ * Extracts the archive on `archive_path` into the `destination` folder, which is created if it doesn't exist.
+ * Tar entries that would end up outside of the `destination` are skipped (see `tar::Archive::unpack()`),
+ *     zip entries with an absolute path or `..` fail the extraction.
+ * Zip symlinks are created last, so no other entry is written through them.
+ */
+fn extract_archive(archive_path: &str, archive_type: ArchiveType, destination: &str) -> Result<(), Error> {
+    create_dir_all(destination)?;
+    let file = File::open(archive_path)?;
+
+    match archive_type {
+        ArchiveType::TarGz => tar::Archive::new(GzDecoder::new(file)).unpack(destination),
+        ArchiveType::TarXz => tar::Archive::new(XzDecoder::new(file)).unpack(destination),
+        ArchiveType::Zip => {
+            let mut archive = zip::ZipArchive::new(file).map_err(|e| Error::new(ErrorKind::InvalidData, e))?;
+            let mut symlinks: Vec<(String, PathBuf)> = Vec::new();
+
+            for index in 0..archive.len() {
+                let mut entry = archive
+                    .by_index(index)
+                    .map_err(|e| Error::new(ErrorKind::InvalidData, e))?;
+
+                let path = match entry.enclosed_name() {
+                    Some(name) => Path::new(destination).join(name),
+                    None => {
+                        return Err(Error::new(
+                            ErrorKind::InvalidData,
+                            format!("Archive entry '{}' points outside of the extraction folder.", entry.name()),
+                        ))
+                    }
+                };
+
+                if entry.is_dir() {
+                    create_dir_all(&path)?;
+                    continue;
+                }
+                if let Some(parent) = path.parent() {
+                    create_dir_all(parent)?;
+                }
+
+                match entry.unix_mode() {
+                    Some(mode) if mode & S_IFMT == S_IFLNK => {
+                        let mut target = String::new();
+                        entry.read_to_string(&mut target)?;
+                        symlinks.push((target, path));
+                    }
+                    mode => {
+                        copy(&mut entry, &mut File::create(&path)?)?;
+                        if let Some(mode) = mode {
+                            set_permissions(&path, Permissions::from_mode(mode & 0o7777))?;
+                        }
+                    }
+                }
+            }
+
+            for (target, path) in symlinks {
+                symlink(target, path)?;
+            }
+
+            Ok(())
+        }
+    }
+}
+
+/**
+ * Verifies the signature of the NECO update package on `file_path`, a bad NECO binary would leave us without an updater.
+ * Fails if there is no `neco_public_key` or the update has no signature.
+ */
+fn verify_neco_signature(file_path: &str, update: &structs::Update, neco_public_key: Option<&str>) -> bool {
+    let public_key = if let Some(key) = neco_public_key {
+        key
+    } else {
+        error!("NECO update packages need a signature, but 'neco_update_public_key' is not set in the settings.");
+        return false;
+    };
+
+    let signature = if let Some(signature) = &update.signature {
+        signature
+    } else {
+        error!("NECO update package {} is not signed.", &update.version);
+        return false;
+    };
+
+    match security::verify_signature(file_path, signature, public_key) {
+        Ok(()) => true,
+        Err(e) => {
+            error!("NECO update package {} signature verification failed. {}", &update.version, e);
+            false
+        }
+    }
+}
+
 /**
  * Downloads and hash-checks the update files using the provided update manifest.
  * Removes the version control temporary directory and recreates it, then it goes through
@@ -570,20 +1623,38 @@ fn unpack_updates(
  * Returns empty `BTreeMap` if there aren't any good* updates to install.
  * **Good updates - the updates that passed the hash validation.
  *
+ * Every component is downloaded from its branch in `component_branches`.
+ * The checksums of the `unverified_components` packages are not compared, every skipped check is logged.
+ * Every file is requested through `http_client` from the `endpoints` in order until one of them serves it.
+ * If a chainlink update can't be downloaded or verified, the later updates of that component are skipped.
+ * NECO packages also need a valid signature made with the `neco_public_key`, they are never verified if it isn't set.
+ * A package bigger than `download_limits.max_package_size` is discarded as unverified. Once all packages together
+ *     grow past `download_limits.max_total_size` the whole download is aborted and the temporary folder removed.
+ * A package whose size doesn't match the response `Content-Length` or its manifest `file_size` is discarded before hashing.
+ * Packages are hashed `hash_buffer_size` bytes at a time.
+ * The failures are also reported as state updates (carrying the `request` id) through the `mqtt_client`, if there is one.
+ *
  * Returns `BTreeMap` with component name as the key and the confirmed update list (`Vec`) as the value.
  */
+#[allow(clippy::too_many_arguments)]
 fn dload_and_verify_updates(
+    mqtt_client: Option<&AsyncClient>,
+    http_client: &dyn HttpClient,
     update_manifest: structs::UpdateManifest,
-    neutron_acc_user: &str,
-    mosquitto_client_user: &str,
-    mosquitto_client_pass: &str,
-    app_name: &str,
-    update_branch: &str,
+    auth_query: &str,
+    component_branches: &BTreeMap<String, String>,
+    unverified_components: &BTreeSet<String>,
+    endpoints: &[String],
+    update_files: &UpdateFiles,
+    neco_public_key: Option<&str>,
+    download_limits: &DownloadLimits,
+    hash_buffer_size: usize,
+    request: &Option<String>,
 ) -> BTreeMap<String, Vec<String>> {
     info!("Initiating Update Download and Checksum Validation.");
 
-    let temp_folder = get_temp_folder_path();
-    if let Err(e) = remove_dir_all(&temp_folder) {
+    let temp_folder = get_temp_folder_path(update_files);
+    if let Err(e) = clear_temp_folder(update_files) {
         warn!("Could not remove root temporary folder. {}", e)
     }
 
@@ -591,48 +1662,131 @@ fn dload_and_verify_updates(
         //let mut unverified_updates: BTreeMap<String, String> = BTreeMap::new();
         let mut verified_updates: BTreeMap<String, Vec<String>> = BTreeMap::new();
         let mut dirty_updates: Vec<String> = Vec::new();
+        let mut downloaded_size: u64 = 0;
 
         for component in update_manifest.list {
             let tmp_dir_component_path = [temp_folder.to_owned(), component.0.to_owned()].concat();
 
             let mut component_updates: Vec<String> = Vec::new();
 
-            // Try to create a temporary component folder
-            if create_dir(&tmp_dir_component_path).is_ok() {
+            let component_branch = component_branches
+                .get(&component.0)
+                .cloned()
+                .unwrap_or_default();
+
+            // Try to create a temporary component folder, it's kept by `clear_temp_folder()` if an update of the component is staged
+            if create_dir_all(&tmp_dir_component_path).is_ok() {
                 for update in component.1 {
-                    // We don't need the .zip extension at the end because 'unzip' command automatically does that
+                    // No extension, the archive type is detected from the file contents when unpacking
                     let file_path = format!("{}/{}", tmp_dir_component_path, &update.version);
 
-                    let url = format!(
-                        "{}{}{}/version_control/download?neutronuser={}&username={}&password={}&application={}&branch={}&component={}&version={}",
-                        NEUTRON_SERVER_PROTOCOL,
-                        NEUTRON_SERVER_IP,
-                        NEUTRON_SERVER_PORT,
-                        neutron_acc_user,
-                        mosquitto_client_user,
-                        mosquitto_client_pass,
-                        app_name,
-                        &update_branch,
+                    let url_path = format!(
+                        "/version_control/download?{}&branch={}&component={}&version={}",
+                        auth_query,
+                        &component_branch,
                         &component.0,
                         &update.version
                     );
 
-                    match reqwest::get(&url) {
-                        Ok(mut response) => {
+                    let is_verified = match fetch_from_endpoints(http_client, endpoints, &url_path) {
+                        Ok((endpoint, mut response)) => {
+                            info!(
+                                "Update package {} {} served by '{}'.",
+                                &component.0, &update.version, endpoint
+                            );
+
+                            let remaining_size = download_limits.max_total_size.saturating_sub(downloaded_size);
+                            let size_limit = download_limits.max_package_size.min(remaining_size);
+
                             if let Ok(mut file) = File::create(&file_path) {
-                                if copy(&mut response, &mut file).is_ok() {
-                                    //info!("{} : {}", &component.0, &update.version);
-                                    //info!("UNVF: {:?}", &unverified_updates);
-                                    if security::compare_hash(&file_path, &update.checksum).is_ok()
-                                    {
-                                        component_updates.push(file_path);
-                                    } else {
-                                        warn!("Update file verification failed. {}", &file_path);
+                                match copy_limited(&mut response.body, &mut file, size_limit) {
+                                    Ok(Some(size)) => {
+                                        downloaded_size += size;
+                                        //info!("{} : {}", &component.0, &update.version);
+                                        //info!("UNVF: {:?}", &unverified_updates);
+                                        if let Err(e) = check_download_size(size, response.content_length, &update.file_size) {
+                                            error!("Update package {} {} is incomplete. {}", &component.0, &update.version, e);
+                                            if let Some(mqtt_client) = mqtt_client {
+                                                send_state(
+                                                    mqtt_client,
+                                                    &format!(
+                                                        "Update package {} {} is incomplete. {}",
+                                                        &component.0, &update.version, e
+                                                    ),
+                                                    request,
+                                                );
+                                            }
+                                            dirty_updates.push(file_path);
+                                            false
+                                        } else {
+                                            let hash_verified = if unverified_components.contains(&component.0) {
+                                                warn!(
+                                                    "Checksum verification is disabled for {}. Update package {} is NOT verified.",
+                                                    &component.0, &update.version
+                                                );
+                                                true
+                                            } else {
+                                                security::compare_hash(&file_path, &update.checksum, hash_buffer_size).is_ok()
+                                            };
+
+                                            if hash_verified
+                                                && (component.0 != APP_NAME
+                                                    || verify_neco_signature(&file_path, &update, neco_public_key))
+                                            {
+                                                component_updates.push(file_path);
+                                                true
+                                            } else {
+                                                warn!("Update file verification failed. {}", &file_path);
+                                                dirty_updates.push(file_path);
+                                                false
+                                            }
+                                        }
+                                    }
+                                    Ok(None) if size_limit < download_limits.max_package_size => {
+                                        error!(
+                                            "Update downloads exceeded the maximum total size of {} bytes. Aborting the download.",
+                                            download_limits.max_total_size
+                                        );
+                                        if let Some(mqtt_client) = mqtt_client {
+                                            send_state(
+                                                mqtt_client,
+                                                "Update downloads exceeded the maximum total size. Download aborted.",
+                                                request,
+                                            );
+                                        }
+
+                                        if let Err(e) = clear_temp_folder(update_files) {
+                                            warn!("Could not remove root temporary folder. {}", e)
+                                        }
+                                        return BTreeMap::new();
+                                    }
+                                    Ok(None) => {
+                                        error!(
+                                            "Update package {} {} exceeds the maximum package size of {} bytes.",
+                                            &component.0, &update.version, download_limits.max_package_size
+                                        );
+                                        if let Some(mqtt_client) = mqtt_client {
+                                            send_state(
+                                                mqtt_client,
+                                                &format!(
+                                                    "Update package {} {} exceeds the maximum package size.",
+                                                    &component.0, &update.version
+                                                ),
+                                                request,
+                                            );
+                                        }
+                                        dirty_updates.push(file_path);
+                                        false
+                                    }
+                                    Err(e) => {
+                                        error!("Could not save the downloaded update package. {} {}", &file_path, e);
                                         dirty_updates.push(file_path);
+                                        false
                                     }
                                 }
                             } else {
                                 error!("Could not create file after downloading.");
+                                false
                             }
                         }
                         Err(e) => {
@@ -642,7 +1796,17 @@ fn dload_and_verify_updates(
                             );
                             // Error message is written in debug because it contains sensitive information
                             debug!("{}", e);
+                            false
                         }
+                    };
+
+                    // Later versions can't be applied without a chainlink update, abort the rest of the chain
+                    if !is_verified && update.chainlink {
+                        warn!(
+                            "Chainlink update {} {} is unavailable. Skipping the later updates of the component.",
+                            &component.0, &update.version
+                        );
+                        break;
                     }
                 }
 
@@ -678,6 +1842,53 @@ fn dload_and_verify_updates(
     BTreeMap::new()
 }
 
+/**
+ * Streams the `reader` into the `writer`, stopping as soon as more than `limit` bytes were read.
+ *
+ * Returns the number of bytes written or `None` if the `reader` had more than `limit` bytes.
+ */
+fn copy_limited(reader: &mut dyn Read, writer: &mut dyn Write, limit: u64) -> Result<Option<u64>, Error> {
+    let size = copy(&mut reader.take(limit.saturating_add(1)), writer)?;
+
+    if size > limit {
+        Ok(None)
+    } else {
+        Ok(Some(size))
+    }
+}
+
+/**
+ * Compares the `size` of a downloaded package to the `Content-Length` of the response and the `file_size` from the
+ *     update manifest, the checks are skipped if they're missing. A `file_size` that isn't a number of bytes is ignored.
+ *
+ * Returns an error of kind `InvalidData` if the sizes don't match.
+ */
+fn check_download_size(size: u64, content_length: Option<u64>, file_size: &Option<String>) -> Result<(), Error> {
+    if let Some(content_length) = content_length {
+        if size != content_length {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("Downloaded {} bytes, the response Content-Length is {} bytes.", size, content_length),
+            ));
+        }
+    }
+
+    if let Some(file_size) = file_size {
+        match file_size.trim().parse::<u64>() {
+            Ok(file_size) if size != file_size => {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    format!("Downloaded {} bytes, the update manifest file size is {} bytes.", size, file_size),
+                ));
+            }
+            Ok(_) => {}
+            Err(_) => debug!("Update manifest file size '{}' is not a number of bytes, not checked.", file_size),
+        }
+    }
+
+    Ok(())
+}
+
 /**
  * Saves the provided update manifest as a leftover update manifest.
  *
@@ -685,9 +1896,10 @@ fn dload_and_verify_updates(
  */
 fn save_leftover_updates(
     update_manifest: &BTreeMap<String, Vec<String>>,
+    update_files: &UpdateFiles,
 ) -> Result<(), std::io::Error> {
     let unfinished_updates_file =
-        [get_temp_folder_path(), LEFTOVER_UPDATES_FILE.to_owned()].concat();
+        [get_temp_folder_path(update_files), update_files.leftover_updates_file.to_owned()].concat();
 
     let mut file = File::create(unfinished_updates_file)?;
     file.write_all(&serde_json::to_string(&update_manifest)?.as_bytes())?;
@@ -695,107 +1907,405 @@ fn save_leftover_updates(
     Ok(())
 }
 
+/**
+ * Adds `updates` to the leftover update manifest, keeping the updates already in it (e.g. staged for the maintenance window).
+ * An update already in the manifest for the same component is replaced.
+ *
+ * Returns the saved leftover update manifest.
+ */
+fn add_leftover_updates(
+    updates: &BTreeMap<String, Vec<String>>,
+    update_files: &UpdateFiles,
+) -> Result<BTreeMap<String, Vec<String>>, Error> {
+    let mut leftover_updates = load_leftover_updates(update_files).unwrap_or_default();
+    leftover_updates.extend(updates.clone());
+
+    save_leftover_updates(&leftover_updates, update_files)?;
+
+    Ok(leftover_updates)
+}
+
+/**
+ * Reads and parses the leftover update manifest.
+ */
+fn load_leftover_updates(update_files: &UpdateFiles) -> Result<BTreeMap<String, Vec<String>>, Error> {
+    let contents = std::fs::read_to_string(
+        [get_temp_folder_path(update_files), update_files.leftover_updates_file.to_owned()].concat(),
+    )?;
+
+    serde_json::from_str(&contents).map_err(|e| Error::new(ErrorKind::InvalidData, e))
+}
+
 /**
  * Tries to open the unfinished updates update manifest, if the file cannot be
  *     opened (because it doesn't exist or is corrupted) we just return the function.
  * If we find the leftover update manifest, try to parse it and call `install_leftover_updates()` on that manifest.
+ * Outside of the `maintenance_window` the leftover updates are kept for when it opens.
+ * If `sandbox_root` is set, they're installed into the sandbox (see `recipe_processor::cook()`).
  */
-pub fn find_leftover_updates(permission_presets: &[UpdateComponent]) {
-    let unfinished_updates_file =
-        [get_temp_folder_path(), LEFTOVER_UPDATES_FILE.to_owned()].concat();
+pub fn find_leftover_updates(
+    permission_presets: &[UpdateComponent],
+    update_files: &UpdateFiles,
+    maintenance_window: &Option<MaintenanceWindow>,
+    sandbox_root: Option<&str>,
+) {
+    let update_list = match load_leftover_updates(update_files) {
+        Ok(update_list) => update_list,
+        Err(e) if e.kind() == ErrorKind::InvalidData => {
+            error!("Could not convert leftover update list from JSON.");
+            return;
+        }
+        Err(_) => {
+            warn!("Could not find/open leftover updates file.");
+            return;
+        }
+    };
 
-    let mut contents = String::new();
+    if !update_list.is_empty() {
+        if !in_maintenance_window(maintenance_window) {
+            info!("Found leftover updates. They're staged until the maintenance window opens.");
+            return;
+        }
+
+        info!("Found leftover updates.");
+        install_leftover_updates(update_list, permission_presets, update_files, sandbox_root);
+    }
+}
+
+/**
+ * Fetches the recipes from the paths found in the cookbook then cooks
+ *     the updates and tries to remove the temporary folder.
+ * If removing the base version control temporary folder fails, we
+ *     at least try to remove the leftover update manifest so the same updates don't get installed again.
+ * If some components fail to install, the leftover update manifest is overwritten with just the failed
+ *     components and the temporary folder is kept, so the next startup retries only those.
+ */
+fn install_leftover_updates(
+    update_list: BTreeMap<String, Vec<String>>,
+    permission_presets: &[UpdateComponent],
+    update_files: &UpdateFiles,
+    sandbox_root: Option<&str>,
+) {
+    let cookbook = get_recipes(update_list.clone(), permission_presets, update_files);
+
+    info!("Updating component(s)...");
+
+    // Start cooking
+    let failed_components = recipe_processor::cook(&SystemCommandRunner, &cookbook, sandbox_root);
+
+    if !failed_components.is_empty() {
+        error!(
+            "Some leftover updates failed to install. Components: {:?}",
+            failed_components
+        );
+
+        let pending_updates: BTreeMap<String, Vec<String>> = update_list
+            .into_iter()
+            .filter(|component| failed_components.contains(&component.0))
+            .collect();
+
+        if save_leftover_updates(&pending_updates, update_files).is_err() {
+            error!("Failed to save the unfinished update list. The failed updates will not be retried.");
+        } else {
+            info!("The failed updates will be retried on the next startup or when the maintenance window opens.");
+        }
 
-    let mut file: File;
-    if let Ok(opened_file) = File::open(unfinished_updates_file) {
-        file = opened_file;
-    } else {
-        warn!("Could not find/open leftover updates file.");
         return;
     }
 
-    if file.read_to_string(&mut contents).is_err() {
-        error!("Could not convert read file.");
-        return;
+    info!("Update installation complete.");
+
+    debug!("Removing temporary update folder...");
+    if remove_temp_dir(update_files, &get_temp_folder_path(update_files)).is_err() {
+        error!("Could not remove temporary update folder.");
+
+        if remove_file([get_temp_folder_path(update_files), update_files.leftover_updates_file.to_owned()].concat()).is_err()
+        {
+            error!("Could not remove leftover update list. It's possible it will try to install the same updates again.");
+        }
+    }
+}
+
+/**
+ * Removes the version control temporary folder before new updates are downloaded into it.
+ * If there is a leftover update manifest, the manifest and the unpacked updates it points to are kept,
+ *     they're staged for the maintenance window or still wait to be installed after a NECO upgrade.
+ */
+fn clear_temp_folder(update_files: &UpdateFiles) -> Result<(), Error> {
+    let temp_folder = get_temp_folder_path(update_files);
+
+    let leftover_paths: Vec<String> = match load_leftover_updates(update_files) {
+        Ok(update_list) => update_list.into_iter().flat_map(|component| component.1).collect(),
+        Err(_) => return remove_temp_dir(update_files, &temp_folder),
+    };
+
+    remove_unlisted_entries(
+        Path::new(&temp_folder),
+        &[temp_folder.as_str(), &update_files.leftover_updates_file].concat(),
+        &leftover_paths,
+    )
+}
+
+/**
+ * Removes the entries of the `folder` except the `manifest_path` file and the `kept_paths`,
+ *     the folders on the way to a kept path are descended into.
+ */
+fn remove_unlisted_entries(folder: &Path, manifest_path: &str, kept_paths: &[String]) -> Result<(), Error> {
+    for entry in read_dir(folder)?.flatten() {
+        let path = entry.path();
+        let path_str = path.to_string_lossy().to_string();
+
+        if path_str == manifest_path || kept_paths.iter().any(|x| x.trim_end_matches('/') == path_str) {
+            continue;
+        }
+
+        if path.is_dir() {
+            if kept_paths.iter().any(|x| x.starts_with(&[path_str.as_str(), "/"].concat())) {
+                remove_unlisted_entries(&path, manifest_path, kept_paths)?;
+            } else {
+                remove_dir_all(&path)?;
+            }
+        } else {
+            remove_file(&path)?;
+        }
+    }
+
+    Ok(())
+}
+
+/**
+ * Removes the files and folders in the version control temporary folder that weren't modified for `STALE_TEMP_THRESHOLD`.
+ * The leftover update manifest and the update folders it points to are kept, they are still needed to resume the installation.
+ *
+ * This is meant to be called on startup, after `find_leftover_updates()`.
+ */
+pub fn purge_stale_temp_files(update_files: &UpdateFiles) {
+    let temp_folder = get_temp_folder_path(update_files);
+
+    let entries = match read_dir(&temp_folder) {
+        Ok(entries) => entries,
+        Err(e) => {
+            debug!("No temporary update folder to clean up. {}", e);
+            return;
+        }
+    };
+
+    // Paths of the unpacked updates that still wait to be installed
+    let leftover_paths: Vec<String> =
+        std::fs::read_to_string([temp_folder.as_str(), &update_files.leftover_updates_file].concat())
+            .ok()
+            .and_then(|contents| serde_json::from_str::<BTreeMap<String, Vec<String>>>(&contents).ok())
+            .map(|list| list.into_iter().flat_map(|component| component.1).collect())
+            .unwrap_or_default();
+
+    for entry in entries.flatten() {
+        let path = entry.path();
+        let path_str = path.to_string_lossy().to_string();
+
+        if entry.file_name().to_string_lossy() == update_files.leftover_updates_file
+            || leftover_paths.iter().any(|x| x.starts_with(&path_str))
+        {
+            continue;
+        }
+
+        match entry.metadata().and_then(|meta| meta.modified()) {
+            Ok(modified) => match modified.elapsed() {
+                Ok(age) if age >= STALE_TEMP_THRESHOLD => {}
+                _ => continue,
+            },
+            Err(_) => continue,
+        }
+
+        let result = if path.is_dir() {
+            remove_dir_all(&path)
+        } else {
+            remove_file(&path)
+        };
+
+        match result {
+            Ok(_) => debug!("Removed stale temporary update file. Path: {}", path_str),
+            Err(e) => warn!("Could not remove stale temporary update file. Path: {} {}", path_str, e),
+        }
+    }
+}
+
+/**
+ * Joins the `update_files.temp_root` (`BASE_DIRECTORY` if it isn't set) and `update_files.temp_folder`.
+ * The path always ends with a `/`, file names are appended to it.
+ */
+fn get_temp_folder_path(update_files: &UpdateFiles) -> String {
+    let temp_root = update_files.temp_root.as_deref().unwrap_or(BASE_DIRECTORY);
+    let temp_folder = Path::new(temp_root)
+        .join(&update_files.temp_folder)
+        .to_string_lossy()
+        .into_owned();
+
+    if temp_folder.ends_with('/') {
+        temp_folder
+    } else {
+        [temp_folder.as_str(), "/"].concat()
+    }
+}
+
+/**
+ * Removes the `folder` (the temporary update folder or a folder in it) with everything in it.
+ *
+ * Returns an error of kind `PermissionDenied` without removing anything if the `folder` is the temp root
+ *     (`update_files.temp_root`, `BASE_DIRECTORY` if it isn't set) or isn't inside it.
+ */
+fn remove_temp_dir(update_files: &UpdateFiles, folder: &str) -> Result<(), Error> {
+    let temp_root = Path::new(update_files.temp_root.as_deref().unwrap_or(BASE_DIRECTORY));
+    let path = Path::new(folder);
+
+    if path == temp_root || !path.starts_with(temp_root) || path.components().any(|x| x == Component::ParentDir) {
+        return Err(Error::new(
+            ErrorKind::PermissionDenied,
+            format!(
+                "Refusing to remove '{}', it isn't inside the temp root '{}'.",
+                folder,
+                temp_root.display()
+            ),
+        ));
+    }
+
+    remove_dir_all(path)
+}
+
+/**
+ * Checks the `update_files` settings, the `temp_folder` must be a non-empty relative path that stays inside its root
+ *     and the `temp_root` an absolute path without `..`.
+ * The whole temporary folder is removed between update downloads, so it must never resolve to the root itself or above it.
+ */
+pub fn validate_update_files(update_files: &UpdateFiles) -> Result<(), Error> {
+    if let Some(temp_root) = &update_files.temp_root {
+        let root = Path::new(temp_root);
+
+        if !root.is_absolute() || root.components().any(|x| x == Component::ParentDir) {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!("Setting 'update_files.temp_root' ('{}') must be an absolute path without '..'.", temp_root),
+            ));
+        }
     }
 
-    if let Ok(json) = serde_json::from_str(&contents) {
-        let update_list: BTreeMap<String, Vec<String>> = json;
+    let temp_folder = Path::new(&update_files.temp_folder);
 
-        if !update_list.is_empty() {
-            info!("Found leftover updates.");
-            install_leftover_updates(update_list, permission_presets);
-        }
-    } else {
-        error!("Could not convert leftover update list from JSON.");
+    if update_files.temp_folder.trim().is_empty() {
+        return Err(Error::new(ErrorKind::InvalidInput, "Setting 'update_files.temp_folder' is empty."));
+    }
+    if temp_folder.is_absolute() || temp_folder.components().any(|x| x == Component::ParentDir) {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            format!(
+                "Setting 'update_files.temp_folder' ('{}') must be a relative path without '..'.",
+                update_files.temp_folder
+            ),
+        ));
+    }
+    if !temp_folder.components().any(|x| matches!(x, Component::Normal(_))) {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            format!(
+                "Setting 'update_files.temp_folder' ('{}') must name a folder.",
+                update_files.temp_folder
+            ),
+        ));
     }
+
+    Ok(())
 }
 
 /**
- * Fetches the recipes from the paths found in the cookbook then cooks
- *     the updates and tries to remove the temporary folder.
- * If removing the base version control temporary folder fails, we
- *     at least try to remove the leftover update manifest so the same updates don't get installed again.
+ * Creates the version control temporary folder if it doesn't exist and checks that files can be written into it.
+ *
+ * Returns the folder path if it's writable.
  */
-fn install_leftover_updates(
-    update_list: BTreeMap<String, Vec<String>>,
-    permission_presets: &[UpdateComponent],
-) {
-    let cookbook = get_recipes(update_list, permission_presets);
-
-    info!("Updating component(s)...");
-
-    // Start cooking
-    recipe_processor::cook(&cookbook);
-
-    info!("Update installation complete.");
+pub fn check_temp_folder(update_files: &UpdateFiles) -> Result<String, Error> {
+    let temp_folder = get_temp_folder_path(update_files);
+    let probe_file = [temp_folder.as_str(), TEMP_FOLDER_PROBE_FILE].concat();
 
-    debug!("Removing temporary update folder...");
-    if remove_dir_all(get_temp_folder_path()).is_err() {
-        error!("Could not remove temporary update folder.");
+    create_dir_all(&temp_folder)
+        .and_then(|_| File::create(&probe_file))
+        .and_then(|_| remove_file(&probe_file))
+        .map_err(|e| Error::new(e.kind(), format!("Temporary update folder '{}' is not writable. {}", temp_folder, e)))?;
 
-        if remove_file([get_temp_folder_path(), LEFTOVER_UPDATES_FILE.to_owned()].concat()).is_err()
-        {
-            error!("Could not remove leftover update list. It's possible it will try to install the same updates again.");
-        }
-    }
+    Ok(temp_folder)
 }
 
 /**
- * Concatenates the `BASE_DIRECTORY` and `TEMP_UPDATE_FOLDER`.
+ * Returns a JSON-formatted `String` describing this NECO build.
+ * Contains the app version, the compiled feature flags, the active update branch
+ * and, when running as a daemon, the uptime in seconds.
  */
-fn get_temp_folder_path() -> String {
-    [BASE_DIRECTORY, TEMP_UPDATE_FOLDER].concat()
+pub fn get_build_info(update_branch: &str, uptime: Option<u64>) -> Result<String, serde_json::Error> {
+    let mut features: Vec<&str> = Vec::new();
+    if cfg!(feature = "SECURE") {
+        features.push("SECURE");
+    }
+    if cfg!(feature = "INSECURE") {
+        features.push("INSECURE");
+    }
+
+    serde_json::to_string(&json!({
+        "name": APP_NAME,
+        "version": APP_VERSION,
+        "features": features,
+        "update_branch": update_branch,
+        "uptime": uptime,
+    }))
 }
 
 /**
  * Loops through the `UpdateComponent` vector (obtained by locking the `UPDATE_COMPONENTS` mutex)
  * determines the component states by running commands using the service/container name.
+ * Components with neither a service nor a container name are reported with the ` - Unconfigured` suffix.
+ * Every component has a `status` (see `ComponentState`) and the legacy `state`, which is `true` if the component is running or paused.
  * The NECO username, used to log into the component network, is used as an ID.
+ * The connection states of NECOs mqtt clients are included so the report tells an offline device from downed components.
+ * Every component carries its `group` (if it has one). If `group` is set, only the members of that group are reported.
  * The `Main` struct is then converted to a JSON-formatted `String`.
  * Mutexes `SETTINGS`, `COMPONENT_VERSIONS`, `UPDATE_COMPONENTS` are locked momentarily.
  */
-pub fn get_component_states() -> Result<String, serde_json::Error> {
+pub fn get_component_states(group: Option<&str>) -> Result<String, serde_json::Error> {
     #[derive(Serialize)]
     struct Main {
         id: String,
+        connections: Connections,
         components: Vec<Component>,
     }
 
+    #[derive(Serialize)]
+    struct Connections {
+        component_mqtt: bool,
+        neutron_mqtt: bool,
+    }
+
     #[derive(Serialize)]
     struct Component {
         component: String,
         version: String,
+        // Kept until the interfaces switch to `status`
         state: bool,
+        status: ComponentState,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        group: Option<String>,
     }
 
     let mut neco_components = Main {
         id: String::new(),
+        connections: Connections {
+            component_mqtt: COMPONENT_MQTT_CONNECTED.load(Ordering::SeqCst),
+            neutron_mqtt: NEUTRON_MQTT_CONNECTED.load(Ordering::SeqCst),
+        },
         components: Vec::new(),
     };
 
     if let Ok(settings) = SETTINGS.lock() {
-        neco_components.id = settings.component_mqtt_client.username.to_owned();
+        neco_components.id = get_client_id(
+            &settings.component_mqtt_client.username,
+            &settings.component_mqtt_client.client_id,
+        )
+        .to_owned();
     } else {
         return Err(serde_json::Error::io(Error::new(
             ErrorKind::Other,
@@ -827,24 +2337,50 @@ pub fn get_component_states() -> Result<String, serde_json::Error> {
             continue;
         }
 
+        if group.is_some() && comp.group.as_deref() != group {
+            continue;
+        }
+
         let ver = component_versions
             .get(&comp.name)
             .unwrap_or(&String::from("Unknown"))
             .to_string();
 
+        if comp.container_name.is_none() && comp.service_name.is_none() {
+            warn!(
+                "Component '{}' has neither a container nor a service name configured.",
+                &comp.name
+            );
+
+            neco_components.components.push(Component {
+                component: [&comp.name, " - Unconfigured"].concat(),
+                version: ver.to_string(),
+                state: false,
+                status: ComponentState::Unknown,
+                group: comp.group.to_owned(),
+            });
+            continue;
+        }
+
         if let Some(name) = comp.container_name {
+            let status = fetch_container_status(&SystemCommandRunner, &name);
             neco_components.components.push(Component {
                 component: [&comp.name, " - Container"].concat(),
                 version: ver.to_string(),
-                state: fetch_container_state(&name),
+                state: status == ComponentState::Running || status == ComponentState::Paused,
+                status,
+                group: comp.group.to_owned(),
             })
         }
 
         if let Some(name) = comp.service_name {
+            let status = get_service_status(&SystemCommandRunner, &name);
             neco_components.components.push(Component {
                 component: [&comp.name, " - Service"].concat(),
                 version: ver.to_string(),
-                state: fetch_service_state(&name),
+                state: status == ComponentState::Running,
+                status,
+                group: comp.group.to_owned(),
             })
         }
     }
@@ -853,43 +2389,176 @@ pub fn get_component_states() -> Result<String, serde_json::Error> {
 }
 
 /**
- * Executes the `systemctl is-active` command and checks if the command returns a non-zero code.
- * Returns false if the command fails to run (also prints out the error), writes to stderr (also prints) or returns a non-zero code.
+ * Looks up the component named `component_name` in the `UpdateComponent` vector and runs its restart command.
+ * Returns `Err` if the component doesn't exist, has no restart command or the restart command fails.
+ *
+ * Mutex `UPDATE_COMPONENTS` is locked momentarily.
+ */
+pub fn restart_component(component_name: &str) -> Result<(), Error> {
+    let restart_command = if let Ok(components) = UPDATE_COMPONENTS.lock() {
+        match components.iter().find(|x| x.name == component_name) {
+            Some(component) => component.restart_command.to_owned(),
+            None => {
+                return Err(Error::new(
+                    ErrorKind::NotFound,
+                    format!("Could not find a component named: '{}'", component_name),
+                ))
+            }
+        }
+    } else {
+        return Err(Error::other("Could not lock UPDATE_COMPONENTS mutex."));
+    };
+
+    if restart_command.trim().is_empty() {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            format!("Component '{}' has no restart command.", component_name),
+        ));
+    }
+
+    warn!("Restarting {} component...", component_name);
+
+    if let Err(e_res) = execute_shell(&SystemCommandRunner, &restart_command) {
+        return Err(Error::other(format!("Failed to restart the component. >> {}", e_res.trim())));
+    }
+
+    Ok(())
+}
+
+// Names of the restarted components with the outcome of their restart
+type RestartResults = Vec<(String, Result<(), Error>)>;
+
+/**
+ * Restarts every member of the component group `group`, one after another.
+ * A member failing to restart doesn't stop the others from being restarted.
+ *
+ * Returns the name of every member with the outcome of its restart.
+ * Returns `Err` if the group has no members.
+ */
+pub fn restart_group(group: &str) -> Result<RestartResults, Error> {
+    Ok(get_group_members(group)?
+        .into_iter()
+        .map(|member| {
+            let result = restart_component(&member.name);
+            (member.name, result)
+        })
+        .collect())
+}
+
+/**
+ * Returns the update components in the group `group`.
+ * Returns `Err` if the group has no members.
+ *
+ * Mutex `UPDATE_COMPONENTS` is locked momentarily.
+ */
+fn get_group_members(group: &str) -> Result<Vec<UpdateComponent>, Error> {
+    let members: Vec<UpdateComponent> = if let Ok(components) = UPDATE_COMPONENTS.lock() {
+        components
+            .iter()
+            .filter(|x| x.group.as_deref() == Some(group))
+            .cloned()
+            .collect()
+    } else {
+        return Err(Error::other("Could not lock UPDATE_COMPONENTS mutex."));
+    };
+
+    if members.is_empty() {
+        return Err(Error::new(
+            ErrorKind::NotFound,
+            format!("Could not find a component group named: '{}'", group),
+        ));
+    }
+
+    Ok(members)
+}
+
+/**
+ * Executes the `systemctl is-active` command and returns the state it printed (`active`, `inactive`, `failed`, `activating`...).
+ * `is-active` exits with a non-zero code for every state except `active`, so the exit code is not treated as a failure.
+ * Returns `None` if the command fails to run or doesn't print a state (e.g. `systemctl` is missing), the error is printed.
  * The `name` parameter is  the name of the service (usually including '.service' at the end).
  */
-fn fetch_service_state(name: &str) -> bool {
+fn fetch_service_state(runner: &dyn CommandRunner, name: &str) -> Option<String> {
     let command = format!("systemctl is-active {}", name);
 
-    match Command::new("sh").arg("-c").arg(command).output() {
+    match runner.output(Command::new("sh").arg("-c").arg(command)) {
         Ok(res) => {
-            if !res.stderr.is_empty() {
-                error!(
-                    "Failed to get service state. {}",
-                    String::from_utf8_lossy(&res.stderr)
-                );
-                return false;
+            let state = String::from_utf8_lossy(&res.stdout).trim().to_owned();
+
+            if state.is_empty() {
+                error!("Failed to get service state. {}", failure_reason(&res));
+                return None;
             }
 
-            return res.status.success();
+            Some(state)
         }
-        Err(e) => error!("Command Digest: Could not execute command. {}", e),
+        Err(e) => {
+            error!("Command Digest: Could not execute command. {}", e);
+            None
+        }
+    }
+}
+
+/**
+ * Maps the `systemctl is-active` state of the service named `name` to a `ComponentState`.
+ * `activating` services are `Restarting`, it's also the state of a crash-looping service waiting for its automatic restart.
+ * `deactivating` services are reported as `Stopped`, `reloading` services are running.
+ */
+fn get_service_status(runner: &dyn CommandRunner, name: &str) -> ComponentState {
+    match fetch_service_state(runner, name).as_deref() {
+        Some("active") | Some("reloading") => ComponentState::Running,
+        Some("inactive") | Some("deactivating") => ComponentState::Stopped,
+        Some("activating") => ComponentState::Restarting,
+        Some("failed") => ComponentState::Failed,
+        Some(state) => {
+            warn!("Unknown service state '{}'. Service: {}", state, name);
+            ComponentState::Unknown
+        }
+        None => ComponentState::Unknown,
     }
+}
+
+/**
+ * Executes the `docker inspect` command and maps the status and exit code of the container named `name` to a `ComponentState`.
+ * Containers that exited with a non-zero code, are restarting (crash-looping) or are dead have `Failed`.
+ * If the container doesn't exist or the command fails, `Unknown` is returned and an error message is printed.
+ */
+fn fetch_container_status(runner: &dyn CommandRunner, name: &str) -> ComponentState {
+    let command = format!("docker inspect -f '{{{{.State.Status}}}} {{{{.State.ExitCode}}}}' {}", name);
+
+    let out = match execute_shell(runner, &command) {
+        Ok(out) => out,
+        Err(e_res) => {
+            error!("Failed to get container status. >> {}", e_res.trim());
+            return ComponentState::Unknown;
+        }
+    };
 
-    false
+    let mut fields = out.split_whitespace();
+    match (fields.next(), fields.next()) {
+        (Some("running"), _) => ComponentState::Running,
+        (Some("paused"), _) => ComponentState::Paused,
+        (Some("exited"), Some("0")) | (Some("created"), _) | (Some("removing"), _) => ComponentState::Stopped,
+        (Some("exited"), _) | (Some("restarting"), _) | (Some("dead"), _) => ComponentState::Failed,
+        _ => {
+            warn!("Unknown container status '{}'. Container: {}", out.trim(), name);
+            ComponentState::Unknown
+        }
+    }
 }
 
 /**
  * Executes the `docker ps` command with some arguments that try to get the ID of the container.
  * If the container is UP or PAUSED this function will return `true`.
- * If the command outputs to stderr, the function returns `false` and an error message is printed.
+ * If the command fails, the function returns `false` and an error message is printed.
  *
  * Will return true even if the container is paused (techically it is still running).
  * The `name` parameter is the name of the docker container.
  */
-fn fetch_container_state(name: &str) -> bool {
+fn fetch_container_state(runner: &dyn CommandRunner, name: &str) -> bool {
     let id_command = format!("docker ps -qf \"name=^{}$\"", name);
 
-    match execute_shell(&id_command) {
+    match execute_shell(runner, &id_command) {
         Ok(out) => !out.is_empty(),
         Err(e_res) => {
             error!("Failed to get container ID. >> {}", e_res.trim());
@@ -905,6 +2574,8 @@ fn fetch_container_state(name: &str) -> bool {
  * After that, we loop through the `UpdateComponent` vector until we find the component with the matching name.
  * If such component cannot be found, `Err` is returned.
  * Then we compare the component type from the request and fetch the log.
+ * The requested number of `lines` is clamped to the `max_log_lines` setting.
+ * If the request has a `group` instead of a `component`, the logs of all the group members are fetched and concatenated.
  * The `JSONOut` struct is then converted to a `String`.
  */
 pub fn get_component_log(data: &str) -> Result<String, serde_json::Error> {
@@ -914,16 +2585,21 @@ pub fn get_component_log(data: &str) -> Result<String, serde_json::Error> {
         data: String,
     }
 
-    // {'id': 'test_neco_aio', 'request': '<random id>', 'component': 'BlackBox - Service'}
+    // {'id': 'test_neco_aio', 'request': '<random id>', 'component': 'BlackBox - Service', 'lines': 100, 'since': '10m'}
+    // {'id': 'test_neco_aio', 'request': '<random id>', 'group': 'web', 'lines': 100}
     #[derive(Deserialize)]
     struct JSONIn {
         request: String,
+        #[serde(default)]
         component: String,
+        group: Option<String>,
+        lines: Option<u32>,
+        since: Option<String>,
     }
 
     // Parse the json to a struct
     let parsed_json: JSONIn;
-    match serde_json::from_str(&data.replace("'", "\"")) {
+    match parse_request_json(data) {
         Ok(result) => parsed_json = result,
         Err(e) => {
             error!("Could not parse get_component_log data. {}", e);
@@ -931,6 +2607,28 @@ pub fn get_component_log(data: &str) -> Result<String, serde_json::Error> {
         }
     }
 
+    // A request can't pull more lines than the settings allow, the default is clamped too
+    let max_lines = max_log_lines();
+    if let Some(lines) = parsed_json.lines.filter(|lines| *lines > max_lines) {
+        warn!("{} log lines requested, only the last {} are fetched.", lines, max_lines);
+    }
+    let lines = parsed_json.lines.unwrap_or(DEFAULT_LOG_LINES).min(max_lines);
+    let max_size = max_log_size();
+    let since = parsed_json.since.as_deref().map(parse_log_since).transpose();
+
+    if let Some(group) = &parsed_json.group {
+        let data = match since {
+            Ok(since) => fetch_group_log(group, lines, since.as_ref(), max_size).map_err(serde_json::Error::io)?,
+            // An invalid time filter is reported in place of the log
+            Err(e) => e,
+        };
+
+        return serde_json::to_string(&JSONOut {
+            request: parsed_json.request,
+            data,
+        });
+    }
+
     // Split the data.component by ' - ' so that we can separate the component name from the component type
     let component_name;
     let comp_type;
@@ -967,18 +2665,27 @@ pub fn get_component_log(data: &str) -> Result<String, serde_json::Error> {
         data: String::new(),
     };
 
+    // An invalid time filter is reported in place of the log
+    let since = match since {
+        Ok(since) => since,
+        Err(e) => {
+            ret_data.data = e;
+            return serde_json::to_string(&ret_data);
+        }
+    };
+
     // Get the component log - it is either a service or a container, we have a variable for the type
     // Save the stdout/stderr to the main struct
     if let Some(component) = update_components.get(0) {
         match comp_type {
             "Service" => {
                 if let Some(n) = &component.service_name {
-                    ret_data.data = fetch_service_log(&n);
+                    ret_data.data = fetch_service_log(&SystemCommandRunner, n, lines, since.as_ref());
                 }
             }
             "Container" => {
                 if let Some(n) = &component.container_name {
-                    ret_data.data = fetch_container_log(&n);
+                    ret_data.data = fetch_container_log(&SystemCommandRunner, n, lines, since.as_ref());
                 }
             }
             _ => {
@@ -1009,18 +2716,130 @@ pub fn get_component_log(data: &str) -> Result<String, serde_json::Error> {
         )));
     }
 
+    ret_data.data = truncate_log(ret_data.data, max_size);
+
     // Convert the main struct to String
     serde_json::to_string(&ret_data)
 }
 
+/**
+ * Fetches the logs of every service and container of the members of the component group `group`.
+ * Each log is preceded by a `==> <component> - <type> <==` header and truncated so the logs together fit into `max_size` bytes.
+ */
+fn fetch_group_log(group: &str, lines: u32, since: Option<&LogSince>, max_size: usize) -> Result<String, Error> {
+    let mut logs: Vec<(String, String)> = Vec::new();
+
+    for member in get_group_members(group)? {
+        if let Some(n) = &member.service_name {
+            let log = fetch_service_log(&SystemCommandRunner, n, lines, since);
+            logs.push(([&member.name, " - Service"].concat(), log));
+        }
+
+        if let Some(n) = &member.container_name {
+            let log = fetch_container_log(&SystemCommandRunner, n, lines, since);
+            logs.push(([&member.name, " - Container"].concat(), log));
+        }
+    }
+
+    let member_max_size = max_size / logs.len().max(1);
+
+    Ok(logs
+        .into_iter()
+        .map(|(component, log)| ["==> ", &component, " <==\n", &truncate_log(log, member_max_size), "\n"].concat())
+        .collect())
+}
+
+/**
+ * If the log is bigger than `max_size` bytes, the oldest part of the log is cut off and
+ *     the `LOG_TRUNCATED_MARKER` is prepended so the receiver knows the log is incomplete.
+ */
+pub fn truncate_log(log: String, max_size: usize) -> String {
+    if log.len() <= max_size {
+        return log;
+    }
+
+    let mut start = log.len() - max_size.saturating_sub(LOG_TRUNCATED_MARKER.len());
+    while !log.is_char_boundary(start) {
+        start += 1;
+    }
+
+    [LOG_TRUNCATED_MARKER, &log[start..]].concat()
+}
+
+/**
+ * Parses the request data as JSON.
+ * If the data isn't valid JSON, we retry after swapping single quotes for double quotes, that way
+ *     we still accept requests from senders that emit Python-style single-quoted JSON.
+ * Returns the error of the first parse attempt if both attempts fail.
+ */
+fn parse_request_json<T: serde::de::DeserializeOwned>(data: &str) -> Result<T, serde_json::Error> {
+    match serde_json::from_str(data) {
+        Ok(result) => Ok(result),
+        Err(e) => serde_json::from_str(&data.replace("'", "\"")).map_err(|_| e),
+    }
+}
+
+/**
+ * The `since` log filter of a log request, translated to the arguments `journalctl` and `docker logs` understand.
+ */
+struct LogSince {
+    journalctl: String,
+    docker: String,
+}
+
+/**
+ * Parses the `since` value of a log request.
+ * Accepts a relative duration (`30s`, `10m`, `2h`, `1d`) or a timestamp formatted as `%Y-%m-%d %H:%M:%S`.
+ *
+ * Returns `Err` with a message meant for the requester if the value is invalid.
+ */
+fn parse_log_since(since: &str) -> Result<LogSince, String> {
+    let since = since.trim();
+
+    if since.len() > 1 {
+        let (amount, unit) = since.split_at(since.len() - 1);
+
+        if let Ok(amount) = amount.parse::<u64>() {
+            // `docker logs` doesn't understand days, so we convert them to hours
+            let spans = match unit {
+                "s" => Some((format!("-{}s", amount), format!("{}s", amount))),
+                "m" => Some((format!("-{}min", amount), format!("{}m", amount))),
+                "h" => Some((format!("-{}h", amount), format!("{}h", amount))),
+                "d" => Some((format!("-{}d", amount), format!("{}h", amount * 24))),
+                _ => None,
+            };
+
+            if let Some((journalctl, docker)) = spans {
+                return Ok(LogSince { journalctl, docker });
+            }
+        }
+    }
+
+    if let Ok(date) = NaiveDateTime::parse_from_str(since, "%Y-%m-%d %H:%M:%S") {
+        return Ok(LogSince {
+            journalctl: date.format("%Y-%m-%d %H:%M:%S").to_string(),
+            docker: date.format("%Y-%m-%dT%H:%M:%S").to_string(),
+        });
+    }
+
+    Err(format!(
+        "Invalid 'since' value: '{}'. Expected a duration (e.g. '10m', '2h') or a timestamp (e.g. '2020-01-31 13:45:00').",
+        since
+    ))
+}
+
 /**
  * Executes the `journalctl -u` command and returns the output (stdout/stderr).
  * The `name` parameter is  the name of the service (usually including '.service' at the end).
+ * Only the last `lines` lines of the log are returned, optionally only the ones logged after `since`.
  */
-fn fetch_service_log(name: &str) -> String {
-    let command = format!("journalctl --no-pager -u {}", name);
+fn fetch_service_log(runner: &dyn CommandRunner, name: &str, lines: u32, since: Option<&LogSince>) -> String {
+    let since_arg = since
+        .map(|since| format!(" --since '{}'", since.journalctl))
+        .unwrap_or_default();
+    let command = format!("journalctl --no-pager -n {}{} -u {}", lines, since_arg, name);
 
-    match execute_shell(&command) {
+    match execute_shell(runner, &command) {
         Ok(res) => res,
         Err(e_res) => format!("Failed to get service log. >> {}", e_res.trim()),
     }
@@ -1029,26 +2848,35 @@ fn fetch_service_log(name: &str) -> String {
 /**
  * Executes the `docker logs` command and returns the output (stdout/stderr).
  * The `name` parameter is the name of the docker container.
+ * Only the last `lines` lines of the log are returned, optionally only the ones logged after `since`.
  */
-fn fetch_container_log(name: &str) -> String {
-    let command = format!("docker logs -t {}", name);
-
-    match execute_shell(&command) {
+fn fetch_container_log(runner: &dyn CommandRunner, name: &str, lines: u32, since: Option<&LogSince>) -> String {
+    let since_arg = since
+        .map(|since| format!(" --since {}", since.docker))
+        .unwrap_or_default();
+    // The container's stderr is forwarded to ours, merge it so it's part of the log
+    let command = format!("docker logs -t --tail {}{} {} 2>&1", lines, since_arg, name);
+
+    match execute_shell(runner, &command) {
         Ok(res) => res,
         Err(e_res) => format!("Failed to get container log. >> {}", e_res.trim()),
     }
 }
 
 /**
- * Executes a given command and returns the output (stdout/stderr) as a `Result`.
+ * Executes a given command through `runner` and returns the output as a `Result`.
+ * Returns `Ok` with the stdout if the command exited with a zero code, otherwise `Err` with the reason it failed (usually the stderr).
  */
-fn execute_shell(command: &str) -> Result<String, String> {
-    match Command::new("sh").arg("-c").arg(command).output() {
+fn execute_shell(runner: &dyn CommandRunner, command: &str) -> Result<String, String> {
+    match runner.output(Command::new("sh").arg("-c").arg(command)) {
         Ok(res) => {
-            return if res.stderr.is_empty() {
+            return if res.status.success() {
+                if !res.stderr.is_empty() {
+                    debug!("'{}' >> {}", command, String::from_utf8_lossy(&res.stderr).trim());
+                }
                 Ok(String::from_utf8_lossy(&res.stdout).into())
             } else {
-                Err(String::from_utf8_lossy(&res.stderr).into())
+                Err(failure_reason(&res))
             }
         }
         Err(e) => error!("Command Digest: Could not execute command. {}", e),
@@ -1056,3 +2884,436 @@ fn execute_shell(command: &str) -> Result<String, String> {
 
     Err(String::from("Internal Error"))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::cell::RefCell;
+    use std::io::Cursor;
+
+    use crate::command_runner::FakeCommandRunner;
+    use data_encoding::HEXLOWER;
+    use flate2::write::GzEncoder;
+    use xz2::write::XzEncoder;
+
+    // (path in the archive, contents, unix mode)
+    const ARCHIVE_FILES: [(&str, &str, u32); 2] = [
+        ("recipe.json", "{\"component\": \"BlackBox\"}", 0o644),
+        ("bin/blackbox.sh", "#!/bin/sh\necho blackbox\n", 0o755),
+    ];
+
+    fn write_tar<W: Write>(writer: W) -> W {
+        let mut builder = tar::Builder::new(writer);
+        for (path, contents, mode) in &ARCHIVE_FILES {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(contents.len() as u64);
+            header.set_mode(*mode);
+            header.set_cksum();
+            builder.append_data(&mut header, path, contents.as_bytes()).unwrap();
+        }
+        builder.into_inner().unwrap()
+    }
+
+    fn write_zip(path: &Path, files: &[(&str, &str, u32)]) {
+        let mut zip = zip::ZipWriter::new(File::create(path).unwrap());
+        for (name, contents, mode) in files {
+            zip.start_file(*name, zip::write::FileOptions::default().unix_permissions(*mode))
+                .unwrap();
+            zip.write_all(contents.as_bytes()).unwrap();
+        }
+        zip.finish().unwrap();
+    }
+
+    #[test]
+    fn unpack_updates_extracts_every_archive_type() {
+        let dir = tempfile::tempdir().unwrap();
+        let archive = |name: &str| dir.path().join(name).to_string_lossy().into_owned();
+
+        write_tar(GzEncoder::new(File::create(archive("1.0.0")).unwrap(), flate2::Compression::default()))
+            .finish()
+            .unwrap();
+        write_tar(XzEncoder::new(File::create(archive("1.1.0")).unwrap(), 6))
+            .finish()
+            .unwrap();
+        write_zip(Path::new(&archive("1.2.0")), &ARCHIVE_FILES);
+
+        let archives = vec![archive("1.0.0"), archive("1.1.0"), archive("1.2.0")];
+        let mut verified_updates = BTreeMap::new();
+        verified_updates.insert(String::from("BlackBox"), archives.clone());
+
+        let inflated_updates = unpack_updates(verified_updates, &UpdateFiles::default());
+
+        let expected: Vec<String> = archives
+            .iter()
+            .map(|x| [x.as_str(), EXTRACTED_FOLDER_SUFFIX, "/"].concat())
+            .collect();
+        assert_eq!(inflated_updates["BlackBox"], expected);
+
+        for (archive, extracted) in archives.iter().zip(&expected) {
+            assert!(!Path::new(archive).exists(), "{} was not removed", archive);
+
+            for (path, contents, mode) in &ARCHIVE_FILES {
+                let file = [extracted.as_str(), path].concat();
+                assert_eq!(std::fs::read_to_string(&file).unwrap(), *contents);
+                assert_eq!(std::fs::metadata(&file).unwrap().permissions().mode() & 0o777, *mode, "{}", file);
+            }
+        }
+    }
+
+    /**
+     * `HttpClient` serving canned responses, the first response whose key is part of the requested URL is served.
+     * Other URLs get a 404. The requested URLs are recorded.
+     */
+    #[derive(Default)]
+    struct FakeHttpClient {
+        responses: Vec<(String, u16, Vec<u8>)>,
+        requests: RefCell<Vec<String>>,
+    }
+
+    impl FakeHttpClient {
+        fn respond(mut self, url_part: &str, status: u16, body: &[u8]) -> Self {
+            self.responses.push((url_part.to_owned(), status, body.to_vec()));
+            self
+        }
+
+        fn requested(&self, url_part: &str) -> bool {
+            self.requests.borrow().iter().any(|x| x.contains(url_part))
+        }
+    }
+
+    impl HttpClient for FakeHttpClient {
+        fn get(&self, url: &str) -> Result<HttpResponse, Error> {
+            self.requests.borrow_mut().push(url.to_owned());
+
+            let (status, body) = self
+                .responses
+                .iter()
+                .find(|(url_part, _, _)| url.contains(url_part.as_str()))
+                .map(|(_, status, body)| (*status, body.clone()))
+                .unwrap_or((404, Vec::new()));
+
+            Ok(HttpResponse {
+                status,
+                content_length: Some(body.len() as u64),
+                body: Box::new(Cursor::new(body)),
+            })
+        }
+
+        fn post_json(&self, url: &str, _body: &serde_json::Value) -> Result<HttpResponse, Error> {
+            self.get(url)
+        }
+    }
+
+    fn package_update(version: &str, chainlink: bool, package: &[u8]) -> structs::Update {
+        structs::Update {
+            chainlink,
+            checksum: HEXLOWER.encode(ring::digest::digest(&ring::digest::SHA256, package).as_ref()),
+            version: version.to_owned(),
+            file_size: Some(package.len().to_string()),
+            ..Default::default()
+        }
+    }
+
+    const NEUTRON: &str = "http://neutron";
+    const MIRROR: &str = "http://mirror";
+
+    fn download_url(version: &str) -> String {
+        ["component=BlackBox&version=", version].concat()
+    }
+
+    /**
+     * Downloads the `updates` of the BlackBox component from the `http_client` into the `temp_root`.
+     */
+    fn download_updates(
+        http_client: &FakeHttpClient,
+        endpoints: &[&str],
+        updates: Vec<structs::Update>,
+        temp_root: &Path,
+    ) -> BTreeMap<String, Vec<String>> {
+        let mut update_manifest = structs::UpdateManifest::default();
+        update_manifest.list.insert(String::from("BlackBox"), updates);
+
+        let update_files = UpdateFiles {
+            temp_root: Some(temp_root.to_string_lossy().into_owned()),
+            ..Default::default()
+        };
+
+        dload_and_verify_updates(
+            None,
+            http_client,
+            update_manifest,
+            "username=test",
+            &BTreeMap::new(),
+            &BTreeSet::new(),
+            &endpoints.iter().map(|x| x.to_string()).collect::<Vec<String>>(),
+            &update_files,
+            None,
+            &DownloadLimits::default(),
+            DEFAULT_HASH_BUFFER_SIZE,
+            &None,
+        )
+    }
+
+    #[test]
+    fn downloaded_updates_are_verified_and_saved() {
+        let dir = tempfile::tempdir().unwrap();
+        let http_client = FakeHttpClient::default().respond(&download_url("1.0.0"), 200, b"package 1.0.0");
+
+        let verified_updates = download_updates(
+            &http_client,
+            &[NEUTRON],
+            vec![package_update("1.0.0", false, b"package 1.0.0")],
+            dir.path(),
+        );
+
+        assert_eq!(std::fs::read(&verified_updates["BlackBox"][0]).unwrap(), b"package 1.0.0");
+        assert!(http_client.requested(&[NEUTRON, "/version_control/download?username=test&"].concat()));
+    }
+
+    #[test]
+    fn download_falls_back_to_the_mirror() {
+        let dir = tempfile::tempdir().unwrap();
+        let http_client = FakeHttpClient::default()
+            .respond(NEUTRON, 503, b"")
+            .respond(MIRROR, 200, b"package 1.0.0");
+
+        let verified_updates = download_updates(
+            &http_client,
+            &[NEUTRON, MIRROR],
+            vec![package_update("1.0.0", false, b"package 1.0.0")],
+            dir.path(),
+        );
+
+        assert_eq!(std::fs::read(&verified_updates["BlackBox"][0]).unwrap(), b"package 1.0.0");
+    }
+
+    #[test]
+    fn download_drops_missing_and_incomplete_packages() {
+        let dir = tempfile::tempdir().unwrap();
+        let http_client = FakeHttpClient::default().respond(&download_url("1.1.0"), 200, b"package 1.1");
+
+        let verified_updates = download_updates(
+            &http_client,
+            &[NEUTRON],
+            vec![
+                package_update("1.0.0", false, b"package 1.0.0"),
+                package_update("1.1.0", false, b"package 1.1.0"),
+            ],
+            dir.path(),
+        );
+
+        assert!(verified_updates.is_empty());
+        // The incomplete package is removed
+        assert!(read_dir(dir.path().join(TEMP_UPDATE_FOLDER).join("BlackBox")).unwrap().next().is_none());
+    }
+
+    fn manifest_response(manifest: serde_json::Value) -> Vec<u8> {
+        json!({ "result": true, "msg": { "manifest": manifest } }).to_string().into_bytes()
+    }
+
+    #[test]
+    fn request_manifest_parses_and_sorts_the_updates() {
+        let update = |version: &str| {
+            json!({ "chainlink": false, "checksum": "", "version": version, "changelog": "", "file_size": null })
+        };
+        let http_client = FakeHttpClient::default().respond(
+            "/api/versioncontrol",
+            200,
+            &manifest_response(json!({ "BlackBox": [update("1.10.0"), update("1.2.0"), update("1.9.1")] })),
+        );
+
+        let manifest = request_manifest(&http_client, &[NEUTRON.to_owned()], "/api/versioncontrol?x=1")
+            .unwrap()
+            .unwrap();
+
+        let versions: Vec<&str> = manifest.list["BlackBox"].iter().map(|x| x.version.as_str()).collect();
+        assert_eq!(versions, vec!["1.2.0", "1.9.1", "1.10.0"]);
+    }
+
+    #[test]
+    fn request_manifest_without_updates_is_none() {
+        let http_client = FakeHttpClient::default().respond("/api/versioncontrol", 200, &manifest_response(json!({})));
+
+        assert!(request_manifest(&http_client, &[NEUTRON.to_owned()], "/api/versioncontrol")
+            .unwrap()
+            .is_none());
+    }
+
+    #[test]
+    fn request_manifest_reports_retryable_and_bad_responses() {
+        let kind = |status: u16, body: &[u8]| {
+            let http_client = FakeHttpClient::default().respond("/api/versioncontrol", status, body);
+            request_manifest(&http_client, &[NEUTRON.to_owned()], "/api/versioncontrol")
+                .unwrap_err()
+                .kind()
+        };
+
+        assert_eq!(kind(503, b""), ErrorKind::Interrupted);
+        assert_eq!(kind(200, b""), ErrorKind::Interrupted);
+        assert_eq!(kind(200, br#"{"result": false, "retry": true, "msg": "Warming up."}"#), ErrorKind::Interrupted);
+        assert_eq!(kind(200, b"<html>Bad gateway</html>"), ErrorKind::InvalidData);
+        assert_eq!(kind(200, br#"{"result": false, "msg": "Unknown device."}"#), ErrorKind::Other);
+        assert_eq!(kind(404, b""), ErrorKind::Other);
+    }
+
+    #[test]
+    fn broken_chainlink_skips_the_later_updates() {
+        // The 1.1.0 package fails the checksum verification
+        let dir = tempfile::tempdir().unwrap();
+        let http_client = FakeHttpClient::default()
+            .respond(&download_url("1.0.0"), 200, b"package 1.0.0")
+            .respond(&download_url("1.1.0"), 200, b"package 1.1.X")
+            .respond(&download_url("1.2.0"), 200, b"package 1.2.0");
+
+        let verified_updates = download_updates(
+            &http_client,
+            &[NEUTRON],
+            vec![
+                package_update("1.0.0", true, b"package 1.0.0"),
+                package_update("1.1.0", true, b"package 1.1.0"),
+                package_update("1.2.0", false, b"package 1.2.0"),
+            ],
+            dir.path(),
+        );
+
+        assert_eq!(verified_updates["BlackBox"].len(), 1);
+        assert!(verified_updates["BlackBox"][0].ends_with("/BlackBox/1.0.0"));
+        assert!(http_client.requested(&download_url("1.1.0")));
+        assert!(!http_client.requested(&download_url("1.2.0")));
+    }
+
+    #[test]
+    fn broken_update_without_chainlink_keeps_the_later_updates() {
+        let dir = tempfile::tempdir().unwrap();
+        let http_client = FakeHttpClient::default()
+            .respond(&download_url("1.0.0"), 200, b"package 1.0.X")
+            .respond(&download_url("1.1.0"), 200, b"package 1.1.0");
+
+        let verified_updates = download_updates(
+            &http_client,
+            &[NEUTRON],
+            vec![
+                package_update("1.0.0", false, b"package 1.0.0"),
+                package_update("1.1.0", false, b"package 1.1.0"),
+            ],
+            dir.path(),
+        );
+
+        assert_eq!(verified_updates["BlackBox"].len(), 1);
+        assert!(verified_updates["BlackBox"][0].ends_with("/BlackBox/1.1.0"));
+    }
+
+    #[test]
+    fn fetch_service_state_of_an_active_service() {
+        let runner = FakeCommandRunner::default().respond(0, "active\n", "");
+
+        assert_eq!(fetch_service_state(&runner, "blackbox.service").as_deref(), Some("active"));
+        assert_eq!(runner.commands(), vec!["sh -c systemctl is-active blackbox.service"]);
+    }
+
+    #[test]
+    fn fetch_service_state_of_an_inactive_service() {
+        // `is-active` exits with 3 for every state but `active`
+        let runner = FakeCommandRunner::default()
+            .respond(3, "inactive\n", "")
+            .respond(3, "activating\n", "");
+
+        assert_eq!(fetch_service_state(&runner, "blackbox.service").as_deref(), Some("inactive"));
+        assert_eq!(get_service_status(&runner, "blackbox.service"), ComponentState::Restarting);
+    }
+
+    #[test]
+    fn fetch_service_state_of_a_missing_unit() {
+        // systemd reports units it doesn't know as `inactive`, older versions as `unknown`
+        let runner = FakeCommandRunner::default()
+            .respond(3, "inactive\n", "")
+            .respond(3, "unknown\n", "")
+            .respond(1, "", "Failed to connect to bus: No such file or directory\n")
+            .fail(ErrorKind::NotFound);
+
+        assert_eq!(get_service_status(&runner, "missing.service"), ComponentState::Stopped);
+        assert_eq!(get_service_status(&runner, "missing.service"), ComponentState::Unknown);
+        assert_eq!(fetch_service_state(&runner, "missing.service"), None);
+        assert_eq!(fetch_service_state(&runner, "missing.service"), None);
+    }
+
+    #[test]
+    fn extract_archive_rejects_zip_entries_outside_the_destination() {
+        let dir = tempfile::tempdir().unwrap();
+        let archive = dir.path().join("update.zip");
+        let destination = dir.path().join("extracted");
+        write_zip(&archive, &[("../escaped.txt", "escaped", 0o644)]);
+
+        let result = extract_archive(
+            &archive.to_string_lossy(),
+            ArchiveType::Zip,
+            &destination.to_string_lossy(),
+        );
+
+        assert_eq!(result.unwrap_err().kind(), ErrorKind::InvalidData);
+        assert!(!dir.path().join("escaped.txt").exists());
+    }
+
+    #[derive(Deserialize, Debug, PartialEq)]
+    struct TestRequest {
+        request: String,
+        lines: Option<u32>,
+    }
+
+    #[test]
+    fn parse_request_json_accepts_json_and_single_quotes() {
+        let expected = TestRequest {
+            request: String::from("abc"),
+            lines: Some(100),
+        };
+
+        assert_eq!(
+            parse_request_json::<TestRequest>(r#"{"request": "abc", "lines": 100}"#).unwrap(),
+            expected
+        );
+        assert_eq!(
+            parse_request_json::<TestRequest>("{'request': 'abc', 'lines': 100}").unwrap(),
+            expected
+        );
+    }
+
+    #[test]
+    fn parse_request_json_rejects_malformed_input() {
+        assert!(parse_request_json::<TestRequest>("").is_err());
+        assert!(parse_request_json::<TestRequest>(r#"{"request": "abc""#).is_err());
+        assert!(parse_request_json::<TestRequest>("request=abc").is_err());
+    }
+
+    #[test]
+    fn parse_request_json_rejects_missing_fields() {
+        let e = parse_request_json::<TestRequest>(r#"{"lines": 100}"#).unwrap_err();
+        assert!(e.to_string().contains("missing field `request`"), "{}", e);
+
+        // Optional fields can be left out
+        assert_eq!(parse_request_json::<TestRequest>("{'request': 'abc'}").unwrap().lines, None);
+    }
+
+    #[test]
+    fn leftover_updates_keep_the_staged_ones() {
+        let dir = tempfile::tempdir().unwrap();
+        let update_files = UpdateFiles {
+            temp_root: Some(dir.path().to_string_lossy().into_owned()),
+            leftover_updates_file: String::from("leftover.json"),
+            ..Default::default()
+        };
+        std::fs::create_dir_all(get_temp_folder_path(&update_files)).unwrap();
+
+        let updates = |entries: &[(&str, &str)]| -> BTreeMap<String, Vec<String>> {
+            entries.iter().map(|(k, v)| (k.to_string(), vec![v.to_string()])).collect()
+        };
+        save_leftover_updates(&updates(&[("BlackBox", "blackbox-1.0.0"), ("Radar", "radar-2.0.0")]), &update_files).unwrap();
+
+        add_leftover_updates(&updates(&[("Radar", "radar-2.1.0"), ("Sonar", "sonar-1.0.0")]), &update_files).unwrap();
+
+        assert_eq!(
+            load_leftover_updates(&update_files).unwrap(),
+            updates(&[("BlackBox", "blackbox-1.0.0"), ("Radar", "radar-2.1.0"), ("Sonar", "sonar-1.0.0")])
+        );
+    }
+}
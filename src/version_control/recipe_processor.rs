@@ -1,34 +1,102 @@
 use fs_extra;
 
-use std::fs::copy;
-use std::path::Path;
-use std::process::Command;
+use std::fs::{copy, create_dir, create_dir_all, metadata, read_to_string, remove_file, rename, write};
+use std::io::{Error, ErrorKind};
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output};
 use std::sync::atomic::Ordering;
+use std::thread::sleep;
+use std::time::Duration;
 
-use crate::{APP_NAME, COMPONENT_VERSIONS, RESTART_NECO, UPDATE_COMPONENTS};
+use crate::command_runner::{failure_reason, CommandRunner};
+use crate::{APP_NAME, BASE_DIRECTORY, COMPONENT_VERSIONS, RESTART_NECO, SETTINGS, UPDATE_COMPONENTS};
 
-use super::find_leftover_updates;
-use super::security::set_file_permissions;
+use super::{fetch_container_state, find_leftover_updates};
+use super::ledger;
+use super::recovery::{backup_neco_binary, restore_neco_binary};
+use super::security::{parse_file_permissions, set_file_permissions};
+use super::structs::{SandboxEntry, SandboxReport};
 
-const DEV_DIR: &str = "/home/system/.neco_test_dir/";
+// File name of the NECO binary in the NECO update packages
+const NECO_BINARY_NAME: &str = env!("CARGO_BIN_NAME");
+// Seconds a `run_command`/`run_script` instruction (or a restart command) may run, if the instruction doesn't set a `timeout`
+const DEFAULT_INSTRUCTION_TIMEOUT: u64 = 5 * 60;
+// Seconds to wait after a restart before checking the component health, if the component doesn't set it
+const DEFAULT_HEALTH_CHECK_GRACE_PERIOD: u64 = 10;
+// Output of the commands ran while installing a component, one file per component, overwritten on every install
+const INSTALL_LOG_FOLDER: &str = "install_logs/";
+
+/**
+ * Where a sandboxed cook puts the files instead of the live system, see `cook_in_sandbox()`.
+ */
+struct Sandbox<'a> {
+    root: &'a str,
+    entries: Vec<SandboxEntry>,
+}
+
+impl Sandbox<'_> {
+    /**
+     * Returns the `path` of the live system moved under the sandbox root, e.g. "/etc/app/" -> "<root>/etc/app/".
+     */
+    fn path(&self, path: &str) -> String {
+        [self.root.trim_end_matches('/'), "/", path.trim_start_matches('/')].concat()
+    }
+
+    fn record(&mut self, component: &str, instruction: &str, source: &str, destination: Option<String>) {
+        self.entries.push(SandboxEntry {
+            component: component.to_owned(),
+            instruction: instruction.to_owned(),
+            source: source.to_owned(),
+            destination,
+        });
+    }
+}
 
 /**
  * Reads through the cookbook and executes (digests) the commands.
+ * External commands (`run_command`, `run_script`, restarts) are executed through `runner`.
+ * If `sandbox_root` is set, the cookbook is cooked into it instead of the live system (see `cook_in_sandbox()`).
  *
- * NOTICE: When in debug, `restart` command will still be executed.
- * NOTICE: When in debug, `copy` instructions are directed into a special folder.
+ * Returns the names of the components that failed to upgrade, the vector is empty if everything succeeded.
  */
-pub fn cook(cookbook: &[serde_json::Value]) -> bool {
-    info!("Heating up the oven...");
+pub fn cook(runner: &dyn CommandRunner, cookbook: &[serde_json::Value], sandbox_root: Option<&str>) -> Vec<String> {
+    match sandbox_root {
+        Some(sandbox_root) => cook_in_sandbox(runner, cookbook, sandbox_root).failed_components,
+        None => cook_components(runner, cookbook, None),
+    }
+}
 
-    if cfg!(debug_assertions) && !Path::new(DEV_DIR).exists() {
-        info!("DEV: Creating dev directory ");
-        if let Err(e) = std::fs::create_dir(DEV_DIR) {
-            error!("Failed to create dev directory. {}", e);
-        }
+/**
+ * Cooks the cookbook without touching the live system, to check that the updates install correctly.
+ * The `copy` and `copy_dir` destinations are moved under `sandbox_root` (their missing folders are created),
+ *     the `run_command` and `run_script` instructions are skipped, the components are not restarted and
+ *     neither the installed versions, the update ledger nor the install logs are changed.
+ *
+ * Returns the names of the components that failed and every copied file or folder (skipped instructions included).
+ */
+pub fn cook_in_sandbox(runner: &dyn CommandRunner, cookbook: &[serde_json::Value], sandbox_root: &str) -> SandboxReport {
+    let mut sandbox = Sandbox {
+        root: sandbox_root,
+        entries: Vec::new(),
+    };
+
+    let failed_components = cook_components(runner, cookbook, Some(&mut sandbox));
+
+    SandboxReport {
+        failed_components,
+        entries: sandbox.entries,
     }
+}
+
+fn cook_components(
+    runner: &dyn CommandRunner,
+    cookbook: &[serde_json::Value],
+    mut sandbox: Option<&mut Sandbox>,
+) -> Vec<String> {
+    info!("Heating up the oven...");
 
-    let mut is_succesfull = true;
+    let mut failed_components: Vec<String> = Vec::new();
 
     for component in cookbook {
         //info!("COMPONENT NAME: {}", component["component"]);
@@ -39,6 +107,9 @@ pub fn cook(cookbook: &[serde_json::Value]) -> bool {
 
         let mut erroneous: bool = false;
 
+        let component_name = component["component"].as_str().unwrap_or_default();
+        let mut install_log = String::new();
+
         let comp_recipes: Vec<serde_json::Value> =
             serde_json::value::from_value(component["updates"].clone()).unwrap_or_default();
 
@@ -48,62 +119,215 @@ pub fn cook(cookbook: &[serde_json::Value]) -> bool {
             match recipe["type"].as_str().unwrap_or_default() {
                 "copy" => {
                     //info!("Exec copy.");
-                    if digest_copy(
-                        &recipe["absolute_update_path"].as_str().unwrap_or_default(),
-                        &recipe["file_path"].as_str().unwrap_or_default(),
-                        if cfg!(debug_assertions) {
-                            &DEV_DIR
-                        } else {
-                            &recipe["destination"].as_str().unwrap_or_default()
-                        },
-                        &recipe["permission_user"].as_str().unwrap_or_default(),
-                        &recipe["permission_group"].as_str().unwrap_or_default(),
-                        &recipe["file_permissions"].as_str().unwrap_or_default(),
-                    )
-                    .is_err()
-                    {
-                        erroneous = true;
+                    let absolute_update_path = recipe["absolute_update_path"].as_str().unwrap_or_default();
+                    let destination = if let Some(sandbox) = &sandbox {
+                        sandbox.path(recipe["destination"].as_str().unwrap_or_default())
+                    } else {
+                        recipe["destination"].as_str().unwrap_or_default().to_owned()
+                    };
+                    let destination = destination.as_str();
+                    let file_permissions = recipe["file_permissions"].as_str().unwrap_or_default();
+
+                    let file_paths =
+                        match expand_file_path(absolute_update_path, recipe["file_path"].as_str().unwrap_or_default()) {
+                            Ok(file_paths) => file_paths,
+                            Err(e) => {
+                                error!("{}", e);
+                                erroneous = true;
+                                continue;
+                            }
+                        };
+
+                    for file_path in &file_paths {
+                        let file_path = file_path.as_str();
+
+                        // A broken NECO binary would leave us without an updater, so it has to run before it replaces the current one
+                        let is_neco_binary = component_name == APP_NAME
+                            && Path::new(file_path).file_name() == Some(NECO_BINARY_NAME.as_ref());
+                        if is_neco_binary {
+                            let staged_binary = [absolute_update_path, file_path].concat();
+
+                            if set_file_permissions(&staged_binary, "root", "root", file_permissions).is_err() {
+                                erroneous = true;
+                                continue;
+                            }
+                            if let Err(e) = check_neco_binary(runner, &staged_binary) {
+                                error!("New NECO binary failed the sanity check, keeping the current one. {}", e);
+                                erroneous = true;
+                                continue;
+                            }
+
+                            // The backup is restored if the new NECO keeps failing to start
+                            if sandbox.is_none() {
+                                if let Err(e) = backup_neco_binary(&[destination, file_path].concat()) {
+                                    error!("Could not back up the current NECO binary, keeping it. {}", e);
+                                    erroneous = true;
+                                    continue;
+                                }
+                            }
+                        }
+
+                        if digest_copy(
+                            absolute_update_path,
+                            file_path,
+                            destination,
+                            recipe["permission_user"].as_str().unwrap_or_default(),
+                            recipe["permission_group"].as_str().unwrap_or_default(),
+                            file_permissions,
+                            recipe["create_destination"].as_bool().unwrap_or(false) || sandbox.is_some(),
+                            is_neco_binary,
+                        )
+                        .is_err()
+                        {
+                            erroneous = true;
+                            continue;
+                        }
+
+                        if let Some(sandbox) = sandbox.as_deref_mut() {
+                            sandbox.record(
+                                component_name,
+                                "copy",
+                                &[absolute_update_path, file_path].concat(),
+                                Some([destination, file_path].concat()),
+                            );
+                        }
+
+                        if is_neco_binary {
+                            if let Err(e) = check_neco_binary(runner, &[destination, file_path].concat()) {
+                                error!("Installed NECO binary failed the sanity check. {}", e);
+                                erroneous = true;
+
+                                if sandbox.is_none() {
+                                    if let Err(e) = restore_neco_binary() {
+                                        error!("Could not restore the previous NECO binary. {}", e);
+                                    }
+                                }
+                            }
+                        }
                     }
                 }
                 "copy_dir" => {
-                    if !cfg!(debug_assertions)
-                        && digest_copy_dir(
-                            &recipe["folder_path"].as_str().unwrap_or_default(),
-                            &recipe["destination"].as_str().unwrap_or_default(),
-                        )
-                        .is_err()
-                    {
-                        erroneous = true;
+                    let folder_path = recipe["folder_path"].as_str().unwrap_or_default();
+                    let destination = recipe["destination"].as_str().unwrap_or_default();
+
+                    let copied_dir = if let Some(sandbox) = sandbox.as_deref_mut() {
+                        let destination = sandbox.path(destination);
+                        // Only the parent, `digest_copy_dir()` copies into the destination if it already exists
+                        let parent = Path::new(&destination).parent().unwrap_or_else(|| Path::new("/"));
+
+                        if let Err(e) = create_dir_all(parent) {
+                            error!("Failed to create the sandbox folder '{}'. {}", parent.display(), e);
+                            Err(())
+                        } else {
+                            let copied_dir = digest_copy_dir(folder_path, &destination);
+                            if copied_dir.is_ok() {
+                                sandbox.record(component_name, "copy_dir", folder_path, Some(destination));
+                            }
+                            copied_dir
+                        }
+                    } else {
+                        digest_copy_dir(folder_path, destination)
+                    };
+
+                    match copied_dir {
+                        // Only set if the recipe asked for it, see `get_recipes()`
+                        Ok(copied_dir) if recipe["file_permissions"].is_string() => {
+                            if set_copied_dir_permissions(
+                                &copied_dir,
+                                recipe["permission_user"].as_str().unwrap_or_default(),
+                                recipe["permission_group"].as_str().unwrap_or_default(),
+                                recipe["file_permissions"].as_str().unwrap_or_default(),
+                                recipe["dir_permissions"].as_str(),
+                            )
+                            .is_err()
+                            {
+                                erroneous = true;
+                            }
+                        }
+                        Ok(_) => {}
+                        Err(()) => erroneous = true,
+                    }
+                }
+                "run_command" | "run_script" if sandbox.is_some() => {
+                    let instruction = recipe["type"].as_str().unwrap_or_default();
+                    let source = match recipe["command"].as_str() {
+                        Some(command) => command.to_owned(),
+                        None => [
+                            recipe["absolute_update_path"].as_str().unwrap_or_default(),
+                            recipe["file_path"].as_str().unwrap_or_default(),
+                        ]
+                        .concat(),
+                    };
+
+                    info!("Sandbox: Skipping {} of component {}. >> {}", instruction, component_name, source);
+                    if let Some(sandbox) = sandbox.as_deref_mut() {
+                        sandbox.record(component_name, instruction, &source, None);
                     }
                 }
                 "run_command" => {
                     //info!("Exec command.");
-                    if !cfg!(debug_assertions) {
-                        digest_run(&recipe["command"].as_str().unwrap_or_default());
+                    if !digest_run(
+                        runner,
+                        recipe["command"].as_str().unwrap_or_default(),
+                        get_instruction_timeout(&recipe),
+                        &mut install_log,
+                    ) {
+                        erroneous = true;
                     }
                 }
                 "run_script" => {
                     //info!("Exec script.");
-                    if !cfg!(debug_assertions) {
-                        digest_script(
-                            &recipe["absolute_update_path"].as_str().unwrap_or_default(),
-                            &recipe["file_path"].as_str().unwrap_or_default(),
-                        );
+                    if !digest_script(
+                        runner,
+                        recipe["absolute_update_path"].as_str().unwrap_or_default(),
+                        recipe["file_path"].as_str().unwrap_or_default(),
+                        get_instruction_timeout(&recipe),
+                        &mut install_log,
+                    ) {
+                        erroneous = true;
                     }
                 }
                 _ => error!("Unknown recipe command type. Type: {}", &recipe["type"]),
             }
         }
 
-        if !restart_set_component_version(
+        if sandbox.is_some() {
+            info!(
+                "Sandbox: Component: {} Install: {}",
+                component_name,
+                if erroneous { "FAILED" } else { "SUCCESSFUL" }
+            );
+
+            if erroneous {
+                failed_components.push(component_name.to_owned());
+            }
+            continue;
+        }
+
+        // NECO must not restart into a binary that failed to install
+        if component_name == APP_NAME && erroneous {
+            error!("NECO upgrade failed. Not restarting NECO.");
+        } else if !restart_set_component_version(
+            runner,
             serde_json::from_value(component["restart"].clone()).unwrap_or_default(),
-            component["component"].as_str().unwrap_or_default(),
+            component_name,
             component["restart_command"].as_str().unwrap_or_default(),
+            &component["health_check"],
             component["final_version"].as_str().unwrap_or_default(),
+            &mut install_log,
         ) {
             erroneous = true;
         }
 
+        if let Err(e) = write_install_log(
+            component_name,
+            component["final_version"].as_str().unwrap_or_default(),
+            !erroneous,
+            &install_log,
+        ) {
+            warn!("Could not write the install log of component {}. {}", component_name, e);
+        }
+
         let status = format!(
             "Component: {} Upgrade: {}",
             &component["component"],
@@ -112,27 +336,64 @@ pub fn cook(cookbook: &[serde_json::Value]) -> bool {
 
         info!("{}", &status);
 
-        is_succesfull = !erroneous;
+        if erroneous {
+            failed_components.push(
+                component["component"]
+                    .as_str()
+                    .unwrap_or_default()
+                    .to_owned(),
+            );
+        } else if let Err(e) = ledger::record(
+            component["component"].as_str().unwrap_or_default(),
+            &get_update_versions(component),
+        ) {
+            warn!("Could not record the installed update versions. {}", e);
+        }
     }
 
     info!("Dinner's ready!");
 
-    is_succesfull
+    failed_components
+}
+
+/**
+ * Collects the versions of every update in the `updates` array of a cookbook component.
+ */
+fn get_update_versions(component: &serde_json::Value) -> Vec<String> {
+    let mut versions: Vec<String> = Vec::new();
+
+    if let Some(updates) = component["updates"].as_array() {
+        for instruction in updates {
+            if let Some(version) = instruction["version"].as_str() {
+                if !versions.iter().any(|x| x == version) {
+                    versions.push(version.to_owned());
+                }
+            }
+        }
+    }
+
+    versions
 }
 
 /**
  * Checks if `restart` is true.
  * If it is, check if the `component_name` is the same as `APP_NAME`.
  *     That means if NECO need to restart, just set the `RESTART_NECO` `AtomicBool` to true so we can escape the main loop.
- *     If the `component_name` is not the same as `APP_NAME`, run the restart command for that component with `digest_run()`.
+ *     If the `component_name` is not the same as `APP_NAME`, run the restart command for that component with `digest_run()`,
+ *     then verify the component came back up with `check_component_health()`.
+ *     While it's not healthy, the restart is re-issued up to `restart_retries` (key of `health_check`) times.
+ *     If it never became healthy, the version is not updated.
  *
  * Returns `bool` true if no errors raised.
  */
 fn restart_set_component_version(
+    runner: &dyn CommandRunner,
     restart: bool,
     component_name: &str,
     restart_command: &str,
+    health_check: &serde_json::Value,
     version: &str,
+    install_log: &mut String,
 ) -> bool {
     if component_name == APP_NAME {
         if restart {
@@ -141,8 +402,16 @@ fn restart_set_component_version(
         } else {
             // Install leftover updates if we don't need to restart NECO
             // This will make the NECO upgrade status show up last, it will actually only print the component upgrade success after everything has finished
+            let (update_files, maintenance_window) = if let Ok(settings) = SETTINGS.lock() {
+                (settings.update_files.to_owned(), settings.maintenance_window.to_owned())
+            } else {
+                error!("Could not acquire lock for settings object. Skipping leftover updates...");
+                return false;
+            };
+
             if let Ok(data) = UPDATE_COMPONENTS.lock() {
-                find_leftover_updates(&data);
+                // Only a live cook restarts components, so these are installed live too
+                find_leftover_updates(&data, &update_files, &maintenance_window, None);
             } else {
                 error!("Could not acquire lock for update_components object. Skipping leftover updates...");
                 return false;
@@ -151,38 +420,125 @@ fn restart_set_component_version(
 
         // This actually isn't necessary, but it doesn't hurt
         // We don't need to update the NECO version number when we're restarting NECO
-        // But it stops Clippy from complaining about collapsable if's
-        if let Ok(mut ver) = COMPONENT_VERSIONS.lock() {
-            if ver
-                .insert(APP_NAME.to_owned(), version.to_owned())
-                .is_none()
-            {
-                warn!("Could not find NECO version number to update?? This is a bug bois!");
-                return false;
-            }
-        }
+        set_component_version(APP_NAME, version);
     } else {
         if restart {
-            warn!("Restarting {} component...", component_name);
-            //digest_run(&component["restart_command"].as_str().unwrap_or_default());
-            digest_run(restart_command);
-        }
+            let restart_retries = health_check["restart_retries"].as_u64().unwrap_or_default();
+            let mut healthy = false;
+
+            for attempt in 0..=restart_retries {
+                if attempt == 0 {
+                    warn!("Restarting {} component...", component_name);
+                } else {
+                    warn!(
+                        "Restarting {} component again (retry {}/{})...",
+                        component_name, attempt, restart_retries
+                    );
+                }
+
+                //digest_run(&component["restart_command"].as_str().unwrap_or_default());
+                if digest_run(runner, restart_command, Duration::from_secs(DEFAULT_INSTRUCTION_TIMEOUT), install_log)
+                    && check_component_health(runner, component_name, health_check)
+                {
+                    healthy = true;
+                    break;
+                }
+            }
 
-        // SET NEW COMPONENT VERSION
-        if let Ok(mut ver) = COMPONENT_VERSIONS.lock() {
-            if ver
-                .insert(component_name.to_owned(), version.to_owned())
-                .is_none()
-            {
-                warn!("Could not find component version to update?? This is a bug if I've ever seen one...");
+            if !healthy {
                 return false;
             }
         }
+
+        set_component_version(component_name, version);
     }
 
     true
 }
 
+/**
+ * Records `version` as the installed version of `component_name` in `COMPONENT_VERSIONS`.
+ * A component without a recorded version (e.g. one added since NECO started) simply gets its first one.
+ * The update itself already succeeded at this point, so failing to lock the mutex is only logged,
+ * `reconcile_component_versions()` corrects the recorded version later.
+ *
+ * Mutex `COMPONENT_VERSIONS` is locked momentarily.
+ */
+fn set_component_version(component_name: &str, version: &str) {
+    if let Ok(mut versions) = COMPONENT_VERSIONS.lock() {
+        if versions.insert(component_name.to_owned(), version.to_owned()).is_none() {
+            info!("Recorded the first version of {} component: {}.", component_name, version);
+        }
+    } else {
+        error!("Could not lock COMPONENT_VERSIONS mutex. Version of {} component not recorded.", component_name);
+    }
+}
+
+/**
+ * Waits for the grace period in `health_check` and then checks if the restarted component is healthy.
+ * If the component has a health check command, it's ran and the component is healthy if it exits with a zero code.
+ * Otherwise, container components are healthy if their container is running, other components are assumed healthy.
+ *
+ * Returns `bool` true if the component is healthy.
+ */
+fn check_component_health(
+    runner: &dyn CommandRunner,
+    component_name: &str,
+    health_check: &serde_json::Value,
+) -> bool {
+    let command = health_check["command"].as_str();
+    let container_name = health_check["container_name"].as_str();
+
+    if command.is_none() && container_name.is_none() {
+        return true;
+    }
+
+    let grace_period = health_check["grace_period"]
+        .as_u64()
+        .unwrap_or(DEFAULT_HEALTH_CHECK_GRACE_PERIOD);
+    info!(
+        "Checking the health of component {} in {} seconds...",
+        component_name, grace_period
+    );
+    sleep(Duration::from_secs(grace_period));
+
+    let healthy = if let Some(command) = command {
+        match runner.output(Command::new("sh").arg("-c").arg(command)) {
+            Ok(res) => {
+                if !res.status.success() {
+                    error!(
+                        "Health check failed. Component: {} >> {}",
+                        component_name,
+                        String::from_utf8_lossy(&res.stderr).trim()
+                    );
+                }
+                res.status.success()
+            }
+            Err(e) => {
+                error!("Health Check: Could not execute command. {}", e);
+                false
+            }
+        }
+    } else if let Some(container_name) = container_name {
+        let running = fetch_container_state(runner, container_name);
+        if !running {
+            error!(
+                "Health check failed. Component: {} >> Container '{}' is not running.",
+                component_name, container_name
+            );
+        }
+        running
+    } else {
+        true
+    };
+
+    if healthy {
+        info!("Component {} is healthy.", component_name);
+    }
+
+    healthy
+}
+
 // NOTE: This may not work. It may refuse to copy and overwrite root owned files.
 //       Without the second 'set_file_permissions' the file at the destination would still be owned by root.
 //  ->Maybe switch to fs_extra?
@@ -191,9 +547,14 @@ fn restart_set_component_version(
  * Before copying the file, it sets the file permissions to root-owned then copies the file and
  *     tries setting the permissions provided by the cookbook.
  * This is in case we fail to set the correct permissions afterwards, the file is still root-owned.
+ * If `create_destination` is set (`create_destination` key of the instruction), the missing folders of the destination
+ *     are created first (see `create_destination_dir()`), otherwise they have to exist already.
+ * If `replace` is set, the file is copied next to the destination and renamed over it, for replacing a running
+ *     binary (NECO) which can't be overwritten in place.
  *
  * Returns `Ok(())` if the permission setting and file copying was successful.
  */
+#[allow(clippy::too_many_arguments)]
 fn digest_copy(
     absolute_update_path: &str,
     file_path: &str,
@@ -201,6 +562,8 @@ fn digest_copy(
     permission_user: &str,
     permission_group: &str,
     file_permissions: &str,
+    create_destination: bool,
+    replace: bool,
 ) -> Result<(), ()> {
     // Update file location
     let file_loc = [absolute_update_path, file_path].concat();
@@ -213,81 +576,560 @@ fn digest_copy(
         return Err(());
     }
 
-    if let Err(e) = copy(&file_loc, &cp_destination) {
+    if create_destination {
+        if let Some(dir_path) = Path::new(&cp_destination).parent() {
+            create_destination_dir(dir_path, permission_user, permission_group, file_permissions)?;
+        }
+    }
+
+    let copy_path = if replace {
+        [cp_destination.as_str(), ".new"].concat()
+    } else {
+        cp_destination.to_owned()
+    };
+
+    if let Err(e) = copy(&file_loc, &copy_path) {
         error!("Failed to digest copy command. {}", e);
         return Err(());
     }
 
     if set_file_permissions(
-        &cp_destination,
+        &copy_path,
         permission_user,
         permission_group,
         file_permissions,
     )
     .is_err()
     {
+        if replace {
+            let _ = remove_file(&copy_path);
+        }
         return Err(());
     }
 
+    if replace {
+        if let Err(e) = rename(&copy_path, &cp_destination) {
+            error!("Failed to replace '{}'. {}", cp_destination, e);
+            let _ = remove_file(&copy_path);
+            return Err(());
+        }
+    }
+
     debug!("Copied: from {} to {}.", &file_loc, &destination);
     Ok(())
 }
 
+/**
+ * Creates the missing folders of `dir_path`, outermost first.
+ * Every created folder gets the owner of the copied file and its `file_permissions`, with the execute bit added
+ *     wherever the read bit is set so the folder can be entered.
+ *
+ * Returns `Ok(())` if all the missing folders were created and their permissions set.
+ */
+fn create_destination_dir(
+    dir_path: &Path,
+    permission_user: &str,
+    permission_group: &str,
+    file_permissions: &str,
+) -> Result<(), ()> {
+    let mut missing_dirs: Vec<PathBuf> = dir_path
+        .ancestors()
+        .take_while(|dir| !dir.as_os_str().is_empty() && !dir.exists())
+        .map(Path::to_path_buf)
+        .collect();
+
+    if missing_dirs.is_empty() {
+        return Ok(());
+    }
+    missing_dirs.reverse();
+
+    let dir_permissions = get_dir_permissions(file_permissions)
+        .map_err(|e| error!("Failed to create the destination folder. {}", e))?;
+
+    for dir in missing_dirs {
+        let dir = dir.to_string_lossy();
+
+        if let Err(e) = create_dir(dir.as_ref()) {
+            error!("Failed to create the destination folder '{}'. {}", dir, e);
+            return Err(());
+        }
+        info!("Created destination folder '{}'.", dir);
+
+        set_file_permissions(&dir, permission_user, permission_group, &dir_permissions)?;
+    }
+
+    Ok(())
+}
+
+/**
+ * Returns the mode of a folder holding files with `file_permissions`: the execute bit is added wherever the read bit
+ *     is set, so the folder can be entered by whoever can read the files.
+ *
+ * Returns an error of kind `InvalidInput` if `file_permissions` is not a valid octal mode.
+ */
+fn get_dir_permissions(file_permissions: &str) -> Result<String, Error> {
+    let mode = parse_file_permissions(file_permissions)?;
+
+    Ok(format!("{:03o}", mode | ((mode & 0o444) >> 2)))
+}
+
+/**
+ * Sets the owner and mode of every file and folder copied by a `copy_dir` instruction, `dir_path` included.
+ * The files get `file_permissions`, the folders get `dir_permissions`, derived from `file_permissions`
+ *     if it isn't set (see `get_dir_permissions()`).
+ *
+ * Returns `Ok(())` if the permissions of everything were set.
+ */
+fn set_copied_dir_permissions(
+    dir_path: &str,
+    permission_user: &str,
+    permission_group: &str,
+    file_permissions: &str,
+    dir_permissions: Option<&str>,
+) -> Result<(), ()> {
+    let dir_permissions = match dir_permissions {
+        Some(dir_permissions) => dir_permissions.to_owned(),
+        None => get_dir_permissions(file_permissions)
+            .map_err(|e| error!("Failed to set the copied folder permissions. {}", e))?,
+    };
+
+    let content = fs_extra::dir::get_dir_content(dir_path)
+        .map_err(|e| error!("Failed to read the copied folder '{}'. {}", dir_path, e))?;
+
+    for dir in &content.directories {
+        set_file_permissions(dir, permission_user, permission_group, &dir_permissions)?;
+    }
+    for file in &content.files {
+        set_file_permissions(file, permission_user, permission_group, file_permissions)?;
+    }
+
+    debug!(
+        "Permissions set on {} folders and {} files in '{}'.",
+        content.directories.len(),
+        content.files.len(),
+        dir_path
+    );
+    Ok(())
+}
+
+/**
+ * Expands the `file_path` of a `copy` instruction if it's a glob pattern (e.g. `*.conf`), matched against the
+ *     files in `absolute_update_path`. The matches are relative to `absolute_update_path`, so the folder structure
+ *     is kept at the destination.
+ * A `file_path` without glob characters is returned as it is. A pattern that matches no files is logged.
+ *
+ * Returns an error of kind `InvalidInput` if the pattern is invalid.
+ */
+fn expand_file_path(absolute_update_path: &str, file_path: &str) -> Result<Vec<String>, Error> {
+    if !file_path.contains(&['*', '?', '['][..]) {
+        return Ok(vec![file_path.to_owned()]);
+    }
+
+    let pattern = [&glob::Pattern::escape(absolute_update_path), file_path].concat();
+    let paths = glob::glob(&pattern).map_err(|e| {
+        Error::new(
+            ErrorKind::InvalidInput,
+            format!("Invalid copy pattern '{}'. {}", file_path, e),
+        )
+    })?;
+
+    let file_paths: Vec<String> = paths
+        .filter_map(Result::ok)
+        .filter(|path| path.is_file())
+        .filter_map(|path| {
+            path.strip_prefix(absolute_update_path)
+                .ok()
+                .map(|path| path.to_string_lossy().into_owned())
+        })
+        .collect();
+
+    if file_paths.is_empty() {
+        warn!(
+            "Copy pattern '{}' did not match any files in '{}'.",
+            file_path, absolute_update_path
+        );
+    }
+
+    Ok(file_paths)
+}
+
+/**
+ * Checks that the NECO binary on `binary_path` is an executable file and that it reports its version with `--version`.
+ *
+ * Returns the reported version.
+ */
+fn check_neco_binary(runner: &dyn CommandRunner, binary_path: &str) -> Result<String, Error> {
+    let file = metadata(binary_path)?;
+    if !file.is_file() || file.permissions().mode() & 0o111 == 0 {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            format!("'{}' is not an executable file.", binary_path),
+        ));
+    }
+
+    let res = runner.output(Command::new(binary_path).arg("--version"))?;
+    let version = String::from_utf8_lossy(&res.stdout).trim().to_owned();
+
+    if !res.status.success() || version.is_empty() {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            format!("'{}' did not report its version.", binary_path),
+        ));
+    }
+
+    debug!("NECO binary '{}' reports version: {}", binary_path, version);
+    Ok(version)
+}
+
 /**
  * Processes the `copy directory` command in the update cookbook.
+ * If `dir_destination` exists, the folder is copied into it, otherwise the folder is copied as `dir_destination`.
  *
- * Returns `Ok(u64)` if the copy was successful.
+ * Returns the path of the copied folder if the copy was successful.
  */
-pub fn digest_copy_dir(
-    dir_loc: &str,
-    dir_destination: &str,
-) -> Result<u64, fs_extra::error::Error> {
+fn digest_copy_dir(dir_loc: &str, dir_destination: &str) -> Result<String, ()> {
     let mut cpy_options = fs_extra::dir::CopyOptions::new();
 
     cpy_options.copy_inside = true;
     //cpy_options.overwrite = true;
 
-    fs_extra::dir::copy(dir_loc, dir_destination, &cpy_options)
+    let copied_dir = match Path::new(dir_loc).file_name() {
+        Some(dir_name) if Path::new(dir_destination).exists() => {
+            Path::new(dir_destination).join(dir_name).to_string_lossy().into_owned()
+        }
+        _ => dir_destination.to_owned(),
+    };
+
+    if let Err(e) = fs_extra::dir::copy(dir_loc, dir_destination, &cpy_options) {
+        error!("Failed to digest copy_dir command. {}", e);
+        return Err(());
+    }
+
+    debug!("Copied: from {} to {}.", dir_loc, copied_dir);
+    Ok(copied_dir)
+}
+
+/**
+ * Returns the `timeout` (seconds) of the `instruction`, or `DEFAULT_INSTRUCTION_TIMEOUT` if it isn't set.
+ */
+fn get_instruction_timeout(instruction: &serde_json::Value) -> Duration {
+    Duration::from_secs(
+        instruction["timeout"]
+            .as_u64()
+            .unwrap_or(DEFAULT_INSTRUCTION_TIMEOUT),
+    )
 }
 
 /**
  * Processes the `run` command in the update cookbook.
- * The provided command is ran as a root user
+ * The provided command is ran as a root user, it is killed if it runs longer than `timeout`.
+ * The output of the command is appended to the `install_log`.
+ *
+ * Returns `false` if the command could not be executed, it timed out or it exited with a non-zero code.
  */
-fn digest_run(command: &str) {
-    match Command::new("sh").arg("-c").arg(command).output() {
+fn digest_run(runner: &dyn CommandRunner, command: &str, timeout: Duration, install_log: &mut String) -> bool {
+    match runner.output_timeout(Command::new("sh").arg("-c").arg(command), timeout) {
         Ok(res) => {
-            if !res.stderr.is_empty() {
-                error!(
-                    "Failed to digest run command. >> {}",
-                    String::from_utf8_lossy(&res.stderr)
-                );
+            append_command_output(install_log, command, &res);
+            if !res.status.success() {
+                error!("Failed to digest run command. >> {}", failure_reason(&res));
             }
+            res.status.success()
+        }
+        Err(e) if e.kind() == ErrorKind::TimedOut => {
+            error!("Command Digest: Command was killed, it ran longer than {} seconds. >> {}", timeout.as_secs(), command);
+            install_log.push_str(&format!("$ {}\n[killed after {} seconds]\n\n", command, timeout.as_secs()));
+            false
+        }
+        Err(e) => {
+            error!("Command Digest: Could not execute command. {}", e);
+            install_log.push_str(&format!("$ {}\n[could not execute: {}]\n\n", command, e));
+            false
         }
-        Err(e) => error!("Command Digest: Could not execute command. {}", e),
     }
 }
 
 /**
  * Processes the `script` command in the update cookbook.
- * The script is run as a root user.
+ * The script is run as a root user, it is killed if it runs longer than `timeout`.
+ * The output of the script is appended to the `install_log`.
+ *
+ * Returns `false` if the script could not be executed, it timed out or it exited with a non-zero code.
  */
-fn digest_script(absolute_update_path: &str, script_path: &str) {
+fn digest_script(
+    runner: &dyn CommandRunner,
+    absolute_update_path: &str,
+    script_path: &str,
+    timeout: Duration,
+    install_log: &mut String,
+) -> bool {
     //match Command::new(["/home/system/Desktop/", "test.sh"].concat()).output()
-    match Command::new([absolute_update_path, script_path].concat()).output() {
+    match runner.output_timeout(&mut Command::new([absolute_update_path, script_path].concat()), timeout) {
         Ok(res) => {
-            if res.stderr.is_empty() {
+            append_command_output(install_log, script_path, &res);
+            if res.status.success() {
                 debug!(
                     "Script exec success: {}",
                     script_path //String::from_utf8_lossy(&res.stdout)
                 );
             } else {
-                error!(
-                    "Failed to digest script command. >> {}",
-                    String::from_utf8_lossy(&res.stderr)
-                );
+                error!("Failed to digest script command. >> {}", failure_reason(&res));
+                return false;
             }
         }
-        Err(e) => error!("Script Digest: Could not execute command. {}", e),
+        Err(e) if e.kind() == ErrorKind::TimedOut => {
+            error!("Script Digest: Script was killed, it ran longer than {} seconds. >> {}", timeout.as_secs(), script_path);
+            install_log.push_str(&format!("$ {}\n[killed after {} seconds]\n\n", script_path, timeout.as_secs()));
+            return false;
+        }
+        Err(e) => {
+            error!("Script Digest: Could not execute command. {}", e);
+            install_log.push_str(&format!("$ {}\n[could not execute: {}]\n\n", script_path, e));
+            return false;
+        }
+    }
+
+    true
+}
+
+/**
+ * Appends the `command`, its exit code and its stdout and stderr to the `install_log`.
+ */
+fn append_command_output(install_log: &mut String, command: &str, output: &Output) {
+    let exit_code = match output.status.code() {
+        Some(code) => code.to_string(),
+        None => "killed by a signal".to_owned(),
+    };
+
+    install_log.push_str(&format!("$ {}\n[exit code: {}]\n", command, exit_code));
+
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    if !stdout.trim().is_empty() {
+        install_log.push_str(&format!("--- stdout ---\n{}\n", stdout.trim_end()));
+    }
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    if !stderr.trim().is_empty() {
+        install_log.push_str(&format!("--- stderr ---\n{}\n", stderr.trim_end()));
+    }
+
+    install_log.push('\n');
+}
+
+/**
+ * Writes the `install_log` of the component to `INSTALL_LOG_FOLDER`, replacing the log of the previous install.
+ */
+fn write_install_log(component_name: &str, version: &str, successful: bool, install_log: &str) -> Result<(), Error> {
+    let folder = [BASE_DIRECTORY, INSTALL_LOG_FOLDER].concat();
+    create_dir_all(&folder)?;
+
+    let header = format!(
+        "Component: {}\nVersion: {}\nInstalled: {}\nResult: {}\n\n",
+        component_name,
+        version,
+        chrono::Utc::now().format("%Y-%m-%d %H:%M:%S UTC"),
+        if successful { "SUCCESSFUL" } else { "FAILED" }
+    );
+
+    write([&folder, component_name, ".log"].concat(), [&header, install_log].concat())
+}
+
+/**
+ * Returns the log of the last install of the component.
+ */
+pub fn read_install_log(component_name: &str) -> Result<String, Error> {
+    read_to_string([BASE_DIRECTORY, INSTALL_LOG_FOLDER, component_name, ".log"].concat())
+}
+
+/**
+ * Checks the recipe on `recipe_path` the way `get_recipes()` and `cook()` would read it, without installing anything.
+ * Every instruction needs a known `type` and the fields that type reads, `copy` file permissions have to be a valid
+ *     octal mode and at least one instruction has to carry a valid version.
+ * Files referenced by `copy`/`run_script` instructions have to exist in `extracted_dir`, the folder the update
+ *     package is extracted to (defaults to the folder of the recipe). Only `copy` expands glob patterns,
+ *     `run_script` has to name a single script.
+ *
+ * Returns every problem found, the list is empty if the recipe is valid.
+ * Returns an error if the recipe can't be read or isn't a JSON array.
+ */
+pub fn validate_recipe(recipe_path: &str, extracted_dir: Option<&str>) -> Result<Vec<String>, Error> {
+    let recipe = read_to_string(recipe_path)?;
+    let instructions: Vec<serde_json::Value> = serde_json::from_str(&recipe).map_err(|e| {
+        Error::new(
+            ErrorKind::InvalidData,
+            format!("Recipe is not an array of instructions. {}", e),
+        )
+    })?;
+
+    // Same as the `absolute_update_path` of the instructions, it always ends with a slash
+    let extracted_dir = match extracted_dir {
+        Some(dir) => [dir.trim_end_matches('/'), "/"].concat(),
+        None => Path::new(recipe_path)
+            .parent()
+            .map(|dir| [&dir.to_string_lossy(), "/"].concat())
+            .unwrap_or_default(),
+    };
+
+    let mut problems: Vec<String> = Vec::new();
+    let mut has_version = false;
+
+    for (index, instruction) in instructions.iter().enumerate() {
+        let instruction_type = instruction["type"].as_str().unwrap_or_default();
+        let mut problem = |msg: String| problems.push(format!("Instruction {} ({}): {}", index, instruction_type, msg));
+
+        if !instruction.is_object() {
+            problem(String::from("not a JSON object."));
+            continue;
+        }
+
+        if let Some(version) = instruction.get("version") {
+            match version.as_str().map(semver::Version::parse) {
+                Some(Ok(_)) => has_version = true,
+                _ => problem(format!("'version' {} is not a valid semantic version.", version)),
+            }
+        }
+        if matches!(instruction.get("timeout"), Some(timeout) if !timeout.is_u64()) {
+            problem(String::from("'timeout' has to be a whole number of seconds."));
+        }
+        if matches!(instruction.get("restart"), Some(restart) if !restart.is_boolean()) {
+            problem(String::from("'restart' has to be true or false."));
+        }
+        if matches!(instruction.get("create_destination"), Some(create) if !create.is_boolean()) {
+            problem(String::from("'create_destination' has to be true or false."));
+        }
+
+        let (required, referenced_file): (&[&str], bool) = match instruction_type {
+            "copy" => (&["file_path", "destination"], true),
+            "copy_dir" => (&["folder_path", "destination"], false),
+            "run_command" => (&["command"], false),
+            "run_script" => (&["file_path"], true),
+            "" => {
+                problem(String::from("missing 'type'."));
+                continue;
+            }
+            _ => {
+                problem(String::from("unknown type."));
+                continue;
+            }
+        };
+
+        for field in required {
+            if instruction[*field].as_str().unwrap_or_default().is_empty() {
+                problem(format!("missing '{}'.", field));
+            }
+        }
+
+        if instruction_type == "copy" || instruction_type == "copy_dir" {
+            if let Some(file_permissions) = instruction.get("file_permissions") {
+                if let Err(e) = parse_file_permissions(file_permissions.as_str().unwrap_or_default()) {
+                    problem(e.to_string());
+                }
+            }
+        }
+        if instruction_type == "copy_dir" {
+            if let Some(dir_permissions) = instruction.get("dir_permissions") {
+                if let Err(e) = parse_file_permissions(dir_permissions.as_str().unwrap_or_default()) {
+                    problem(e.to_string());
+                }
+            }
+        }
+
+        if referenced_file {
+            if let Some(file_path) = instruction["file_path"].as_str().filter(|path| !path.is_empty()) {
+                // Scripts are run by their literal path, only `copy` expands glob patterns
+                if instruction_type == "run_script" && file_path.contains(&['*', '?', '['][..]) {
+                    problem(format!(
+                        "'{}' is a glob pattern, 'run_script' needs the path of a single script.",
+                        file_path
+                    ));
+                    continue;
+                }
+
+                match expand_file_path(&extracted_dir, file_path) {
+                    Ok(file_paths) if file_paths.is_empty() => {
+                        problem(format!("'{}' does not match any files in '{}'.", file_path, extracted_dir))
+                    }
+                    Ok(file_paths) => {
+                        if !file_paths.iter().all(|path| Path::new(&[extracted_dir.as_str(), path].concat()).is_file()) {
+                            problem(format!("'{}' does not exist in '{}'.", file_path, extracted_dir));
+                        }
+                    }
+                    Err(e) => problem(e.to_string()),
+                }
+            }
+        }
+    }
+
+    if !has_version {
+        problems.push(String::from("No instruction carries a version, the component would be skipped."));
+    }
+
+    Ok(problems)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::command_runner::FakeCommandRunner;
+
+    const TIMEOUT: Duration = Duration::from_secs(5);
+
+    #[test]
+    fn digest_run_succeeds_and_logs_the_output() {
+        let runner = FakeCommandRunner::default().respond(0, "restarted\n", "");
+        let mut install_log = String::new();
+
+        assert!(digest_run(&runner, "systemctl restart blackbox", TIMEOUT, &mut install_log));
+        assert_eq!(runner.commands(), vec!["sh -c systemctl restart blackbox"]);
+        assert_eq!(
+            install_log,
+            "$ systemctl restart blackbox\n[exit code: 0]\n--- stdout ---\nrestarted\n\n"
+        );
+    }
+
+    #[test]
+    fn digest_run_fails_on_a_non_zero_exit_code() {
+        let runner = FakeCommandRunner::default().respond(3, "", "unit not found\n");
+        let mut install_log = String::new();
+
+        assert!(!digest_run(&runner, "systemctl restart blackbox", TIMEOUT, &mut install_log));
+        assert!(install_log.contains("[exit code: 3]"), "{}", install_log);
+        assert!(install_log.contains("--- stderr ---\nunit not found\n"), "{}", install_log);
+    }
+
+    #[test]
+    fn digest_run_fails_if_the_command_times_out_or_cant_run() {
+        let runner = FakeCommandRunner::default().fail(ErrorKind::TimedOut).fail(ErrorKind::NotFound);
+        let mut install_log = String::new();
+
+        assert!(!digest_run(&runner, "sleep 10", TIMEOUT, &mut install_log));
+        assert!(install_log.contains("$ sleep 10\n[killed after 5 seconds]"), "{}", install_log);
+
+        assert!(!digest_run(&runner, "missing", TIMEOUT, &mut install_log));
+        assert!(install_log.contains("$ missing\n[could not execute: "), "{}", install_log);
+    }
+
+    #[test]
+    fn validate_recipe_expands_copy_globs_but_not_run_script_globs() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("install.sh"), "#!/bin/sh\n").unwrap();
+        let recipe_path = dir.path().join("recipe.json");
+        std::fs::write(
+            &recipe_path,
+            r#"[
+                {"type": "copy", "file_path": "*.sh", "destination": "/opt/blackbox/", "version": "1.0.0"},
+                {"type": "run_script", "file_path": "install.sh"},
+                {"type": "run_script", "file_path": "*.sh"}
+            ]"#,
+        )
+        .unwrap();
+
+        let problems = validate_recipe(&recipe_path.to_string_lossy(), None).unwrap();
+
+        assert_eq!(problems.len(), 1, "{:?}", problems);
+        assert!(
+            problems[0].contains("'*.sh' is a glob pattern"),
+            "{:?}",
+            problems
+        );
     }
 }
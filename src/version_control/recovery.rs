@@ -0,0 +1,132 @@
+use std::fs::{copy, metadata, read_to_string, remove_file, rename, write};
+use std::io::{Error, ErrorKind};
+use std::path::Path;
+
+use crate::BASE_DIRECTORY;
+
+// These live outside of the temporary folder, they have to survive the temporary folder purges
+const NECO_BACKUP_FILE: &str = "neco_backup";
+const UPGRADE_MARKER_FILE: &str = "neco_upgrade.pending";
+// Failed starts of an upgraded NECO after which the backup is restored
+const MAX_UPGRADE_START_ATTEMPTS: u32 = 3;
+
+/**
+ * Written when the NECO binary is replaced, removed once the new NECO starts up successfully.
+ */
+#[derive(Serialize, Deserialize, Debug)]
+struct UpgradeMarker {
+    binary_path: String,
+    backup_path: String,
+    start_attempts: u32,
+}
+
+/**
+ * Copies the current NECO binary on `binary_path` to the recovery path and writes the upgrade marker the next start checks.
+ * If there is no binary on `binary_path` yet (first install), there is nothing to back up.
+ */
+pub fn backup_neco_binary(binary_path: &str) -> Result<(), Error> {
+    if metadata(binary_path).is_err() {
+        warn!("No NECO binary at '{}' to back up.", binary_path);
+        return Ok(());
+    }
+
+    let backup_path = [BASE_DIRECTORY, NECO_BACKUP_FILE].concat();
+    copy(binary_path, &backup_path)?;
+
+    save_marker(&UpgradeMarker {
+        binary_path: binary_path.to_owned(),
+        backup_path: backup_path.to_owned(),
+        start_attempts: 0,
+    })?;
+
+    info!("Backed up the NECO binary to '{}'.", backup_path);
+    Ok(())
+}
+
+/**
+ * Puts the backed up NECO binary back in place and removes the upgrade marker.
+ * The backup is copied next to the binary and renamed over it, a running binary can't be overwritten in place.
+ */
+pub fn restore_neco_binary() -> Result<(), Error> {
+    let marker = load_marker()?;
+
+    let staging_path = [&marker.binary_path, ".restore"].concat();
+    copy(&marker.backup_path, &staging_path)?;
+    rename(&staging_path, &marker.binary_path)?;
+
+    remove_file([BASE_DIRECTORY, UPGRADE_MARKER_FILE].concat())?;
+
+    warn!("Restored the previous NECO binary to '{}'.", &marker.binary_path);
+    Ok(())
+}
+
+/**
+ * Counts the start attempts of an upgraded NECO, ran at startup before anything that could fail.
+ * After `MAX_UPGRADE_START_ATTEMPTS` starts that didn't reach `confirm_upgrade()`, the backed up binary is restored.
+ *
+ * Returns `true` if the backup was restored and NECO has to exit so it's started again with the previous binary.
+ */
+pub fn check_upgrade() -> bool {
+    let mut marker = match load_marker() {
+        Ok(marker) => marker,
+        Err(e) if e.kind() == ErrorKind::NotFound => return false,
+        Err(e) => {
+            error!("Could not read the NECO upgrade marker. {}", e);
+            return false;
+        }
+    };
+
+    marker.start_attempts += 1;
+
+    if marker.start_attempts > MAX_UPGRADE_START_ATTEMPTS {
+        error!(
+            "Upgraded NECO failed to start {} times. Restoring the previous binary...",
+            MAX_UPGRADE_START_ATTEMPTS
+        );
+
+        return match restore_neco_binary() {
+            Ok(()) => true,
+            Err(e) => {
+                error!("Could not restore the previous NECO binary. {}", e);
+                false
+            }
+        };
+    }
+
+    warn!(
+        "Starting an upgraded NECO. Attempt {} of {}.",
+        marker.start_attempts, MAX_UPGRADE_START_ATTEMPTS
+    );
+
+    if let Err(e) = save_marker(&marker) {
+        error!("Could not update the NECO upgrade marker. {}", e);
+    }
+
+    false
+}
+
+/**
+ * Removes the upgrade marker, the upgraded NECO started successfully and the backup is no longer needed for recovery.
+ */
+pub fn confirm_upgrade() {
+    let marker_path = [BASE_DIRECTORY, UPGRADE_MARKER_FILE].concat();
+
+    if Path::new(&marker_path).exists() {
+        match remove_file(&marker_path) {
+            Ok(()) => info!("NECO upgrade confirmed."),
+            Err(e) => error!("Could not remove the NECO upgrade marker. {}", e),
+        }
+    }
+}
+
+fn load_marker() -> Result<UpgradeMarker, Error> {
+    let contents = read_to_string([BASE_DIRECTORY, UPGRADE_MARKER_FILE].concat())?;
+
+    serde_json::from_str(&contents).map_err(|e| Error::new(ErrorKind::InvalidData, e))
+}
+
+fn save_marker(marker: &UpgradeMarker) -> Result<(), Error> {
+    let json = serde_json::to_string(marker).map_err(Error::other)?;
+
+    write([BASE_DIRECTORY, UPGRADE_MARKER_FILE].concat(), json)
+}
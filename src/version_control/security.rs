@@ -1,16 +1,19 @@
-use std::fs::File;
-use std::io::{BufReader, Error, Read};
-use std::process::Command;
+use std::fs::{read, set_permissions, File, Permissions};
+use std::io::{Error, ErrorKind, Read};
+use std::os::unix::fs::PermissionsExt;
 
-use data_encoding::HEXLOWER;
+use data_encoding::{BASE64, HEXLOWER};
+use nix::unistd::{chown, Group, User};
 use ring::digest::{Context, Digest, SHA256};
+use ring::signature::{UnparsedPublicKey, ED25519};
 
 /**
- * Calculates the sha256 hash from a provided file.
+ * Calculates the sha256 hash from a provided file, reading `buffer_size` bytes at a time.
  */
-fn sha256_digest<R: Read>(mut reader: R) -> Result<Digest, Error> {
+fn sha256_digest<R: Read>(mut reader: R, buffer_size: usize) -> Result<Digest, Error> {
     let mut context = Context::new(&SHA256);
-    let mut buffer = [0; 1024];
+    // A zero sized buffer would make every read return 0 and end the loop right away
+    let mut buffer = vec![0; buffer_size.max(1)];
 
     loop {
         let count = reader.read(&mut buffer)?;
@@ -25,13 +28,13 @@ fn sha256_digest<R: Read>(mut reader: R) -> Result<Digest, Error> {
 
 /**
  * Compares the calculated hash from the file on the `file_path` and the provided hash.
+ * The file is read `buffer_size` bytes at a time.
  *
  * Returns `Ok(())` if the hashes are identical.
  */
-pub fn compare_hash(file_path: &str, hash: &str) -> Result<(), Error> {
+pub fn compare_hash(file_path: &str, hash: &str, buffer_size: usize) -> Result<(), Error> {
     let input = File::open(file_path)?;
-    let reader = BufReader::new(input);
-    let digest = sha256_digest(reader)?;
+    let digest = sha256_digest(input, buffer_size)?;
 
     if HEXLOWER.encode(digest.as_ref()) == hash {
         return Ok(());
@@ -44,10 +47,54 @@ pub fn compare_hash(file_path: &str, hash: &str) -> Result<(), Error> {
 }
 
 /**
- * Runs `chmod` and `chown` with parameters from `permission_user`, `permission_group`, `file_permissions`.
- * Command `chmod` is the first to run, if it fails; command `chown` is never ran.
+ * Verifies the base64 encoded Ed25519 `signature` of the file on `file_path` with the base64 encoded Ed25519 `public_key`.
  *
- * Returns `Ok(())` if `stderr` from both commands is empty.
+ * Returns `Ok(())` if the signature is valid.
+ */
+pub fn verify_signature(file_path: &str, signature: &str, public_key: &str) -> Result<(), Error> {
+    let public_key = BASE64.decode(public_key.trim().as_bytes()).map_err(|e| {
+        Error::new(ErrorKind::InvalidInput, format!("Invalid public key. {}", e))
+    })?;
+    let signature = BASE64.decode(signature.trim().as_bytes()).map_err(|e| {
+        Error::new(ErrorKind::InvalidData, format!("Invalid signature. {}", e))
+    })?;
+
+    let contents = read(file_path)?;
+
+    UnparsedPublicKey::new(&ED25519, public_key)
+        .verify(&contents, &signature)
+        .map_err(|_| Error::new(ErrorKind::InvalidData, "Signature verification failed."))
+}
+
+/**
+ * Parses an octal file mode (3-4 digits, each 0-7), e.g. `644` or `0755`.
+ *
+ * Returns an error of kind `InvalidInput` if `file_permissions` is not a valid octal mode.
+ */
+pub fn parse_file_permissions(file_permissions: &str) -> Result<u32, Error> {
+    if (3..=4).contains(&file_permissions.len())
+        && file_permissions.chars().all(|c| ('0'..='7').contains(&c))
+    {
+        if let Ok(mode) = u32::from_str_radix(file_permissions, 8) {
+            return Ok(mode);
+        }
+    }
+
+    Err(Error::new(
+        ErrorKind::InvalidInput,
+        format!(
+            "Invalid file permissions '{}'. Expected an octal mode of 3-4 digits, e.g. '644'.",
+            file_permissions
+        ),
+    ))
+}
+
+/**
+ * Sets the mode (`file_permissions`) and owner (`permission_user`, `permission_group`) of the file at `file_loc`.
+ * The user and group names are resolved to their uid/gid and the permissions are set directly, without spawning `chmod`/`chown`.
+ * The mode is set first, if it fails; the ownership is never changed.
+ *
+ * Returns `Ok(())` if both the mode and the ownership were set.
  */
 pub fn set_file_permissions(
     file_loc: &str,
@@ -55,50 +102,92 @@ pub fn set_file_permissions(
     permission_group: &str,
     file_permissions: &str,
 ) -> Result<(), ()> {
-    match Command::new("chmod")
-        .arg(file_permissions)
-        .arg(file_loc)
-        .output()
-    {
-        Ok(res) => {
-            if res.stderr.is_empty() {
-                debug!("Update file permissions set.");
-            } else {
-                error!(
-                    "Failed to set update file permissions. {}",
-                    String::from_utf8_lossy(&res.stderr)
-                );
-
-                return Err(());
-            }
-        }
+    let mode = match parse_file_permissions(file_permissions) {
+        Ok(mode) => mode,
         Err(e) => {
-            error!("Could not run 'chmod'. {}", e);
+            error!("Failed to set update file permissions. {}", e);
             return Err(());
         }
+    };
+
+    if let Err(e) = set_permissions(file_loc, Permissions::from_mode(mode)) {
+        error!("Failed to set update file permissions. {}", e);
+        return Err(());
     }
+    debug!("Update file permissions set.");
 
-    match Command::new("chown")
-        .arg([permission_user, ":", permission_group].concat())
-        .arg(file_loc)
-        .output()
-    {
-        Ok(res) => {
-            if res.stderr.is_empty() {
-                debug!("Update file ownership set.");
-            } else {
-                error!(
-                    "Failed to set update file ownership. {}",
-                    String::from_utf8_lossy(&res.stderr)
-                );
-                return Err(());
-            }
+    let uid = match User::from_name(permission_user) {
+        Ok(Some(user)) => user.uid,
+        Ok(None) => {
+            error!("Failed to set update file ownership. Unknown user: {}", permission_user);
+            return Err(());
         }
         Err(e) => {
-            error!("Could not run 'chown'. {}", e);
+            error!("Could not look up user '{}'. {}", permission_user, e);
             return Err(());
         }
+    };
+
+    let gid = match Group::from_name(permission_group) {
+        Ok(Some(group)) => group.gid,
+        Ok(None) => {
+            error!("Failed to set update file ownership. Unknown group: {}", permission_group);
+            return Err(());
+        }
+        Err(e) => {
+            error!("Could not look up group '{}'. {}", permission_group, e);
+            return Err(());
+        }
+    };
+
+    if let Err(e) = chown(file_loc, Some(uid), Some(gid)) {
+        error!("Failed to set update file ownership. {}", e);
+        return Err(());
     }
+    debug!("Update file ownership set.");
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sha256_digest_is_independent_of_the_buffer_size() {
+        // Not a multiple of any of the buffer sizes, so the last read is a partial one
+        let data: Vec<u8> = (0..200_003u32).map(|x| (x % 251) as u8).collect();
+        let expected = HEXLOWER.encode(ring::digest::digest(&SHA256, &data).as_ref());
+
+        for buffer_size in &[1, 7, 4096, 65536] {
+            let digest = sha256_digest(data.as_slice(), *buffer_size).unwrap();
+            assert_eq!(HEXLOWER.encode(digest.as_ref()), expected, "buffer size {}", buffer_size);
+        }
+    }
+
+    #[test]
+    fn parse_file_permissions_accepts_octal_modes() {
+        assert_eq!(parse_file_permissions("644").unwrap(), 0o644);
+        assert_eq!(parse_file_permissions("700").unwrap(), 0o700);
+        assert_eq!(parse_file_permissions("000").unwrap(), 0);
+    }
+
+    #[test]
+    fn parse_file_permissions_accepts_four_digit_modes() {
+        assert_eq!(parse_file_permissions("0755").unwrap(), 0o755);
+        assert_eq!(parse_file_permissions("4755").unwrap(), 0o4755);
+    }
+
+    #[test]
+    fn parse_file_permissions_rejects_non_octal_modes() {
+        for mode in &["648", "9", "rwx", "0x1ed", "-644", " 644", "64", "07555"] {
+            let e = parse_file_permissions(mode).unwrap_err();
+            assert_eq!(e.kind(), ErrorKind::InvalidInput, "{}", mode);
+        }
+    }
+
+    #[test]
+    fn parse_file_permissions_rejects_an_empty_mode() {
+        assert_eq!(parse_file_permissions("").unwrap_err().kind(), ErrorKind::InvalidInput);
+    }
+}
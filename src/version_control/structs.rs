@@ -8,9 +8,87 @@ pub struct UpdateManifest {
 
 #[derive(Debug, Serialize, Deserialize, Clone, Default)]
 pub struct Update {
+    // A chainlink update can't be skipped, every later version of the component depends on it.
+    // If it fails to download or verify, the later updates of the component are not installed.
     pub chainlink: bool,
     pub checksum: String,
     pub version: String,
     pub changelog: String,
     pub file_size: Option<String>,
+    // Base64 Ed25519 signature of the package, required for NECO packages
+    #[serde(default)]
+    pub signature: Option<String>,
+}
+
+/**
+ * Changelogs of the updates in the update manifest, grouped by component.
+ * `text` is the legacy form, all changelogs concatenated into one blob.
+ */
+#[derive(Debug, Serialize, Default)]
+pub struct Changelogs {
+    pub components: BTreeMap<String, Vec<Changelog>>,
+    pub text: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Changelog {
+    pub version: String,
+    pub changelog: String,
+}
+
+/**
+ * A component whose version file doesn't match the version NECO recorded, see `reconcile_component_versions()`.
+ * A `None` version is unknown, it wasn't recorded or the version file can't be read.
+ */
+#[derive(Debug, Serialize, Clone, PartialEq)]
+pub struct VersionDrift {
+    pub component: String,
+    pub recorded: Option<String>,
+    pub actual: Option<String>,
+}
+
+/**
+ * Outcome of the update of a component, reported back to the Neutron server by `report_update_results()`.
+ */
+#[derive(Debug, Serialize, Clone, PartialEq)]
+pub struct UpdateResult {
+    pub component: String,
+    pub version: String, // Latest version of the component in the update manifest
+    pub success: bool,
+    pub error: Option<String>,
+}
+
+/**
+ * State of a component as reported by `get_component_states()`, derived from the `systemctl`/`docker` output.
+ */
+#[derive(Debug, Serialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum ComponentState {
+    Running,
+    Stopped,
+    Failed,
+    Paused,
+    Restarting,
+    Unknown,
+}
+
+/**
+ * Outcome of cooking the updates in a sandbox, see `test_update()`.
+ */
+#[derive(Debug, Serialize, Clone, PartialEq)]
+pub struct SandboxReport {
+    pub failed_components: Vec<String>,
+    pub entries: Vec<SandboxEntry>,
+}
+
+/**
+ * An instruction of a component cooked in a sandbox.
+ * `destination` is the path in the sandbox the `source` was copied to, `None` if the instruction was skipped.
+ */
+#[derive(Debug, Serialize, Clone, PartialEq)]
+pub struct SandboxEntry {
+    pub component: String,
+    pub instruction: String,
+    pub source: String,
+    pub destination: Option<String>,
 }